@@ -0,0 +1,93 @@
+//! The "fctools-agent" binary, which runs inside the guest and serves the agent protocol via an
+//! [AgentServer](fctools::extension::agent::server::AgentServer) on a vsock port or, mainly for testing, on a Unix socket.
+//! Each connection is served on its own thread.
+
+use std::{
+    fs::File,
+    io::{BufReader, Read, Write},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::net::UnixListener,
+    },
+    path::PathBuf,
+};
+
+use fctools::extension::agent::{server::AgentServer, DEFAULT_AGENT_PORT};
+use nix::sys::socket::{AddressFamily, Backlog, SockFlag, SockType, VsockAddr};
+
+/// The wildcard CID that accepts connections addressed to any CID of the guest.
+const VMADDR_CID_ANY: u32 = u32::MAX;
+
+fn main() {
+    let mut arguments = std::env::args().skip(1);
+    let mut vsock_port = DEFAULT_AGENT_PORT;
+    let mut unix_path = None;
+
+    while let Some(argument) = arguments.next() {
+        match argument.as_str() {
+            "--vsock-port" => {
+                vsock_port = next_value(&mut arguments, &argument)
+                    .parse()
+                    .expect("The vsock port is not a valid number")
+            }
+            "--unix" => unix_path = Some(PathBuf::from(next_value(&mut arguments, &argument))),
+            _ => panic!("Unknown argument: {argument}"),
+        }
+    }
+
+    let server = AgentServer::new();
+
+    match unix_path {
+        Some(unix_path) => {
+            let _ = std::fs::remove_file(&unix_path);
+            let listener = UnixListener::bind(&unix_path).expect("Could not bind the Unix socket");
+
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                let Ok(writer) = stream.try_clone() else {
+                    continue;
+                };
+                spawn_connection(&server, stream, writer);
+            }
+        }
+        None => {
+            let listener = bind_vsock(vsock_port).expect("Could not bind the vsock socket");
+
+            loop {
+                let Ok(fd) = nix::sys::socket::accept(listener.as_raw_fd()) else {
+                    continue;
+                };
+                let stream = File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+                let Ok(writer) = stream.try_clone() else {
+                    continue;
+                };
+                spawn_connection(&server, stream, writer);
+            }
+        }
+    }
+}
+
+fn bind_vsock(port: u32) -> Result<OwnedFd, nix::Error> {
+    let fd = nix::sys::socket::socket(AddressFamily::Vsock, SockType::Stream, SockFlag::SOCK_CLOEXEC, None)?;
+    nix::sys::socket::bind(fd.as_raw_fd(), &VsockAddr::new(VMADDR_CID_ANY, port))?;
+    nix::sys::socket::listen(&fd, Backlog::MAXCONN)?;
+    Ok(fd)
+}
+
+fn spawn_connection(server: &AgentServer, reader: impl Read + Send + 'static, writer: impl Write + Send + 'static) {
+    let server = server.clone();
+
+    std::thread::spawn(move || {
+        if let Err(err) = server.serve_connection(BufReader::new(reader), writer) {
+            eprintln!("An agent connection failed: {err}");
+        }
+    });
+}
+
+fn next_value(arguments: &mut impl Iterator<Item = String>, argument: &str) -> String {
+    arguments
+        .next()
+        .unwrap_or_else(|| panic!("A value must be provided for {argument}"))
+}
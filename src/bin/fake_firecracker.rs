@@ -0,0 +1,131 @@
+//! A fake "firecracker" binary that accepts the same arguments as Firecracker, serves a
+//! [MockApiServer](fctools::testing::server::MockApiServer) on the API socket and emulates a serial console over stdio.
+
+use std::{
+    io::{BufRead, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use fctools::testing::{
+    fault::MockFault,
+    server::{MockApiServer, MockVmmEvent},
+    MOCK_FAULTS_ARGUMENT, MOCK_FIRECRACKER_VERSION, MOCK_JAIL_ROOT_ARGUMENT,
+};
+
+fn main() {
+    let mut arguments = std::env::args().skip(1);
+    let mut server = MockApiServer::new();
+    let mut socket_path = PathBuf::from("/run/firecracker.socket");
+    let mut no_api = false;
+
+    while let Some(argument) = arguments.next() {
+        match argument.as_str() {
+            "--version" => {
+                println!("Firecracker v{MOCK_FIRECRACKER_VERSION}");
+                return;
+            }
+            "--api-sock" => socket_path = PathBuf::from(next_value(&mut arguments, &argument)),
+            "--no-api" => no_api = true,
+            "--id" => server = server.id(next_value(&mut arguments, &argument)),
+            "--config-file" => server = server.config_path(next_value(&mut arguments, &argument)),
+            "--log-path" => server = server.log_path(next_value(&mut arguments, &argument)),
+            "--metrics-path" => server = server.metrics_path(next_value(&mut arguments, &argument)),
+            "--metadata" => server = server.metadata_path(next_value(&mut arguments, &argument)),
+            "--level"
+            | "--module"
+            | "--http-api-max-payload-size"
+            | "--mmds-size-limit"
+            | "--seccomp-filter"
+            | "--start-time-us"
+            | "--start-time-cpu-us"
+            | "--parent-cpu-time-us" => {
+                next_value(&mut arguments, &argument);
+            }
+            "--show-log-origin" | "--show-level" | "--boot-timer" | "--no-seccomp" => {}
+            MOCK_JAIL_ROOT_ARGUMENT => {
+                // resolving relative to the jail as the working directory keeps socket paths within SUN_LEN, which
+                // absolute paths prefixed with the jail path could easily exceed
+                std::env::set_current_dir(next_value(&mut arguments, &argument))
+                    .unwrap_or_else(|err| fail(format!("Could not enter the jail: {err}")));
+                server = server.jail_root(".");
+            }
+            MOCK_FAULTS_ARGUMENT => {
+                let faults = serde_json::from_str::<Vec<MockFault>>(&next_value(&mut arguments, &argument))
+                    .unwrap_or_else(|err| fail(format!("Invalid mock faults: {err}")));
+                server = server.faults(faults);
+            }
+            _ => fail(format!("Found argument '{argument}' which wasn't expected")),
+        }
+    }
+
+    if !no_api {
+        server = server.socket_path(socket_path);
+    }
+
+    let serial_connected = Arc::new(AtomicBool::new(false));
+    let stdin_serial_connected = serial_connected.clone();
+    std::thread::spawn(move || run_serial_input(stdin_serial_connected));
+
+    let handle = server.start().unwrap_or_else(|err| fail(format!("Error: {err}")));
+
+    while let Some(event) = handle.recv_event() {
+        match event {
+            MockVmmEvent::Started => {
+                let boot_args = handle
+                    .state()
+                    .boot_source
+                    .and_then(|boot_source| boot_source.get("boot_args").cloned())
+                    .and_then(|boot_args| boot_args.as_str().map(str::to_owned))
+                    .unwrap_or_default();
+                let mut stdout = std::io::stdout().lock();
+                let _ = writeln!(stdout, "[    0.000000] Command line: {boot_args}");
+                let _ = writeln!(stdout, "[    0.010000] Artificially kick devices.");
+                let _ = writeln!(stdout, "\nfctools-mock login: root (automatic login)");
+                let _ = stdout.flush();
+                serial_connected.store(true, Ordering::Release);
+            }
+            MockVmmEvent::SnapshotLoaded => serial_connected.store(true, Ordering::Release),
+            MockVmmEvent::Exited(exit_code) => {
+                let _ = std::io::stdout().flush();
+                std::process::exit(exit_code);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Echo serial input back once the guest is "booted", emulating a shell, and exit when the guest is told to reboot.
+fn run_serial_input(serial_connected: Arc<AtomicBool>) {
+    for line in std::io::stdin().lock().lines() {
+        let Ok(line) = line else {
+            return;
+        };
+
+        if !serial_connected.load(Ordering::Acquire) {
+            continue;
+        }
+
+        let mut stdout = std::io::stdout().lock();
+        let _ = writeln!(stdout, "{line}");
+        let _ = stdout.flush();
+
+        if line.trim_end().ends_with("reboot") {
+            std::process::exit(0);
+        }
+    }
+}
+
+fn next_value(arguments: &mut impl Iterator<Item = String>, argument: &str) -> String {
+    arguments
+        .next()
+        .unwrap_or_else(|| fail(format!("Argument '{argument}' requires a value")))
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("{message}");
+    std::process::exit(1);
+}
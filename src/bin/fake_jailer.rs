@@ -0,0 +1,128 @@
+//! A fake "jailer" binary that accepts the same arguments as the Firecracker jailer. Instead of performing a chroot, it
+//! creates the jail directory and passes its path to the fake "firecracker" binary, which then resolves all paths
+//! relative to the jail. Cgroups, namespaces and resource limits are accepted but not applied.
+
+use std::{
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use fctools::testing::{MOCK_FIRECRACKER_VERSION, MOCK_JAIL_ROOT_ARGUMENT};
+
+fn main() {
+    let mut arguments = std::env::args().skip(1);
+    let mut exec_file = None;
+    let mut uid = None;
+    let mut gid = None;
+    let mut id = None;
+    let mut chroot_base_dir = PathBuf::from("/srv/jailer");
+    let mut daemonize = false;
+    let mut new_pid_ns = false;
+    let mut firecracker_arguments = Vec::new();
+
+    while let Some(argument) = arguments.next() {
+        match argument.as_str() {
+            "--version" => {
+                println!("Jailer v{MOCK_FIRECRACKER_VERSION}");
+                return;
+            }
+            "--exec-file" => exec_file = Some(PathBuf::from(next_value(&mut arguments, &argument))),
+            "--uid" => uid = Some(parse_id(next_value(&mut arguments, &argument))),
+            "--gid" => gid = Some(parse_id(next_value(&mut arguments, &argument))),
+            "--id" => id = Some(next_value(&mut arguments, &argument)),
+            "--chroot-base-dir" => chroot_base_dir = PathBuf::from(next_value(&mut arguments, &argument)),
+            "--daemonize" => daemonize = true,
+            "--new-pid-ns" => new_pid_ns = true,
+            "--cgroup" | "--cgroup-version" | "--netns" | "--parent-cgroup" | "--resource-limit" => {
+                next_value(&mut arguments, &argument);
+            }
+            "--" => firecracker_arguments.extend(arguments.by_ref()),
+            _ => fail(format!("Found argument '{argument}' which wasn't expected")),
+        }
+    }
+
+    let exec_file = exec_file.unwrap_or_else(|| fail("Missing argument: exec-file".to_string()));
+    let id = id.unwrap_or_else(|| fail("Missing argument: id".to_string()));
+    let (Some(uid), Some(gid)) = (uid, gid) else {
+        fail("Missing argument: uid or gid".to_string());
+    };
+
+    let exec_file_name = exec_file
+        .file_name()
+        .and_then(|file_name| file_name.to_str())
+        .unwrap_or_else(|| fail("Invalid exec file".to_string()))
+        .to_owned();
+    let jail_path = chroot_base_dir.join(&exec_file_name).join(&id).join("root");
+    std::fs::create_dir_all(&jail_path).unwrap_or_else(|err| fail(format!("Could not create the jail: {err}")));
+
+    let mut command = Command::new(&exec_file);
+    command
+        .arg("--id")
+        .arg(&id)
+        .args(firecracker_arguments)
+        .arg(MOCK_JAIL_ROOT_ARGUMENT)
+        .arg(&jail_path)
+        .current_dir(&jail_path);
+
+    // dropping privileges is only possible (and only needed) when running as root
+    if effective_uid() == 0 {
+        command.uid(uid).gid(gid);
+    }
+
+    if daemonize || new_pid_ns {
+        if daemonize {
+            command
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .process_group(0);
+        }
+
+        // the jailer exits right after spawning, leaving the "firecracker" process orphaned just like the real one does
+        #[allow(clippy::zombie_processes)]
+        let child = command
+            .spawn()
+            .unwrap_or_else(|err| fail(format!("Could not spawn the exec file: {err}")));
+        write_pid_file(&jail_path, &exec_file_name, child.id());
+        return;
+    }
+
+    let err = command.exec();
+    fail(format!("Could not exec the exec file: {err}"));
+}
+
+fn write_pid_file(jail_path: &Path, exec_file_name: &str, pid: u32) {
+    std::fs::write(jail_path.join(format!("{exec_file_name}.pid")), pid.to_string())
+        .unwrap_or_else(|err| fail(format!("Could not write the PID file: {err}")));
+}
+
+fn effective_uid() -> u32 {
+    std::fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix("Uid:"))
+                .and_then(|uids| uids.split_whitespace().nth(1).map(str::to_owned))
+        })
+        .and_then(|euid| euid.parse().ok())
+        .unwrap_or(u32::MAX)
+}
+
+fn parse_id(value: String) -> u32 {
+    value
+        .parse()
+        .unwrap_or_else(|_| fail(format!("Invalid uid or gid: {value}")))
+}
+
+fn next_value(arguments: &mut impl Iterator<Item = String>, argument: &str) -> String {
+    arguments
+        .next()
+        .unwrap_or_else(|| fail(format!("Argument '{argument}' requires a value")))
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("{message}");
+    std::process::exit(1);
+}
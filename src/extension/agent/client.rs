@@ -0,0 +1,548 @@
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    path::{Path, PathBuf},
+};
+
+use futures_io::{AsyncRead, AsyncWrite};
+
+use crate::{
+    extension::vsock_stream::{self, VsockStream, VsockStreamError},
+    process_spawner::ProcessSpawner,
+    runtime::Runtime,
+    vm::Vm,
+    vmm::executor::VmmExecutor,
+};
+
+use super::protocol::{
+    read_frame, write_frame, AgentProtocolError, AgentRequest, AgentResponse, AGENT_PROTOCOL_VERSION,
+};
+
+/// An error that can be emitted by the agent extension.
+#[derive(Debug)]
+pub enum AgentError {
+    VsockStreamError(VsockStreamError),
+    ProtocolError(AgentProtocolError),
+    ConnectionClosed,
+    VersionMismatch { version: u32 },
+    Remote { message: String },
+    UnexpectedResponse(AgentResponse),
+}
+
+impl std::error::Error for AgentError {}
+
+impl std::fmt::Display for AgentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AgentError::VsockStreamError(err) => write!(f, "Could not connect to the agent: {err}"),
+            AgentError::ProtocolError(err) => write!(f, "A protocol error occurred: {err}"),
+            AgentError::ConnectionClosed => write!(f, "The agent closed the connection"),
+            AgentError::VersionMismatch { version } => write!(
+                f,
+                "The agent uses protocol version {version}, while version {AGENT_PROTOCOL_VERSION} is expected"
+            ),
+            AgentError::Remote { message } => write!(f, "The agent returned an error: {message}"),
+            AgentError::UnexpectedResponse(response) => {
+                write!(f, "The agent sent an unexpected response: {response:?}")
+            }
+        }
+    }
+}
+
+/// A command to be executed by the agent. Executed processes inherit the environment of the agent, which the
+/// variables of the command override.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AgentCommand {
+    program: String,
+    args: Vec<String>,
+    env: BTreeMap<String, String>,
+    working_dir: Option<PathBuf>,
+}
+
+impl AgentCommand {
+    pub fn new(program: impl Into<String>) -> Self {
+        Self {
+            program: program.into(),
+            args: Vec::new(),
+            env: BTreeMap::new(),
+            working_dir: None,
+        }
+    }
+
+    pub fn arg(mut self, arg: impl Into<String>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    pub fn args(mut self, args: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn env(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.env.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn working_dir(mut self, working_dir: impl Into<PathBuf>) -> Self {
+        self.working_dir = Some(working_dir.into());
+        self
+    }
+}
+
+/// The exit status of a process executed by the agent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct AgentExitStatus {
+    /// The exit code, if the process exited normally.
+    pub code: Option<i32>,
+    /// The signal that terminated the process, if it didn't exit normally.
+    pub signal: Option<i32>,
+}
+
+impl AgentExitStatus {
+    /// Whether the process exited normally with a zero exit code.
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+}
+
+/// An event of a process executed by the agent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AgentProcessEvent {
+    Stdout(Vec<u8>),
+    Stderr(Vec<u8>),
+    Exited(AgentExitStatus),
+}
+
+/// The collected output of a process executed by the agent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AgentOutput {
+    pub status: AgentExitStatus,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+/// A connection to the agent over any byte stream, after a successful version handshake.
+#[derive(Debug)]
+pub struct AgentConnection<S: AsyncRead + AsyncWrite + Unpin + Send> {
+    stream: S,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> AgentConnection<S> {
+    /// Perform the version handshake over the given stream.
+    pub async fn handshake(mut stream: S) -> Result<Self, AgentError> {
+        write_frame(
+            &mut stream,
+            AgentRequest::Hello {
+                version: AGENT_PROTOCOL_VERSION,
+            },
+        )
+        .await
+        .map_err(AgentError::ProtocolError)?;
+
+        match read_response(&mut stream).await? {
+            AgentResponse::Hello { version } if version == AGENT_PROTOCOL_VERSION => Ok(Self { stream }),
+            AgentResponse::Hello { version } => Err(AgentError::VersionMismatch { version }),
+            response => Err(AgentError::UnexpectedResponse(response)),
+        }
+    }
+
+    /// Execute a command, dedicating this connection to the spawned process.
+    pub async fn exec(mut self, command: AgentCommand) -> Result<AgentProcess<S>, AgentError> {
+        let request = AgentRequest::Exec {
+            program: command.program,
+            args: command.args,
+            env: command.env,
+            working_dir: command.working_dir,
+        };
+
+        match self.request(request).await? {
+            AgentResponse::Started { pid } => Ok(AgentProcess {
+                stream: self.stream,
+                pid,
+                exit_status: None,
+            }),
+            response => Err(AgentError::UnexpectedResponse(response)),
+        }
+    }
+
+    /// Write the given content to a file in the guest, creating or truncating it and optionally setting its mode.
+    pub async fn put_file(
+        &mut self,
+        path: impl Into<PathBuf>,
+        content: Vec<u8>,
+        mode: Option<u32>,
+    ) -> Result<(), AgentError> {
+        let request = AgentRequest::PutFile {
+            path: path.into(),
+            mode,
+            content,
+        };
+        self.request_ok(request).await
+    }
+
+    /// Read the contents of a file in the guest.
+    pub async fn get_file(&mut self, path: impl Into<PathBuf>) -> Result<Vec<u8>, AgentError> {
+        match self.request(AgentRequest::GetFile { path: path.into() }).await? {
+            AgentResponse::FileContent { content } => Ok(content),
+            response => Err(AgentError::UnexpectedResponse(response)),
+        }
+    }
+
+    /// Get the environment that processes executed by the agent inherit.
+    pub async fn get_environment(&mut self) -> Result<BTreeMap<String, String>, AgentError> {
+        match self.request(AgentRequest::GetEnvironment).await? {
+            AgentResponse::Environment { variables } => Ok(variables),
+            response => Err(AgentError::UnexpectedResponse(response)),
+        }
+    }
+
+    /// Set and unset variables of the environment that processes executed by the agent inherit.
+    pub async fn set_environment(
+        &mut self,
+        set: BTreeMap<String, String>,
+        unset: Vec<String>,
+    ) -> Result<(), AgentError> {
+        self.request_ok(AgentRequest::SetEnvironment { set, unset }).await
+    }
+
+    /// Send a signal to an arbitrary process in the guest.
+    pub async fn signal_process(&mut self, pid: i32, signal: i32) -> Result<(), AgentError> {
+        self.request_ok(AgentRequest::SignalProcess { pid, signal }).await
+    }
+
+    /// Get the underlying stream of this connection.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    async fn request(&mut self, request: AgentRequest) -> Result<AgentResponse, AgentError> {
+        write_frame(&mut self.stream, request)
+            .await
+            .map_err(AgentError::ProtocolError)?;
+        read_response(&mut self.stream).await
+    }
+
+    async fn request_ok(&mut self, request: AgentRequest) -> Result<(), AgentError> {
+        match self.request(request).await? {
+            AgentResponse::Ok => Ok(()),
+            response => Err(AgentError::UnexpectedResponse(response)),
+        }
+    }
+}
+
+/// A process executed by the agent, whose output is streamed over the connection dedicated to it.
+#[derive(Debug)]
+pub struct AgentProcess<S: AsyncRead + AsyncWrite + Unpin + Send> {
+    stream: S,
+    pid: i32,
+    exit_status: Option<AgentExitStatus>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> AgentProcess<S> {
+    /// Get the PID of the process inside the guest.
+    pub fn pid(&self) -> i32 {
+        self.pid
+    }
+
+    /// Receive the next event of the process, or [None] once it has exited and its exit event was received.
+    pub async fn next_event(&mut self) -> Result<Option<AgentProcessEvent>, AgentError> {
+        if self.exit_status.is_some() {
+            return Ok(None);
+        }
+
+        match read_response(&mut self.stream).await? {
+            AgentResponse::Stdout { data } => Ok(Some(AgentProcessEvent::Stdout(data))),
+            AgentResponse::Stderr { data } => Ok(Some(AgentProcessEvent::Stderr(data))),
+            AgentResponse::Exited { code, signal } => {
+                let exit_status = AgentExitStatus { code, signal };
+                self.exit_status = Some(exit_status);
+                Ok(Some(AgentProcessEvent::Exited(exit_status)))
+            }
+            response => Err(AgentError::UnexpectedResponse(response)),
+        }
+    }
+
+    /// Write data to the stdin of the process.
+    pub async fn write_stdin(&mut self, data: Vec<u8>) -> Result<(), AgentError> {
+        write_frame(&mut self.stream, AgentRequest::Stdin { data })
+            .await
+            .map_err(AgentError::ProtocolError)
+    }
+
+    /// Close the stdin of the process.
+    pub async fn close_stdin(&mut self) -> Result<(), AgentError> {
+        write_frame(&mut self.stream, AgentRequest::CloseStdin)
+            .await
+            .map_err(AgentError::ProtocolError)
+    }
+
+    /// Send a signal to the process. A failure to deliver it is reported by the agent as an error in place of the
+    /// next event.
+    pub async fn signal(&mut self, signal: i32) -> Result<(), AgentError> {
+        write_frame(&mut self.stream, AgentRequest::Signal { signal })
+            .await
+            .map_err(AgentError::ProtocolError)
+    }
+
+    /// Wait for the process to exit, collecting all of its remaining output.
+    pub async fn wait_with_output(mut self) -> Result<AgentOutput, AgentError> {
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+
+        while let Some(event) = self.next_event().await? {
+            match event {
+                AgentProcessEvent::Stdout(data) => stdout.extend(data),
+                AgentProcessEvent::Stderr(data) => stderr.extend(data),
+                AgentProcessEvent::Exited(_) => {}
+            }
+        }
+
+        Ok(AgentOutput {
+            status: self
+                .exit_status
+                .expect("No exit status was received after the last event"),
+            stdout,
+            stderr,
+        })
+    }
+}
+
+/// An extension that allows the host to interact with the agent running inside the guest, with each operation
+/// establishing a new vsock connection to the agent on the given guest port.
+pub trait AgentExt<R: Runtime> {
+    /// Connect to the agent and perform the version handshake, allowing multiple operations over one connection.
+    fn agent_connect(
+        &self,
+        port: u32,
+    ) -> impl Future<Output = Result<AgentConnection<VsockStream<R>>, AgentError>> + Send;
+
+    /// Execute a command via the agent, streaming the events of the spawned process.
+    fn agent_exec(
+        &self,
+        port: u32,
+        command: AgentCommand,
+    ) -> impl Future<Output = Result<AgentProcess<VsockStream<R>>, AgentError>> + Send;
+
+    /// Execute a command via the agent and wait for it to exit, collecting its output.
+    fn agent_exec_output(
+        &self,
+        port: u32,
+        command: AgentCommand,
+    ) -> impl Future<Output = Result<AgentOutput, AgentError>> + Send;
+
+    /// Write a file inside the guest via the agent.
+    fn agent_put_file(
+        &self,
+        port: u32,
+        path: impl Into<PathBuf> + Send,
+        content: Vec<u8>,
+        mode: Option<u32>,
+    ) -> impl Future<Output = Result<(), AgentError>> + Send;
+
+    /// Read a file inside the guest via the agent.
+    fn agent_get_file(
+        &self,
+        port: u32,
+        path: impl Into<PathBuf> + Send,
+    ) -> impl Future<Output = Result<Vec<u8>, AgentError>> + Send;
+
+    /// Get the environment of the agent that executed processes inherit.
+    fn agent_get_environment(
+        &self,
+        port: u32,
+    ) -> impl Future<Output = Result<BTreeMap<String, String>, AgentError>> + Send;
+
+    /// Set and unset variables of the environment of the agent that executed processes inherit.
+    fn agent_set_environment(
+        &self,
+        port: u32,
+        set: BTreeMap<String, String>,
+        unset: Vec<String>,
+    ) -> impl Future<Output = Result<(), AgentError>> + Send;
+
+    /// Send a signal to a process inside the guest via the agent.
+    fn agent_signal_process(
+        &self,
+        port: u32,
+        pid: i32,
+        signal: i32,
+    ) -> impl Future<Output = Result<(), AgentError>> + Send;
+}
+
+impl<E: VmmExecutor, S: ProcessSpawner, R: Runtime> AgentExt<R> for Vm<E, S, R> {
+    async fn agent_connect(&self, port: u32) -> Result<AgentConnection<VsockStream<R>>, AgentError> {
+        let socket_path = vsock_stream::get_socket_path(self).map_err(AgentError::VsockStreamError)?;
+        connect(&self.runtime, socket_path, port).await
+    }
+
+    async fn agent_exec(&self, port: u32, command: AgentCommand) -> Result<AgentProcess<VsockStream<R>>, AgentError> {
+        self.agent_connect(port).await?.exec(command).await
+    }
+
+    async fn agent_exec_output(&self, port: u32, command: AgentCommand) -> Result<AgentOutput, AgentError> {
+        self.agent_exec(port, command).await?.wait_with_output().await
+    }
+
+    async fn agent_put_file(
+        &self,
+        port: u32,
+        path: impl Into<PathBuf> + Send,
+        content: Vec<u8>,
+        mode: Option<u32>,
+    ) -> Result<(), AgentError> {
+        self.agent_connect(port).await?.put_file(path, content, mode).await
+    }
+
+    async fn agent_get_file(&self, port: u32, path: impl Into<PathBuf> + Send) -> Result<Vec<u8>, AgentError> {
+        self.agent_connect(port).await?.get_file(path).await
+    }
+
+    async fn agent_get_environment(&self, port: u32) -> Result<BTreeMap<String, String>, AgentError> {
+        self.agent_connect(port).await?.get_environment().await
+    }
+
+    async fn agent_set_environment(
+        &self,
+        port: u32,
+        set: BTreeMap<String, String>,
+        unset: Vec<String>,
+    ) -> Result<(), AgentError> {
+        self.agent_connect(port).await?.set_environment(set, unset).await
+    }
+
+    async fn agent_signal_process(&self, port: u32, pid: i32, signal: i32) -> Result<(), AgentError> {
+        self.agent_connect(port).await?.signal_process(pid, signal).await
+    }
+}
+
+async fn connect<R: Runtime>(
+    runtime: &R,
+    socket_path: &Path,
+    port: u32,
+) -> Result<AgentConnection<VsockStream<R>>, AgentError> {
+    let stream = vsock_stream::connect(runtime, socket_path, port)
+        .await
+        .map_err(AgentError::VsockStreamError)?;
+    AgentConnection::handshake(stream).await
+}
+
+async fn read_response(stream: &mut (impl AsyncRead + Unpin)) -> Result<AgentResponse, AgentError> {
+    match read_frame(stream).await.map_err(AgentError::ProtocolError)? {
+        Some(AgentResponse::Error { message }) => Err(AgentError::Remote { message }),
+        Some(response) => Ok(response),
+        None => Err(AgentError::ConnectionClosed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+
+    use crate::extension::agent::server::AgentServer;
+
+    use super::{AgentCommand, AgentConnection, AgentError, AgentProcessEvent};
+
+    async fn connect(server: &AgentServer) -> AgentConnection<Compat<tokio::net::UnixStream>> {
+        let (client, guest) = std::os::unix::net::UnixStream::pair().unwrap();
+        let server = server.clone();
+        std::thread::spawn(move || {
+            let writer = guest.try_clone().unwrap();
+            server.serve_connection(guest, writer).unwrap();
+        });
+
+        client.set_nonblocking(true).unwrap();
+        let client = tokio::net::UnixStream::from_std(client).unwrap().compat();
+        AgentConnection::handshake(client).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn agent_client_and_server_can_transfer_files_and_environment() {
+        let server = AgentServer::new();
+        let mut connection = connect(&server).await;
+        let path = std::env::temp_dir().join(format!("fctools-agent-{}", rand::random::<u32>()));
+
+        connection
+            .put_file(&path, b"content".to_vec(), Some(0o600))
+            .await
+            .unwrap();
+        assert_eq!(connection.get_file(&path).await.unwrap(), b"content");
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            connection.get_file(&path).await,
+            Err(AgentError::Remote { .. })
+        ));
+
+        connection
+            .set_environment(
+                BTreeMap::from([("AGENT_VAR".to_string(), "value".to_string())]),
+                Vec::new(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            connection.get_environment().await.unwrap().get("AGENT_VAR").unwrap(),
+            "value"
+        );
+        connection
+            .set_environment(BTreeMap::new(), vec!["AGENT_VAR".to_string()])
+            .await
+            .unwrap();
+        assert!(!connection.get_environment().await.unwrap().contains_key("AGENT_VAR"));
+    }
+
+    #[tokio::test]
+    async fn agent_client_and_server_can_exec_with_stdin_and_environment() {
+        let server = AgentServer::new();
+        connect(&server)
+            .await
+            .set_environment(BTreeMap::from([("INHERITED".to_string(), "a".to_string())]), Vec::new())
+            .await
+            .unwrap();
+
+        let mut process = connect(&server)
+            .await
+            .exec(
+                AgentCommand::new("sh")
+                    .arg("-c")
+                    .arg("read line; echo $INHERITED $OWN $line; echo error >&2; exit 3")
+                    .env("OWN", "b"),
+            )
+            .await
+            .unwrap();
+        assert!(process.pid() > 0);
+        process.write_stdin(b"c\n".to_vec()).await.unwrap();
+        process.close_stdin().await.unwrap();
+
+        let output = process.wait_with_output().await.unwrap();
+        assert_eq!(output.stdout, b"a b c\n");
+        assert_eq!(output.stderr, b"error\n");
+        assert_eq!(output.status.code, Some(3));
+        assert!(!output.status.success());
+    }
+
+    #[tokio::test]
+    async fn agent_client_and_server_can_signal_executed_process() {
+        let server = AgentServer::new();
+        let mut process = connect(&server)
+            .await
+            .exec(AgentCommand::new("sleep").arg("10"))
+            .await
+            .unwrap();
+        process.signal(15).await.unwrap();
+
+        let event = process.next_event().await.unwrap().unwrap();
+        let AgentProcessEvent::Exited(status) = event else {
+            panic!("Expected an exit event, got {event:?}");
+        };
+        assert_eq!(status.signal, Some(15));
+        assert!(process.next_event().await.unwrap().is_none());
+
+        assert!(matches!(
+            connect(&server).await.exec(AgentCommand::new("/nonexistent")).await,
+            Err(AgentError::Remote { .. })
+        ));
+    }
+}
@@ -0,0 +1,20 @@
+//! A guest agent that allows the host to execute processes, transfer files, manage the environment and send signals
+//! inside the guest over a versioned request/response protocol carried by a vsock connection.
+//!
+//! The guest side is the [server::AgentServer], available with the `agent-server` feature and shipped as the
+//! "fctools-agent" binary. It only uses the standard library with blocking I/O, so it can be built as a small static
+//! binary (i.e. for the `x86_64-unknown-linux-musl` target with `--no-default-features --features
+//! syscall-nix,agent-server`) and placed into a guest root filesystem. The host side is the [client::AgentExt]
+//! extension, available with the `agent-extension` feature. Both sides work with any byte stream, so they can be
+//! tested against each other without a VM.
+
+pub mod protocol;
+
+pub mod server;
+
+#[cfg(feature = "agent-extension")]
+#[cfg_attr(docsrs, doc(cfg(feature = "agent-extension")))]
+pub mod client;
+
+/// The guest vsock port that the agent listens on by default.
+pub const DEFAULT_AGENT_PORT: u32 = 10000;
@@ -0,0 +1,259 @@
+//! The wire format of the agent protocol. Every message is sent as a frame consisting of a header with the big-endian
+//! 32-bit lengths of the JSON-encoded message and of its binary payload, followed by the message and the payload.
+//! Binary data, such as process output and file contents, is carried in the payload to avoid encoding it as JSON.
+
+use std::{
+    collections::BTreeMap,
+    io::{Read, Write},
+    path::PathBuf,
+};
+
+use futures_util::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// The version of the agent protocol implemented by this crate. Both sides of a connection must use the same version.
+pub const AGENT_PROTOCOL_VERSION: u32 = 1;
+
+/// The maximum length of the JSON-encoded message of a frame.
+pub const MAX_MESSAGE_LENGTH: u32 = 1024 * 1024;
+
+/// The maximum length of the binary payload of a frame, which also limits the size of transferred files.
+pub const MAX_PAYLOAD_LENGTH: u32 = 64 * 1024 * 1024;
+
+/// A request sent from the host to the agent.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AgentRequest {
+    /// Must be the first request of every connection.
+    Hello { version: u32 },
+    /// Execute a process. The connection is dedicated to the process afterwards, only accepting [AgentRequest::Stdin],
+    /// [AgentRequest::CloseStdin] and [AgentRequest::Signal] requests.
+    Exec {
+        program: String,
+        args: Vec<String>,
+        env: BTreeMap<String, String>,
+        working_dir: Option<PathBuf>,
+    },
+    /// Write the payload to the stdin of the executed process.
+    Stdin {
+        #[serde(skip)]
+        data: Vec<u8>,
+    },
+    /// Close the stdin of the executed process.
+    CloseStdin,
+    /// Send a signal to the executed process.
+    Signal { signal: i32 },
+    /// Send a signal to an arbitrary process in the guest.
+    SignalProcess { pid: i32, signal: i32 },
+    /// Write the payload to a file, creating or truncating it.
+    PutFile {
+        path: PathBuf,
+        mode: Option<u32>,
+        #[serde(skip)]
+        content: Vec<u8>,
+    },
+    /// Read the contents of a file.
+    GetFile { path: PathBuf },
+    /// Get the environment that executed processes inherit.
+    GetEnvironment,
+    /// Set and unset variables of the environment that executed processes inherit.
+    SetEnvironment {
+        set: BTreeMap<String, String>,
+        unset: Vec<String>,
+    },
+}
+
+/// A response sent from the agent to the host.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AgentResponse {
+    /// The response to [AgentRequest::Hello].
+    Hello { version: u32 },
+    /// The request succeeded without producing any data.
+    Ok,
+    /// The request failed.
+    Error { message: String },
+    /// The executed process was spawned.
+    Started { pid: i32 },
+    /// A chunk of the stdout of the executed process.
+    Stdout {
+        #[serde(skip)]
+        data: Vec<u8>,
+    },
+    /// A chunk of the stderr of the executed process.
+    Stderr {
+        #[serde(skip)]
+        data: Vec<u8>,
+    },
+    /// The executed process exited, which is the last response for it.
+    Exited { code: Option<i32>, signal: Option<i32> },
+    /// The contents of a file.
+    FileContent {
+        #[serde(skip)]
+        content: Vec<u8>,
+    },
+    /// The environment that executed processes inherit.
+    Environment { variables: BTreeMap<String, String> },
+}
+
+/// A message that can be sent as a frame, with an optional binary payload.
+pub trait AgentMessage: Serialize + DeserializeOwned {
+    /// Get a mutable reference to the payload of the message, if it has one.
+    fn payload_mut(&mut self) -> Option<&mut Vec<u8>>;
+}
+
+impl AgentMessage for AgentRequest {
+    fn payload_mut(&mut self) -> Option<&mut Vec<u8>> {
+        match self {
+            AgentRequest::Stdin { data } => Some(data),
+            AgentRequest::PutFile { content, .. } => Some(content),
+            _ => None,
+        }
+    }
+}
+
+impl AgentMessage for AgentResponse {
+    fn payload_mut(&mut self) -> Option<&mut Vec<u8>> {
+        match self {
+            AgentResponse::Stdout { data } | AgentResponse::Stderr { data } => Some(data),
+            AgentResponse::FileContent { content } => Some(content),
+            _ => None,
+        }
+    }
+}
+
+/// An error that can occur when reading or writing a frame.
+#[derive(Debug)]
+pub enum AgentProtocolError {
+    IoError(std::io::Error),
+    SerdeError(serde_json::Error),
+    FrameTooLarge { length: u32, max_length: u32 },
+    PayloadNotExpected,
+}
+
+impl std::error::Error for AgentProtocolError {}
+
+impl std::fmt::Display for AgentProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AgentProtocolError::IoError(err) => write!(f, "An I/O error occurred on the agent connection: {err}"),
+            AgentProtocolError::SerdeError(err) => write!(f, "An agent message could not be (de)serialized: {err}"),
+            AgentProtocolError::FrameTooLarge { length, max_length } => {
+                write!(
+                    f,
+                    "A frame part of {length} bytes exceeds the maximum of {max_length} bytes"
+                )
+            }
+            AgentProtocolError::PayloadNotExpected => write!(f, "A payload was sent for a message that has none"),
+        }
+    }
+}
+
+/// Asynchronously write a message as a frame, taking its payload out of it.
+pub async fn write_frame<M: AgentMessage>(
+    writer: &mut (impl AsyncWrite + Unpin),
+    mut message: M,
+) -> Result<(), AgentProtocolError> {
+    let frame = encode_frame(&mut message)?;
+    writer.write_all(&frame).await.map_err(AgentProtocolError::IoError)?;
+    writer.flush().await.map_err(AgentProtocolError::IoError)
+}
+
+/// Asynchronously read a frame and decode its message, or return [None] if the connection was closed at a frame boundary.
+pub async fn read_frame<M: AgentMessage>(
+    reader: &mut (impl AsyncRead + Unpin),
+) -> Result<Option<M>, AgentProtocolError> {
+    let mut header = [0; 8];
+    match reader.read_exact(&mut header).await {
+        Ok(()) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(AgentProtocolError::IoError(err)),
+    }
+
+    let (message_length, payload_length) = decode_header(header)?;
+    let mut message = vec![0; message_length];
+    reader
+        .read_exact(&mut message)
+        .await
+        .map_err(AgentProtocolError::IoError)?;
+    let mut payload = vec![0; payload_length];
+    reader
+        .read_exact(&mut payload)
+        .await
+        .map_err(AgentProtocolError::IoError)?;
+
+    decode_message(&message, payload).map(Some)
+}
+
+/// Synchronously write a message as a frame, taking its payload out of it.
+pub fn write_frame_blocking<M: AgentMessage>(
+    writer: &mut impl Write,
+    mut message: M,
+) -> Result<(), AgentProtocolError> {
+    let frame = encode_frame(&mut message)?;
+    writer.write_all(&frame).map_err(AgentProtocolError::IoError)?;
+    writer.flush().map_err(AgentProtocolError::IoError)
+}
+
+/// Synchronously read a frame and decode its message, or return [None] if the connection was closed at a frame
+/// boundary.
+pub fn read_frame_blocking<M: AgentMessage>(reader: &mut impl Read) -> Result<Option<M>, AgentProtocolError> {
+    let mut header = [0; 8];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(AgentProtocolError::IoError(err)),
+    }
+
+    let (message_length, payload_length) = decode_header(header)?;
+    let mut message = vec![0; message_length];
+    reader.read_exact(&mut message).map_err(AgentProtocolError::IoError)?;
+    let mut payload = vec![0; payload_length];
+    reader.read_exact(&mut payload).map_err(AgentProtocolError::IoError)?;
+
+    decode_message(&message, payload).map(Some)
+}
+
+fn encode_frame<M: AgentMessage>(message: &mut M) -> Result<Vec<u8>, AgentProtocolError> {
+    let payload = message.payload_mut().map(std::mem::take).unwrap_or_default();
+    let encoded_message = serde_json::to_vec(message).map_err(AgentProtocolError::SerdeError)?;
+    let message_length = check_length(encoded_message.len(), MAX_MESSAGE_LENGTH)?;
+    let payload_length = check_length(payload.len(), MAX_PAYLOAD_LENGTH)?;
+
+    let mut frame = Vec::with_capacity(8 + encoded_message.len() + payload.len());
+    frame.extend_from_slice(&message_length.to_be_bytes());
+    frame.extend_from_slice(&payload_length.to_be_bytes());
+    frame.extend_from_slice(&encoded_message);
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+fn decode_header(header: [u8; 8]) -> Result<(usize, usize), AgentProtocolError> {
+    let message_length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+    let payload_length = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+    check_length(message_length as usize, MAX_MESSAGE_LENGTH)?;
+    check_length(payload_length as usize, MAX_PAYLOAD_LENGTH)?;
+    Ok((message_length as usize, payload_length as usize))
+}
+
+fn decode_message<M: AgentMessage>(message: &[u8], payload: Vec<u8>) -> Result<M, AgentProtocolError> {
+    let mut message = serde_json::from_slice::<M>(message).map_err(AgentProtocolError::SerdeError)?;
+
+    match message.payload_mut() {
+        Some(message_payload) => *message_payload = payload,
+        None if !payload.is_empty() => return Err(AgentProtocolError::PayloadNotExpected),
+        None => {}
+    }
+
+    Ok(message)
+}
+
+fn check_length(length: usize, max_length: u32) -> Result<u32, AgentProtocolError> {
+    match u32::try_from(length) {
+        Ok(length) if length <= max_length => Ok(length),
+        _ => Err(AgentProtocolError::FrameTooLarge {
+            length: u32::try_from(length).unwrap_or(u32::MAX),
+            max_length,
+        }),
+    }
+}
@@ -0,0 +1,233 @@
+//! The reference implementation of the agent, which runs inside the guest. It only relies on the standard library and
+//! blocking I/O, serving each connection on its own thread, so that it stays small enough to be shipped as a static
+//! binary inside guest root filesystems.
+
+use std::{
+    collections::BTreeMap,
+    io::{Read, Write},
+    os::unix::{fs::PermissionsExt, process::ExitStatusExt},
+    process::{Command, Stdio},
+    sync::{Arc, Mutex},
+};
+
+use crate::syscall;
+
+use super::protocol::{
+    read_frame_blocking, write_frame_blocking, AgentProtocolError, AgentRequest, AgentResponse, AGENT_PROTOCOL_VERSION,
+};
+
+/// A guest-side agent server. The environment of the server, which executed processes inherit, is shared between all
+/// clones of the server.
+#[derive(Debug, Clone)]
+pub struct AgentServer {
+    environment: Arc<Mutex<BTreeMap<String, String>>>,
+}
+
+impl Default for AgentServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AgentServer {
+    /// Create an [AgentServer] with an environment initialized from the environment of the current process.
+    pub fn new() -> Self {
+        Self {
+            environment: Arc::new(Mutex::new(std::env::vars().collect())),
+        }
+    }
+
+    /// Serve a single connection, given as its reading and writing halves, until the client closes it. A connection
+    /// that executes a process is served until the client closes it as well, but the process keeps running if the
+    /// connection is closed before it exits.
+    pub fn serve_connection<W: Write + Send + 'static>(
+        &self,
+        mut reader: impl Read,
+        writer: W,
+    ) -> Result<(), AgentProtocolError> {
+        let writer = Arc::new(Mutex::new(writer));
+
+        match read_frame_blocking(&mut reader)? {
+            Some(AgentRequest::Hello { version }) if version == AGENT_PROTOCOL_VERSION => {
+                write_response(&writer, AgentResponse::Hello { version })?;
+            }
+            Some(AgentRequest::Hello { version }) => {
+                return write_response(
+                    &writer,
+                    error(format!("The protocol version {version} is not supported")),
+                );
+            }
+            Some(_) => return write_response(&writer, error("The first request must be a hello")),
+            None => return Ok(()),
+        }
+
+        while let Some(request) = read_frame_blocking(&mut reader)? {
+            let response = match request {
+                AgentRequest::Exec {
+                    program,
+                    args,
+                    env,
+                    working_dir,
+                } => {
+                    let mut command = Command::new(program);
+                    command
+                        .args(args)
+                        .env_clear()
+                        .envs(self.lock_environment().iter())
+                        .envs(env)
+                        .stdin(Stdio::piped())
+                        .stdout(Stdio::piped())
+                        .stderr(Stdio::piped());
+
+                    if let Some(working_dir) = working_dir {
+                        command.current_dir(working_dir);
+                    }
+
+                    return serve_exec(command, reader, writer);
+                }
+                AgentRequest::SignalProcess { pid, signal } => match syscall::kill(pid, signal) {
+                    Ok(()) => AgentResponse::Ok,
+                    Err(err) => error(format!("Could not signal process {pid}: {err}")),
+                },
+                AgentRequest::PutFile { path, mode, content } => {
+                    let result = std::fs::write(&path, content).and_then(|_| match mode {
+                        Some(mode) => std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)),
+                        None => Ok(()),
+                    });
+
+                    match result {
+                        Ok(()) => AgentResponse::Ok,
+                        Err(err) => error(format!("Could not write {}: {err}", path.display())),
+                    }
+                }
+                AgentRequest::GetFile { path } => match std::fs::read(&path) {
+                    Ok(content) => AgentResponse::FileContent { content },
+                    Err(err) => error(format!("Could not read {}: {err}", path.display())),
+                },
+                AgentRequest::GetEnvironment => AgentResponse::Environment {
+                    variables: self.lock_environment().clone(),
+                },
+                AgentRequest::SetEnvironment { set, unset } => {
+                    let mut environment = self.lock_environment();
+                    environment.extend(set);
+
+                    for key in unset {
+                        environment.remove(&key);
+                    }
+
+                    AgentResponse::Ok
+                }
+                AgentRequest::Hello { .. } => error("The hello was already performed"),
+                AgentRequest::Stdin { .. } | AgentRequest::CloseStdin | AgentRequest::Signal { .. } => {
+                    error("No process is being executed on this connection")
+                }
+            };
+
+            write_response(&writer, response)?;
+        }
+
+        Ok(())
+    }
+
+    fn lock_environment(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, String>> {
+        self.environment.lock().expect("Environment mutex was poisoned")
+    }
+}
+
+fn serve_exec<W: Write + Send + 'static>(
+    mut command: Command,
+    mut reader: impl Read,
+    writer: Arc<Mutex<W>>,
+) -> Result<(), AgentProtocolError> {
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(err) => return write_response(&writer, error(format!("Could not spawn the process: {err}"))),
+    };
+    let pid = child.id() as i32;
+    write_response(&writer, AgentResponse::Started { pid })?;
+
+    let mut stdin = child.stdin.take();
+    let output_threads = [
+        child
+            .stdout
+            .take()
+            .map(|stdout| spawn_output_thread(stdout, writer.clone(), |data| AgentResponse::Stdout { data })),
+        child
+            .stderr
+            .take()
+            .map(|stderr| spawn_output_thread(stderr, writer.clone(), |data| AgentResponse::Stderr { data })),
+    ];
+
+    // the exit is only reported once all output has been forwarded, so that it is always the last response
+    let exit_writer = writer.clone();
+    std::thread::spawn(move || {
+        for output_thread in output_threads.into_iter().flatten() {
+            let _ = output_thread.join();
+        }
+
+        let response = match child.wait() {
+            Ok(exit_status) => AgentResponse::Exited {
+                code: exit_status.code(),
+                signal: exit_status.signal(),
+            },
+            Err(err) => error(format!("Could not wait for the process: {err}")),
+        };
+        let _ = write_response(&exit_writer, response);
+    });
+
+    while let Some(request) = read_frame_blocking(&mut reader)? {
+        match request {
+            AgentRequest::Stdin { data } => {
+                if let Some(ref mut child_stdin) = stdin {
+                    if child_stdin.write_all(&data).and_then(|_| child_stdin.flush()).is_err() {
+                        stdin = None;
+                    }
+                }
+            }
+            AgentRequest::CloseStdin => stdin = None,
+            AgentRequest::Signal { signal } => {
+                if let Err(err) = syscall::kill(pid, signal) {
+                    write_response(&writer, error(format!("Could not signal the process: {err}")))?;
+                }
+            }
+            _ => write_response(
+                &writer,
+                error("Only stdin and signal requests are accepted while a process is being executed"),
+            )?,
+        }
+    }
+
+    Ok(())
+}
+
+fn spawn_output_thread<W: Write + Send + 'static>(
+    mut output: impl Read + Send + 'static,
+    writer: Arc<Mutex<W>>,
+    to_response: fn(Vec<u8>) -> AgentResponse,
+) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut buf = [0; 8192];
+
+        loop {
+            let read = match output.read(&mut buf) {
+                Ok(0) | Err(_) => return,
+                Ok(read) => read,
+            };
+
+            if write_response(&writer, to_response(buf[..read].to_vec())).is_err() {
+                return;
+            }
+        }
+    })
+}
+
+fn write_response<W: Write>(writer: &Mutex<W>, response: AgentResponse) -> Result<(), AgentProtocolError> {
+    let mut writer = writer.lock().expect("Writer mutex was poisoned");
+    write_frame_blocking(&mut *writer, response)
+}
+
+fn error(message: impl Into<String>) -> AgentResponse {
+    AgentResponse::Error {
+        message: message.into(),
+    }
+}
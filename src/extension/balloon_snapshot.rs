@@ -0,0 +1,347 @@
+use std::{
+    fs::File,
+    future::Future,
+    os::{
+        fd::AsFd,
+        unix::fs::{FileExt, MetadataExt},
+    },
+    path::Path,
+    time::Duration,
+};
+
+use futures_channel::oneshot;
+
+use crate::{
+    process_spawner::ProcessSpawner,
+    runtime::Runtime,
+    vm::{
+        api::{VmApi, VmApiError},
+        models::{CreateSnapshot, SnapshotType, UpdateBalloonDevice},
+        snapshot::VmSnapshot,
+        Vm, VmState,
+    },
+    vmm::executor::VmmExecutor,
+};
+
+const MIB: u64 = 1024 * 1024;
+const PAGE_SIZE: usize = 4096;
+const SCAN_BUFFER_SIZE: usize = 256 * PAGE_SIZE;
+
+/// An error that can be emitted by [BalloonSnapshotExt].
+#[derive(Debug)]
+pub enum BalloonSnapshotError {
+    NotRunning(VmState),
+    BalloonNotConfigured,
+    BalloonError(VmApiError),
+    PauseError(VmApiError),
+    SnapshotError(VmApiError),
+    ResumeError(VmApiError),
+    FilesystemError(std::io::Error),
+}
+
+impl std::error::Error for BalloonSnapshotError {}
+
+impl std::fmt::Display for BalloonSnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BalloonSnapshotError::NotRunning(state) => {
+                write!(
+                    f,
+                    "The VM must be running to inflate its balloon, but its state was {state}"
+                )
+            }
+            BalloonSnapshotError::BalloonNotConfigured => write!(f, "The VM has no balloon device configured"),
+            BalloonSnapshotError::BalloonError(err) => {
+                write!(f, "Querying or updating the balloon device failed: {err}")
+            }
+            BalloonSnapshotError::PauseError(err) => write!(f, "Pausing the VM failed: {err}"),
+            BalloonSnapshotError::SnapshotError(err) => write!(f, "Creating the snapshot failed: {err}"),
+            BalloonSnapshotError::ResumeError(err) => write!(f, "Resuming the VM failed: {err}"),
+            BalloonSnapshotError::FilesystemError(err) => {
+                write!(f, "A filesystem operation on the memory file failed: {err}")
+            }
+        }
+    }
+}
+
+/// The options for inflating the balloon of a [Vm] before snapshotting it with [BalloonSnapshotExt].
+#[derive(Debug, Clone)]
+pub struct BalloonSnapshotOptions {
+    settle_timeout: Duration,
+    poll_interval: Duration,
+    headroom_mib: u64,
+}
+
+impl BalloonSnapshotOptions {
+    /// Create options that wait up to the given timeout for the guest to hand the requested memory over to the
+    /// balloon, after which the snapshot is taken with whatever has been reclaimed so far. By default, the balloon
+    /// statistics are polled every 100 milliseconds and all free guest memory is reclaimed.
+    pub fn new(settle_timeout: Duration) -> Self {
+        Self {
+            settle_timeout,
+            poll_interval: Duration::from_millis(100),
+            headroom_mib: 0,
+        }
+    }
+
+    /// Set the interval at which the balloon statistics are polled while waiting for the balloon to settle.
+    pub fn poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Set the amount of free guest memory in MiB that is left to the guest instead of being reclaimed, so that the
+    /// guest doesn't run out of memory while the balloon is inflated.
+    pub fn headroom_mib(mut self, headroom_mib: u64) -> Self {
+        self.headroom_mib = headroom_mib;
+        self
+    }
+}
+
+/// A report of how much memory file space a snapshot taken with [BalloonSnapshotExt] saved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BalloonSnapshotReport {
+    /// The amount of guest memory in MiB that the inflated balloon reclaimed.
+    pub reclaimed_mib: u64,
+    /// The apparent size of the memory file, which equals the memory size of the [Vm].
+    pub mem_file_size: u64,
+    /// The amount of disk space the memory file occupies after zero pages have been punched out of it.
+    pub allocated_bytes: u64,
+    /// The amount of zero pages in bytes that were punched out of the memory file as written by the VMM, which is
+    /// always zero for diff snapshots.
+    pub punched_bytes: u64,
+}
+
+impl BalloonSnapshotReport {
+    /// The amount of disk space in bytes saved compared to a fully allocated memory file.
+    pub fn saved_bytes(&self) -> u64 {
+        self.mem_file_size.saturating_sub(self.allocated_bytes)
+    }
+}
+
+/// An extension that shrinks the memory files of snapshots by reclaiming free guest memory with the balloon device
+/// before taking the snapshot and storing the reclaimed pages as holes in the memory file.
+pub trait BalloonSnapshotExt {
+    /// Inflate the balloon of this running [Vm] by the amount of free guest memory reported by its balloon statistics,
+    /// which must be enabled, wait for the balloon to settle, pause the [Vm] and take the snapshot. Afterwards, the
+    /// [Vm] is resumed and the balloon is deflated back to its previous size, and the zero pages of the memory file are
+    /// punched out so that they take no disk space. Zero pages aren't punched out of the memory files of diff
+    /// snapshots, where a hole means that the page is unchanged since the previous snapshot. If any step fails, the
+    /// [Vm] is resumed and its balloon deflated on a best-effort basis and any created snapshot is removed.
+    fn api_create_ballooned_snapshot(
+        &mut self,
+        create_snapshot: CreateSnapshot,
+        options: BalloonSnapshotOptions,
+    ) -> impl Future<Output = Result<(VmSnapshot, BalloonSnapshotReport), BalloonSnapshotError>> + Send;
+}
+
+impl<E: VmmExecutor, S: ProcessSpawner, R: Runtime> BalloonSnapshotExt for Vm<E, S, R> {
+    async fn api_create_ballooned_snapshot(
+        &mut self,
+        create_snapshot: CreateSnapshot,
+        options: BalloonSnapshotOptions,
+    ) -> Result<(VmSnapshot, BalloonSnapshotReport), BalloonSnapshotError> {
+        let state = self.state();
+        if state != VmState::Running {
+            return Err(BalloonSnapshotError::NotRunning(state));
+        }
+
+        if self.configuration().data().balloon_device.is_none() {
+            return Err(BalloonSnapshotError::BalloonNotConfigured);
+        }
+
+        let balloon_device = self
+            .api_get_balloon_device()
+            .await
+            .map_err(BalloonSnapshotError::BalloonError)?;
+        let statistics = self
+            .api_get_balloon_statistics()
+            .await
+            .map_err(BalloonSnapshotError::BalloonError)?;
+
+        let original_amount_mib = balloon_device.amount_mib.max(0) as u64;
+        let free_mib = statistics.free_memory.unwrap_or_default() / MIB;
+        let target_amount_mib = (original_amount_mib + free_mib.saturating_sub(options.headroom_mib))
+            .min(self.configuration().data().machine_configuration.mem_size_mib as u64)
+            .min(u16::MAX as u64);
+
+        let is_diff = create_snapshot.snapshot_type == Some(SnapshotType::Diff);
+        let result = inflate_and_snapshot(self, create_snapshot, target_amount_mib, &options).await;
+
+        if self.state() == VmState::Paused {
+            if let Err(err) = self.api_resume().await {
+                if let Ok((snapshot, _)) = result {
+                    snapshot.remove(&self.runtime).await;
+                }
+
+                return Err(BalloonSnapshotError::ResumeError(err));
+            }
+        }
+
+        let deflate_result = self
+            .api_update_balloon_device(UpdateBalloonDevice {
+                amount_mib: original_amount_mib as u16,
+            })
+            .await
+            .map_err(BalloonSnapshotError::BalloonError);
+
+        let (snapshot, settled_amount_mib) = result?;
+        if let Err(err) = deflate_result {
+            snapshot.remove(&self.runtime).await;
+            return Err(err);
+        }
+
+        let mem_file_path = snapshot.mem_file.effective_path();
+        let punched_bytes = if is_diff {
+            0
+        } else {
+            match punch_zero_pages(mem_file_path).await {
+                Ok(punched_bytes) => punched_bytes,
+                Err(err) => {
+                    snapshot.remove(&self.runtime).await;
+                    return Err(BalloonSnapshotError::FilesystemError(err));
+                }
+            }
+        };
+
+        let metadata = match self.runtime.fs_metadata(mem_file_path).await {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                snapshot.remove(&self.runtime).await;
+                return Err(BalloonSnapshotError::FilesystemError(err));
+            }
+        };
+
+        let report = BalloonSnapshotReport {
+            reclaimed_mib: settled_amount_mib.saturating_sub(statistics.actual_mib as u64),
+            mem_file_size: metadata.len(),
+            allocated_bytes: metadata.blocks() * 512,
+            punched_bytes,
+        };
+
+        Ok((snapshot, report))
+    }
+}
+
+async fn inflate_and_snapshot<E: VmmExecutor, S: ProcessSpawner, R: Runtime>(
+    vm: &mut Vm<E, S, R>,
+    create_snapshot: CreateSnapshot,
+    target_amount_mib: u64,
+    options: &BalloonSnapshotOptions,
+) -> Result<(VmSnapshot, u64), BalloonSnapshotError> {
+    vm.api_update_balloon_device(UpdateBalloonDevice {
+        amount_mib: target_amount_mib as u16,
+    })
+    .await
+    .map_err(BalloonSnapshotError::BalloonError)?;
+
+    // the guest hands pages over to the balloon asynchronously, so the snapshot is taken once it has caught up or
+    // the timeout has elapsed, whichever comes first
+    let runtime = vm.runtime.clone();
+    let mut settled_amount_mib = 0;
+    let settle_result = runtime
+        .clone()
+        .timeout(options.settle_timeout, async {
+            loop {
+                let statistics = vm
+                    .api_get_balloon_statistics()
+                    .await
+                    .map_err(BalloonSnapshotError::BalloonError)?;
+                settled_amount_mib = statistics.actual_mib as u64;
+
+                if settled_amount_mib >= target_amount_mib {
+                    return Ok(());
+                }
+
+                runtime.sleep(options.poll_interval).await;
+            }
+        })
+        .await;
+
+    if let Ok(Err(err)) = settle_result {
+        return Err(err);
+    }
+
+    vm.api_pause().await.map_err(BalloonSnapshotError::PauseError)?;
+    let snapshot = vm
+        .api_create_snapshot(create_snapshot)
+        .await
+        .map_err(BalloonSnapshotError::SnapshotError)?;
+
+    Ok((snapshot, settled_amount_mib))
+}
+
+/// Punch the pages of the file at the given path that consist entirely of zeroes out of it, so that they take no disk
+/// space while the apparent size of the file stays the same, returning the amount of bytes punched out. Only the data
+/// segments of the file are scanned, so pages that already are holes are neither punched again nor counted. The file
+/// is scanned on a separate thread, since the scan is blocking.
+pub async fn punch_zero_pages(path: &Path) -> Result<u64, std::io::Error> {
+    let path = path.to_owned();
+    let (sender, receiver) = oneshot::channel();
+    std::thread::spawn(move || {
+        let _ = sender.send(punch_zero_pages_blocking(&path));
+    });
+
+    receiver
+        .await
+        .map_err(|_| std::io::Error::other("The thread punching zero pages exited without a result"))?
+}
+
+fn punch_zero_pages_blocking(path: &Path) -> Result<u64, std::io::Error> {
+    let file = File::options().read(true).write(true).open(path)?;
+    let len = file.metadata()?.len();
+    let mut buf = vec![0; SCAN_BUFFER_SIZE];
+    let mut offset = 0;
+    let mut punched_bytes = 0;
+
+    // filesystems that don't report holes are scanned as a single data segment
+    while offset < len {
+        let data_start = match crate::syscall::seek_data(file.as_fd(), offset) {
+            Ok(Some(data_start)) => data_start,
+            Ok(None) => break,
+            Err(_) => offset,
+        };
+        let data_end = crate::syscall::seek_hole(file.as_fd(), data_start)
+            .unwrap_or(len)
+            .min(len);
+
+        punched_bytes += punch_zero_pages_in_range(&file, data_start, data_end, &mut buf)?;
+        offset = data_end;
+    }
+
+    Ok(punched_bytes)
+}
+
+fn punch_zero_pages_in_range(file: &File, start: u64, end: u64, buf: &mut [u8]) -> Result<u64, std::io::Error> {
+    let mut offset = start;
+    let mut zero_range_start = None;
+    let mut punched_bytes = 0;
+
+    while offset < end {
+        let length = (end - offset).min(buf.len() as u64) as usize;
+        file.read_exact_at(&mut buf[..length], offset)?;
+
+        for page in buf[..length].chunks(PAGE_SIZE) {
+            let is_zero = page.len() == PAGE_SIZE && page.iter().all(|byte| *byte == 0);
+
+            match (is_zero, zero_range_start) {
+                (true, None) => zero_range_start = Some(offset),
+                (false, Some(start)) => {
+                    crate::syscall::punch_hole(file.as_fd(), start, offset - start)?;
+                    punched_bytes += offset - start;
+                    zero_range_start = None;
+                }
+                _ => {}
+            }
+
+            offset += page.len() as u64;
+        }
+    }
+
+    if let Some(start) = zero_range_start {
+        crate::syscall::punch_hole(file.as_fd(), start, offset - start)?;
+        punched_bytes += offset - start;
+    }
+
+    Ok(punched_bytes)
+}
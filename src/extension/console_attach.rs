@@ -0,0 +1,464 @@
+use std::{
+    future::Future,
+    io::{IsTerminal, Read, Write},
+    os::fd::{AsFd, OwnedFd},
+    path::{Path, PathBuf},
+    pin::pin,
+};
+
+use futures_channel::{mpsc, oneshot};
+use futures_util::{future::Either, AsyncRead, AsyncReadExt, AsyncWriteExt, StreamExt};
+
+use crate::{
+    process_spawner::ProcessSpawner,
+    runtime::{Runtime, RuntimeAsyncFd, RuntimeListener, RuntimeTask},
+    syscall,
+    vm::{Vm, VmError},
+    vmm::{console::VmmConsole, executor::VmmExecutor},
+};
+
+/// The default detach key of console sessions: Ctrl+], the same one used by `virsh console` and `telnet`.
+pub const DEFAULT_DETACH_KEY: u8 = 0x1d;
+
+/// The amount of raw output chunks buffered for each session before the session starts missing output.
+const SESSION_OUTPUT_CAPACITY: usize = 256;
+
+/// The read timeout, in deciseconds, applied to a local terminal in raw mode, so that the thread reading from it can
+/// notice the end of the session.
+const TERMINAL_READ_TIMEOUT_DECISECONDS: u8 = 1;
+
+/// The task pumping data between the console and its PTY, which ends with an error if writing to the PTY failed.
+type PtyTask<R> = <R as Runtime>::Task<Result<(), std::io::Error>>;
+
+/// An error that can be emitted by the console attach extension.
+#[derive(Debug)]
+pub enum ConsoleAttachError {
+    ConsoleUnavailable(VmError),
+    CannotBind(std::io::Error),
+    CannotCreatePty(std::io::Error),
+    CannotConnect(std::io::Error),
+    CannotConfigureTerminal(std::io::Error),
+}
+
+impl std::error::Error for ConsoleAttachError {}
+
+impl std::fmt::Display for ConsoleAttachError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConsoleAttachError::ConsoleUnavailable(err) => write!(f, "The console of the VM is unavailable: {err}"),
+            ConsoleAttachError::CannotBind(err) => write!(f, "Could not bind the console socket: {err}"),
+            ConsoleAttachError::CannotCreatePty(err) => write!(f, "Could not create the console PTY: {err}"),
+            ConsoleAttachError::CannotConnect(err) => write!(f, "Could not connect to the console socket: {err}"),
+            ConsoleAttachError::CannotConfigureTerminal(err) => {
+                write!(f, "Could not configure the local terminal: {err}")
+            }
+        }
+    }
+}
+
+/// The options of a [ConsoleAttachServer].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConsoleAttachOptions {
+    socket_path: PathBuf,
+    pty: bool,
+    detach_key: Option<u8>,
+    replay_scrollback: bool,
+}
+
+impl ConsoleAttachOptions {
+    /// Create options that serve the console on a Unix socket at the given path. A stale socket at this path is
+    /// removed before binding.
+    pub fn new(socket_path: impl Into<PathBuf>) -> Self {
+        Self {
+            socket_path: socket_path.into(),
+            pty: false,
+            detach_key: Some(DEFAULT_DETACH_KEY),
+            replay_scrollback: true,
+        }
+    }
+
+    /// Additionally serve the console on a host pseudo-terminal, which can be opened by terminal programs such as
+    /// `screen` or `minicom`.
+    pub fn pty(mut self) -> Self {
+        self.pty = true;
+        self
+    }
+
+    /// Use the given byte as the detach key: a socket session ends when the key is received, instead of forwarding it.
+    pub fn detach_key(mut self, detach_key: u8) -> Self {
+        self.detach_key = Some(detach_key);
+        self
+    }
+
+    /// Forward all bytes received from socket sessions, so that sessions only end when the client disconnects.
+    pub fn disable_detach_key(mut self) -> Self {
+        self.detach_key = None;
+        self
+    }
+
+    /// Don't replay the serial output kept in the scrollback of the console to newly attached socket sessions.
+    pub fn disable_scrollback_replay(mut self) -> Self {
+        self.replay_scrollback = false;
+        self
+    }
+}
+
+/// A server that exposes the [VmmConsole] of a VM on a Unix socket (and optionally a PTY) for interactive attach.
+/// Any amount of socket sessions can be attached at the same time: all of them receive the serial output of the VM,
+/// and the input of all of them is forwarded to the serial console. Dropping the server detaches its tasks, which keep
+/// serving until the console is closed, so [ConsoleAttachServer::stop] should be called to stop them eagerly.
+pub struct ConsoleAttachServer<R: Runtime> {
+    socket_path: PathBuf,
+    pty_path: Option<PathBuf>,
+    accept_task: R::Task<()>,
+    pty_task: Option<PtyTask<R>>,
+    runtime: R,
+}
+
+impl<R: Runtime> ConsoleAttachServer<R> {
+    /// Get the path of the Unix socket the console is served on.
+    pub fn socket_path(&self) -> &Path {
+        &self.socket_path
+    }
+
+    /// Get the path of the PTY the console is served on, if a PTY was requested.
+    pub fn pty_path(&self) -> Option<&Path> {
+        self.pty_path.as_deref()
+    }
+
+    /// Stop the server, ending all attached sessions and removing its Unix socket. If the PTY was served and writing
+    /// to it failed before the server was stopped, the error of that write is returned.
+    pub async fn stop(self) -> Result<(), std::io::Error> {
+        self.accept_task.cancel().await;
+
+        let pty_result = match self.pty_task {
+            Some(pty_task) => pty_task.cancel().await,
+            None => None,
+        };
+
+        self.runtime.fs_remove_file(&self.socket_path).await?;
+        pty_result.unwrap_or(Ok(()))
+    }
+}
+
+/// The reason a [attach_console] session ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConsoleAttachExit {
+    /// The local user pressed the detach key or closed the input.
+    Detached,
+    /// The server closed the session, typically because the VM's console was closed.
+    Closed,
+}
+
+/// An extension that serves the serial console of a [Vm] for interactive attach, similarly to `virsh console`.
+pub trait ConsoleAttachExt<R: Runtime> {
+    /// Start a [ConsoleAttachServer] with the given [ConsoleAttachOptions], taking the pipes of the VM if its
+    /// [VmmConsole] hasn't been created yet.
+    fn serve_console(
+        &mut self,
+        options: ConsoleAttachOptions,
+    ) -> impl Future<Output = Result<ConsoleAttachServer<R>, ConsoleAttachError>> + Send;
+}
+
+impl<E: VmmExecutor, S: ProcessSpawner, R: Runtime> ConsoleAttachExt<R> for Vm<E, S, R> {
+    fn serve_console(
+        &mut self,
+        options: ConsoleAttachOptions,
+    ) -> impl Future<Output = Result<ConsoleAttachServer<R>, ConsoleAttachError>> + Send {
+        let console = self.console();
+        let runtime = self.runtime.clone();
+
+        async move {
+            let console = console.map_err(ConsoleAttachError::ConsoleUnavailable)?;
+
+            if runtime.fs_exists(&options.socket_path).await.unwrap_or(false) {
+                runtime
+                    .fs_remove_file(&options.socket_path)
+                    .await
+                    .map_err(ConsoleAttachError::CannotBind)?;
+            }
+
+            let listener = runtime
+                .unix_bind(&options.socket_path)
+                .map_err(ConsoleAttachError::CannotBind)?;
+
+            let (pty_path, pty_task) = match options.pty {
+                true => {
+                    let (pty_path, pty_task) =
+                        serve_pty(console.clone(), &runtime).map_err(ConsoleAttachError::CannotCreatePty)?;
+                    (Some(pty_path), Some(pty_task))
+                }
+                false => (None, None),
+            };
+
+            let accept_task = runtime.spawn_task(accept_sessions(
+                listener,
+                console,
+                runtime.clone(),
+                options.detach_key,
+                options.replay_scrollback,
+            ));
+
+            Ok(ConsoleAttachServer {
+                socket_path: options.socket_path,
+                pty_path,
+                accept_task,
+                pty_task,
+                runtime,
+            })
+        }
+    }
+}
+
+/// Connect the local terminal (stdin and stdout of the current process) to a console served by a
+/// [ConsoleAttachServer] at the given socket path, until the given detach key is pressed, the input is closed or the
+/// server closes the session. If stdin is a terminal, it is put into raw mode for the duration of the session, so that
+/// keys such as Ctrl+C reach the guest, and its original mode is restored afterwards.
+pub async fn attach_console<R: Runtime>(
+    runtime: &R,
+    socket_path: &Path,
+    detach_key: Option<u8>,
+) -> Result<ConsoleAttachExit, ConsoleAttachError> {
+    let stream = runtime
+        .unix_connect(socket_path)
+        .await
+        .map_err(ConsoleAttachError::CannotConnect)?;
+    let (mut reader, mut writer) = stream.split();
+    let _raw_mode_guard = RawModeGuard::enable().map_err(ConsoleAttachError::CannotConfigureTerminal)?;
+
+    let (input_sender, mut input_receiver) = mpsc::unbounded::<Vec<u8>>();
+    std::thread::spawn(move || read_local_input(input_sender));
+
+    let output_future = async {
+        let mut buf = [0; 4096];
+
+        loop {
+            let read = match reader.read(&mut buf).await {
+                Ok(0) | Err(_) => return,
+                Ok(read) => read,
+            };
+
+            // writes to the local terminal are short, so they are performed in place instead of on a separate thread
+            let mut stdout = std::io::stdout().lock();
+            if stdout.write_all(&buf[..read]).and_then(|_| stdout.flush()).is_err() {
+                return;
+            }
+        }
+    };
+
+    let input_future = async {
+        while let Some(input) = input_receiver.next().await {
+            let (input, detached) = split_at_detach_key(&input, detach_key);
+
+            if !input.is_empty() && (writer.write_all(input).await.is_err() || writer.flush().await.is_err()) {
+                return ConsoleAttachExit::Closed;
+            }
+
+            if detached {
+                return ConsoleAttachExit::Detached;
+            }
+        }
+
+        ConsoleAttachExit::Detached
+    };
+
+    let exit = match futures_util::future::select(pin!(output_future), pin!(input_future)).await {
+        Either::Left(((), _)) => ConsoleAttachExit::Closed,
+        Either::Right((exit, _)) => exit,
+    };
+
+    Ok(exit)
+}
+
+async fn accept_sessions<R: Runtime>(
+    listener: R::UnixListener,
+    console: VmmConsole<R>,
+    runtime: R,
+    detach_key: Option<u8>,
+    replay_scrollback: bool,
+) {
+    // dropping a stop sender, which happens when this task is cancelled, ends the corresponding session
+    let mut stop_senders: Vec<oneshot::Sender<()>> = Vec::new();
+
+    while let Ok(stream) = listener.accept().await {
+        stop_senders.retain(|stop_sender| !stop_sender.is_canceled());
+        let (stop_sender, stop_receiver) = oneshot::channel();
+        stop_senders.push(stop_sender);
+
+        runtime.spawn_task(serve_session(
+            stream,
+            console.clone(),
+            detach_key,
+            replay_scrollback,
+            stop_receiver,
+        ));
+    }
+}
+
+async fn serve_session<R: Runtime>(
+    stream: R::UnixStream,
+    console: VmmConsole<R>,
+    detach_key: Option<u8>,
+    replay_scrollback: bool,
+    stop_receiver: oneshot::Receiver<()>,
+) {
+    let (reader, mut writer) = stream.split();
+    let mut output = console.subscribe_raw(SESSION_OUTPUT_CAPACITY, replay_scrollback);
+
+    let output_future = async move {
+        while let Some(chunk) = output.next().await {
+            if writer.write_all(&chunk).await.is_err() || writer.flush().await.is_err() {
+                return;
+            }
+        }
+    };
+
+    let input_future = pin!(forward_session_input(reader, &console, detach_key));
+    let output_future = pin!(output_future);
+    futures_util::future::select(futures_util::future::select(output_future, input_future), stop_receiver).await;
+}
+
+async fn forward_session_input<R: Runtime>(
+    mut reader: impl AsyncRead + Unpin,
+    console: &VmmConsole<R>,
+    detach_key: Option<u8>,
+) {
+    let mut buf = [0; 1024];
+
+    loop {
+        let read = match reader.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(read) => read,
+        };
+
+        let (input, detached) = split_at_detach_key(&buf[..read], detach_key);
+
+        if !input.is_empty() && console.write(input).await.is_err() {
+            return;
+        }
+
+        if detached {
+            return;
+        }
+    }
+}
+
+fn split_at_detach_key(input: &[u8], detach_key: Option<u8>) -> (&[u8], bool) {
+    match detach_key.and_then(|detach_key| input.iter().position(|byte| *byte == detach_key)) {
+        Some(position) => (&input[..position], true),
+        None => (input, false),
+    }
+}
+
+fn serve_pty<R: Runtime>(console: VmmConsole<R>, runtime: &R) -> Result<(PathBuf, PtyTask<R>), std::io::Error> {
+    let (master, slave, pty_path) = syscall::openpty()?;
+
+    // the guest already performs line discipline on its serial console, so the PTY must pass bytes through unmodified
+    let mut termios = syscall::tcgetattr(slave.as_fd())?;
+    syscall::make_raw(&mut termios, None);
+    syscall::tcsetattr(slave.as_fd(), &termios)?;
+
+    let async_master = runtime.create_async_fd(master.try_clone()?)?;
+    let task = runtime.spawn_task(pump_pty::<R>(console, std::fs::File::from(master), async_master, slave));
+
+    Ok((pty_path, task))
+}
+
+/// Forward data between the non-blocking PTY master and the console until either of them is closed, waiting for the
+/// master to become readable or for console output to arrive. The slave is kept open for the lifetime of the pump, so
+/// that reads from the master don't fail while no terminal has the PTY open, and output is discarded while the PTY
+/// buffer is full because no terminal is reading from it. Any other failure to write to the master stops the pump.
+async fn pump_pty<R: Runtime>(
+    console: VmmConsole<R>,
+    mut master: std::fs::File,
+    async_master: R::AsyncFd,
+    _slave: OwnedFd,
+) -> Result<(), std::io::Error> {
+    let mut output = console.subscribe_raw(SESSION_OUTPUT_CAPACITY, false);
+    let mut buf = [0; 4096];
+
+    loop {
+        // readiness is only reported for new data, so the master is drained before waiting on it again
+        loop {
+            match master.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(read) => {
+                    if console.write(&buf[..read]).await.is_err() {
+                        return Ok(());
+                    }
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        let chunk = match futures_util::future::select(pin!(async_master.readable()), output.next()).await {
+            Either::Left((result, _)) => {
+                result?;
+                continue;
+            }
+            Either::Right((Some(chunk), _)) => chunk,
+            Either::Right((None, _)) => return Ok(()),
+        };
+
+        match master.write_all(&chunk) {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+fn read_local_input(input_sender: mpsc::UnboundedSender<Vec<u8>>) {
+    let mut stdin = std::io::stdin().lock();
+    let is_terminal = stdin.is_terminal();
+    let mut buf = [0; 1024];
+
+    while !input_sender.is_closed() {
+        match stdin.read(&mut buf) {
+            // a terminal in raw mode returns no data once the read timeout elapses, which isn't the end of input
+            Ok(0) if is_terminal => {}
+            Ok(0) => return,
+            Ok(read) => {
+                if input_sender.unbounded_send(buf[..read].to_vec()).is_err() {
+                    return;
+                }
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(_) => return,
+        }
+    }
+}
+
+/// A guard that puts stdin into raw mode, if it is a terminal, and restores its original mode on drop.
+struct RawModeGuard {
+    original_termios: Option<syscall::Termios>,
+}
+
+impl RawModeGuard {
+    fn enable() -> Result<Self, std::io::Error> {
+        let stdin = std::io::stdin();
+
+        if !stdin.is_terminal() {
+            return Ok(Self { original_termios: None });
+        }
+
+        let original_termios = syscall::tcgetattr(stdin.as_fd())?;
+        let mut termios = original_termios.clone();
+        syscall::make_raw(&mut termios, Some(TERMINAL_READ_TIMEOUT_DECISECONDS));
+        syscall::tcsetattr(stdin.as_fd(), &termios)?;
+
+        Ok(Self {
+            original_termios: Some(original_termios),
+        })
+    }
+}
+
+impl Drop for RawModeGuard {
+    fn drop(&mut self) {
+        if let Some(ref original_termios) = self.original_termios {
+            let _ = syscall::tcsetattr(std::io::stdin().as_fd(), original_termios);
+        }
+    }
+}
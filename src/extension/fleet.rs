@@ -0,0 +1,423 @@
+use std::{
+    collections::{hash_map::Entry, BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use futures_channel::mpsc;
+use futures_util::{
+    future::join_all,
+    lock::{Mutex as AsyncMutex, MutexGuard as AsyncMutexGuard},
+    StreamExt,
+};
+
+use crate::{
+    process_spawner::ProcessSpawner,
+    runtime::Runtime,
+    vm::{
+        configuration::VmConfiguration,
+        shutdown::{VmShutdownAction, VmShutdownError, VmShutdownOutcome},
+        Vm, VmError, VmState,
+    },
+    vmm::{
+        executor::VmmExecutor,
+        id::{VmmId, VmmIdError},
+        installation::VmmInstallation,
+        ownership::VmmOwnershipModel,
+    },
+};
+
+/// The default prefix of the [VmmId]s allocated by a [VmFleet].
+pub const DEFAULT_FLEET_ID_PREFIX: &str = "fleet";
+
+/// An error that can be emitted by a [VmFleet].
+#[derive(Debug)]
+pub enum VmFleetError {
+    IdError(VmmIdError),
+    IdAlreadyInUse(VmmId),
+    NotFound(VmmId),
+    PrepareError(VmError),
+    StartError(VmError),
+}
+
+impl std::error::Error for VmFleetError {}
+
+impl std::fmt::Display for VmFleetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VmFleetError::IdError(err) => write!(f, "Allocating a VMM ID from the fleet's prefix failed: {err}"),
+            VmFleetError::IdAlreadyInUse(id) => write!(f, "The VMM ID {} is already in use in the fleet", id.as_ref()),
+            VmFleetError::NotFound(id) => write!(f, "No VM with the VMM ID {} exists in the fleet", id.as_ref()),
+            VmFleetError::PrepareError(err) => write!(f, "Preparing a VM of the fleet failed: {err}"),
+            VmFleetError::StartError(err) => write!(f, "Starting a VM of the fleet failed: {err}"),
+        }
+    }
+}
+
+/// A handle to a [Vm] that is a member of a [VmFleet]. The handle can be cheaply cloned and remains usable after the
+/// [Vm] was removed from the [VmFleet].
+#[derive(Debug)]
+pub struct VmFleetMember<E: VmmExecutor, S: ProcessSpawner, R: Runtime> {
+    id: VmmId,
+    labels: Arc<BTreeMap<String, String>>,
+    vm: Arc<AsyncMutex<Vm<E, S, R>>>,
+}
+
+impl<E: VmmExecutor, S: ProcessSpawner, R: Runtime> Clone for VmFleetMember<E, S, R> {
+    fn clone(&self) -> Self {
+        Self {
+            id: self.id.clone(),
+            labels: self.labels.clone(),
+            vm: self.vm.clone(),
+        }
+    }
+}
+
+impl<E: VmmExecutor, S: ProcessSpawner, R: Runtime> VmFleetMember<E, S, R> {
+    /// Get the [VmmId] that the [Vm] is registered under in the [VmFleet].
+    pub fn id(&self) -> &VmmId {
+        &self.id
+    }
+
+    /// Get all labels of the [Vm].
+    pub fn labels(&self) -> &BTreeMap<String, String> {
+        &self.labels
+    }
+
+    /// Get the value of a single label of the [Vm], if it is set.
+    pub fn label(&self, key: &str) -> Option<&str> {
+        self.labels.get(key).map(|value| value.as_str())
+    }
+
+    /// Lock the [Vm] for exclusive access. Bulk operations of the [VmFleet] wait for this lock to be released.
+    pub async fn lock(&self) -> AsyncMutexGuard<'_, Vm<E, S, R>> {
+        self.vm.lock().await
+    }
+}
+
+/// A fleet of many concurrently managed [Vm]s sharing the same [ProcessSpawner], [Runtime], [VmmOwnershipModel] and
+/// [VmmInstallation]. A [VmFleet] allocates unique [VmmId]s that are passed to a closure producing each [Vm]'s
+/// [VmmExecutor], where they can be used as both the VMM ID and the jail ID. It supports lookup by ID or by labels,
+/// caps the number of concurrent prepares and starts, and performs bulk shutdowns and cleanups.
+#[derive(Debug)]
+pub struct VmFleet<E: VmmExecutor, S: ProcessSpawner, R: Runtime> {
+    process_spawner: S,
+    runtime: R,
+    ownership_model: VmmOwnershipModel,
+    installation: Arc<VmmInstallation>,
+    id_prefix: String,
+    next_id: AtomicU64,
+    // IDs of VMs that are still being prepared are reserved with a placeholder entry
+    members: Mutex<VmFleetMembers<E, S, R>>,
+    prepare_limit: ConcurrencyLimit,
+    start_limit: ConcurrencyLimit,
+}
+
+impl<E: VmmExecutor, S: ProcessSpawner, R: Runtime> VmFleet<E, S, R> {
+    /// Create an empty [VmFleet] whose [Vm]s are prepared with the given components. By default, [VmmId]s are
+    /// allocated with the [DEFAULT_FLEET_ID_PREFIX] and the number of concurrent prepares and starts is unlimited.
+    pub fn new(
+        process_spawner: S,
+        runtime: R,
+        ownership_model: VmmOwnershipModel,
+        installation: Arc<VmmInstallation>,
+    ) -> Self {
+        Self {
+            process_spawner,
+            runtime,
+            ownership_model,
+            installation,
+            id_prefix: DEFAULT_FLEET_ID_PREFIX.to_owned(),
+            next_id: AtomicU64::new(0),
+            members: Mutex::new(HashMap::new()),
+            prepare_limit: ConcurrencyLimit::new(None),
+            start_limit: ConcurrencyLimit::new(None),
+        }
+    }
+
+    /// Set the prefix of allocated [VmmId]s, which are formatted as the prefix, a dash and a sequential number.
+    pub fn id_prefix(mut self, id_prefix: impl Into<String>) -> Self {
+        self.id_prefix = id_prefix.into();
+        self
+    }
+
+    /// Set the maximum amount of [Vm]s that can be prepared at the same time, with further prepares waiting. A limit
+    /// of zero is treated as a limit of one.
+    pub fn max_concurrent_prepares(mut self, max_concurrent_prepares: usize) -> Self {
+        self.prepare_limit = ConcurrencyLimit::new(Some(max_concurrent_prepares));
+        self
+    }
+
+    /// Set the maximum amount of [Vm]s that can be started at the same time, with further starts waiting. A limit
+    /// of zero is treated as a limit of one.
+    pub fn max_concurrent_starts(mut self, max_concurrent_starts: usize) -> Self {
+        self.start_limit = ConcurrencyLimit::new(Some(max_concurrent_starts));
+        self
+    }
+
+    /// Allocate a [VmmId] that is not in use in the [VmFleet] at the moment of allocation and will not be allocated
+    /// again by it.
+    pub fn allocate_id(&self) -> Result<VmmId, VmFleetError> {
+        loop {
+            let number = self.next_id.fetch_add(1, Ordering::Relaxed);
+            let id = VmmId::new(format!("{}-{number}", self.id_prefix)).map_err(VmFleetError::IdError)?;
+
+            if !self.lock_members().contains_key(&id) {
+                return Ok(id);
+            }
+        }
+    }
+
+    /// Prepare a [Vm] with a newly allocated [VmmId], the [VmmExecutor] produced by the given closure from that
+    /// [VmmId], the given [VmConfiguration] and labels, and add it to the [VmFleet].
+    pub async fn prepare(
+        &self,
+        executor_factory: impl FnOnce(&VmmId) -> E,
+        configuration: VmConfiguration,
+        labels: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<VmFleetMember<E, S, R>, VmFleetError> {
+        let id = self.allocate_id()?;
+        self.prepare_with_id(id, executor_factory, configuration, labels).await
+    }
+
+    /// Prepare a [Vm] analogously to [VmFleet::prepare], but with the given [VmmId] instead of an allocated one. The
+    /// [VmmId] is reserved while the [Vm] is being prepared, so concurrent prepares with the same [VmmId] fail instead
+    /// of preparing their [Vm]s with the same jail.
+    pub async fn prepare_with_id(
+        &self,
+        id: VmmId,
+        executor_factory: impl FnOnce(&VmmId) -> E,
+        configuration: VmConfiguration,
+        labels: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<VmFleetMember<E, S, R>, VmFleetError> {
+        let reservation = match self.lock_members().entry(id.clone()) {
+            Entry::Occupied(_) => return Err(VmFleetError::IdAlreadyInUse(id)),
+            Entry::Vacant(entry) => {
+                entry.insert(None);
+                IdReservation { fleet: self, id }
+            }
+        };
+
+        let executor = executor_factory(&reservation.id);
+        let vm = {
+            let _permit = self.prepare_limit.acquire().await;
+            Vm::prepare(
+                executor,
+                self.process_spawner.clone(),
+                self.runtime.clone(),
+                self.ownership_model,
+                self.installation.clone(),
+                configuration,
+            )
+            .await
+            .map_err(VmFleetError::PrepareError)?
+        };
+
+        let member = VmFleetMember {
+            id: reservation.id.clone(),
+            labels: Arc::new(labels.into_iter().collect()),
+            vm: Arc::new(AsyncMutex::new(vm)),
+        };
+
+        // the reserved placeholder can only be replaced here, so registering the member can't fail and leave the
+        // prepared VM behind
+        self.lock_members().insert(reservation.id.clone(), Some(member.clone()));
+        Ok(member)
+    }
+
+    /// Add an already existing [Vm], such as a recovered one, to the [VmFleet] under the given [VmmId] and labels.
+    pub fn insert(
+        &self,
+        id: VmmId,
+        vm: Vm<E, S, R>,
+        labels: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<VmFleetMember<E, S, R>, VmFleetError> {
+        let mut members = self.lock_members();
+
+        if members.contains_key(&id) {
+            return Err(VmFleetError::IdAlreadyInUse(id));
+        }
+
+        let member = VmFleetMember {
+            id: id.clone(),
+            labels: Arc::new(labels.into_iter().collect()),
+            vm: Arc::new(AsyncMutex::new(vm)),
+        };
+        members.insert(id, Some(member.clone()));
+        Ok(member)
+    }
+
+    /// Start the [Vm] with the given [VmmId], waiting for the concurrent start limit if it has been reached.
+    pub async fn start(&self, id: &VmmId, socket_wait_timeout: Duration) -> Result<(), VmFleetError> {
+        let member = self.get(id).ok_or_else(|| VmFleetError::NotFound(id.clone()))?;
+        let _permit = self.start_limit.acquire().await;
+        let mut vm = member.lock().await;
+        vm.start(socket_wait_timeout).await.map_err(VmFleetError::StartError)
+    }
+
+    /// Get the member of the [VmFleet] with the given [VmmId].
+    pub fn get(&self, id: &VmmId) -> Option<VmFleetMember<E, S, R>> {
+        self.lock_members().get(id).cloned().flatten()
+    }
+
+    /// Get all members of the [VmFleet] whose label with the given key is set to the given value.
+    pub fn find(&self, key: &str, value: &str) -> Vec<VmFleetMember<E, S, R>> {
+        self.lock_members()
+            .values()
+            .flatten()
+            .filter(|member| member.label(key) == Some(value))
+            .cloned()
+            .collect()
+    }
+
+    /// Get all members of the [VmFleet].
+    pub fn members(&self) -> Vec<VmFleetMember<E, S, R>> {
+        self.lock_members().values().flatten().cloned().collect()
+    }
+
+    /// Get the [VmmId]s of all members of the [VmFleet].
+    pub fn ids(&self) -> Vec<VmmId> {
+        self.lock_members()
+            .iter()
+            .filter(|(_, member)| member.is_some())
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    /// Get the amount of members of the [VmFleet].
+    pub fn len(&self) -> usize {
+        self.lock_members().values().flatten().count()
+    }
+
+    /// Whether the [VmFleet] has no members.
+    pub fn is_empty(&self) -> bool {
+        self.lock_members().values().all(Option::is_none)
+    }
+
+    /// Remove the member with the given [VmmId] from the [VmFleet] without performing any operations on its [Vm].
+    pub fn remove(&self, id: &VmmId) -> Option<VmFleetMember<E, S, R>> {
+        let mut members = self.lock_members();
+
+        match members.get(id) {
+            Some(Some(_)) => members.remove(id).flatten(),
+            _ => None,
+        }
+    }
+
+    /// Concurrently shut down all running or paused [Vm]s of the [VmFleet] with the given sequence of
+    /// [VmShutdownAction]s, returning the result for each of them.
+    pub async fn shutdown_all(
+        &self,
+        actions: impl IntoIterator<Item = VmShutdownAction>,
+    ) -> Vec<(VmmId, Result<VmShutdownOutcome, VmShutdownError>)> {
+        let actions = actions.into_iter().collect::<Vec<_>>();
+
+        join_all(self.members().into_iter().map(|member| {
+            let actions = actions.clone();
+
+            async move {
+                let mut vm = member.lock().await;
+
+                match vm.state() {
+                    VmState::Running | VmState::Paused => Some((member.id.clone(), vm.shutdown(actions).await)),
+                    _ => None,
+                }
+            }
+        }))
+        .await
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    /// Concurrently clean up all exited or crashed [Vm]s of the [VmFleet], removing the ones that were cleaned up
+    /// successfully from the [VmFleet] and returning the result for each of them.
+    pub async fn cleanup_all(&self) -> Vec<(VmmId, Result<(), VmError>)> {
+        let results = join_all(self.members().into_iter().map(|member| async move {
+            let mut vm = member.lock().await;
+
+            match vm.state() {
+                VmState::Exited | VmState::Crashed(_) => Some((member.id.clone(), vm.cleanup().await)),
+                _ => None,
+            }
+        }))
+        .await
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+
+        let mut members = self.lock_members();
+        for (id, result) in &results {
+            if result.is_ok() {
+                members.remove(id);
+            }
+        }
+
+        results
+    }
+
+    fn lock_members(&self) -> std::sync::MutexGuard<'_, VmFleetMembers<E, S, R>> {
+        self.members.lock().expect("Fleet members mutex was poisoned")
+    }
+}
+
+type VmFleetMembers<E, S, R> = HashMap<VmmId, Option<VmFleetMember<E, S, R>>>;
+
+/// A reservation of a [VmmId] in a [VmFleet] that is released when dropped, including when the prepare holding it is
+/// cancelled, unless a member has been registered under the [VmmId] by then.
+struct IdReservation<'a, E: VmmExecutor, S: ProcessSpawner, R: Runtime> {
+    fleet: &'a VmFleet<E, S, R>,
+    id: VmmId,
+}
+
+impl<E: VmmExecutor, S: ProcessSpawner, R: Runtime> Drop for IdReservation<'_, E, S, R> {
+    fn drop(&mut self) {
+        let mut members = self.fleet.lock_members();
+
+        if let Some(None) = members.get(&self.id) {
+            members.remove(&self.id);
+        }
+    }
+}
+
+/// A runtime-independent counting semaphore that holds its available permits as messages in a channel.
+#[derive(Debug)]
+struct ConcurrencyLimit(Option<(mpsc::UnboundedSender<()>, AsyncMutex<mpsc::UnboundedReceiver<()>>)>);
+
+struct ConcurrencyPermit<'a>(Option<&'a mpsc::UnboundedSender<()>>);
+
+impl ConcurrencyLimit {
+    fn new(limit: Option<usize>) -> Self {
+        Self(limit.map(|limit| {
+            let (sender, receiver) = mpsc::unbounded();
+
+            for _ in 0..limit.max(1) {
+                let _ = sender.unbounded_send(());
+            }
+
+            (sender, AsyncMutex::new(receiver))
+        }))
+    }
+
+    async fn acquire(&self) -> ConcurrencyPermit<'_> {
+        match self.0 {
+            Some((ref sender, ref receiver)) => {
+                // the sender is owned by the limit, so the channel can't be closed while a permit is being awaited
+                receiver.lock().await.next().await;
+                ConcurrencyPermit(Some(sender))
+            }
+            None => ConcurrencyPermit(None),
+        }
+    }
+}
+
+impl Drop for ConcurrencyPermit<'_> {
+    fn drop(&mut self) {
+        if let Some(sender) = self.0 {
+            let _ = sender.unbounded_send(());
+        }
+    }
+}
@@ -0,0 +1,458 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use futures_util::future::join_all;
+
+use crate::{
+    process_spawner::ProcessSpawner,
+    runtime::Runtime,
+    vm::{
+        api::{VmApi, VmApiError},
+        configuration::{VmConfiguration, VmConfigurationData},
+        models::Drive,
+        snapshot::VmSnapshot,
+        Vm, VmError, VmState,
+    },
+    vmm::{
+        executor::VmmExecutor,
+        id::{VmmId, VmmIdError},
+        installation::VmmInstallation,
+        ownership::{ChangeOwnerError, VmmOwnershipModel},
+        resource::{CreatedVmmResource, MovedVmmResource, ProducedVmmResource, VmmResourceMoveMethod},
+    },
+};
+
+use super::util::{stage_snapshot, tear_down, SnapshotStagingError};
+
+/// The default prefix of the [VmmId]s of clones created by a [VmForker].
+pub const DEFAULT_FORK_ID_PREFIX: &str = "fork";
+
+static FILE_NAME_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// An error that can be emitted by a [VmForker].
+#[derive(Debug)]
+pub enum VmForkError {
+    NotRunning(VmState),
+    IdError(VmmIdError),
+    PauseError(VmApiError),
+    SnapshotError(VmApiError),
+    ResumeError(VmApiError),
+    SnapshotStorageError(std::io::Error),
+    DriveCopyError(std::io::Error),
+    ChangeOwnerError(ChangeOwnerError),
+    CloneError { id: VmmId, error: VmError },
+    SharedWritableDrive { id: VmmId, drive_id: String },
+}
+
+impl std::error::Error for VmForkError {}
+
+impl std::fmt::Display for VmForkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VmForkError::NotRunning(state) => {
+                write!(
+                    f,
+                    "The source VM must be running to be forked, but its state was {state}"
+                )
+            }
+            VmForkError::IdError(err) => write!(f, "Formatting the VMM ID of a clone failed: {err}"),
+            VmForkError::PauseError(err) => write!(f, "Pausing the source VM failed: {err}"),
+            VmForkError::SnapshotError(err) => write!(f, "Creating a snapshot of the source VM failed: {err}"),
+            VmForkError::ResumeError(err) => write!(f, "Resuming the source VM failed: {err}"),
+            VmForkError::SnapshotStorageError(err) => {
+                write!(
+                    f,
+                    "A filesystem operation backed by the runtime for the snapshot failed: {err}"
+                )
+            }
+            VmForkError::DriveCopyError(err) => {
+                write!(f, "Copying a writable drive of the source VM for a clone failed: {err}")
+            }
+            VmForkError::ChangeOwnerError(err) => {
+                write!(f, "An ownership change of the snapshot directory failed: {err}")
+            }
+            VmForkError::CloneError { id, error } => {
+                write!(f, "Restoring the clone with the VMM ID {} failed: {error}", id.as_ref())
+            }
+            VmForkError::SharedWritableDrive { id, drive_id } => write!(
+                f,
+                "The writable drive {drive_id} of the clone with the VMM ID {} would be shared with the source VM",
+                id.as_ref()
+            ),
+        }
+    }
+}
+
+/// The options for forking a [Vm] with a [VmForker].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VmForkOptions {
+    snapshot_directory: PathBuf,
+    socket_wait_timeout: Duration,
+    id_prefix: String,
+    move_method: VmmResourceMoveMethod,
+}
+
+impl VmForkOptions {
+    /// Create options with the given snapshot directory and timeout for the API sockets of the clones to become
+    /// available. The snapshot directory is used both inside the jail of a jailed source [Vm] and on the host, where
+    /// the snapshot is kept after forking under file names unique to every fork, so that forking more than once into
+    /// the same directory keeps the snapshots of earlier forks intact. By default, clone [VmmId]s are formatted with the [DEFAULT_FORK_ID_PREFIX]
+    /// and the snapshot is hard linked into the clones, falling back to copying it.
+    pub fn new(snapshot_directory: impl Into<PathBuf>, socket_wait_timeout: Duration) -> Self {
+        Self {
+            snapshot_directory: snapshot_directory.into(),
+            socket_wait_timeout,
+            id_prefix: DEFAULT_FORK_ID_PREFIX.to_owned(),
+            move_method: VmmResourceMoveMethod::HardLinkOrCopy,
+        }
+    }
+
+    /// Set the prefix of the clone [VmmId]s, which are formatted as the prefix, a dash and the index of the clone.
+    /// Forking more than once while earlier clones still exist requires a different prefix every time.
+    pub fn id_prefix(mut self, id_prefix: impl Into<String>) -> Self {
+        self.id_prefix = id_prefix.into();
+        self
+    }
+
+    /// Set the [VmmResourceMoveMethod] used to move the snapshot and memory file into every clone.
+    pub fn move_method(mut self, move_method: VmmResourceMoveMethod) -> Self {
+        self.move_method = move_method;
+        self
+    }
+}
+
+/// A clone restored by a [VmForker], alongside the [VmmId] its [VmmExecutor] was created with.
+#[derive(Debug)]
+pub struct VmClone<E: VmmExecutor, S: ProcessSpawner, R: Runtime> {
+    pub id: VmmId,
+    pub vm: Vm<E, S, R>,
+}
+
+/// The outcome of forking a [Vm]: the shared [VmSnapshot] that all clones were restored from and the clones
+/// themselves, in the order of their indices. The [VmSnapshot] can be removed once the clones are no longer needed,
+/// or kept around to restore more clones from.
+#[derive(Debug)]
+pub struct VmFork<E: VmmExecutor, S: ProcessSpawner, R: Runtime> {
+    pub snapshot: VmSnapshot,
+    pub clones: Vec<VmClone<E, S, R>>,
+}
+
+/// A forker of running [Vm]s, which pauses and snapshots a source [Vm] once and restores many clones from the same
+/// [VmSnapshot] with the given components. Every clone gets its own [VmmId], which is passed to a closure producing
+/// its [VmmExecutor] and can be used as both the VMM ID and the jail ID, and its own copy of the
+/// [VmConfigurationData]: the vsock Unix socket, log and metrics paths are suffixed with the [VmmId], and further
+/// changes such as the host devices of network interfaces are made by a closure. The vsock and network interface
+/// changes are passed to the VMM as overrides when loading the snapshot, which requires a Firecracker release that
+/// accepts them (see [LoadSnapshot](crate::vm::models::LoadSnapshot)). Writable drives must not be shared between
+/// the source and the clones: the closure must either replace them or make them be copied into the clones with
+/// [VmmResourceMoveMethod::Copy] or [VmmResourceMoveMethod::FastCopy] by a [VmmExecutor] that moves resources,
+/// otherwise forking fails with [VmForkError::SharedWritableDrive]. A copied drive is copied from the file the source
+/// uses for it while the source is paused, so that its contents match the memory in the snapshot, and the copy keeps
+/// the file name of the drive, which the clone's [VmmExecutor] must map to the same VMM-local path as the source's
+/// did, as is the case with a [FlatJailRenamer](crate::vmm::executor::jailed::FlatJailRenamer).
+#[derive(Debug)]
+pub struct VmForker<S: ProcessSpawner, R: Runtime> {
+    process_spawner: S,
+    runtime: R,
+    ownership_model: VmmOwnershipModel,
+    installation: Arc<VmmInstallation>,
+}
+
+impl<S: ProcessSpawner, R: Runtime> VmForker<S, R> {
+    /// Create a [VmForker] whose clones are prepared with the given components.
+    pub fn new(
+        process_spawner: S,
+        runtime: R,
+        ownership_model: VmmOwnershipModel,
+        installation: Arc<VmmInstallation>,
+    ) -> Self {
+        Self {
+            process_spawner,
+            runtime,
+            ownership_model,
+            installation,
+        }
+    }
+
+    /// Fork the given running source [Vm] into the given number of clones according to the [VmForkOptions]. If any
+    /// clone would share a writable drive with the source, forking fails before the source is paused. Otherwise, the
+    /// source is paused while the snapshot is created and the writable drives of the clones are copied, and resumed
+    /// afterwards, and the clones are restored and resumed concurrently. If any clone fails to be restored, all other
+    /// clones are killed and cleaned up, and the snapshot is removed.
+    pub async fn fork<SourceE: VmmExecutor, E: VmmExecutor>(
+        &self,
+        source: &mut Vm<SourceE, S, R>,
+        count: usize,
+        executor_factory: impl Fn(&VmmId) -> E,
+        configure_clone: impl Fn(&VmmId, &mut VmConfigurationData),
+        options: VmForkOptions,
+    ) -> Result<VmFork<E, S, R>, VmForkError> {
+        let state = source.state();
+        if state != VmState::Running {
+            return Err(VmForkError::NotRunning(state));
+        }
+
+        let ids = (0..count)
+            .map(|index| VmmId::new(format!("{}-{index}", options.id_prefix)))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(VmForkError::IdError)?;
+
+        let source_data = source.configuration().data().clone();
+        let mut pending_clones = Vec::with_capacity(ids.len());
+
+        for id in ids {
+            let mut data = source_data.clone();
+            rewrite_configuration_data(&id, &mut data);
+            configure_clone(&id, &mut data);
+            let executor = executor_factory(&id);
+
+            if let Some(drive_id) = find_shared_writable_drive(&executor, &self.installation, &source_data, &data) {
+                return Err(VmForkError::SharedWritableDrive { id, drive_id });
+            }
+
+            pending_clones.push((id, executor, data));
+        }
+
+        let file_name = unique_file_name();
+        let snapshot = self.create_snapshot(source, &file_name, &options).await?;
+        let drive_directory = options.snapshot_directory.join(format!("{file_name}.drives"));
+
+        // the source stays paused until its writable drives are copied, so that they match the memory in the snapshot
+        if let Err(err) = self
+            .copy_drives(&source_data, &drive_directory, &mut pending_clones)
+            .await
+        {
+            let _ = source.api_resume().await;
+            let _ = self.runtime.fs_remove_dir_all(&drive_directory).await;
+            snapshot.remove(&self.runtime).await;
+            return Err(VmForkError::DriveCopyError(err));
+        }
+
+        if let Err(err) = source.api_resume().await {
+            let _ = self.runtime.fs_remove_dir_all(&drive_directory).await;
+            snapshot.remove(&self.runtime).await;
+            return Err(VmForkError::ResumeError(err));
+        }
+
+        let pending_clones = pending_clones.into_iter().map(|(id, executor, data)| {
+            let mut configuration = VmSnapshot {
+                snapshot: snapshot.snapshot.clone(),
+                mem_file: snapshot.mem_file.clone(),
+                configuration_data: data,
+            }
+            .into_configuration(options.move_method, None, Some(true));
+            configuration.override_devices();
+            self.restore_clone(id, executor, configuration, options.socket_wait_timeout)
+        });
+        let results = join_all(pending_clones).await;
+        // the copies of the drives have been moved into the clones by now, or are no longer needed
+        let _ = self.runtime.fs_remove_dir_all(&drive_directory).await;
+
+        let mut clones = Vec::with_capacity(results.len());
+        let mut error = None;
+
+        for result in results {
+            match result {
+                Ok(clone) => clones.push(clone),
+                Err(err) => {
+                    error.get_or_insert(err);
+                }
+            }
+        }
+
+        if let Some(error) = error {
+            join_all(
+                clones
+                    .iter_mut()
+                    .map(|clone| async { tear_down(&mut clone.vm).await.ok() }),
+            )
+            .await;
+            snapshot.remove(&self.runtime).await;
+            return Err(error);
+        }
+
+        Ok(VmFork { snapshot, clones })
+    }
+
+    async fn create_snapshot<SourceE: VmmExecutor>(
+        &self,
+        source: &mut Vm<SourceE, S, R>,
+        file_name: &str,
+        options: &VmForkOptions,
+    ) -> Result<VmSnapshot, VmForkError> {
+        stage_snapshot(
+            source,
+            &self.runtime,
+            self.ownership_model,
+            &options.snapshot_directory,
+            &format!("{file_name}.snapshot"),
+            &format!("{file_name}.mem"),
+            Some(&options.snapshot_directory),
+        )
+        .await
+        .map_err(|err| match err {
+            SnapshotStagingError::Storage(err) => VmForkError::SnapshotStorageError(err),
+            SnapshotStagingError::ChangeOwner(err) => VmForkError::ChangeOwnerError(err),
+            SnapshotStagingError::Pause(err) => VmForkError::PauseError(err),
+            SnapshotStagingError::Snapshot(err) => VmForkError::SnapshotError(err),
+        })
+    }
+
+    async fn copy_drives<E: VmmExecutor>(
+        &self,
+        source_data: &VmConfigurationData,
+        drive_directory: &Path,
+        pending_clones: &mut [(VmmId, E, VmConfigurationData)],
+    ) -> Result<(), std::io::Error> {
+        for (id, _, data) in pending_clones {
+            for drive in &mut data.drives {
+                let (Some(block), Some(source_drive)) = (drive.block.as_ref(), find_source_drive(source_data, drive))
+                else {
+                    continue;
+                };
+                let Some(source_path) = source_drive
+                    .block
+                    .as_ref()
+                    .and_then(|block| block.effective_path_checked())
+                else {
+                    continue;
+                };
+
+                let copy_directory = drive_directory.join(id.as_ref());
+                self.runtime.fs_create_dir_all(&copy_directory).await?;
+                let copy_path = copy_directory.join(block.source_path().file_name().unwrap_or_default());
+
+                match block.move_method() {
+                    VmmResourceMoveMethod::FastCopy => {
+                        self.runtime.fs_fast_copy(source_path, &copy_path).await?;
+                    }
+                    _ => self.runtime.fs_copy(source_path, &copy_path).await?,
+                }
+
+                drive.block = Some(MovedVmmResource::new(
+                    copy_path,
+                    VmmResourceMoveMethod::HardLinkOrFastCopy,
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn restore_clone<E: VmmExecutor>(
+        &self,
+        id: VmmId,
+        executor: E,
+        configuration: VmConfiguration,
+        socket_wait_timeout: Duration,
+    ) -> Result<VmClone<E, S, R>, VmForkError> {
+        let mut vm = match Vm::prepare(
+            executor,
+            self.process_spawner.clone(),
+            self.runtime.clone(),
+            self.ownership_model,
+            self.installation.clone(),
+            configuration,
+        )
+        .await
+        {
+            Ok(vm) => vm,
+            Err(error) => return Err(VmForkError::CloneError { id, error }),
+        };
+
+        if let Err(error) = vm.start(socket_wait_timeout).await {
+            let _ = tear_down(&mut vm).await;
+            return Err(VmForkError::CloneError { id, error });
+        }
+
+        Ok(VmClone { id, vm })
+    }
+}
+
+fn rewrite_configuration_data(id: &VmmId, data: &mut VmConfigurationData) {
+    if let Some(ref mut vsock_device) = data.vsock_device {
+        vsock_device.uds = ProducedVmmResource::new(with_id_suffix(vsock_device.uds.local_path(), id));
+    }
+
+    if let Some(ref mut logger_system) = data.logger_system {
+        if let Some(ref mut logs) = logger_system.logs {
+            *logs = CreatedVmmResource::new(with_id_suffix(logs.local_path(), id), logs.r#type());
+        }
+    }
+
+    if let Some(ref mut metrics_system) = data.metrics_system {
+        metrics_system.metrics = CreatedVmmResource::new(
+            with_id_suffix(metrics_system.metrics.local_path(), id),
+            metrics_system.metrics.r#type(),
+        );
+    }
+}
+
+// a writable drive of a clone is shared with the source if the source has a drive with the same source path
+fn find_source_drive<'a>(source_data: &'a VmConfigurationData, drive: &Drive) -> Option<&'a Drive> {
+    let source_path = drive.block.as_ref()?.source_path();
+
+    if drive.is_read_only == Some(true) {
+        return None;
+    }
+
+    source_data
+        .drives
+        .iter()
+        .find(|source_drive| source_drive.block.as_ref().map(|block| block.source_path()) == Some(source_path))
+}
+
+// a shared writable drive is only safe if it gets copied to another path in the clone
+fn find_shared_writable_drive<E: VmmExecutor>(
+    executor: &E,
+    installation: &VmmInstallation,
+    source_data: &VmConfigurationData,
+    data: &VmConfigurationData,
+) -> Option<String> {
+    data.drives
+        .iter()
+        .find(|drive| {
+            let Some(block) = drive.block.as_ref() else {
+                return false;
+            };
+
+            if find_source_drive(source_data, drive).is_none() {
+                return false;
+            }
+
+            let is_copied = matches!(
+                block.move_method(),
+                VmmResourceMoveMethod::Copy | VmmResourceMoveMethod::FastCopy
+            ) && executor.local_to_effective_path(installation, block.source_path().to_owned())
+                != block.source_path();
+            !is_copied
+        })
+        .map(|drive| drive.drive_id.clone())
+}
+
+fn unique_file_name() -> String {
+    format!(
+        "fork.{}.{}",
+        std::process::id(),
+        FILE_NAME_COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+fn with_id_suffix(path: &Path, id: &VmmId) -> PathBuf {
+    let mut file_name = path.file_stem().unwrap_or_default().to_owned();
+    file_name.push("-");
+    file_name.push(id.as_ref());
+
+    if let Some(extension) = path.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+
+    path.with_file_name(file_name)
+}
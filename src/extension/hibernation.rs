@@ -0,0 +1,537 @@
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use futures_util::{
+    lock::{MappedMutexGuard, Mutex as AsyncMutex, MutexGuard as AsyncMutexGuard},
+    AsyncRead, AsyncReadExt, AsyncWrite,
+};
+
+use crate::{
+    process_spawner::ProcessSpawner,
+    runtime::{Runtime, RuntimeListener, RuntimeTask},
+    vm::{
+        api::{VmApi, VmApiError},
+        shutdown::{VmShutdownAction, VmShutdownError, VmShutdownMethod},
+        snapshot::VmSnapshot,
+        Vm, VmError, VmState,
+    },
+    vmm::{
+        executor::VmmExecutor,
+        installation::VmmInstallation,
+        ownership::{ChangeOwnerError, VmmOwnershipModel},
+        resource::VmmResourceMoveMethod,
+    },
+};
+
+use super::{
+    util::{stage_snapshot, tear_down, SnapshotStagingError},
+    vsock_forward::{splice, VsockForwardAddress},
+    vsock_stream::{connect, get_socket_path, VsockStreamError},
+};
+
+/// An error that can be emitted by the hibernation extension.
+#[derive(Debug)]
+pub enum HibernationError {
+    VsockNotConfigured,
+    NotRunning(VmState),
+    Stopped,
+    CannotBind(std::io::Error),
+    CannotRemoveSocket(std::io::Error),
+    PauseError(VmApiError),
+    SnapshotError(VmApiError),
+    SnapshotStorageError(std::io::Error),
+    ChangeOwnerError(ChangeOwnerError),
+    ShutdownError(VmShutdownError),
+    CleanupError(VmError),
+    RestoreError(VmError),
+}
+
+impl std::error::Error for HibernationError {}
+
+impl std::fmt::Display for HibernationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HibernationError::VsockNotConfigured => write!(f, "A vsock device was not configured for this VM"),
+            HibernationError::NotRunning(state) => {
+                write!(f, "The VM must be running to be hibernated, but its state was {state}")
+            }
+            HibernationError::Stopped => write!(f, "The hibernating VM was already stopped"),
+            HibernationError::CannotBind(err) => write!(f, "Could not bind the host listener: {err}"),
+            HibernationError::CannotRemoveSocket(err) => {
+                write!(f, "Could not remove the Unix socket of the host listener: {err}")
+            }
+            HibernationError::PauseError(err) => write!(f, "Pausing the VM before hibernation failed: {err}"),
+            HibernationError::SnapshotError(err) => write!(f, "Creating the hibernation snapshot failed: {err}"),
+            HibernationError::SnapshotStorageError(err) => {
+                write!(
+                    f,
+                    "Moving the hibernation snapshot into its storage directory failed: {err}"
+                )
+            }
+            HibernationError::ChangeOwnerError(err) => write!(f, "An ownership change failed: {err}"),
+            HibernationError::ShutdownError(err) => write!(f, "Shutting down the hibernated VM failed: {err}"),
+            HibernationError::CleanupError(err) => write!(f, "Cleaning up the hibernated VM failed: {err}"),
+            HibernationError::RestoreError(err) => write!(f, "Restoring the VM from hibernation failed: {err}"),
+        }
+    }
+}
+
+/// The options of a [HibernatingVm].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HibernationOptions {
+    address: VsockForwardAddress,
+    guest_port: u32,
+    snapshot_directory: PathBuf,
+    idle_timeout: Duration,
+    idle_check_interval: Duration,
+    socket_wait_timeout: Duration,
+    move_method: VmmResourceMoveMethod,
+}
+
+impl HibernationOptions {
+    /// Create options that wake the [Vm] up for connections accepted on the given host address and hand them through
+    /// to the given guest vsock port, and that store hibernation snapshots in the given directory. By default, the
+    /// [Vm] is hibernated after 5 minutes without connections, which is checked every second, and the snapshot is
+    /// copied into the restored [Vm].
+    pub fn new(
+        address: VsockForwardAddress,
+        guest_port: u32,
+        snapshot_directory: impl Into<PathBuf>,
+        socket_wait_timeout: Duration,
+    ) -> Self {
+        Self {
+            address,
+            guest_port,
+            snapshot_directory: snapshot_directory.into(),
+            idle_timeout: Duration::from_secs(300),
+            idle_check_interval: Duration::from_secs(1),
+            socket_wait_timeout,
+            move_method: VmmResourceMoveMethod::Copy,
+        }
+    }
+
+    /// Set how long the [Vm] must have had no active connections to be hibernated.
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Set the interval at which the [Vm] is checked for being idle.
+    pub fn idle_check_interval(mut self, idle_check_interval: Duration) -> Self {
+        self.idle_check_interval = idle_check_interval;
+        self
+    }
+
+    /// Set the [VmmResourceMoveMethod] used to move the stored snapshot into the restored [Vm], which must keep the
+    /// stored files intact.
+    pub fn move_method(mut self, move_method: VmmResourceMoveMethod) -> Self {
+        self.move_method = move_method;
+        self
+    }
+}
+
+/// The state of a [HibernatingVm].
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum HibernationState<E: VmmExecutor, S: ProcessSpawner, R: Runtime> {
+    /// The [Vm] is running.
+    Awake(Vm<E, S, R>),
+    /// The [Vm] was snapshotted into the given [VmSnapshot] and killed.
+    Hibernated(VmSnapshot),
+}
+
+/// A snapshot of the counters of a [HibernatingVm].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct HibernationStats {
+    /// The amount of times the [Vm] was hibernated.
+    pub hibernations: u64,
+    /// The amount of times the [Vm] was restored from hibernation.
+    pub wakes: u64,
+    /// The amount of connections that are currently being handed through to the guest.
+    pub active_connections: usize,
+    /// The amount of connections that were closed because the [Vm] couldn't be woken up or the guest port couldn't
+    /// be connected to.
+    pub failed_connections: u64,
+    /// The amount of times hibernating the idle [Vm] failed. The [Vm] is left awake in that case, so hibernating it
+    /// is retried on the next idle check.
+    pub failed_hibernations: u64,
+}
+
+/// A hibernation manager that scales [Vm]s sharing the same [ProcessSpawner], [Runtime], [VmmOwnershipModel] and
+/// [VmmInstallation] to zero: idle [Vm]s are snapshotted to disk and killed, and restored on demand once a
+/// connection arrives on a host address owned by their [HibernatingVm].
+#[derive(Debug)]
+pub struct VmHibernationManager<S: ProcessSpawner, R: Runtime> {
+    process_spawner: S,
+    runtime: R,
+    ownership_model: VmmOwnershipModel,
+    installation: Arc<VmmInstallation>,
+}
+
+impl<S: ProcessSpawner, R: Runtime> VmHibernationManager<S, R> {
+    /// Create a [VmHibernationManager] whose [Vm]s are restored with the given components.
+    pub fn new(
+        process_spawner: S,
+        runtime: R,
+        ownership_model: VmmOwnershipModel,
+        installation: Arc<VmmInstallation>,
+    ) -> Self {
+        Self {
+            process_spawner,
+            runtime,
+            ownership_model,
+            installation,
+        }
+    }
+
+    /// Start managing the hibernation of the given running [Vm], which must have a vsock device. Restores prepare a
+    /// new [Vm] with the [VmmExecutor] returned by the given closure.
+    pub async fn manage<E: VmmExecutor + 'static>(
+        &self,
+        vm: Vm<E, S, R>,
+        executor_factory: impl Fn() -> E + Send + Sync + 'static,
+        options: HibernationOptions,
+    ) -> Result<HibernatingVm<E, S, R>, HibernationError> {
+        if vm.configuration().data().vsock_device.is_none() {
+            return Err(HibernationError::VsockNotConfigured);
+        }
+
+        let (local_address, listener) = match options.address {
+            VsockForwardAddress::Tcp(address) => {
+                let (listener, local_address) = self.runtime.tcp_bind(address).map_err(HibernationError::CannotBind)?;
+                (VsockForwardAddress::Tcp(local_address), Listener::<R>::Tcp(listener))
+            }
+            VsockForwardAddress::Unix(ref socket_path) => {
+                if self.runtime.fs_exists(socket_path).await.unwrap_or(false) {
+                    self.runtime
+                        .fs_remove_file(socket_path)
+                        .await
+                        .map_err(HibernationError::CannotBind)?;
+                }
+
+                let listener = self
+                    .runtime
+                    .unix_bind(socket_path)
+                    .map_err(HibernationError::CannotBind)?;
+                (
+                    VsockForwardAddress::Unix(socket_path.clone()),
+                    Listener::<R>::Unix(listener),
+                )
+            }
+        };
+
+        let shared = Arc::new(HibernationShared {
+            process_spawner: self.process_spawner.clone(),
+            runtime: self.runtime.clone(),
+            ownership_model: self.ownership_model,
+            installation: self.installation.clone(),
+            executor_factory: Box::new(executor_factory),
+            options,
+            state: AsyncMutex::new(Some(HibernationState::Awake(vm))),
+            last_activity: Mutex::new(Instant::now()),
+            hibernations: AtomicU64::new(0),
+            wakes: AtomicU64::new(0),
+            active_connections: AtomicUsize::new(0),
+            failed_connections: AtomicU64::new(0),
+            failed_hibernations: AtomicU64::new(0),
+        });
+
+        let accept_task = match listener {
+            Listener::Tcp(listener) => self.runtime.spawn_task(accept_connections(listener, shared.clone())),
+            Listener::Unix(listener) => self.runtime.spawn_task(accept_connections(listener, shared.clone())),
+        };
+        let idle_task = self.runtime.spawn_task(hibernate_when_idle(shared.clone()));
+
+        Ok(HibernatingVm {
+            local_address,
+            shared,
+            accept_task,
+            idle_task,
+        })
+    }
+}
+
+enum Listener<R: Runtime> {
+    Tcp(R::TcpListener),
+    Unix(R::UnixListener),
+}
+
+/// A [Vm] whose hibernation is managed by a [VmHibernationManager]. Dropping it detaches its tasks, so
+/// [HibernatingVm::stop] should be called to stop managing the [Vm].
+pub struct HibernatingVm<E: VmmExecutor, S: ProcessSpawner, R: Runtime> {
+    local_address: VsockForwardAddress,
+    shared: Arc<HibernationShared<E, S, R>>,
+    accept_task: R::Task<()>,
+    idle_task: R::Task<()>,
+}
+
+struct HibernationShared<E: VmmExecutor, S: ProcessSpawner, R: Runtime> {
+    process_spawner: S,
+    runtime: R,
+    ownership_model: VmmOwnershipModel,
+    installation: Arc<VmmInstallation>,
+    executor_factory: Box<dyn Fn() -> E + Send + Sync>,
+    options: HibernationOptions,
+    // none once the hibernating VM was stopped
+    state: AsyncMutex<Option<HibernationState<E, S, R>>>,
+    last_activity: Mutex<Instant>,
+    hibernations: AtomicU64,
+    wakes: AtomicU64,
+    active_connections: AtomicUsize,
+    failed_connections: AtomicU64,
+    failed_hibernations: AtomicU64,
+}
+
+impl<E: VmmExecutor, S: ProcessSpawner, R: Runtime> HibernatingVm<E, S, R> {
+    /// Get the host address connections are accepted on, with the actual port if TCP port 0 was requested.
+    pub fn local_address(&self) -> &VsockForwardAddress {
+        &self.local_address
+    }
+
+    /// Whether the [Vm] is currently hibernated.
+    pub async fn is_hibernated(&self) -> bool {
+        matches!(*self.shared.state.lock().await, Some(HibernationState::Hibernated(_)))
+    }
+
+    /// Get a snapshot of the counters of this [HibernatingVm].
+    pub fn stats(&self) -> HibernationStats {
+        HibernationStats {
+            hibernations: self.shared.hibernations.load(Ordering::Acquire),
+            wakes: self.shared.wakes.load(Ordering::Acquire),
+            active_connections: self.shared.active_connections.load(Ordering::Acquire),
+            failed_connections: self.shared.failed_connections.load(Ordering::Acquire),
+            failed_hibernations: self.shared.failed_hibernations.load(Ordering::Acquire),
+        }
+    }
+
+    /// Wake the [Vm] up if it is hibernated and lock it for exclusive access, which also prevents hibernation until
+    /// the lock is released. This counts as activity for idle detection.
+    pub async fn wake(
+        &self,
+    ) -> Result<MappedMutexGuard<'_, Option<HibernationState<E, S, R>>, Vm<E, S, R>>, HibernationError> {
+        let mut state = self.shared.state.lock().await;
+        wake(&self.shared, &mut state).await?;
+        self.shared.touch();
+
+        Ok(AsyncMutexGuard::map(state, |state| match state {
+            Some(HibernationState::Awake(vm)) => vm,
+            _ => unreachable!("The VM wasn't awake after being woken up"),
+        }))
+    }
+
+    /// Hibernate the [Vm] right away, regardless of it being idle. If the [Vm] survives being shut down, it is resumed
+    /// and the snapshot is removed. If only its cleanup fails, the [Vm] is hibernated regardless and the
+    /// [HibernationError::CleanupError] is returned.
+    pub async fn hibernate(&self) -> Result<(), HibernationError> {
+        let mut state = self.shared.state.lock().await;
+        hibernate(&self.shared, &mut state).await
+    }
+
+    /// Stop accepting connections and managing the hibernation of the [Vm], returning its [HibernationState].
+    /// Connections that are being handed through are left open until either side closes them. The Unix socket of
+    /// the host listener, if any, is removed.
+    pub async fn stop(self) -> Result<HibernationState<E, S, R>, HibernationError> {
+        self.accept_task.cancel().await;
+        self.idle_task.cancel().await;
+
+        let state = self.shared.state.lock().await.take().ok_or(HibernationError::Stopped)?;
+
+        if let VsockForwardAddress::Unix(ref socket_path) = self.local_address {
+            self.shared
+                .runtime
+                .fs_remove_file(socket_path)
+                .await
+                .map_err(HibernationError::CannotRemoveSocket)?;
+        }
+
+        Ok(state)
+    }
+}
+
+impl<E: VmmExecutor, S: ProcessSpawner, R: Runtime> HibernationShared<E, S, R> {
+    fn touch(&self) {
+        *self.last_activity.lock().expect("Last activity mutex was poisoned") = Instant::now();
+    }
+
+    fn idle_for(&self) -> Duration {
+        self.last_activity
+            .lock()
+            .expect("Last activity mutex was poisoned")
+            .elapsed()
+    }
+}
+
+async fn accept_connections<L: RuntimeListener, E: VmmExecutor + 'static, S: ProcessSpawner, R: Runtime>(
+    listener: L,
+    shared: Arc<HibernationShared<E, S, R>>,
+) {
+    while let Ok(stream) = listener.accept().await {
+        // the connection is counted right away, so that the VM can't be hibernated before it is handed through
+        shared.active_connections.fetch_add(1, Ordering::AcqRel);
+        drop(shared.runtime.spawn_task(hand_through(stream, shared.clone())));
+    }
+}
+
+async fn hand_through<T: AsyncRead + AsyncWrite + Unpin, E: VmmExecutor, S: ProcessSpawner, R: Runtime>(
+    stream: T,
+    shared: Arc<HibernationShared<E, S, R>>,
+) {
+    let vsock_stream = async {
+        let mut state = shared.state.lock().await;
+        wake(&shared, &mut state).await.ok()?;
+
+        let socket_path = match *state {
+            Some(HibernationState::Awake(ref vm)) => get_socket_path(vm).ok()?.to_owned(),
+            _ => return None,
+        };
+        drop(state);
+
+        connect(&shared.runtime, &socket_path, shared.options.guest_port)
+            .await
+            .map_err(|_: VsockStreamError| ())
+            .ok()
+    }
+    .await;
+
+    match vsock_stream {
+        Some(vsock_stream) => {
+            let (client_reader, client_writer) = stream.split();
+            let (guest_reader, guest_writer) = vsock_stream.split();
+            let bytes_transferred = AtomicU64::new(0);
+
+            // an error in either direction closes the whole connection, while EOF only half-closes it
+            let _ = futures_util::future::try_join(
+                splice(client_reader, guest_writer, &bytes_transferred, &AtomicU64::new(0)),
+                splice(guest_reader, client_writer, &bytes_transferred, &AtomicU64::new(0)),
+            )
+            .await;
+        }
+        None => {
+            shared.failed_connections.fetch_add(1, Ordering::AcqRel);
+        }
+    }
+
+    shared.touch();
+    shared.active_connections.fetch_sub(1, Ordering::AcqRel);
+}
+
+async fn hibernate_when_idle<E: VmmExecutor, S: ProcessSpawner, R: Runtime>(shared: Arc<HibernationShared<E, S, R>>) {
+    loop {
+        shared.runtime.sleep(shared.options.idle_check_interval).await;
+
+        if shared.active_connections.load(Ordering::Acquire) > 0 || shared.idle_for() < shared.options.idle_timeout {
+            continue;
+        }
+
+        let mut state = shared.state.lock().await;
+
+        // connections may have arrived while waiting for the lock
+        if shared.active_connections.load(Ordering::Acquire) > 0 || shared.idle_for() < shared.options.idle_timeout {
+            continue;
+        }
+
+        if let Some(HibernationState::Awake(_)) = *state {
+            if hibernate(&shared, &mut state).await.is_err() {
+                shared.failed_hibernations.fetch_add(1, Ordering::AcqRel);
+            }
+        }
+    }
+}
+
+async fn hibernate<E: VmmExecutor, S: ProcessSpawner, R: Runtime>(
+    shared: &HibernationShared<E, S, R>,
+    state: &mut Option<HibernationState<E, S, R>>,
+) -> Result<(), HibernationError> {
+    let vm = match state {
+        Some(HibernationState::Awake(vm)) => vm,
+        Some(HibernationState::Hibernated(_)) => return Ok(()),
+        None => return Err(HibernationError::Stopped),
+    };
+
+    let vm_state = vm.state();
+    if vm_state != VmState::Running {
+        return Err(HibernationError::NotRunning(vm_state));
+    }
+
+    let snapshot = stage_snapshot(
+        vm,
+        &shared.runtime,
+        shared.ownership_model,
+        &shared.options.snapshot_directory,
+        "hibernation.snapshot",
+        "hibernation.mem",
+        Some(&shared.options.snapshot_directory),
+    )
+    .await
+    .map_err(|err| match err {
+        SnapshotStagingError::Storage(err) => HibernationError::SnapshotStorageError(err),
+        SnapshotStagingError::ChangeOwner(err) => HibernationError::ChangeOwnerError(err),
+        SnapshotStagingError::Pause(err) => HibernationError::PauseError(err),
+        SnapshotStagingError::Snapshot(err) => HibernationError::SnapshotError(err),
+    })?;
+
+    let shutdown_result = vm
+        .shutdown(VmShutdownAction {
+            method: VmShutdownMethod::Kill,
+            timeout: Some(Duration::from_secs(1)),
+            graceful: false,
+        })
+        .await;
+
+    // a VM that survived the shutdown stays awake, while one that exited regardless can only be restored from the
+    // snapshot, so it counts as hibernated even if it couldn't be cleaned up
+    if let Err(err) = shutdown_result {
+        if matches!(vm.state(), VmState::Running | VmState::Paused) {
+            let _ = vm.api_resume().await;
+            snapshot.remove(&shared.runtime).await;
+            return Err(HibernationError::ShutdownError(err));
+        }
+    }
+
+    let cleanup_result = vm.cleanup().await;
+    *state = Some(HibernationState::Hibernated(snapshot));
+    shared.hibernations.fetch_add(1, Ordering::AcqRel);
+    cleanup_result.map_err(HibernationError::CleanupError)
+}
+
+async fn wake<E: VmmExecutor, S: ProcessSpawner, R: Runtime>(
+    shared: &HibernationShared<E, S, R>,
+    state: &mut Option<HibernationState<E, S, R>>,
+) -> Result<(), HibernationError> {
+    let snapshot = match state {
+        Some(HibernationState::Awake(_)) => return Ok(()),
+        Some(HibernationState::Hibernated(snapshot)) => snapshot.clone(),
+        None => return Err(HibernationError::Stopped),
+    };
+
+    let mut vm = Vm::prepare(
+        (shared.executor_factory)(),
+        shared.process_spawner.clone(),
+        shared.runtime.clone(),
+        shared.ownership_model,
+        shared.installation.clone(),
+        snapshot
+            .clone()
+            .into_configuration(shared.options.move_method, None, Some(true)),
+    )
+    .await
+    .map_err(HibernationError::RestoreError)?;
+
+    if let Err(err) = vm.start(shared.options.socket_wait_timeout).await {
+        let _ = tear_down(&mut vm).await;
+        return Err(HibernationError::RestoreError(err));
+    }
+
+    // the restored VM doesn't need the stored files anymore, even if it was backed by them directly
+    *state = Some(HibernationState::Awake(vm));
+    snapshot.remove(&shared.runtime).await;
+    shared.wakes.fetch_add(1, Ordering::AcqRel);
+    shared.touch();
+    Ok(())
+}
@@ -0,0 +1,434 @@
+use std::{
+    collections::VecDeque,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures_channel::mpsc;
+use futures_util::StreamExt;
+
+use crate::{
+    process_spawner::ProcessSpawner,
+    runtime::Runtime,
+    vm::{
+        configuration::VmConfiguration,
+        shutdown::{VmShutdownAction, VmShutdownError, VmShutdownMethod, VmShutdownOutcome},
+        Vm, VmError, VmState,
+    },
+    vmm::{
+        console::{VmmConsoleError, VmmConsoleLine, VmmConsoleSource},
+        executor::VmmExecutor,
+        installation::VmmInstallation,
+        ownership::VmmOwnershipModel,
+    },
+};
+
+use super::util::tear_down;
+
+/// The default marker that a guest prints to its serial console, immediately followed by the exit code of the job, to
+/// signal that the job has finished, such as "FCTOOLS_JOB_EXIT_CODE=0".
+pub const DEFAULT_JOB_EXIT_MARKER: &str = "FCTOOLS_JOB_EXIT_CODE=";
+
+/// The interval at which the state of a [Vm] is polled after its console was closed, until the VMM process exits.
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// An error that can be emitted by the job runner extension. The [Vm] is always torn down as far as possible before
+/// an error is returned.
+#[derive(Debug)]
+pub enum JobError {
+    PrepareError(VmError),
+    StartError(VmError),
+    ConsoleUnavailable(VmError),
+    InputError(VmmConsoleError),
+    ShutdownError {
+        shutdown_error: VmShutdownError,
+        cleanup_error: Option<VmError>,
+    },
+}
+
+impl std::error::Error for JobError {}
+
+impl std::fmt::Display for JobError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JobError::PrepareError(err) => write!(f, "Preparing the VM of the job failed: {err}"),
+            JobError::StartError(err) => write!(f, "Starting the VM of the job failed: {err}"),
+            JobError::ConsoleUnavailable(err) => write!(f, "The console of the VM of the job is unavailable: {err}"),
+            JobError::InputError(err) => write!(f, "Writing the input of the job to the console failed: {err}"),
+            JobError::ShutdownError {
+                shutdown_error,
+                cleanup_error,
+            } => {
+                write!(f, "Shutting down the VM of the job failed: {shutdown_error}")?;
+
+                if let Some(cleanup_error) = cleanup_error {
+                    write!(f, ", and cleaning it up afterwards failed as well: {cleanup_error}")?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The specification of a job run by a [JobRunner].
+#[derive(Debug, Clone)]
+pub struct JobSpec {
+    timeout: Duration,
+    socket_wait_timeout: Duration,
+    exit_marker: Option<String>,
+    input: Option<Vec<u8>>,
+    shutdown_actions: Vec<VmShutdownAction>,
+    output_sender: Option<mpsc::UnboundedSender<VmmConsoleLine>>,
+    max_output_lines: usize,
+}
+
+impl JobSpec {
+    /// Create a [JobSpec] with the given wall-clock timeout, which covers starting the [Vm] and running the job
+    /// until it finishes. By default, the [DEFAULT_JOB_EXIT_MARKER] is detected, no input is written, up to 10000
+    /// lines of output are captured and the [Vm] is shut down via Ctrl+Alt+Del with a fallback to a SIGKILL.
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            socket_wait_timeout: Duration::from_secs(5),
+            exit_marker: Some(DEFAULT_JOB_EXIT_MARKER.to_owned()),
+            input: None,
+            shutdown_actions: vec![
+                VmShutdownAction {
+                    method: VmShutdownMethod::CtrlAltDel,
+                    timeout: Some(Duration::from_secs(5)),
+                    graceful: true,
+                },
+                VmShutdownAction {
+                    method: VmShutdownMethod::Kill,
+                    timeout: Some(Duration::from_secs(1)),
+                    graceful: false,
+                },
+            ],
+            output_sender: None,
+            max_output_lines: 10000,
+        }
+    }
+
+    /// Set the timeout for the API socket to become available when starting the [Vm].
+    pub fn socket_wait_timeout(mut self, socket_wait_timeout: Duration) -> Self {
+        self.socket_wait_timeout = socket_wait_timeout;
+        self
+    }
+
+    /// Set the marker that the guest prints to the serial console, immediately followed by the exit code.
+    pub fn exit_marker(mut self, exit_marker: impl Into<String>) -> Self {
+        self.exit_marker = Some(exit_marker.into());
+        self
+    }
+
+    /// Don't detect any exit marker, so the job only finishes when the VMM process exits on its own, such as when the
+    /// guest powers off or reboots.
+    pub fn disable_exit_marker(mut self) -> Self {
+        self.exit_marker = None;
+        self
+    }
+
+    /// Write the given input to the serial console once the [Vm] has started, such as a command to be run by a shell.
+    pub fn input(mut self, input: impl Into<Vec<u8>>) -> Self {
+        self.input = Some(input.into());
+        self
+    }
+
+    /// Set the sequence of [VmShutdownAction]s used to shut down the [Vm] once the job has finished or timed out.
+    pub fn shutdown_actions(mut self, shutdown_actions: impl IntoIterator<Item = VmShutdownAction>) -> Self {
+        self.shutdown_actions = shutdown_actions.into_iter().collect();
+        self
+    }
+
+    /// Stream every line of output to the given sender while the job is running, in addition to capturing it.
+    pub fn output_sender(mut self, output_sender: mpsc::UnboundedSender<VmmConsoleLine>) -> Self {
+        self.output_sender = Some(output_sender);
+        self
+    }
+
+    /// Set the maximum amount of output lines captured into the [JobResult], with older lines being discarded.
+    pub fn max_output_lines(mut self, max_output_lines: usize) -> Self {
+        self.max_output_lines = max_output_lines;
+        self
+    }
+}
+
+/// How a job run by a [JobRunner] terminated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobTermination {
+    /// The guest printed the exit marker with the given exit code.
+    ExitMarker { exit_code: i32 },
+    /// The VMM process exited on its own before an exit marker was detected.
+    VmExited,
+    /// The wall-clock timeout elapsed before the job finished.
+    TimedOut,
+}
+
+/// The durations of the phases of a job run by a [JobRunner].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct JobTimings {
+    pub prepare: Duration,
+    pub start: Duration,
+    pub run: Duration,
+    pub shutdown: Duration,
+    pub cleanup: Duration,
+    pub total: Duration,
+}
+
+/// The result of a job run by a [JobRunner].
+#[derive(Debug)]
+pub struct JobResult {
+    /// How the job terminated.
+    pub termination: JobTermination,
+    /// The [VmState] of the [Vm] after it was shut down.
+    pub vm_state: VmState,
+    /// The captured output lines, which include both the serial console and the diagnostic output of the VMM.
+    pub output: Vec<VmmConsoleLine>,
+    /// The [VmShutdownOutcome], if the [Vm] had to be shut down rather than exiting on its own.
+    pub shutdown_outcome: Option<VmShutdownOutcome>,
+    /// The error that occurred when cleaning up the [Vm], if any.
+    pub cleanup_error: Option<VmError>,
+    /// The [JobTimings] of the job.
+    pub timings: JobTimings,
+}
+
+impl JobResult {
+    /// Get the exit code of the job: the one printed along with the exit marker or, if the VMM process exited on its
+    /// own, the exit code of the VMM process.
+    pub fn exit_code(&self) -> Option<i32> {
+        match (self.termination, self.vm_state) {
+            (JobTermination::ExitMarker { exit_code }, _) => Some(exit_code),
+            (JobTermination::VmExited, VmState::Exited) => Some(0),
+            (JobTermination::VmExited, VmState::Crashed(exit_status)) => exit_status.code(),
+            _ => None,
+        }
+    }
+
+    /// Get the captured serial console output, with lines joined by newlines.
+    pub fn serial_output(&self) -> String {
+        self.output
+            .iter()
+            .filter(|line| line.source == VmmConsoleSource::Stdout)
+            .map(|line| line.content.as_str())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// A runner for ephemeral jobs, each of which prepares and starts a [Vm], captures its console output until the guest
+/// signals that the job has finished or the VMM process exits, shuts the [Vm] down and always cleans it up.
+#[derive(Debug, Clone)]
+pub struct JobRunner<S: ProcessSpawner, R: Runtime> {
+    process_spawner: S,
+    runtime: R,
+    ownership_model: VmmOwnershipModel,
+    installation: Arc<VmmInstallation>,
+}
+
+impl<S: ProcessSpawner, R: Runtime> JobRunner<S, R> {
+    /// Create a [JobRunner] whose [Vm]s are prepared with the given components.
+    pub fn new(
+        process_spawner: S,
+        runtime: R,
+        ownership_model: VmmOwnershipModel,
+        installation: Arc<VmmInstallation>,
+    ) -> Self {
+        Self {
+            process_spawner,
+            runtime,
+            ownership_model,
+            installation,
+        }
+    }
+
+    /// Run a job in a [Vm] with the given [VmmExecutor] and [VmConfiguration] according to the [JobSpec]. The
+    /// [VmmExecutor] must not daemonize the VMM process, since the console of the [Vm] is required.
+    pub async fn run<E: VmmExecutor>(
+        &self,
+        executor: E,
+        configuration: VmConfiguration,
+        spec: JobSpec,
+    ) -> Result<JobResult, JobError> {
+        let total_start = Instant::now();
+        let mut timings = JobTimings::default();
+
+        let mut vm = Vm::prepare(
+            executor,
+            self.process_spawner.clone(),
+            self.runtime.clone(),
+            self.ownership_model,
+            self.installation.clone(),
+            configuration,
+        )
+        .await
+        .map_err(JobError::PrepareError)?;
+        timings.prepare = total_start.elapsed();
+
+        let deadline = Instant::now() + spec.timeout;
+        let phase_start = Instant::now();
+        let start_result = self
+            .runtime
+            .timeout(spec.timeout, vm.start(spec.socket_wait_timeout))
+            .await
+            .unwrap_or(Err(VmError::SocketWaitTimeout));
+        timings.start = phase_start.elapsed();
+
+        if let Err(err) = start_result {
+            let _ = tear_down(&mut vm).await;
+            return Err(JobError::StartError(err));
+        }
+
+        let console = match vm.console() {
+            Ok(console) => console,
+            Err(err) => {
+                let _ = tear_down(&mut vm).await;
+                return Err(JobError::ConsoleUnavailable(err));
+            }
+        };
+        let (scrollback, mut receiver) = console.subscribe_with_scrollback(256);
+
+        let phase_start = Instant::now();
+        let mut output = VecDeque::new();
+        let mut termination = None;
+
+        for line in scrollback {
+            if let Some(exit_code) = capture_line(&spec, &mut output, line) {
+                termination.get_or_insert(JobTermination::ExitMarker { exit_code });
+            }
+        }
+
+        if let Some(ref input) = spec.input {
+            if let Err(err) = console.write(input).await {
+                let _ = tear_down(&mut vm).await;
+                return Err(JobError::InputError(err));
+            }
+        }
+
+        while termination.is_none() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+
+            match self.runtime.timeout(remaining, receiver.next()).await {
+                Ok(Some(line)) => {
+                    if let Some(exit_code) = capture_line(&spec, &mut output, line) {
+                        termination = Some(JobTermination::ExitMarker { exit_code });
+                    }
+                }
+                Ok(None) => {
+                    termination = Some(wait_for_exit(&mut vm, deadline).await);
+                }
+                Err(_) => termination = Some(JobTermination::TimedOut),
+            }
+        }
+
+        let termination = termination.expect("No termination was determined for the job");
+        timings.run = phase_start.elapsed();
+
+        let phase_start = Instant::now();
+        let mut shutdown_outcome = None;
+
+        if matches!(vm.state(), VmState::Running | VmState::Paused) {
+            match vm.shutdown(spec.shutdown_actions.clone()).await {
+                Ok(outcome) => shutdown_outcome = Some(outcome),
+                Err(err) => {
+                    return Err(JobError::ShutdownError {
+                        shutdown_error: err,
+                        cleanup_error: tear_down(&mut vm).await.err(),
+                    })
+                }
+            }
+        }
+
+        timings.shutdown = phase_start.elapsed();
+
+        // lines that were read while the VM was shutting down are still captured
+        while let Ok(line) = receiver.try_recv() {
+            capture_line(&spec, &mut output, line);
+        }
+
+        let phase_start = Instant::now();
+        let cleanup_error = vm.cleanup().await.err();
+        timings.cleanup = phase_start.elapsed();
+        timings.total = total_start.elapsed();
+
+        Ok(JobResult {
+            termination,
+            vm_state: vm.state(),
+            output: output.into(),
+            shutdown_outcome,
+            cleanup_error,
+            timings,
+        })
+    }
+}
+
+fn capture_line(spec: &JobSpec, output: &mut VecDeque<VmmConsoleLine>, line: VmmConsoleLine) -> Option<i32> {
+    let exit_code = match (&spec.exit_marker, line.source) {
+        (Some(exit_marker), VmmConsoleSource::Stdout) => parse_exit_code(&line.content, exit_marker),
+        _ => None,
+    };
+
+    if let Some(ref output_sender) = spec.output_sender {
+        let _ = output_sender.unbounded_send(line.clone());
+    }
+
+    if spec.max_output_lines > 0 {
+        if output.len() == spec.max_output_lines {
+            output.pop_front();
+        }
+
+        output.push_back(line);
+    }
+
+    exit_code
+}
+
+fn parse_exit_code(content: &str, exit_marker: &str) -> Option<i32> {
+    let (_, rest) = content.split_once(exit_marker)?;
+    let length = rest
+        .char_indices()
+        .find(|(index, c)| !(c.is_ascii_digit() || (*index == 0 && *c == '-')))
+        .map(|(index, _)| index)
+        .unwrap_or(rest.len());
+
+    // shells echo the command printing the marker, which has no valid exit code after it, so such lines are ignored
+    rest[..length].parse().ok()
+}
+
+async fn wait_for_exit<E: VmmExecutor, S: ProcessSpawner, R: Runtime>(
+    vm: &mut Vm<E, S, R>,
+    deadline: Instant,
+) -> JobTermination {
+    // the console is closed when the VMM process closes its stdout, which can happen slightly before it exits
+    while matches!(vm.state(), VmState::Running | VmState::Paused) {
+        if Instant::now() >= deadline {
+            return JobTermination::TimedOut;
+        }
+
+        vm.runtime.sleep(EXIT_POLL_INTERVAL).await;
+    }
+
+    JobTermination::VmExited
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_exit_code, DEFAULT_JOB_EXIT_MARKER};
+
+    #[test]
+    fn exit_code_is_parsed_after_marker() {
+        assert_eq!(
+            parse_exit_code("FCTOOLS_JOB_EXIT_CODE=0", DEFAULT_JOB_EXIT_MARKER),
+            Some(0)
+        );
+        assert_eq!(
+            parse_exit_code("[  1.0] FCTOOLS_JOB_EXIT_CODE=-1 done", DEFAULT_JOB_EXIT_MARKER),
+            Some(-1)
+        );
+        assert_eq!(
+            parse_exit_code("# echo FCTOOLS_JOB_EXIT_CODE=$?", DEFAULT_JOB_EXIT_MARKER),
+            None
+        );
+        assert_eq!(parse_exit_code("no marker", DEFAULT_JOB_EXIT_MARKER), None);
+    }
+}
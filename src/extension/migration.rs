@@ -0,0 +1,703 @@
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    pin::pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Poll, Waker},
+    time::Duration,
+};
+
+use futures_util::{
+    future::{poll_fn, select, Either},
+    AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    process_spawner::ProcessSpawner,
+    runtime::Runtime,
+    vm::{
+        api::{VmApi, VmApiError},
+        configuration::{ReprVmConfigurationData, VmConfigurationData},
+        snapshot::VmSnapshot,
+        Vm, VmError, VmState,
+    },
+    vmm::{
+        executor::VmmExecutor,
+        installation::VmmInstallation,
+        ownership::{ChangeOwnerError, VmmOwnershipModel},
+        resource::{ProducedVmmResource, VmmResourceMoveMethod},
+    },
+};
+
+use super::util::{stage_snapshot, tear_down, SnapshotStagingError};
+
+/// The version of the migration protocol spoken by a [VmMigrator]. Both ends of a migration must speak the same
+/// version.
+pub const MIGRATION_PROTOCOL_VERSION: u32 = 2;
+
+const CHUNK_SIZE: usize = 256 * 1024;
+const MAX_MESSAGE_LENGTH: u64 = 16 * 1024 * 1024;
+const STATUS_RESTORED: u8 = 0;
+const STATUS_FAILED: u8 = 1;
+const STATUS_COMMITTED: u8 = 2;
+
+static FILE_NAME_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// An error that can be emitted by a [VmMigrator].
+#[derive(Debug)]
+pub enum VmMigrationError {
+    NotRunning(VmState),
+    PauseError(VmApiError),
+    ResumeError(VmApiError),
+    SnapshotError(VmApiError),
+    SnapshotStorageError(std::io::Error),
+    ChangeOwnerError(ChangeOwnerError),
+    TransferError(std::io::Error),
+    SerdeError(serde_json::Error),
+    UnsupportedVersion(u32),
+    Cancelled,
+    RestoreError(VmError),
+    ReceiverFailed(String),
+    SourceNotKilled(VmState),
+    SenderFailed(String),
+}
+
+impl std::error::Error for VmMigrationError {}
+
+impl std::fmt::Display for VmMigrationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VmMigrationError::NotRunning(state) => {
+                write!(f, "The VM must be running to be migrated, but its state was {state}")
+            }
+            VmMigrationError::PauseError(err) => write!(f, "Pausing the source VM failed: {err}"),
+            VmMigrationError::ResumeError(err) => write!(f, "Resuming the restored VM failed: {err}"),
+            VmMigrationError::SnapshotError(err) => write!(f, "Creating a snapshot of the source VM failed: {err}"),
+            VmMigrationError::SnapshotStorageError(err) => {
+                write!(
+                    f,
+                    "A filesystem operation backed by the runtime for the snapshot failed: {err}"
+                )
+            }
+            VmMigrationError::ChangeOwnerError(err) => {
+                write!(f, "An ownership change of the snapshot directory failed: {err}")
+            }
+            VmMigrationError::TransferError(err) => write!(f, "Transferring over the connection failed: {err}"),
+            VmMigrationError::SerdeError(err) => write!(f, "Serializing or deserializing the header failed: {err}"),
+            VmMigrationError::UnsupportedVersion(version) => {
+                write!(f, "The other end speaks the unsupported protocol version {version}")
+            }
+            VmMigrationError::Cancelled => write!(f, "The migration was cancelled"),
+            VmMigrationError::RestoreError(err) => write!(f, "Restoring the migrated VM failed: {err}"),
+            VmMigrationError::ReceiverFailed(message) => {
+                write!(f, "The receiving end failed to restore the VM: {message}")
+            }
+            VmMigrationError::SourceNotKilled(state) => {
+                write!(f, "The source VM could not be killed, its state was {state}")
+            }
+            VmMigrationError::SenderFailed(message) => {
+                write!(f, "The sending end failed to commit the migration: {message}")
+            }
+        }
+    }
+}
+
+/// A handle to cancel an ongoing migration from elsewhere, which can be cloned and shared by both ends of a migration.
+/// A cancelled sender resumes its source [Vm], while a cancelled receiver tears down the restored [Vm] if there is one
+/// and removes the files it has received, and both close the connection so that the other end fails as well. Once
+/// the sender has killed the source [Vm], the migration can no longer be cancelled.
+#[derive(Debug, Clone, Default)]
+pub struct VmMigrationCancellation(Arc<CancellationInner>);
+
+#[derive(Debug, Default)]
+struct CancellationInner {
+    cancelled: AtomicBool,
+    wakers: Mutex<Vec<Waker>>,
+}
+
+impl VmMigrationCancellation {
+    /// Create a handle that isn't cancelled yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancel the migrations this handle was passed to.
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::Release);
+
+        for waker in self.lock_wakers().drain(..) {
+            waker.wake();
+        }
+    }
+
+    /// Whether this handle was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::Acquire)
+    }
+
+    fn cancelled(&self) -> impl Future<Output = ()> + '_ {
+        poll_fn(|cx| {
+            if self.is_cancelled() {
+                return Poll::Ready(());
+            }
+
+            let mut wakers = self.lock_wakers();
+            if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+                wakers.push(cx.waker().clone());
+            }
+            drop(wakers);
+
+            // the handle may have been cancelled before the waker was registered
+            match self.is_cancelled() {
+                true => Poll::Ready(()),
+                false => Poll::Pending,
+            }
+        })
+    }
+
+    fn lock_wakers(&self) -> std::sync::MutexGuard<'_, Vec<Waker>> {
+        self.0.wakers.lock().expect("Wakers mutex was poisoned")
+    }
+}
+
+/// The options for either end of a migration with a [VmMigrator].
+#[derive(Debug, Clone)]
+pub struct VmMigrationOptions {
+    snapshot_directory: PathBuf,
+    socket_wait_timeout: Duration,
+    move_method: VmmResourceMoveMethod,
+    cancellation: VmMigrationCancellation,
+}
+
+impl VmMigrationOptions {
+    /// Create options with the given snapshot directory and timeout for the API socket of the restored [Vm] to become
+    /// available, which is only used by the receiving end. The sending end creates the snapshot in the directory,
+    /// both inside the jail of a jailed [Vm] and on the host, while the receiving end writes the received snapshot
+    /// into it. The files of every migration are named uniquely, so the directory can be shared by concurrent
+    /// migrations. By default, the received snapshot is hard linked into the restored [Vm], falling back to copying it.
+    pub fn new(snapshot_directory: impl Into<PathBuf>, socket_wait_timeout: Duration) -> Self {
+        Self {
+            snapshot_directory: snapshot_directory.into(),
+            socket_wait_timeout,
+            move_method: VmmResourceMoveMethod::HardLinkOrCopy,
+            cancellation: VmMigrationCancellation::new(),
+        }
+    }
+
+    /// Set the [VmmResourceMoveMethod] used by the receiving end to move the received snapshot into the restored [Vm].
+    pub fn move_method(mut self, move_method: VmmResourceMoveMethod) -> Self {
+        self.move_method = move_method;
+        self
+    }
+
+    /// Set the [VmMigrationCancellation] handle that can cancel the migration.
+    pub fn cancellation(mut self, cancellation: VmMigrationCancellation) -> Self {
+        self.cancellation = cancellation;
+        self
+    }
+}
+
+/// The phase a migration is in, as reported to the progress callback of either end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum VmMigrationPhase {
+    /// The sending end is pausing and snapshotting the source [Vm].
+    Snapshotting,
+    /// The snapshot and memory file are being transferred.
+    Transferring,
+    /// The receiving end is restoring the [Vm], while the sending end waits for it to finish.
+    Restoring,
+    /// The [Vm] runs on the receiving end, and the source [Vm] has been killed on the sending end.
+    Completed,
+}
+
+/// The progress of a migration, as reported to the progress callback of either end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct VmMigrationProgress {
+    /// The current phase of the migration.
+    pub phase: VmMigrationPhase,
+    /// The amount of bytes of the snapshot and memory file transferred so far.
+    pub transferred_bytes: u64,
+    /// The total size of the snapshot and memory file, which is zero until it is known.
+    pub total_bytes: u64,
+}
+
+/// The outcome of receiving a migration: the restored [Vm] and the received [VmSnapshot] it was restored from, which
+/// can be removed once it's no longer needed.
+#[derive(Debug)]
+pub struct ReceivedVmMigration<E: VmmExecutor, S: ProcessSpawner, R: Runtime> {
+    pub vm: Vm<E, S, R>,
+    pub snapshot: VmSnapshot,
+}
+
+/// A migrator of running [Vm]s between hosts, which streams a snapshot of the source [Vm] over a connection such as
+/// one made via [Runtime::tcp_connect] and [Runtime::tcp_bind], and restores it on the receiving end with the given
+/// components. The source [Vm] stays paused during the transfer and is only killed once the receiving end reports
+/// that the [Vm] was restored, otherwise it is resumed. The restored [Vm] stays paused until the sending end commits
+/// the migration after killing the source [Vm], otherwise it is torn down, so that both never run at the same time.
+/// If the connection breaks after the source [Vm] was killed but before the commit was received, the [Vm] is lost.
+#[derive(Debug)]
+pub struct VmMigrator<S: ProcessSpawner, R: Runtime> {
+    process_spawner: S,
+    runtime: R,
+    ownership_model: VmmOwnershipModel,
+    installation: Arc<VmmInstallation>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ReprMigrationHeader {
+    version: u32,
+    snapshot_size: u64,
+    mem_file_size: u64,
+    #[serde(flatten)]
+    configuration_data: ReprVmConfigurationData,
+}
+
+struct ProgressReporter<F: Fn(VmMigrationProgress)> {
+    on_progress: F,
+    progress: VmMigrationProgress,
+}
+
+impl<F: Fn(VmMigrationProgress)> ProgressReporter<F> {
+    fn report(&mut self, phase: VmMigrationPhase) {
+        self.progress.phase = phase;
+        (self.on_progress)(self.progress);
+    }
+
+    fn advance(&mut self, bytes: u64) {
+        self.progress.transferred_bytes += bytes;
+        (self.on_progress)(self.progress);
+    }
+}
+
+impl<S: ProcessSpawner, R: Runtime> VmMigrator<S, R> {
+    /// Create a [VmMigrator] whose received [Vm]s are prepared with the given components.
+    pub fn new(
+        process_spawner: S,
+        runtime: R,
+        ownership_model: VmmOwnershipModel,
+        installation: Arc<VmmInstallation>,
+    ) -> Self {
+        Self {
+            process_spawner,
+            runtime,
+            ownership_model,
+            installation,
+        }
+    }
+
+    /// Migrate the given running source [Vm] over the given connection to a receiving end calling
+    /// [VmMigrator::receive], reporting progress to the given callback. Once the receiving end reports that the
+    /// [Vm] was restored, the source [Vm] is killed and cleaned up, and the migration is committed for the receiving
+    /// end to resume the restored [Vm]. If the migration fails or is cancelled before that, or the source [Vm] can't
+    /// be killed, the source [Vm] is resumed instead and the receiving end is told to tear the restored [Vm] down.
+    /// Either way, the snapshot is removed from the sending end.
+    pub async fn send<E: VmmExecutor, T: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        source: &mut Vm<E, S, R>,
+        mut stream: T,
+        options: VmMigrationOptions,
+        on_progress: impl Fn(VmMigrationProgress),
+    ) -> Result<(), VmMigrationError> {
+        let state = source.state();
+        if state != VmState::Running {
+            return Err(VmMigrationError::NotRunning(state));
+        }
+
+        let mut reporter = ProgressReporter {
+            on_progress,
+            progress: VmMigrationProgress {
+                phase: VmMigrationPhase::Snapshotting,
+                transferred_bytes: 0,
+                total_bytes: 0,
+            },
+        };
+        reporter.report(VmMigrationPhase::Snapshotting);
+
+        let snapshot = self.create_snapshot(source, &options).await?;
+        let result = self
+            .send_snapshot(&snapshot, &mut stream, &options, &mut reporter)
+            .await;
+        snapshot.remove(&self.runtime).await;
+
+        if let Err(err) = result {
+            let _ = stream.close().await;
+            let _ = source.api_resume().await;
+            return Err(err);
+        }
+
+        // the source must be gone before the commit is sent, since the receiving end resumes the restored VM as soon
+        // as it receives the commit, and a commit that never arrives makes it tear the restored VM down instead
+        let _ = tear_down(source).await;
+        let source_state = source.state();
+        let source_killed = matches!(source_state, VmState::Exited | VmState::Crashed(_));
+
+        let status = match source_killed {
+            true => vec![STATUS_COMMITTED],
+            false => {
+                let _ = source.api_resume().await;
+                let mut status = vec![STATUS_FAILED];
+                status.extend_from_slice(&encode_message(
+                    VmMigrationError::SourceNotKilled(source_state).to_string().as_bytes(),
+                ));
+                status
+            }
+        };
+        let commit_result = async {
+            stream.write_all(&status).await?;
+            stream.flush().await
+        }
+        .await
+        .map_err(VmMigrationError::TransferError);
+        let _ = stream.close().await;
+
+        if !source_killed {
+            return Err(VmMigrationError::SourceNotKilled(source_state));
+        }
+
+        commit_result?;
+
+        reporter.report(VmMigrationPhase::Completed);
+        Ok(())
+    }
+
+    /// Receive a migration over the given connection from a sending end calling [VmMigrator::send], reporting progress
+    /// to the given callback. The [VmConfigurationData] of the source [Vm] is passed to the given closure, which can
+    /// adapt host-specific paths and network host devices to this host, and the restored [Vm] is prepared with the
+    /// given [VmmExecutor] and only resumed once the sending end commits the migration. The network interfaces and vsock
+    /// device are passed to the VMM as overrides when loading the snapshot, which requires a Firecracker release that
+    /// accepts them (see [LoadSnapshot](crate::vm::models::LoadSnapshot)). If the migration fails or is cancelled, or
+    /// the sending end can't kill the source [Vm], the restored [Vm] is torn down and the received files are removed.
+    pub async fn receive<E: VmmExecutor, T: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        mut stream: T,
+        executor: E,
+        configure: impl FnOnce(&mut VmConfigurationData),
+        options: VmMigrationOptions,
+        on_progress: impl Fn(VmMigrationProgress),
+    ) -> Result<ReceivedVmMigration<E, S, R>, VmMigrationError> {
+        let mut reporter = ProgressReporter {
+            on_progress,
+            progress: VmMigrationProgress {
+                phase: VmMigrationPhase::Transferring,
+                transferred_bytes: 0,
+                total_bytes: 0,
+            },
+        };
+
+        let snapshot = match self
+            .receive_snapshot(&mut stream, configure, &options, &mut reporter)
+            .await
+        {
+            Ok(snapshot) => snapshot,
+            Err(err) => {
+                let _ = stream.close().await;
+                return Err(err);
+            }
+        };
+
+        reporter.report(VmMigrationPhase::Restoring);
+        let mut configuration = snapshot
+            .clone()
+            .into_configuration(options.move_method, None, Some(false));
+        configuration.override_devices();
+
+        let result = match Vm::prepare(
+            executor,
+            self.process_spawner.clone(),
+            self.runtime.clone(),
+            self.ownership_model,
+            self.installation.clone(),
+            configuration,
+        )
+        .await
+        {
+            Ok(mut vm) => match vm.start(options.socket_wait_timeout).await {
+                Ok(()) => Ok(vm),
+                Err(err) => {
+                    let _ = tear_down(&mut vm).await;
+                    Err(err)
+                }
+            },
+            Err(err) => Err(err),
+        };
+
+        let status = match result {
+            Ok(ref _vm) => vec![STATUS_RESTORED],
+            Err(ref err) => {
+                let mut status = vec![STATUS_FAILED];
+                status.extend_from_slice(&encode_message(err.to_string().as_bytes()));
+                status
+            }
+        };
+        let status_result = cancellable(&options.cancellation, async {
+            stream.write_all(&status).await?;
+            stream.flush().await
+        })
+        .await;
+
+        let mut vm = match result {
+            Ok(vm) => vm,
+            Err(err) => {
+                let _ = stream.close().await;
+                snapshot.remove(&self.runtime).await;
+                return Err(VmMigrationError::RestoreError(err));
+            }
+        };
+
+        // the sending end resumes the source unless it has killed it and committed the migration, so the restored VM
+        // may only be resumed once the commit has arrived
+        let commit_result = match status_result {
+            Ok(()) => {
+                let mut commit = [0];
+                match cancellable(&options.cancellation, stream.read_exact(&mut commit)).await {
+                    Ok(()) => match commit[0] {
+                        STATUS_COMMITTED => Ok(()),
+                        STATUS_FAILED => match read_message(&mut stream, &options).await {
+                            Ok(message) => Err(VmMigrationError::SenderFailed(
+                                String::from_utf8_lossy(&message).into_owned(),
+                            )),
+                            Err(err) => Err(err),
+                        },
+                        status => Err(VmMigrationError::TransferError(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!("Expected the commit of the migration but received {status}"),
+                        ))),
+                    },
+                    Err(err) => Err(err),
+                }
+            }
+            Err(err) => Err(err),
+        };
+        let _ = stream.close().await;
+
+        if let Err(err) = match commit_result {
+            Ok(()) => vm.api_resume().await.map_err(VmMigrationError::ResumeError),
+            Err(err) => Err(err),
+        } {
+            let _ = tear_down(&mut vm).await;
+            snapshot.remove(&self.runtime).await;
+            return Err(err);
+        }
+
+        reporter.report(VmMigrationPhase::Completed);
+        Ok(ReceivedVmMigration { vm, snapshot })
+    }
+
+    async fn create_snapshot<E: VmmExecutor>(
+        &self,
+        source: &mut Vm<E, S, R>,
+        options: &VmMigrationOptions,
+    ) -> Result<VmSnapshot, VmMigrationError> {
+        // the snapshot is streamed from where the VMM wrote it, since the source is only cleaned up afterwards
+        let (snapshot_file_name, mem_file_name) = unique_file_names();
+        let snapshot = stage_snapshot(
+            source,
+            &self.runtime,
+            self.ownership_model,
+            &options.snapshot_directory,
+            &snapshot_file_name,
+            &mem_file_name,
+            None,
+        )
+        .await
+        .map_err(|err| match err {
+            SnapshotStagingError::Storage(err) => VmMigrationError::SnapshotStorageError(err),
+            SnapshotStagingError::ChangeOwner(err) => VmMigrationError::ChangeOwnerError(err),
+            SnapshotStagingError::Pause(err) => VmMigrationError::PauseError(err),
+            SnapshotStagingError::Snapshot(err) => VmMigrationError::SnapshotError(err),
+        })?;
+
+        Ok(snapshot)
+    }
+
+    async fn send_snapshot<T: AsyncRead + AsyncWrite + Unpin, F: Fn(VmMigrationProgress)>(
+        &self,
+        snapshot: &VmSnapshot,
+        stream: &mut T,
+        options: &VmMigrationOptions,
+        reporter: &mut ProgressReporter<F>,
+    ) -> Result<(), VmMigrationError> {
+        let snapshot_size = file_size(&self.runtime, snapshot.snapshot.effective_path()).await?;
+        let mem_file_size = file_size(&self.runtime, snapshot.mem_file.effective_path()).await?;
+        let header = serde_json::to_vec(&ReprMigrationHeader {
+            version: MIGRATION_PROTOCOL_VERSION,
+            snapshot_size,
+            mem_file_size,
+            configuration_data: ReprVmConfigurationData::new(&snapshot.configuration_data),
+        })
+        .map_err(VmMigrationError::SerdeError)?;
+
+        reporter.progress.total_bytes = snapshot_size + mem_file_size;
+        reporter.report(VmMigrationPhase::Transferring);
+        cancellable(&options.cancellation, stream.write_all(&encode_message(&header))).await?;
+
+        for (path, size) in [
+            (snapshot.snapshot.effective_path(), snapshot_size),
+            (snapshot.mem_file.effective_path(), mem_file_size),
+        ] {
+            let mut file = self
+                .runtime
+                .fs_open_file_for_read(path)
+                .await
+                .map_err(VmMigrationError::SnapshotStorageError)?;
+            transfer(&mut file, stream, size, options, reporter).await?;
+        }
+
+        cancellable(&options.cancellation, stream.flush()).await?;
+        reporter.report(VmMigrationPhase::Restoring);
+
+        let mut status = [0];
+        cancellable(&options.cancellation, stream.read_exact(&mut status)).await?;
+
+        match status[0] {
+            STATUS_RESTORED => Ok(()),
+            _ => {
+                let message = read_message(stream, options).await?;
+                Err(VmMigrationError::ReceiverFailed(
+                    String::from_utf8_lossy(&message).into_owned(),
+                ))
+            }
+        }
+    }
+
+    async fn receive_snapshot<T: AsyncRead + AsyncWrite + Unpin, F: Fn(VmMigrationProgress)>(
+        &self,
+        stream: &mut T,
+        configure: impl FnOnce(&mut VmConfigurationData),
+        options: &VmMigrationOptions,
+        reporter: &mut ProgressReporter<F>,
+    ) -> Result<VmSnapshot, VmMigrationError> {
+        let header = read_message(stream, options).await?;
+        let header = serde_json::from_slice::<ReprMigrationHeader>(&header).map_err(VmMigrationError::SerdeError)?;
+
+        if header.version != MIGRATION_PROTOCOL_VERSION {
+            return Err(VmMigrationError::UnsupportedVersion(header.version));
+        }
+
+        reporter.progress.total_bytes = header.snapshot_size + header.mem_file_size;
+        reporter.report(VmMigrationPhase::Transferring);
+
+        self.runtime
+            .fs_create_dir_all(&options.snapshot_directory)
+            .await
+            .map_err(VmMigrationError::SnapshotStorageError)?;
+
+        let mut configuration_data = header.configuration_data.into_data();
+        configure(&mut configuration_data);
+        let (snapshot_file_name, mem_file_name) = unique_file_names();
+        let snapshot = VmSnapshot {
+            snapshot: received_resource(options.snapshot_directory.join(snapshot_file_name)),
+            mem_file: received_resource(options.snapshot_directory.join(mem_file_name)),
+            configuration_data,
+        };
+
+        for (path, size) in [
+            (snapshot.snapshot.effective_path(), header.snapshot_size),
+            (snapshot.mem_file.effective_path(), header.mem_file_size),
+        ] {
+            let result = async {
+                let mut file = self
+                    .runtime
+                    .fs_open_file_for_write(path)
+                    .await
+                    .map_err(VmMigrationError::SnapshotStorageError)?;
+                transfer(stream, &mut file, size, options, reporter).await?;
+                file.close().await.map_err(VmMigrationError::SnapshotStorageError)
+            }
+            .await;
+
+            if let Err(err) = result {
+                let _ = self.runtime.fs_remove_file(snapshot.snapshot.effective_path()).await;
+                let _ = self.runtime.fs_remove_file(snapshot.mem_file.effective_path()).await;
+                return Err(err);
+            }
+        }
+
+        Ok(snapshot)
+    }
+}
+
+async fn cancellable<O>(
+    cancellation: &VmMigrationCancellation,
+    future: impl Future<Output = Result<O, std::io::Error>>,
+) -> Result<O, VmMigrationError> {
+    match select(pin!(cancellation.cancelled()), pin!(future)).await {
+        Either::Left(_) => Err(VmMigrationError::Cancelled),
+        Either::Right((result, _)) => result.map_err(VmMigrationError::TransferError),
+    }
+}
+
+async fn transfer<Rd: AsyncRead + Unpin, W: AsyncWrite + Unpin, F: Fn(VmMigrationProgress)>(
+    reader: &mut Rd,
+    writer: &mut W,
+    size: u64,
+    options: &VmMigrationOptions,
+    reporter: &mut ProgressReporter<F>,
+) -> Result<(), VmMigrationError> {
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut remaining = size;
+
+    while remaining > 0 {
+        let chunk_size = remaining.min(CHUNK_SIZE as u64) as usize;
+        cancellable(&options.cancellation, reader.read_exact(&mut buf[..chunk_size])).await?;
+        cancellable(&options.cancellation, writer.write_all(&buf[..chunk_size])).await?;
+        remaining -= chunk_size as u64;
+        reporter.advance(chunk_size as u64);
+    }
+
+    Ok(())
+}
+
+fn encode_message(message: &[u8]) -> Vec<u8> {
+    let mut encoded = (message.len() as u64).to_be_bytes().to_vec();
+    encoded.extend_from_slice(message);
+    encoded
+}
+
+async fn read_message<T: AsyncRead + Unpin>(
+    stream: &mut T,
+    options: &VmMigrationOptions,
+) -> Result<Vec<u8>, VmMigrationError> {
+    let mut length = [0; 8];
+    cancellable(&options.cancellation, stream.read_exact(&mut length)).await?;
+    let length = u64::from_be_bytes(length);
+
+    if length > MAX_MESSAGE_LENGTH {
+        return Err(VmMigrationError::TransferError(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("The message length {length} exceeds the maximum"),
+        )));
+    }
+
+    let mut message = vec![0; length as usize];
+    cancellable(&options.cancellation, stream.read_exact(&mut message)).await?;
+    Ok(message)
+}
+
+async fn file_size<R: Runtime>(runtime: &R, path: &Path) -> Result<u64, VmMigrationError> {
+    runtime
+        .fs_metadata(path)
+        .await
+        .map(|metadata| metadata.len())
+        .map_err(VmMigrationError::SnapshotStorageError)
+}
+
+fn unique_file_names() -> (String, String) {
+    let id = format!(
+        "{}.{}",
+        std::process::id(),
+        FILE_NAME_COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+    (format!("migration.{id}.snapshot"), format!("migration.{id}.mem"))
+}
+
+fn received_resource(path: PathBuf) -> ProducedVmmResource {
+    let mut resource = ProducedVmmResource::new(&path);
+    resource.mark_initialized(path);
+    resource.unlink();
+    resource
+}
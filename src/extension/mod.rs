@@ -1,18 +1,65 @@
 //! A set of extensions to the rest of fctools' functionality. These currently include:
+//! - `agent-extension`, communicates with a guest agent over vsock to execute processes, transfer files, manage the environment and send signals. The guest side of the agent is available separately with the `agent-server` feature.
+//! - `balloon-snapshot-extension`, inflates the balloon device to reclaim free guest memory before taking a snapshot and punches the zero pages out of the memory file so that they take no disk space.
+//! - `console-attach-extension`, serves a VM's serial console on a Unix socket (and optionally a PTY) for interactive attach, with a client helper for local terminals.
+//! - `fleet-extension`, manages many concurrent VMs with ID allocation, label-based lookup, concurrency limits and bulk shutdown and cleanup.
+//! - `fork-extension`, pauses and snapshots a running VM once and restores many clones from the snapshot, each with its own VMM ID, vsock socket, network host devices, logs and metrics.
 //! - `grpc-vsock-extension`, allows gRPC connections to VMs via the tonic and tower crates.
+//! - `hibernation-extension`, snapshots idle VMs to disk and kills them, restoring them on demand when a connection arrives on a host address they own.
 //! - `http-vsock-extension`, allows plain HTTP connections to VMs via the hyper crate.
+//! - `job-runner-extension`, runs ephemeral jobs that boot a VM, capture its console output until the guest signals an exit code, and tear the VM down.
 //! - `link-local-extension`, performs sequential IPAM for IPv4 subnets in the link-local range (169.254.0.0) by performing the needed math internally.
 //! - `metrics-extension`, maps out the entire format of Firecracker's metrics to be used with `serde`, and provides a task that can collect these metrics.
+//! - `migration-extension`, live-migrates running VMs between hosts by streaming a snapshot over a connection and restoring it on the receiving end, with progress reporting and cancellation.
+//! - `resource-source-extension`, resolves moved resources lazily during preparation from local paths, file:// URIs, HTTP(S) URLs and resource store digests, with checksum verification.
+//! - `resource-store-extension`, ingests shared files such as kernels and root filesystems into a content-addressed cache directory and hands them out as resources that hard link or reflink from the cache, with per-VMM reference tracking and garbage collection.
+//! - `snapshot-bundle-extension`, exports snapshots together with their moved resources into portable tar bundles with a versioned manifest, checksums and optional compression, and imports them for restoration.
+//! - `snapshot-catalog-extension`, persists snapshot metadata and lineage in an on-disk manifest, flattens chains of diff snapshots into full snapshots and prunes old generations.
 //! - `snapshot-editor-extension`, abstracts away the CLI interface of the "snapshot-editor" behind a typed interface that spawns and awaits the process.
+//! - `snapshot-encryption-extension`, encrypts snapshot and memory files at rest with authenticated encryption right after they are created and decrypts them just before a VM is restored from them, with keys supplied by a pluggable key provider.
+//! - `supervisor-extension`, watches VMs, cleans them up after their VMM exits and restarts them according to a restart policy with backoff.
+//! - `uffd-handler-extension`, serves the guest memory of VMs restored with the UFFD memory backend from snapshot memory files, with lazy faulting, eager prefetch, zero-page detection and fault statistics.
+//! - `vsock-stream-extension`, allows raw byte stream connections to VMs for applications with custom protocols.
+//! - `vsock-forward-extension`, forwards connections accepted on a host TCP address or Unix socket to a guest vsock port.
+//! - `vsock-listener-extension`, accepts connections initiated by the guest over the vsock device on Unix sockets bound by the host.
+//! - `warm-pool-extension`, keeps a pool of booted or restored VMs ready to be taken, refilling it in the background.
+
+#[cfg(feature = "agent-server")]
+#[cfg_attr(docsrs, doc(cfg(feature = "agent-server")))]
+pub mod agent;
+
+#[cfg(feature = "balloon-snapshot-extension")]
+#[cfg_attr(docsrs, doc(cfg(feature = "balloon-snapshot-extension")))]
+pub mod balloon_snapshot;
+
+#[cfg(feature = "console-attach-extension")]
+#[cfg_attr(docsrs, doc(cfg(feature = "console-attach-extension")))]
+pub mod console_attach;
+
+#[cfg(feature = "fleet-extension")]
+#[cfg_attr(docsrs, doc(cfg(feature = "fleet-extension")))]
+pub mod fleet;
+
+#[cfg(feature = "fork-extension")]
+#[cfg_attr(docsrs, doc(cfg(feature = "fork-extension")))]
+pub mod fork;
 
 #[cfg(feature = "grpc-vsock-extension")]
 #[cfg_attr(docsrs, doc(cfg(feature = "grpc-vsock-extension")))]
 pub mod grpc_vsock;
 
+#[cfg(feature = "hibernation-extension")]
+#[cfg_attr(docsrs, doc(cfg(feature = "hibernation-extension")))]
+pub mod hibernation;
+
 #[cfg(feature = "http-vsock-extension")]
 #[cfg_attr(docsrs, doc(cfg(feature = "http-vsock-extension")))]
 pub mod http_vsock;
 
+#[cfg(feature = "job-runner-extension")]
+#[cfg_attr(docsrs, doc(cfg(feature = "job-runner-extension")))]
+pub mod job_runner;
+
 #[cfg(feature = "link-local-extension")]
 #[cfg_attr(docsrs, doc(cfg(feature = "link-local-extension")))]
 pub mod link_local;
@@ -21,6 +68,57 @@ pub mod link_local;
 #[cfg_attr(docsrs, doc(cfg(feature = "metrics-extension")))]
 pub mod metrics;
 
+#[cfg(feature = "migration-extension")]
+#[cfg_attr(docsrs, doc(cfg(feature = "migration-extension")))]
+pub mod migration;
+
+#[cfg(feature = "resource-source-extension")]
+#[cfg_attr(docsrs, doc(cfg(feature = "resource-source-extension")))]
+pub mod resource_source;
+
+#[cfg(feature = "resource-store-extension")]
+#[cfg_attr(docsrs, doc(cfg(feature = "resource-store-extension")))]
+pub mod resource_store;
+
+#[cfg(feature = "snapshot-bundle-extension")]
+#[cfg_attr(docsrs, doc(cfg(feature = "snapshot-bundle-extension")))]
+pub mod snapshot_bundle;
+
+#[cfg(feature = "snapshot-catalog-extension")]
+#[cfg_attr(docsrs, doc(cfg(feature = "snapshot-catalog-extension")))]
+pub mod snapshot_catalog;
+
 #[cfg(feature = "snapshot-editor-extension")]
 #[cfg_attr(docsrs, doc(cfg(feature = "snapshot-editor-extension")))]
 pub mod snapshot_editor;
+
+#[cfg(feature = "snapshot-encryption-extension")]
+#[cfg_attr(docsrs, doc(cfg(feature = "snapshot-encryption-extension")))]
+pub mod snapshot_encryption;
+
+#[cfg(feature = "supervisor-extension")]
+#[cfg_attr(docsrs, doc(cfg(feature = "supervisor-extension")))]
+pub mod supervisor;
+
+#[cfg(feature = "uffd-handler-extension")]
+#[cfg_attr(docsrs, doc(cfg(feature = "uffd-handler-extension")))]
+pub mod uffd_handler;
+
+#[cfg(feature = "vsock-stream-extension")]
+#[cfg_attr(docsrs, doc(cfg(feature = "vsock-stream-extension")))]
+pub mod vsock_stream;
+
+#[cfg(feature = "vsock-forward-extension")]
+#[cfg_attr(docsrs, doc(cfg(feature = "vsock-forward-extension")))]
+pub mod vsock_forward;
+
+#[cfg(feature = "vsock-listener-extension")]
+#[cfg_attr(docsrs, doc(cfg(feature = "vsock-listener-extension")))]
+pub mod vsock_listener;
+
+#[cfg(feature = "warm-pool-extension")]
+#[cfg_attr(docsrs, doc(cfg(feature = "warm-pool-extension")))]
+pub mod warm_pool;
+
+#[cfg(feature = "extension-util")]
+mod util;
@@ -0,0 +1,710 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, LazyLock, Mutex,
+    },
+};
+
+use bytes::Bytes;
+use futures_rustls::{
+    rustls::{self, pki_types::ServerName, ClientConfig, RootCertStore},
+    TlsConnector,
+};
+use futures_util::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use http::{header, Request, Response, StatusCode, Uri};
+use http_body_util::{BodyExt, Empty};
+use hyper::body::Incoming;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    process_spawner::ProcessSpawner,
+    runtime::{util::RuntimeHyperIo, Runtime},
+    vmm::id::VmmId,
+};
+
+use super::resource_store::{hash_file, ResourceStore, ResourceStoreError};
+
+const DEFAULT_MAX_REDIRECTS: usize = 5;
+
+static PART_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+static SOURCE_RESOLUTION_LOCKS: LazyLock<Mutex<HashMap<PathBuf, Arc<futures_util::lock::Mutex<()>>>>> =
+    LazyLock::new(Default::default);
+
+static DEFAULT_TLS_CONFIG: LazyLock<Arc<ClientConfig>> = LazyLock::new(|| {
+    let root_store = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+
+    Arc::new(
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .expect("The ring crypto provider doesn't support the default protocol versions")
+            .with_root_certificates(root_store)
+            .with_no_client_auth(),
+    )
+});
+
+/// An error that can be produced by a [ResourceSource] when resolving it, which is surfaced as
+/// [VmmResourceError::SourceResolutionFailed](crate::vmm::resource::VmmResourceError::SourceResolutionFailed).
+#[derive(Debug)]
+pub enum ResourceSourceError {
+    FilesystemError(std::io::Error),
+    ChecksumMismatch { expected: String, actual: String },
+    Other(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl std::error::Error for ResourceSourceError {}
+
+impl std::fmt::Display for ResourceSourceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResourceSourceError::FilesystemError(err) => {
+                write!(f, "A filesystem operation performed by the source failed: {err}")
+            }
+            ResourceSourceError::ChecksumMismatch { expected, actual } => {
+                write!(f, "Expected the checksum {expected} but the resolved file had {actual}")
+            }
+            ResourceSourceError::Other(err) => write!(f, "Another error occurred: {err}"),
+        }
+    }
+}
+
+/// A source that a [MovedVmmResource](crate::vmm::resource::MovedVmmResource) is lazily resolved from when it is initialized, for example by downloading it
+/// or by verifying its checksum, instead of its source path having to exist beforehand. Resolutions of sources with
+/// the same local path are de-duplicated: while one is in progress, all others wait for it and then resolve again,
+/// which is expected to be a cheap no-op once the file exists.
+pub trait ResourceSource: std::fmt::Debug + Send + Sync + 'static {
+    /// The local path at which the resolved file is made available, which becomes the source path of the
+    /// [MovedVmmResource](crate::vmm::resource::MovedVmmResource).
+    fn local_path(&self) -> &Path;
+
+    /// Resolve the source so that a complete file exists at its local path.
+    fn resolve(&self) -> Pin<Box<dyn Future<Output = Result<(), ResourceSourceError>> + Send + '_>>;
+}
+
+pub(crate) async fn resolve_source_deduplicated(source: &dyn ResourceSource) -> Result<(), ResourceSourceError> {
+    let lock = SOURCE_RESOLUTION_LOCKS
+        .lock()
+        .expect("Source resolution locks mutex was poisoned")
+        .entry(source.local_path().to_owned())
+        .or_default()
+        .clone();
+    let guard = lock.lock().await;
+    let result = source.resolve().await;
+    drop(guard);
+
+    // the lock is only kept around for as long as another resolution of the same path holds onto it
+    let mut locks = SOURCE_RESOLUTION_LOCKS
+        .lock()
+        .expect("Source resolution locks mutex was poisoned");
+    if Arc::strong_count(&lock) == 2 {
+        locks.remove(source.local_path());
+    }
+
+    result
+}
+
+/// An error that can be emitted when constructing a [ResourceSource] from a URI or URL.
+#[derive(Debug)]
+pub enum ResourceSourceUriError {
+    InvalidUri(String),
+    UnsupportedScheme(String),
+}
+
+impl std::error::Error for ResourceSourceUriError {}
+
+impl std::fmt::Display for ResourceSourceUriError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResourceSourceUriError::InvalidUri(uri) => write!(f, "The URI {uri} is invalid"),
+            ResourceSourceUriError::UnsupportedScheme(scheme) => {
+                write!(f, "The URI scheme {scheme} isn't supported by this source")
+            }
+        }
+    }
+}
+
+/// An error that can be emitted by an [HttpResourceSource] when fetching its file, which is surfaced as
+/// [ResourceSourceError::Other].
+#[derive(Debug)]
+pub enum HttpResourceSourceError {
+    ResolveFailed(std::io::Error),
+    ConnectFailed(std::io::Error),
+    InvalidServerName(String),
+    TlsHandshakeFailed(std::io::Error),
+    HandshakeFailed(hyper::Error),
+    RequestBuildFailed(http::Error),
+    RequestFailed(hyper::Error),
+    BodyFailed(hyper::Error),
+    UnexpectedStatus(StatusCode),
+    InvalidRedirect(String),
+    InsecureRedirect(String),
+    TooManyRedirects,
+}
+
+impl std::error::Error for HttpResourceSourceError {}
+
+impl std::fmt::Display for HttpResourceSourceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HttpResourceSourceError::ResolveFailed(err) => write!(f, "Resolving the host failed: {err}"),
+            HttpResourceSourceError::ConnectFailed(err) => write!(f, "Connecting to the host failed: {err}"),
+            HttpResourceSourceError::InvalidServerName(host) => {
+                write!(f, "The host {host} isn't a valid TLS server name")
+            }
+            HttpResourceSourceError::TlsHandshakeFailed(err) => write!(f, "The TLS handshake failed: {err}"),
+            HttpResourceSourceError::HandshakeFailed(err) => write!(f, "The HTTP handshake failed: {err}"),
+            HttpResourceSourceError::RequestBuildFailed(err) => write!(f, "Building the HTTP request failed: {err}"),
+            HttpResourceSourceError::RequestFailed(err) => write!(f, "Sending the HTTP request failed: {err}"),
+            HttpResourceSourceError::BodyFailed(err) => write!(f, "Receiving the HTTP response body failed: {err}"),
+            HttpResourceSourceError::UnexpectedStatus(status) => {
+                write!(f, "The server responded with the unexpected status {status}")
+            }
+            HttpResourceSourceError::InvalidRedirect(location) => {
+                write!(f, "The server redirected to the invalid location {location}")
+            }
+            HttpResourceSourceError::InsecureRedirect(location) => {
+                write!(
+                    f,
+                    "The server redirected from HTTPS to the insecure location {location}"
+                )
+            }
+            HttpResourceSourceError::TooManyRedirects => write!(f, "The server redirected too many times"),
+        }
+    }
+}
+
+/// A [ResourceSource] that resolves to a file that already exists locally, optionally verifying its SHA-256 checksum
+/// every time it is resolved.
+pub struct LocalResourceSource<R: Runtime> {
+    path: PathBuf,
+    sha256: Option<String>,
+    runtime: R,
+}
+
+impl<R: Runtime> LocalResourceSource<R> {
+    /// Create a source for the file at the given local path.
+    pub fn new(path: impl Into<PathBuf>, runtime: R) -> Self {
+        Self {
+            path: path.into(),
+            sha256: None,
+            runtime,
+        }
+    }
+
+    /// Create a source for the file referred to by the given file:// URI, which must have an absolute path and either
+    /// no host or "localhost" as its host.
+    pub fn from_file_uri(uri: impl AsRef<str>, runtime: R) -> Result<Self, ResourceSourceUriError> {
+        let uri = uri.as_ref();
+        let Some((scheme, rest)) = uri.split_once("://") else {
+            return Err(ResourceSourceUriError::InvalidUri(uri.to_owned()));
+        };
+
+        if !scheme.eq_ignore_ascii_case("file") {
+            return Err(ResourceSourceUriError::UnsupportedScheme(scheme.to_owned()));
+        }
+
+        let path = rest.strip_prefix("localhost").unwrap_or(rest);
+        if !path.starts_with('/') {
+            return Err(ResourceSourceUriError::InvalidUri(uri.to_owned()));
+        }
+
+        let path = percent_decode(path).ok_or_else(|| ResourceSourceUriError::InvalidUri(uri.to_owned()))?;
+        Ok(Self::new(path, runtime))
+    }
+
+    /// Verify that the file has the given hex-encoded SHA-256 checksum whenever the source is resolved.
+    pub fn sha256(mut self, sha256: impl Into<String>) -> Self {
+        self.sha256 = Some(sha256.into());
+        self
+    }
+}
+
+impl<R: Runtime> std::fmt::Debug for LocalResourceSource<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LocalResourceSource")
+            .field("path", &self.path)
+            .field("sha256", &self.sha256)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<R: Runtime> ResourceSource for LocalResourceSource<R> {
+    fn local_path(&self) -> &Path {
+        &self.path
+    }
+
+    fn resolve(&self) -> Pin<Box<dyn Future<Output = Result<(), ResourceSourceError>> + Send + '_>> {
+        Box::pin(async move {
+            if let Some(ref sha256) = self.sha256 {
+                let actual = hash_file(&self.runtime, &self.path)
+                    .await
+                    .map_err(ResourceSourceError::FilesystemError)?;
+                verify_checksum(sha256, actual)?;
+            }
+
+            Ok(())
+        })
+    }
+}
+
+/// A [ResourceSource] that downloads a file from an HTTP or HTTPS URL with hyper into a cache directory, following
+/// redirects. A file that was already downloaded into the cache directory isn't downloaded again, and a file is only
+/// placed into the cache directory once it has been fully downloaded and its checksum verified, if one is given.
+/// HTTPS connections are made with rustls, trusting the Mozilla root certificates by default.
+pub struct HttpResourceSource<R: Runtime> {
+    uri: Uri,
+    cache_directory: PathBuf,
+    local_path: PathBuf,
+    sha256: Option<String>,
+    max_redirects: usize,
+    tls_config: Option<Arc<ClientConfig>>,
+    runtime: R,
+}
+
+impl<R: Runtime> HttpResourceSource<R> {
+    /// Create a source for the given HTTP or HTTPS URL that downloads into the given cache directory, under a file
+    /// name derived from the URL.
+    pub fn new(
+        url: impl AsRef<str>,
+        cache_directory: impl Into<PathBuf>,
+        runtime: R,
+    ) -> Result<Self, ResourceSourceUriError> {
+        let url = url.as_ref();
+        let uri = parse_http_uri(url)?;
+        let cache_directory = cache_directory.into();
+        let local_path = cache_directory.join(sha256_hex(url.as_bytes()));
+
+        Ok(Self {
+            uri,
+            cache_directory,
+            local_path,
+            sha256: None,
+            max_redirects: DEFAULT_MAX_REDIRECTS,
+            tls_config: None,
+            runtime,
+        })
+    }
+
+    /// Verify that the downloaded file has the given hex-encoded SHA-256 checksum. The file is then cached under the
+    /// checksum instead of the URL, so that sources for mirrors of the same file share the cached file.
+    pub fn sha256(mut self, sha256: impl Into<String>) -> Self {
+        let sha256 = sha256.into().to_ascii_lowercase();
+        self.local_path = self.cache_directory.join(&sha256);
+        self.sha256 = Some(sha256);
+        self
+    }
+
+    /// Set the maximum amount of redirects that are followed, which is 5 by default.
+    pub fn max_redirects(mut self, max_redirects: usize) -> Self {
+        self.max_redirects = max_redirects;
+        self
+    }
+
+    /// Use the given rustls [ClientConfig] for HTTPS connections instead of one trusting the Mozilla root
+    /// certificates, for example in order to trust a private certificate authority.
+    pub fn tls_config(mut self, tls_config: Arc<ClientConfig>) -> Self {
+        self.tls_config = Some(tls_config);
+        self
+    }
+
+    /// Get the URL of the source.
+    pub fn uri(&self) -> &Uri {
+        &self.uri
+    }
+
+    async fn download(&self, part_path: &Path) -> Result<String, ResourceSourceError> {
+        let mut uri = self.uri.clone();
+
+        for _ in 0..=self.max_redirects {
+            let response = self.send_request(&uri).await.map_err(http_error)?;
+
+            if response.status().is_redirection() {
+                let location = response
+                    .headers()
+                    .get(header::LOCATION)
+                    .and_then(|location| location.to_str().ok())
+                    .unwrap_or_default();
+                uri = resolve_redirect(&uri, location).map_err(http_error)?;
+                continue;
+            }
+
+            if !response.status().is_success() {
+                return Err(http_error(HttpResourceSourceError::UnexpectedStatus(response.status())));
+            }
+
+            let mut file = self
+                .runtime
+                .fs_open_file_for_write(part_path)
+                .await
+                .map_err(ResourceSourceError::FilesystemError)?;
+            let mut hasher = Sha256::new();
+            let mut body = response.into_body();
+
+            while let Some(frame) = body.frame().await {
+                let frame = frame.map_err(|err| http_error(HttpResourceSourceError::BodyFailed(err)))?;
+
+                if let Ok(data) = frame.into_data() {
+                    hasher.update(&data);
+                    file.write_all(&data)
+                        .await
+                        .map_err(ResourceSourceError::FilesystemError)?;
+                }
+            }
+
+            file.close().await.map_err(ResourceSourceError::FilesystemError)?;
+            return Ok(hex(&hasher.finalize()));
+        }
+
+        Err(http_error(HttpResourceSourceError::TooManyRedirects))
+    }
+
+    async fn send_request(&self, uri: &Uri) -> Result<Response<Incoming>, HttpResourceSourceError> {
+        let https = uri.scheme() == Some(&http::uri::Scheme::HTTPS);
+        let host = uri
+            .host()
+            .unwrap_or_default()
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+
+        let addresses = self
+            .runtime
+            .tcp_resolve(host, port)
+            .await
+            .map_err(HttpResourceSourceError::ResolveFailed)?;
+        let mut last_error = std::io::Error::new(std::io::ErrorKind::NotFound, "The host resolved to no addresses");
+        let mut stream = None;
+
+        for address in addresses {
+            match self.runtime.tcp_connect(address).await {
+                Ok(connected_stream) => {
+                    stream = Some(connected_stream);
+                    break;
+                }
+                Err(err) => last_error = err,
+            }
+        }
+
+        let stream = stream.ok_or(HttpResourceSourceError::ConnectFailed(last_error))?;
+        let request = Request::get(uri.path_and_query().map(|path| path.as_str()).unwrap_or("/"))
+            .header(
+                header::HOST,
+                uri.authority().map(|authority| authority.as_str()).unwrap_or(host),
+            )
+            .header(header::USER_AGENT, "fctools")
+            .body(Empty::<Bytes>::new())
+            .map_err(HttpResourceSourceError::RequestBuildFailed)?;
+
+        if https {
+            let server_name = ServerName::try_from(host.to_owned())
+                .map_err(|_| HttpResourceSourceError::InvalidServerName(host.to_owned()))?;
+            let tls_config = self.tls_config.clone().unwrap_or_else(|| DEFAULT_TLS_CONFIG.clone());
+            let stream = TlsConnector::from(tls_config)
+                .connect(server_name, stream)
+                .await
+                .map_err(HttpResourceSourceError::TlsHandshakeFailed)?;
+            send_request_over(&self.runtime, stream, request).await
+        } else {
+            send_request_over(&self.runtime, stream, request).await
+        }
+    }
+}
+
+impl<R: Runtime> std::fmt::Debug for HttpResourceSource<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HttpResourceSource")
+            .field("uri", &self.uri)
+            .field("local_path", &self.local_path)
+            .field("sha256", &self.sha256)
+            .field("max_redirects", &self.max_redirects)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<R: Runtime> ResourceSource for HttpResourceSource<R> {
+    fn local_path(&self) -> &Path {
+        &self.local_path
+    }
+
+    fn resolve(&self) -> Pin<Box<dyn Future<Output = Result<(), ResourceSourceError>> + Send + '_>> {
+        Box::pin(async move {
+            if self
+                .runtime
+                .fs_exists(&self.local_path)
+                .await
+                .map_err(ResourceSourceError::FilesystemError)?
+            {
+                return Ok(());
+            }
+
+            self.runtime
+                .fs_create_dir_all(&self.cache_directory)
+                .await
+                .map_err(ResourceSourceError::FilesystemError)?;
+
+            // the file is downloaded next to its final path and only renamed into place once it's complete, so that
+            // an interrupted download is never mistaken for a cached file
+            let part_path = self.cache_directory.join(format!(
+                "{}.{}.{}.part",
+                self.local_path.file_name().unwrap_or_default().to_string_lossy(),
+                std::process::id(),
+                PART_COUNTER.fetch_add(1, Ordering::Relaxed)
+            ));
+
+            let result = match self.download(&part_path).await {
+                Ok(actual) => match self.sha256 {
+                    Some(ref sha256) => verify_checksum(sha256, actual),
+                    None => Ok(()),
+                },
+                Err(err) => Err(err),
+            };
+
+            if let Err(err) = result {
+                let _ = self.runtime.fs_remove_file(&part_path).await;
+                return Err(err);
+            }
+
+            self.runtime
+                .fs_rename(&part_path, &self.local_path)
+                .await
+                .map_err(ResourceSourceError::FilesystemError)
+        })
+    }
+}
+
+/// A [ResourceSource] that resolves to the entry with a given digest in a [ResourceStore], recording a reference to
+/// it on behalf of a VMM. If the entry doesn't exist yet, it can be ingested from a fallback [ResourceSource], such as
+/// an [HttpResourceSource], whose file must then match the digest. As with entries acquired from the [ResourceStore],
+/// hard linking the entry requires the VMM to use the same ownership model as the store.
+pub struct DigestResourceSource<S: ProcessSpawner, R: Runtime> {
+    store: Arc<ResourceStore<S, R>>,
+    digest: String,
+    vmm_id: VmmId,
+    local_path: PathBuf,
+    fallback: Option<Box<dyn ResourceSource>>,
+}
+
+impl<S: ProcessSpawner, R: Runtime> DigestResourceSource<S, R> {
+    /// Create a source for the entry with the given digest in the given [ResourceStore], which is referenced on
+    /// behalf of the VMM with the given [VmmId] once resolved.
+    pub fn new(
+        store: Arc<ResourceStore<S, R>>,
+        digest: impl Into<String>,
+        vmm_id: VmmId,
+    ) -> Result<Self, ResourceStoreError> {
+        let digest = digest.into();
+        let local_path = store.entry_path(&digest)?;
+
+        Ok(Self {
+            store,
+            digest,
+            vmm_id,
+            local_path,
+            fallback: None,
+        })
+    }
+
+    /// Ingest the entry from the given fallback [ResourceSource] if it doesn't exist in the [ResourceStore].
+    pub fn fallback(mut self, fallback: impl ResourceSource) -> Self {
+        self.fallback = Some(Box::new(fallback));
+        self
+    }
+}
+
+impl<S: ProcessSpawner, R: Runtime> std::fmt::Debug for DigestResourceSource<S, R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DigestResourceSource")
+            .field("digest", &self.digest)
+            .field("vmm_id", &self.vmm_id)
+            .field("local_path", &self.local_path)
+            .field("fallback", &self.fallback)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S: ProcessSpawner, R: Runtime> ResourceSource for DigestResourceSource<S, R> {
+    fn local_path(&self) -> &Path {
+        &self.local_path
+    }
+
+    fn resolve(&self) -> Pin<Box<dyn Future<Output = Result<(), ResourceSourceError>> + Send + '_>> {
+        Box::pin(async move {
+            let fallback = match self.store.add_reference(&self.digest, &self.vmm_id).await {
+                Ok(_) => return Ok(()),
+                Err(ResourceStoreError::NotFound(_)) if self.fallback.is_some() => self.fallback.as_ref().unwrap(),
+                Err(err) => return Err(ResourceSourceError::Other(Box::new(err))),
+            };
+
+            fallback.resolve().await?;
+            let actual = self
+                .store
+                .ingest(fallback.local_path())
+                .await
+                .map_err(|err| ResourceSourceError::Other(Box::new(err)))?;
+            verify_checksum(&self.digest, actual)?;
+
+            self.store
+                .add_reference(&self.digest, &self.vmm_id)
+                .await
+                .map(|_| ())
+                .map_err(|err| ResourceSourceError::Other(Box::new(err)))
+        })
+    }
+}
+
+async fn send_request_over<R: Runtime, T: AsyncRead + AsyncWrite + Send + Unpin + 'static>(
+    runtime: &R,
+    stream: T,
+    request: Request<Empty<Bytes>>,
+) -> Result<Response<Incoming>, HttpResourceSourceError> {
+    let (mut send_request, connection) = hyper::client::conn::http1::handshake(RuntimeHyperIo(stream))
+        .await
+        .map_err(HttpResourceSourceError::HandshakeFailed)?;
+    runtime.spawn_task(connection);
+
+    send_request
+        .send_request(request)
+        .await
+        .map_err(HttpResourceSourceError::RequestFailed)
+}
+
+fn parse_http_uri(url: &str) -> Result<Uri, ResourceSourceUriError> {
+    let uri: Uri = url
+        .parse()
+        .map_err(|_| ResourceSourceUriError::InvalidUri(url.to_owned()))?;
+
+    match uri.scheme_str() {
+        Some("http") | Some("https") => {}
+        Some(scheme) => return Err(ResourceSourceUriError::UnsupportedScheme(scheme.to_owned())),
+        None => return Err(ResourceSourceUriError::InvalidUri(url.to_owned())),
+    }
+
+    match uri.host() {
+        Some(host) if !host.is_empty() => Ok(uri),
+        _ => Err(ResourceSourceUriError::InvalidUri(url.to_owned())),
+    }
+}
+
+fn resolve_redirect(uri: &Uri, location: &str) -> Result<Uri, HttpResourceSourceError> {
+    let invalid = || HttpResourceSourceError::InvalidRedirect(location.to_owned());
+
+    // absolute-path references are resolved against the current URL, while other relative references aren't
+    // supported since servers practically never send them
+    if location.starts_with('/') && !location.starts_with("//") {
+        let mut parts = uri.clone().into_parts();
+        parts.path_and_query = Some(location.parse().map_err(|_| invalid())?);
+        return Uri::from_parts(parts).map_err(|_| invalid());
+    }
+
+    let redirect_uri = parse_http_uri(location).map_err(|_| invalid())?;
+
+    // downgrading to plain HTTP would let anyone on the path tamper with content that was requested over TLS
+    if uri.scheme() == Some(&http::uri::Scheme::HTTPS) && redirect_uri.scheme() != Some(&http::uri::Scheme::HTTPS) {
+        return Err(HttpResourceSourceError::InsecureRedirect(location.to_owned()));
+    }
+
+    Ok(redirect_uri)
+}
+
+fn verify_checksum(expected: &str, actual: String) -> Result<(), ResourceSourceError> {
+    if !expected.eq_ignore_ascii_case(&actual) {
+        return Err(ResourceSourceError::ChecksumMismatch {
+            expected: expected.to_owned(),
+            actual,
+        });
+    }
+
+    Ok(())
+}
+
+fn http_error(err: HttpResourceSourceError) -> ResourceSourceError {
+    ResourceSourceError::Other(Box::new(err))
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex(&Sha256::digest(data))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn percent_decode(value: &str) -> Option<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            // from_str_radix alone would also accept a sign in place of the first digit
+            let hex = value
+                .get(index + 1..index + 3)
+                .filter(|hex| hex.bytes().all(|byte| byte.is_ascii_hexdigit()))?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+    use http::Uri;
+
+    use super::{percent_decode, resolve_redirect, HttpResourceSourceError};
+
+    #[test]
+    fn percent_escapes_are_decoded() {
+        assert_eq!(percent_decode("/opt/kernel").unwrap(), "/opt/kernel");
+        assert_eq!(percent_decode("/opt/my%20kernel%2Fv1").unwrap(), "/opt/my kernel/v1");
+        assert_eq!(percent_decode("/opt/%C3%A9").unwrap(), "/opt/é");
+    }
+
+    #[test]
+    fn malformed_percent_escapes_are_rejected() {
+        assert_eq!(percent_decode("/opt/kernel%"), None);
+        assert_eq!(percent_decode("/opt/kernel%2"), None);
+        assert_eq!(percent_decode("/opt/kernel%zz"), None);
+        assert_eq!(percent_decode("/opt/kernel%+1"), None);
+        assert_eq!(percent_decode("/opt/kernel%ff"), None);
+    }
+
+    #[test]
+    fn redirect_is_resolved_against_current_uri() {
+        let uri = Uri::from_static("https://example.com/old/kernel?version=1");
+        assert_eq!(
+            resolve_redirect(&uri, "/new/kernel").unwrap(),
+            Uri::from_static("https://example.com/new/kernel")
+        );
+        assert_eq!(
+            resolve_redirect(&uri, "https://mirror.example.com/kernel").unwrap(),
+            Uri::from_static("https://mirror.example.com/kernel")
+        );
+    }
+
+    #[test]
+    fn redirect_from_https_to_http_is_rejected() {
+        let uri = Uri::from_static("https://example.com/kernel");
+        assert_matches!(
+            resolve_redirect(&uri, "http://example.com/kernel"),
+            Err(HttpResourceSourceError::InsecureRedirect(_))
+        );
+
+        let uri = Uri::from_static("http://example.com/kernel");
+        assert_eq!(
+            resolve_redirect(&uri, "https://example.com/kernel").unwrap(),
+            Uri::from_static("https://example.com/kernel")
+        );
+    }
+}
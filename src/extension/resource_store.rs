@@ -0,0 +1,449 @@
+use std::{
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use futures_util::AsyncReadExt;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    process_spawner::ProcessSpawner,
+    runtime::{Runtime, RuntimeChild},
+    vmm::{
+        id::VmmId,
+        ownership::{downgrade_owner, upgrade_owner, ChangeOwnerError, VmmOwnershipModel},
+        resource::{MovedVmmResource, VmmResourceMoveMethod},
+    },
+};
+
+const OBJECTS_DIRECTORY_NAME: &str = "objects";
+const REFS_DIRECTORY_NAME: &str = "refs";
+const TMP_DIRECTORY_NAME: &str = "tmp";
+const HASH_BUFFER_SIZE: usize = 1024 * 1024;
+
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// An error that can be emitted by a [ResourceStore].
+#[derive(Debug)]
+pub enum ResourceStoreError {
+    FilesystemError(std::io::Error),
+    ChangeOwnerError(ChangeOwnerError),
+    CopyProcessSpawnFailed(std::io::Error),
+    CopyProcessWaitFailed(std::io::Error),
+    CopyProcessExitedWithWrongStatus(ExitStatus),
+    InvalidDigest(String),
+    NotFound(String),
+    OwnershipModelMismatch(VmmOwnershipModel),
+}
+
+impl std::error::Error for ResourceStoreError {}
+
+impl std::fmt::Display for ResourceStoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResourceStoreError::FilesystemError(err) => {
+                write!(f, "A filesystem operation backed by the runtime failed: {err}")
+            }
+            ResourceStoreError::ChangeOwnerError(err) => write!(f, "An ownership change failed: {err}"),
+            ResourceStoreError::CopyProcessSpawnFailed(err) => write!(f, "Spawning a cp process failed: {err}"),
+            ResourceStoreError::CopyProcessWaitFailed(err) => {
+                write!(f, "Waiting on the completion of a cp process failed: {err}")
+            }
+            ResourceStoreError::CopyProcessExitedWithWrongStatus(exit_status) => {
+                write!(f, "The cp process exited with a non-zero exit status: {exit_status}")
+            }
+            ResourceStoreError::InvalidDigest(digest) => {
+                write!(f, "The digest {digest} isn't a hex-encoded SHA-256 checksum")
+            }
+            ResourceStoreError::NotFound(digest) => write!(f, "No entry with the digest {digest} exists in the store"),
+            ResourceStoreError::OwnershipModelMismatch(ownership_model) => write!(
+                f,
+                "Hard linking an entry requires the ownership model of the store, not {ownership_model:?}"
+            ),
+        }
+    }
+}
+
+/// The method used by the [MovedVmmResource]s handed out by a [ResourceStore] to move its entries into place.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ResourceStoreLinkMethod {
+    /// Hard link the entry, which shares it with the store and thus requires the VMM not to modify it, falling back
+    /// to a fast copy across filesystems. Since the owner of a hard link is that of the entry itself, the VMM must use
+    /// the same [VmmOwnershipModel] as the store, so that its executor never changes the owner of the entry. See
+    /// [VmmResourceMoveMethod::HardLinkOrFastCopy].
+    HardLink,
+    /// Reflink the entry, which shares its data until either side modifies it, falling back to a fast copy on
+    /// filesystems without reflink support. See [VmmResourceMoveMethod::FastCopy].
+    Reflink,
+}
+
+impl ResourceStoreLinkMethod {
+    fn move_method(self) -> VmmResourceMoveMethod {
+        match self {
+            ResourceStoreLinkMethod::HardLink => VmmResourceMoveMethod::HardLinkOrFastCopy,
+            ResourceStoreLinkMethod::Reflink => VmmResourceMoveMethod::FastCopy,
+        }
+    }
+}
+
+/// A report of the entries removed from a [ResourceStore] by garbage collection.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ResourceStoreGarbageReport {
+    /// The digests of the removed entries.
+    pub removed_digests: Vec<String>,
+    /// The total apparent size in bytes of the removed entries.
+    pub removed_bytes: u64,
+}
+
+/// A content-addressed store of files, such as kernels and base root filesystems, that are shared between many VMs.
+/// Files are ingested into a cache directory under their SHA-256 digest, so that identical files are only stored
+/// once, and are handed out as [MovedVmmResource]s that hard link or reflink from the cache instead of copying.
+///
+/// The references held by every VMM are tracked on disk as marker files, one per [VmmId] in a directory per entry,
+/// so that the store can be shared between processes and survives restarts. Entries that are no longer referenced by
+/// any VMM are removed by garbage collection, which shouldn't run concurrently with acquiring entries or with
+/// ingesting them from other processes.
+#[derive(Debug)]
+pub struct ResourceStore<S: ProcessSpawner, R: Runtime> {
+    directory: PathBuf,
+    process_spawner: S,
+    runtime: R,
+    ownership_model: VmmOwnershipModel,
+}
+
+impl<S: ProcessSpawner, R: Runtime> ResourceStore<S, R> {
+    /// Open the store inside the given directory, creating the directory if it doesn't exist yet. Ingested files
+    /// that the control process can't read are copied via the [ProcessSpawner] if the [VmmOwnershipModel] upgrades,
+    /// and the entries are made accessible to the VMM according to the [VmmOwnershipModel].
+    pub async fn open(
+        directory: impl Into<PathBuf>,
+        process_spawner: S,
+        runtime: R,
+        ownership_model: VmmOwnershipModel,
+    ) -> Result<Self, ResourceStoreError> {
+        let directory = directory.into();
+
+        for directory_name in [OBJECTS_DIRECTORY_NAME, REFS_DIRECTORY_NAME, TMP_DIRECTORY_NAME] {
+            runtime
+                .fs_create_dir_all(&directory.join(directory_name))
+                .await
+                .map_err(ResourceStoreError::FilesystemError)?;
+        }
+
+        Ok(Self {
+            directory,
+            process_spawner,
+            runtime,
+            ownership_model,
+        })
+    }
+
+    /// Get the directory of the store.
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// Get the path of the entry with the given digest inside the store directory, which may not exist.
+    pub fn entry_path(&self, digest: &str) -> Result<PathBuf, ResourceStoreError> {
+        validate_digest(digest)?;
+        Ok(self.directory.join(OBJECTS_DIRECTORY_NAME).join(digest))
+    }
+
+    /// Ingest the file at the given path into the store, returning its digest. The file is copied into the store as
+    /// fast as the filesystem allows and hashed afterwards, so that the entry is guaranteed to match its digest even
+    /// if the file is modified concurrently. If an entry with the same digest already exists, the copy is discarded.
+    ///
+    /// The file itself is never modified: if it isn't readable by the control process, it's copied by an elevated
+    /// "cp" process spawned via the [ProcessSpawner] when the [VmmOwnershipModel] upgrades, and otherwise the
+    /// permission error is returned.
+    pub async fn ingest(&self, path: impl AsRef<Path>) -> Result<String, ResourceStoreError> {
+        let path = path.as_ref();
+        let tmp_path = self.directory.join(TMP_DIRECTORY_NAME).join(format!(
+            "{}.{}",
+            std::process::id(),
+            TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        let result = self.ingest_from(path, &tmp_path).await;
+        if result.is_err() {
+            let _ = self.runtime.fs_remove_file(&tmp_path).await;
+        }
+
+        result
+    }
+
+    async fn ingest_from(&self, path: &Path, tmp_path: &Path) -> Result<String, ResourceStoreError> {
+        match self.runtime.fs_fast_copy(path, tmp_path).await {
+            Ok(_) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::PermissionDenied && self.ownership_model.is_upgrade() => {
+                self.copy_elevated(path, tmp_path).await?;
+                upgrade_owner(tmp_path, self.ownership_model, &self.process_spawner, &self.runtime)
+                    .await
+                    .map_err(ResourceStoreError::ChangeOwnerError)?;
+            }
+            Err(err) => return Err(ResourceStoreError::FilesystemError(err)),
+        }
+
+        self.ingest_tmp(tmp_path).await
+    }
+
+    async fn copy_elevated(&self, path: &Path, tmp_path: &Path) -> Result<(), ResourceStoreError> {
+        let mut process = self
+            .process_spawner
+            .spawn(
+                &PathBuf::from("cp"),
+                vec![
+                    "--sparse=always".to_string(),
+                    "--reflink=auto".to_string(),
+                    path.to_string_lossy().into_owned(),
+                    tmp_path.to_string_lossy().into_owned(),
+                ],
+                false,
+                &self.runtime,
+            )
+            .await
+            .map_err(ResourceStoreError::CopyProcessSpawnFailed)?;
+        let exit_status = process
+            .wait()
+            .await
+            .map_err(ResourceStoreError::CopyProcessWaitFailed)?;
+
+        if !exit_status.success() {
+            return Err(ResourceStoreError::CopyProcessExitedWithWrongStatus(exit_status));
+        }
+
+        Ok(())
+    }
+
+    async fn ingest_tmp(&self, tmp_path: &Path) -> Result<String, ResourceStoreError> {
+        let digest = hash_file(&self.runtime, tmp_path)
+            .await
+            .map_err(ResourceStoreError::FilesystemError)?;
+        let entry_path = self.entry_path(&digest)?;
+
+        if self
+            .runtime
+            .fs_exists(&entry_path)
+            .await
+            .map_err(ResourceStoreError::FilesystemError)?
+        {
+            self.runtime
+                .fs_remove_file(tmp_path)
+                .await
+                .map_err(ResourceStoreError::FilesystemError)?;
+            return Ok(digest);
+        }
+
+        downgrade_owner(tmp_path, self.ownership_model).map_err(ResourceStoreError::ChangeOwnerError)?;
+        self.runtime
+            .fs_rename(tmp_path, &entry_path)
+            .await
+            .map_err(ResourceStoreError::FilesystemError)?;
+
+        Ok(digest)
+    }
+
+    /// Get the digests of all entries in the store.
+    pub async fn digests(&self) -> Result<Vec<String>, ResourceStoreError> {
+        let paths = self
+            .runtime
+            .fs_read_dir(&self.directory.join(OBJECTS_DIRECTORY_NAME))
+            .await
+            .map_err(ResourceStoreError::FilesystemError)?;
+
+        Ok(paths.iter().filter_map(|path| file_name(path)).collect())
+    }
+
+    /// Acquire a reference to the entry with the given digest on behalf of the VMM with the given [VmmId], returning
+    /// an uninitialized [MovedVmmResource] that moves the entry into place with the given [ResourceStoreLinkMethod].
+    /// Acquiring the same entry multiple times for the same VMM holds a single reference. The [VmmOwnershipModel] of
+    /// the VMM must match that of the store when hard linking.
+    pub async fn acquire(
+        &self,
+        digest: &str,
+        vmm_id: &VmmId,
+        ownership_model: VmmOwnershipModel,
+        link_method: ResourceStoreLinkMethod,
+    ) -> Result<MovedVmmResource, ResourceStoreError> {
+        if link_method == ResourceStoreLinkMethod::HardLink && ownership_model != self.ownership_model {
+            return Err(ResourceStoreError::OwnershipModelMismatch(ownership_model));
+        }
+
+        let entry_path = self.add_reference(digest, vmm_id).await?;
+        Ok(MovedVmmResource::new(entry_path, link_method.move_method()))
+    }
+
+    /// Record a reference to the entry with the given digest on behalf of the VMM with the given [VmmId] like
+    /// [ResourceStore::acquire] does, returning the path of the entry, for callers that move it into place themselves.
+    pub async fn add_reference(&self, digest: &str, vmm_id: &VmmId) -> Result<PathBuf, ResourceStoreError> {
+        let entry_path = self.entry_path(digest)?;
+        let refs_path = self.directory.join(REFS_DIRECTORY_NAME).join(digest);
+
+        self.runtime
+            .fs_create_dir_all(&refs_path)
+            .await
+            .map_err(ResourceStoreError::FilesystemError)?;
+        self.runtime
+            .fs_create_file(&refs_path.join(vmm_id.as_ref()))
+            .await
+            .map_err(ResourceStoreError::FilesystemError)?;
+
+        if !self
+            .runtime
+            .fs_exists(&entry_path)
+            .await
+            .map_err(ResourceStoreError::FilesystemError)?
+        {
+            let _ = self.runtime.fs_remove_file(&refs_path.join(vmm_id.as_ref())).await;
+            return Err(ResourceStoreError::NotFound(digest.to_owned()));
+        }
+
+        Ok(entry_path)
+    }
+
+    /// Get the [VmmId]s of all VMMs that hold a reference to the entry with the given digest.
+    pub async fn references(&self, digest: &str) -> Result<Vec<VmmId>, ResourceStoreError> {
+        validate_digest(digest)?;
+        let refs_path = self.directory.join(REFS_DIRECTORY_NAME).join(digest);
+
+        if !self
+            .runtime
+            .fs_exists(&refs_path)
+            .await
+            .map_err(ResourceStoreError::FilesystemError)?
+        {
+            return Ok(Vec::new());
+        }
+
+        let paths = self
+            .runtime
+            .fs_read_dir(&refs_path)
+            .await
+            .map_err(ResourceStoreError::FilesystemError)?;
+
+        Ok(paths
+            .iter()
+            .filter_map(|path| file_name(path))
+            .filter_map(|name| VmmId::new(name).ok())
+            .collect())
+    }
+
+    /// Release all references held by the VMM with the given [VmmId], which should be done once the VMM has been
+    /// cleaned up. The entries themselves are only removed by garbage collection.
+    pub async fn release(&self, vmm_id: &VmmId) -> Result<(), ResourceStoreError> {
+        let refs_paths = self
+            .runtime
+            .fs_read_dir(&self.directory.join(REFS_DIRECTORY_NAME))
+            .await
+            .map_err(ResourceStoreError::FilesystemError)?;
+
+        for refs_path in refs_paths {
+            let ref_path = refs_path.join(vmm_id.as_ref());
+
+            if self
+                .runtime
+                .fs_exists(&ref_path)
+                .await
+                .map_err(ResourceStoreError::FilesystemError)?
+            {
+                self.runtime
+                    .fs_remove_file(&ref_path)
+                    .await
+                    .map_err(ResourceStoreError::FilesystemError)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remove all entries that aren't referenced by any VMM, together with leftovers of interrupted ingestions.
+    /// Resources that were already moved into place with a hard link or reflink stay intact.
+    pub async fn collect_garbage(&self) -> Result<ResourceStoreGarbageReport, ResourceStoreError> {
+        let mut report = ResourceStoreGarbageReport::default();
+
+        for digest in self.digests().await? {
+            if !self.references(&digest).await?.is_empty() {
+                continue;
+            }
+
+            let entry_path = self.entry_path(&digest)?;
+            report.removed_bytes += self
+                .runtime
+                .fs_metadata(&entry_path)
+                .await
+                .map_err(ResourceStoreError::FilesystemError)?
+                .len();
+            self.runtime
+                .fs_remove_file(&entry_path)
+                .await
+                .map_err(ResourceStoreError::FilesystemError)?;
+            report.removed_digests.push(digest);
+        }
+
+        for refs_path in self
+            .runtime
+            .fs_read_dir(&self.directory.join(REFS_DIRECTORY_NAME))
+            .await
+            .map_err(ResourceStoreError::FilesystemError)?
+        {
+            if self
+                .runtime
+                .fs_read_dir(&refs_path)
+                .await
+                .map_err(ResourceStoreError::FilesystemError)?
+                .is_empty()
+            {
+                self.runtime
+                    .fs_remove_dir_all(&refs_path)
+                    .await
+                    .map_err(ResourceStoreError::FilesystemError)?;
+            }
+        }
+
+        // leftovers of ingestions that are still in progress in this process are kept
+        let tmp_prefix = format!("{}.", std::process::id());
+        for tmp_path in self
+            .runtime
+            .fs_read_dir(&self.directory.join(TMP_DIRECTORY_NAME))
+            .await
+            .map_err(ResourceStoreError::FilesystemError)?
+        {
+            if file_name(&tmp_path).is_some_and(|name| !name.starts_with(&tmp_prefix)) {
+                self.runtime
+                    .fs_remove_file(&tmp_path)
+                    .await
+                    .map_err(ResourceStoreError::FilesystemError)?;
+            }
+        }
+
+        Ok(report)
+    }
+}
+
+fn validate_digest(digest: &str) -> Result<(), ResourceStoreError> {
+    if digest.len() != 64 || !digest.chars().all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c)) {
+        return Err(ResourceStoreError::InvalidDigest(digest.to_owned()));
+    }
+
+    Ok(())
+}
+
+fn file_name(path: &Path) -> Option<String> {
+    path.file_name().map(|name| name.to_string_lossy().into_owned())
+}
+
+pub(crate) async fn hash_file<R: Runtime>(runtime: &R, path: &Path) -> Result<String, std::io::Error> {
+    let mut file = runtime.fs_open_file_for_read(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; HASH_BUFFER_SIZE];
+
+    loop {
+        match file.read(&mut buf).await? {
+            0 => break,
+            read => hasher.update(&buf[..read]),
+        }
+    }
+
+    Ok(hasher.finalize().iter().map(|byte| format!("{byte:02x}")).collect())
+}
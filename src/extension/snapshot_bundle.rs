@@ -0,0 +1,587 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use async_compression::futures::{
+    bufread::{GzipDecoder, ZstdDecoder},
+    write::{GzipEncoder, ZstdEncoder},
+};
+use futures_util::{io::BufReader, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    runtime::Runtime,
+    vm::{
+        configuration::{ReprVmConfigurationData, VmConfiguration, VmConfigurationData},
+        snapshot::VmSnapshot,
+    },
+    vmm::resource::{MovedVmmResource, ProducedVmmResource, VmmResourceMoveMethod},
+};
+
+/// The version of the bundle format written by [export_snapshot_bundle].
+pub const SNAPSHOT_BUNDLE_VERSION: u32 = 1;
+
+const MANIFEST_ENTRY_NAME: &str = "manifest.json";
+const SNAPSHOT_ENTRY_NAME: &str = "snapshot";
+const MEM_FILE_ENTRY_NAME: &str = "memory";
+const RESOURCE_ENTRY_PREFIX: &str = "resources/";
+
+const BLOCK_SIZE: usize = 512;
+// the manifest is read into memory, so its size is capped instead of allocating whatever an untrusted header claims
+const MAX_MANIFEST_SIZE: u64 = 16 * 1024 * 1024;
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// An error that can be emitted by the snapshot bundle extension.
+#[derive(Debug)]
+pub enum SnapshotBundleError {
+    FilesystemError(std::io::Error),
+    ArchiveError(std::io::Error),
+    SerdeError(serde_json::Error),
+    InvalidArchive(String),
+    UnsupportedVersion(u32),
+    UnexpectedEntry(String),
+    MissingEntry(String),
+    ChecksumMismatch(String),
+}
+
+impl std::error::Error for SnapshotBundleError {}
+
+impl std::fmt::Display for SnapshotBundleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotBundleError::FilesystemError(err) => {
+                write!(f, "A filesystem operation backed by the runtime failed: {err}")
+            }
+            SnapshotBundleError::ArchiveError(err) => write!(f, "Reading or writing the archive failed: {err}"),
+            SnapshotBundleError::SerdeError(err) => {
+                write!(f, "Serializing or deserializing the manifest failed: {err}")
+            }
+            SnapshotBundleError::InvalidArchive(reason) => write!(f, "The archive is invalid: {reason}"),
+            SnapshotBundleError::UnsupportedVersion(version) => {
+                write!(f, "The bundle has the unsupported version {version}")
+            }
+            SnapshotBundleError::UnexpectedEntry(name) => write!(f, "The archive has the unexpected entry {name}"),
+            SnapshotBundleError::MissingEntry(name) => write!(f, "The archive is missing the entry {name}"),
+            SnapshotBundleError::ChecksumMismatch(name) => {
+                write!(f, "The size or checksum of the entry {name} doesn't match the manifest")
+            }
+        }
+    }
+}
+
+/// The compression applied to the whole archive of a snapshot bundle. Compressed bundles are detected automatically
+/// when importing them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum SnapshotBundleCompression {
+    /// Don't compress the archive, which is the fastest option for sparse memory files transferred locally.
+    #[default]
+    None,
+    /// Compress the archive with gzip.
+    Gzip,
+    /// Compress the archive with zstd.
+    Zstd,
+}
+
+/// A snapshot bundle that was imported with [import_snapshot_bundle].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportedSnapshotBundle {
+    /// The version of Firecracker that created the snapshot, which should be compatible with the local installation.
+    pub firecracker_version: String,
+    /// The imported [VmSnapshot], whose files and bundled moved resources are inside the import directory.
+    pub snapshot: VmSnapshot,
+}
+
+impl ImportedSnapshotBundle {
+    /// Create a [VmConfiguration] restoring the imported [VmSnapshot], analogously to [VmSnapshot::into_configuration].
+    pub fn into_configuration(
+        self,
+        move_method: VmmResourceMoveMethod,
+        enable_diff_snapshots: Option<bool>,
+        resume_vm: Option<bool>,
+    ) -> VmConfiguration {
+        self.snapshot
+            .into_configuration(move_method, enable_diff_snapshots, resume_vm)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct ReprManifest {
+    version: u32,
+    firecracker_version: String,
+    files: Vec<ReprFile>,
+    #[serde(flatten)]
+    configuration_data: ReprVmConfigurationData,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+struct ReprFile {
+    name: String,
+    size: u64,
+    sha256: String,
+    // the index of the bundled moved resource in the resource references of the configuration data
+    #[serde(skip_serializing_if = "Option::is_none")]
+    moved_resource_index: Option<usize>,
+}
+
+/// Export the given [VmSnapshot], created by the given Firecracker version, as a bundle written to the given writer
+/// with the given [SnapshotBundleCompression]. The bundle is a tar archive containing the snapshot and memory files, the
+/// kernel, initrd and drive images referenced by the [VmConfigurationData], and a versioned manifest with the
+/// [VmConfigurationData] and the sizes and SHA-256 checksums of all files. Drive sockets aren't bundled, as they are
+/// only meaningful on the host they were created on.
+pub async fn export_snapshot_bundle<R: Runtime, W: AsyncWrite + Send + Unpin>(
+    runtime: &R,
+    snapshot: &VmSnapshot,
+    firecracker_version: impl Into<String>,
+    compression: SnapshotBundleCompression,
+    writer: W,
+) -> Result<(), SnapshotBundleError> {
+    let mut writer: Box<dyn AsyncWrite + Send + Unpin> = match compression {
+        SnapshotBundleCompression::None => Box::new(writer),
+        SnapshotBundleCompression::Gzip => Box::new(GzipEncoder::new(writer)),
+        SnapshotBundleCompression::Zstd => Box::new(ZstdEncoder::new(writer)),
+    };
+
+    let mut sources = vec![
+        (
+            SNAPSHOT_ENTRY_NAME.to_owned(),
+            snapshot.snapshot.effective_path().to_owned(),
+            None,
+        ),
+        (
+            MEM_FILE_ENTRY_NAME.to_owned(),
+            snapshot.mem_file.effective_path().to_owned(),
+            None,
+        ),
+    ];
+    for (index, source_path) in bundled_resource_paths(&snapshot.configuration_data) {
+        sources.push((format!("{RESOURCE_ENTRY_PREFIX}{index}"), source_path, Some(index)));
+    }
+
+    let mut files = Vec::with_capacity(sources.len());
+    for (name, path, moved_resource_index) in sources {
+        let (size, sha256) = write_file_entry(runtime, &mut writer, &name, &path).await?;
+        files.push(ReprFile {
+            name,
+            size,
+            sha256,
+            moved_resource_index,
+        });
+    }
+
+    let manifest = ReprManifest {
+        version: SNAPSHOT_BUNDLE_VERSION,
+        firecracker_version: firecracker_version.into(),
+        files,
+        configuration_data: ReprVmConfigurationData::new(&snapshot.configuration_data),
+    };
+    let manifest_json = serde_json::to_vec_pretty(&manifest).map_err(SnapshotBundleError::SerdeError)?;
+
+    write_header(&mut writer, MANIFEST_ENTRY_NAME, manifest_json.len() as u64).await?;
+    writer
+        .write_all(&manifest_json)
+        .await
+        .map_err(SnapshotBundleError::ArchiveError)?;
+    write_padding(&mut writer, manifest_json.len() as u64).await?;
+
+    writer
+        .write_all(&[0; 2 * BLOCK_SIZE])
+        .await
+        .map_err(SnapshotBundleError::ArchiveError)?;
+    writer.close().await.map_err(SnapshotBundleError::ArchiveError)
+}
+
+/// Import a bundle produced by [export_snapshot_bundle] from the given reader into the given directory, verifying the
+/// version, sizes and checksums. The moved resources of the imported [VmConfigurationData] that were bundled point to
+/// their files inside the directory and keep their original [VmmResourceMoveMethod]s. If the import fails, all
+/// extracted files are removed again.
+pub async fn import_snapshot_bundle<R: Runtime, Rd: AsyncRead + Send + Unpin>(
+    runtime: &R,
+    reader: Rd,
+    directory: impl Into<PathBuf>,
+) -> Result<ImportedSnapshotBundle, SnapshotBundleError> {
+    let directory = directory.into();
+    let mut extracted = HashMap::new();
+
+    let result = import_entries(runtime, reader, &directory, &mut extracted).await;
+    if result.is_err() {
+        for name in extracted.keys() {
+            let _ = runtime.fs_remove_file(&directory.join(name)).await;
+        }
+    }
+
+    let manifest = result?;
+    let mut configuration_data = manifest.configuration_data.into_data();
+    let bundled_indices = manifest
+        .files
+        .iter()
+        .filter_map(|file| {
+            file.moved_resource_index
+                .map(|index| (index, directory.join(&file.name)))
+        })
+        .collect::<HashMap<_, _>>();
+
+    for (index, moved_resource) in configuration_data
+        .resource_references()
+        .moved_resources
+        .into_iter()
+        .enumerate()
+    {
+        if let Some(path) = bundled_indices.get(&index) {
+            *moved_resource = MovedVmmResource::new(path, moved_resource.move_method());
+        }
+    }
+
+    Ok(ImportedSnapshotBundle {
+        firecracker_version: manifest.firecracker_version,
+        snapshot: VmSnapshot {
+            snapshot: imported_resource(directory.join(SNAPSHOT_ENTRY_NAME)),
+            mem_file: imported_resource(directory.join(MEM_FILE_ENTRY_NAME)),
+            configuration_data,
+        },
+    })
+}
+
+async fn import_entries<R: Runtime, Rd: AsyncRead + Send + Unpin>(
+    runtime: &R,
+    reader: Rd,
+    directory: &Path,
+    extracted: &mut HashMap<String, (u64, String)>,
+) -> Result<ReprManifest, SnapshotBundleError> {
+    let mut reader = BufReader::new(reader);
+    let magic = reader.fill_buf().await.map_err(SnapshotBundleError::ArchiveError)?;
+    let mut reader: Box<dyn AsyncRead + Send + Unpin> = if magic.starts_with(GZIP_MAGIC) {
+        Box::new(GzipDecoder::new(reader))
+    } else if magic.starts_with(ZSTD_MAGIC) {
+        Box::new(ZstdDecoder::new(reader))
+    } else {
+        Box::new(reader)
+    };
+
+    runtime
+        .fs_create_dir_all(&directory.join(RESOURCE_ENTRY_PREFIX))
+        .await
+        .map_err(SnapshotBundleError::FilesystemError)?;
+
+    let mut manifest = None;
+    while let Some((name, size)) = read_header(&mut reader).await? {
+        if name == MANIFEST_ENTRY_NAME {
+            if size > MAX_MANIFEST_SIZE {
+                return Err(SnapshotBundleError::InvalidArchive(format!(
+                    "the manifest is {size} bytes long, exceeding the maximum of {MAX_MANIFEST_SIZE} bytes"
+                )));
+            }
+
+            let mut manifest_json = vec![0; size as usize];
+            reader
+                .read_exact(&mut manifest_json)
+                .await
+                .map_err(SnapshotBundleError::ArchiveError)?;
+            manifest =
+                Some(serde_json::from_slice::<ReprManifest>(&manifest_json).map_err(SnapshotBundleError::SerdeError)?);
+        } else {
+            let is_valid_name = name == SNAPSHOT_ENTRY_NAME
+                || name == MEM_FILE_ENTRY_NAME
+                || name
+                    .strip_prefix(RESOURCE_ENTRY_PREFIX)
+                    .is_some_and(|index| index.parse::<usize>().is_ok());
+
+            if !is_valid_name || extracted.contains_key(&name) {
+                return Err(SnapshotBundleError::UnexpectedEntry(name));
+            }
+
+            extracted.insert(name.clone(), (size, String::new()));
+            let mut file = runtime
+                .fs_open_file_for_write(&directory.join(&name))
+                .await
+                .map_err(SnapshotBundleError::FilesystemError)?;
+            let sha256 = copy_exact(&mut reader, &mut file, size).await?;
+            extracted.insert(name, (size, sha256));
+        }
+
+        skip_padding(&mut reader, size).await?;
+    }
+
+    let manifest = manifest.ok_or_else(|| SnapshotBundleError::MissingEntry(MANIFEST_ENTRY_NAME.to_owned()))?;
+    if manifest.version != SNAPSHOT_BUNDLE_VERSION {
+        return Err(SnapshotBundleError::UnsupportedVersion(manifest.version));
+    }
+
+    for file in &manifest.files {
+        match extracted.get(&file.name) {
+            Some((size, sha256)) if *size == file.size && *sha256 == file.sha256 => {}
+            Some(_) => return Err(SnapshotBundleError::ChecksumMismatch(file.name.clone())),
+            None => return Err(SnapshotBundleError::MissingEntry(file.name.clone())),
+        }
+    }
+
+    for name in [SNAPSHOT_ENTRY_NAME, MEM_FILE_ENTRY_NAME] {
+        if !extracted.contains_key(name) {
+            return Err(SnapshotBundleError::MissingEntry(name.to_owned()));
+        }
+    }
+
+    if let Some(name) = extracted
+        .keys()
+        .find(|name| !manifest.files.iter().any(|file| &file.name == *name))
+    {
+        return Err(SnapshotBundleError::UnexpectedEntry(name.clone()));
+    }
+
+    Ok(manifest)
+}
+
+fn bundled_resource_paths(data: &VmConfigurationData) -> Vec<(usize, PathBuf)> {
+    let mut data = data.clone();
+    let socket_paths = data
+        .drives
+        .iter()
+        .filter_map(|drive| drive.socket.as_ref().map(|socket| socket.source_path().to_owned()))
+        .collect::<Vec<_>>();
+
+    data.resource_references()
+        .moved_resources
+        .into_iter()
+        .enumerate()
+        .map(|(index, moved_resource)| (index, moved_resource.source_path().to_owned()))
+        .filter(|(_, source_path)| !socket_paths.contains(source_path))
+        .collect()
+}
+
+fn imported_resource(path: PathBuf) -> ProducedVmmResource {
+    let mut resource = ProducedVmmResource::new(&path);
+    resource.mark_initialized(path);
+    resource.unlink();
+    resource
+}
+
+async fn write_file_entry<R: Runtime, W: AsyncWrite + Unpin>(
+    runtime: &R,
+    writer: &mut W,
+    name: &str,
+    path: &Path,
+) -> Result<(u64, String), SnapshotBundleError> {
+    let size = runtime
+        .fs_metadata(path)
+        .await
+        .map_err(SnapshotBundleError::FilesystemError)?
+        .len();
+    let mut file = runtime
+        .fs_open_file_for_read(path)
+        .await
+        .map_err(SnapshotBundleError::FilesystemError)?;
+
+    write_header(writer, name, size).await?;
+    let sha256 = copy_exact(&mut file, writer, size).await?;
+    write_padding(writer, size).await?;
+    Ok((size, sha256))
+}
+
+// copies exactly the given amount of bytes while hashing them, returning the hex-encoded SHA-256 checksum
+async fn copy_exact<Rd: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    reader: &mut Rd,
+    writer: &mut W,
+    size: u64,
+) -> Result<String, SnapshotBundleError> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; COPY_BUFFER_SIZE];
+    let mut remaining = size;
+
+    while remaining > 0 {
+        let chunk_size = remaining.min(buf.len() as u64) as usize;
+        reader
+            .read_exact(&mut buf[..chunk_size])
+            .await
+            .map_err(SnapshotBundleError::ArchiveError)?;
+        hasher.update(&buf[..chunk_size]);
+        writer
+            .write_all(&buf[..chunk_size])
+            .await
+            .map_err(SnapshotBundleError::ArchiveError)?;
+        remaining -= chunk_size as u64;
+    }
+
+    writer.flush().await.map_err(SnapshotBundleError::ArchiveError)?;
+    Ok(hasher.finalize().iter().map(|byte| format!("{byte:02x}")).collect())
+}
+
+// writes a ustar header for a regular file, using the GNU base-256 extension for sizes not fitting into 11 octal digits
+async fn write_header<W: AsyncWrite + Unpin>(writer: &mut W, name: &str, size: u64) -> Result<(), SnapshotBundleError> {
+    let mut header = [0; BLOCK_SIZE];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    write_octal(&mut header[100..108], 0o644);
+    write_octal(&mut header[108..116], 0);
+    write_octal(&mut header[116..124], 0);
+
+    if size < 1 << 33 {
+        write_octal(&mut header[124..136], size);
+    } else {
+        header[124] = 0x80;
+        header[128..136].copy_from_slice(&size.to_be_bytes());
+    }
+
+    write_octal(&mut header[136..148], 0);
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    let checksum = header_checksum(&header);
+    header[148..155].copy_from_slice(format!("{checksum:06o}\0").as_bytes());
+    header[155] = b' ';
+
+    writer
+        .write_all(&header)
+        .await
+        .map_err(SnapshotBundleError::ArchiveError)
+}
+
+async fn read_header<Rd: AsyncRead + Unpin>(reader: &mut Rd) -> Result<Option<(String, u64)>, SnapshotBundleError> {
+    let mut header = [0; BLOCK_SIZE];
+    reader
+        .read_exact(&mut header)
+        .await
+        .map_err(SnapshotBundleError::ArchiveError)?;
+
+    if header.iter().all(|byte| *byte == 0) {
+        return Ok(None);
+    }
+
+    if parse_octal(&header[148..156]) != Some(header_checksum(&header)) {
+        return Err(SnapshotBundleError::InvalidArchive(
+            "a header checksum is invalid".to_owned(),
+        ));
+    }
+
+    if !matches!(header[156], b'0' | 0) {
+        return Err(SnapshotBundleError::InvalidArchive(
+            "an entry isn't a regular file".to_owned(),
+        ));
+    }
+
+    let size = if header[124] & 0x80 != 0 {
+        header[125..136]
+            .iter()
+            .fold(0u64, |size, byte| (size << 8) | u64::from(*byte))
+    } else {
+        parse_octal(&header[124..136])
+            .ok_or_else(|| SnapshotBundleError::InvalidArchive("an entry size is invalid".to_owned()))?
+    };
+
+    let name = String::from_utf8(header[..100].iter().copied().take_while(|byte| *byte != 0).collect())
+        .map_err(|_| SnapshotBundleError::InvalidArchive("an entry name isn't UTF-8".to_owned()))?;
+    Ok(Some((name, size)))
+}
+
+async fn write_padding<W: AsyncWrite + Unpin>(writer: &mut W, size: u64) -> Result<(), SnapshotBundleError> {
+    writer
+        .write_all(&[0; BLOCK_SIZE][..padding(size)])
+        .await
+        .map_err(SnapshotBundleError::ArchiveError)
+}
+
+async fn skip_padding<Rd: AsyncRead + Unpin>(reader: &mut Rd, size: u64) -> Result<(), SnapshotBundleError> {
+    reader
+        .read_exact(&mut [0; BLOCK_SIZE][..padding(size)])
+        .await
+        .map_err(SnapshotBundleError::ArchiveError)
+}
+
+fn padding(size: u64) -> usize {
+    (BLOCK_SIZE - (size % BLOCK_SIZE as u64) as usize) % BLOCK_SIZE
+}
+
+fn header_checksum(header: &[u8; BLOCK_SIZE]) -> u64 {
+    // the checksum field itself is summed up as if it consisted of spaces
+    header
+        .iter()
+        .enumerate()
+        .map(|(index, byte)| match index {
+            148..156 => u64::from(b' '),
+            _ => u64::from(*byte),
+        })
+        .sum()
+}
+
+fn write_octal(field: &mut [u8], value: u64) {
+    let octal = format!("{value:0width$o}", width = field.len() - 1);
+    field[..octal.len()].copy_from_slice(octal.as_bytes());
+}
+
+fn parse_octal(field: &[u8]) -> Option<u64> {
+    let octal = std::str::from_utf8(field).ok()?.trim_matches(|c| c == '\0' || c == ' ');
+    u64::from_str_radix(octal, 8).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use assert_matches::assert_matches;
+    use futures_util::io::Cursor;
+
+    use crate::runtime::tokio::TokioRuntime;
+
+    use super::{
+        import_entries, padding, read_header, write_header, SnapshotBundleError, BLOCK_SIZE, MANIFEST_ENTRY_NAME,
+        MAX_MANIFEST_SIZE,
+    };
+
+    #[tokio::test]
+    async fn header_roundtrips_octal_and_base256_sizes() {
+        for size in [0, 1234, (1 << 33) - 1, 1 << 40] {
+            let mut archive = Cursor::new(Vec::new());
+            write_header(&mut archive, "resources/0", size).await.unwrap();
+            assert_eq!(archive.get_ref().len(), BLOCK_SIZE);
+            archive.set_position(0);
+
+            assert_eq!(
+                read_header(&mut archive).await.unwrap(),
+                Some(("resources/0".to_owned(), size))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn zero_block_ends_archive() {
+        let mut archive = Cursor::new(vec![0; BLOCK_SIZE]);
+        assert_eq!(read_header(&mut archive).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn header_with_invalid_checksum_is_rejected() {
+        let mut archive = Cursor::new(Vec::new());
+        write_header(&mut archive, "snapshot", 1).await.unwrap();
+        archive.get_mut()[0] = b'S';
+        archive.set_position(0);
+
+        assert_matches!(
+            read_header(&mut archive).await,
+            Err(SnapshotBundleError::InvalidArchive(_))
+        );
+    }
+
+    #[test]
+    fn entries_are_padded_to_whole_blocks() {
+        assert_eq!(padding(0), 0);
+        assert_eq!(padding(1), BLOCK_SIZE - 1);
+        assert_eq!(padding(BLOCK_SIZE as u64), 0);
+        assert_eq!(padding(BLOCK_SIZE as u64 + 2), BLOCK_SIZE - 2);
+    }
+
+    #[tokio::test]
+    async fn oversized_manifest_is_rejected_before_being_read() {
+        let mut archive = Cursor::new(Vec::new());
+        write_header(&mut archive, MANIFEST_ENTRY_NAME, MAX_MANIFEST_SIZE + 1)
+            .await
+            .unwrap();
+        archive.set_position(0);
+
+        let directory = std::env::temp_dir().join(format!("bundle{}", rand::random::<u32>()));
+        let result = import_entries(&TokioRuntime, archive, &directory, &mut HashMap::new()).await;
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_matches!(result.map(|_| ()), Err(SnapshotBundleError::InvalidArchive(_)));
+    }
+}
@@ -0,0 +1,672 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    runtime::Runtime,
+    vm::{
+        configuration::{ReprVmConfigurationData, VmConfiguration, VmConfigurationData},
+        models::SnapshotType,
+        snapshot::VmSnapshot,
+    },
+    vmm::resource::{ProducedVmmResource, VmmResourceMoveMethod},
+};
+
+use super::snapshot_editor::{SnapshotEditor, SnapshotEditorError};
+
+/// The version of the manifest format written by a [SnapshotCatalog].
+pub const SNAPSHOT_CATALOG_MANIFEST_VERSION: u32 = 1;
+
+/// The name of the manifest file inside the directory of a [SnapshotCatalog].
+pub const SNAPSHOT_CATALOG_MANIFEST_NAME: &str = "manifest.json";
+
+/// An error that can be emitted by a [SnapshotCatalog].
+#[derive(Debug)]
+pub enum SnapshotCatalogError {
+    FilesystemError(std::io::Error),
+    SerdeError(serde_json::Error),
+    UnsupportedManifestVersion(u32),
+    NotFound(u64),
+    ParentNotFound(u64),
+    DiffWithoutParent,
+    BrokenChain(u64),
+    NotRestorable(u64),
+    SnapshotEditorError(SnapshotEditorError),
+}
+
+impl std::error::Error for SnapshotCatalogError {}
+
+impl std::fmt::Display for SnapshotCatalogError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotCatalogError::FilesystemError(err) => {
+                write!(f, "A filesystem operation backed by the runtime failed: {err}")
+            }
+            SnapshotCatalogError::SerdeError(err) => {
+                write!(f, "Serializing or deserializing the manifest failed: {err}")
+            }
+            SnapshotCatalogError::UnsupportedManifestVersion(version) => {
+                write!(f, "The manifest has the unsupported version {version}")
+            }
+            SnapshotCatalogError::NotFound(id) => write!(f, "No snapshot with the ID {id} exists in the catalog"),
+            SnapshotCatalogError::ParentNotFound(id) => {
+                write!(f, "The parent snapshot with the ID {id} doesn't exist in the catalog")
+            }
+            SnapshotCatalogError::DiffWithoutParent => write!(f, "A diff snapshot was recorded without a parent"),
+            SnapshotCatalogError::BrokenChain(id) => {
+                write!(
+                    f,
+                    "The chain of the snapshot with the ID {id} doesn't lead to a full snapshot"
+                )
+            }
+            SnapshotCatalogError::NotRestorable(id) => write!(
+                f,
+                "The snapshot with the ID {id} is a diff snapshot that must be flattened before being restored"
+            ),
+            SnapshotCatalogError::SnapshotEditorError(err) => {
+                write!(f, "Rebasing memory with the snapshot-editor failed: {err}")
+            }
+        }
+    }
+}
+
+/// The metadata of a single snapshot recorded in a [SnapshotCatalog].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotCatalogEntry {
+    /// The ID of the snapshot, unique within its [SnapshotCatalog].
+    pub id: u64,
+    /// The ID of the snapshot that this snapshot was derived from: the snapshot a diff snapshot is applied on top of,
+    /// or the diff snapshot a full snapshot was flattened from.
+    pub parent: Option<u64>,
+    pub snapshot_type: SnapshotType,
+    pub firecracker_version: String,
+    /// The time of recording the snapshot, as seconds since the UNIX epoch.
+    pub created_at: u64,
+    pub configuration_data: VmConfigurationData,
+}
+
+/// A retention policy for pruning a [SnapshotCatalog]. A generation is a full snapshot together with all diff
+/// snapshots applied on top of it, and generations are always pruned as a whole. The newest generation is never
+/// pruned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SnapshotRetentionPolicy {
+    keep_generations: Option<usize>,
+    max_age: Option<Duration>,
+}
+
+impl SnapshotRetentionPolicy {
+    /// Create a policy that doesn't prune any generations.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep only the given number of newest generations.
+    pub fn keep_generations(mut self, keep_generations: usize) -> Self {
+        self.keep_generations = Some(keep_generations);
+        self
+    }
+
+    /// Keep only the generations whose full snapshot isn't older than the given age.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct ReprManifest {
+    version: u32,
+    next_id: u64,
+    entries: Vec<ReprSnapshotCatalogEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ReprSnapshotCatalogEntry {
+    id: u64,
+    parent: Option<u64>,
+    snapshot_type: SnapshotType,
+    firecracker_version: String,
+    created_at: u64,
+    #[serde(flatten)]
+    configuration_data: ReprVmConfigurationData,
+}
+
+/// A catalog of snapshots that persists their metadata as a JSON manifest inside a directory, alongside the snapshot
+/// and memory files that are moved into it. The catalog records the lineage of full and diff snapshots, flattens
+/// chains of diff snapshots into restorable full snapshots using the "snapshot-editor", and prunes old generations
+/// according to a [SnapshotRetentionPolicy]. Every mutation is persisted to the manifest immediately.
+#[derive(Debug)]
+pub struct SnapshotCatalog<R: Runtime> {
+    directory: PathBuf,
+    runtime: R,
+    next_id: u64,
+    entries: Vec<SnapshotCatalogEntry>,
+}
+
+impl<R: Runtime> SnapshotCatalog<R> {
+    /// Open the catalog inside the given directory, creating the directory and an empty catalog if no manifest
+    /// exists yet.
+    pub async fn open(directory: impl Into<PathBuf>, runtime: R) -> Result<Self, SnapshotCatalogError> {
+        let directory = directory.into();
+        runtime
+            .fs_create_dir_all(&directory)
+            .await
+            .map_err(SnapshotCatalogError::FilesystemError)?;
+
+        let manifest_path = directory.join(SNAPSHOT_CATALOG_MANIFEST_NAME);
+        if !runtime
+            .fs_exists(&manifest_path)
+            .await
+            .map_err(SnapshotCatalogError::FilesystemError)?
+        {
+            return Ok(Self {
+                directory,
+                runtime,
+                next_id: 0,
+                entries: Vec::new(),
+            });
+        }
+
+        let manifest_json = runtime
+            .fs_read_to_string(&manifest_path)
+            .await
+            .map_err(SnapshotCatalogError::FilesystemError)?;
+        let manifest: ReprManifest = serde_json::from_str(&manifest_json).map_err(SnapshotCatalogError::SerdeError)?;
+
+        if manifest.version != SNAPSHOT_CATALOG_MANIFEST_VERSION {
+            return Err(SnapshotCatalogError::UnsupportedManifestVersion(manifest.version));
+        }
+
+        Ok(Self {
+            directory,
+            runtime,
+            next_id: manifest.next_id,
+            entries: manifest.entries.into_iter().map(from_repr).collect(),
+        })
+    }
+
+    /// Get the directory of the catalog.
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// Get all entries of the catalog in the order of recording.
+    pub fn entries(&self) -> &[SnapshotCatalogEntry] {
+        &self.entries
+    }
+
+    /// Get the entry with the given ID.
+    pub fn get(&self, id: u64) -> Option<&SnapshotCatalogEntry> {
+        self.entries.iter().find(|entry| entry.id == id)
+    }
+
+    /// Get the path of the snapshot file of the snapshot with the given ID inside the catalog directory.
+    pub fn snapshot_path(&self, id: u64) -> PathBuf {
+        self.directory.join(format!("{id}.snapshot"))
+    }
+
+    /// Get the path of the memory file of the snapshot with the given ID inside the catalog directory.
+    pub fn mem_file_path(&self, id: u64) -> PathBuf {
+        self.directory.join(format!("{id}.mem"))
+    }
+
+    /// Record the given [VmSnapshot] of the given [SnapshotType], created by the given Firecracker version, in the
+    /// catalog. Diff snapshots must have the parent they were taken on top of. The snapshot and memory files are
+    /// moved into the catalog directory, by renaming them if possible or by copying them otherwise.
+    pub async fn record(
+        &mut self,
+        snapshot: VmSnapshot,
+        snapshot_type: SnapshotType,
+        parent: Option<u64>,
+        firecracker_version: impl Into<String>,
+    ) -> Result<u64, SnapshotCatalogError> {
+        match parent {
+            Some(parent) if self.get(parent).is_none() => return Err(SnapshotCatalogError::ParentNotFound(parent)),
+            None if snapshot_type == SnapshotType::Diff => return Err(SnapshotCatalogError::DiffWithoutParent),
+            _ => {}
+        }
+
+        let id = self.next_id;
+        rename_or_copy(
+            &self.runtime,
+            snapshot.snapshot.effective_path(),
+            &self.snapshot_path(id),
+        )
+        .await?;
+        rename_or_copy(
+            &self.runtime,
+            snapshot.mem_file.effective_path(),
+            &self.mem_file_path(id),
+        )
+        .await?;
+
+        self.next_id += 1;
+        self.entries.push(SnapshotCatalogEntry {
+            id,
+            parent,
+            snapshot_type,
+            firecracker_version: firecracker_version.into(),
+            created_at: now(),
+            configuration_data: snapshot.configuration_data.into_uninitialized(),
+        });
+        self.persist().await?;
+        Ok(id)
+    }
+
+    /// Get the chain of entries that must be applied in order to restore the snapshot with the given ID: its nearest
+    /// full ancestor (or itself), followed by all diff snapshots up to and including the snapshot.
+    pub fn chain(&self, id: u64) -> Result<Vec<&SnapshotCatalogEntry>, SnapshotCatalogError> {
+        let mut chain = Vec::new();
+        let mut entry = self.get(id).ok_or(SnapshotCatalogError::NotFound(id))?;
+
+        loop {
+            chain.push(entry);
+
+            if entry.snapshot_type == SnapshotType::Full {
+                chain.reverse();
+                return Ok(chain);
+            }
+
+            entry = entry
+                .parent
+                .and_then(|parent| self.get(parent))
+                .ok_or(SnapshotCatalogError::BrokenChain(id))?;
+        }
+    }
+
+    /// Flatten the chain of the diff snapshot with the given ID into a new full snapshot by rebasing the memory files
+    /// of all diff snapshots in the chain onto a copy of the memory file of the full snapshot with the given
+    /// [SnapshotEditor]. The new full snapshot has the diff snapshot as its parent, and its ID is returned. The ID of a
+    /// full snapshot is returned as is.
+    pub async fn flatten(
+        &mut self,
+        id: u64,
+        snapshot_editor: &SnapshotEditor<'_, R>,
+    ) -> Result<u64, SnapshotCatalogError> {
+        let chain = self.chain(id)?;
+        let (full, diffs) = chain.split_first().expect("A chain can't be empty");
+        if diffs.is_empty() {
+            return Ok(full.id);
+        }
+
+        let target = chain[chain.len() - 1];
+        let new_entry = SnapshotCatalogEntry {
+            id: self.next_id,
+            parent: Some(target.id),
+            snapshot_type: SnapshotType::Full,
+            firecracker_version: target.firecracker_version.clone(),
+            created_at: now(),
+            configuration_data: target.configuration_data.clone(),
+        };
+        let diff_mem_file_paths = diffs.iter().map(|diff| self.mem_file_path(diff.id)).collect::<Vec<_>>();
+        let full_mem_file_path = self.mem_file_path(full.id);
+
+        let mem_file_path = self.mem_file_path(new_entry.id);
+        let snapshot_path = self.snapshot_path(new_entry.id);
+
+        let flatten = async {
+            self.runtime
+                .fs_copy(&full_mem_file_path, &mem_file_path)
+                .await
+                .map_err(SnapshotCatalogError::FilesystemError)?;
+
+            for diff_mem_file_path in diff_mem_file_paths {
+                snapshot_editor
+                    .rebase_memory(&mem_file_path, &diff_mem_file_path)
+                    .await
+                    .map_err(SnapshotCatalogError::SnapshotEditorError)?;
+            }
+
+            self.runtime
+                .fs_copy(&self.snapshot_path(id), &snapshot_path)
+                .await
+                .map_err(SnapshotCatalogError::FilesystemError)
+        };
+
+        if let Err(err) = flatten.await {
+            let _ = self.runtime.fs_remove_file(&mem_file_path).await;
+            let _ = self.runtime.fs_remove_file(&snapshot_path).await;
+            return Err(err);
+        }
+
+        self.next_id += 1;
+        let new_id = new_entry.id;
+        self.entries.push(new_entry);
+        self.persist().await?;
+        Ok(new_id)
+    }
+
+    /// Create a [VmConfiguration] that restores the full snapshot with the given ID directly from the files inside
+    /// the catalog directory, with the given [VmmResourceMoveMethod] and parameters. Diff snapshots must be flattened
+    /// first. The files must not be renamed out of the catalog directory, so [VmmResourceMoveMethod::Rename] shouldn't
+    /// be used.
+    pub fn restore(
+        &self,
+        id: u64,
+        move_method: VmmResourceMoveMethod,
+        enable_diff_snapshots: Option<bool>,
+        resume_vm: Option<bool>,
+    ) -> Result<VmConfiguration, SnapshotCatalogError> {
+        let entry = self.get(id).ok_or(SnapshotCatalogError::NotFound(id))?;
+        if entry.snapshot_type != SnapshotType::Full {
+            return Err(SnapshotCatalogError::NotRestorable(id));
+        }
+
+        let mut snapshot = ProducedVmmResource::new(self.snapshot_path(id));
+        snapshot.mark_initialized(self.snapshot_path(id));
+        let mut mem_file = ProducedVmmResource::new(self.mem_file_path(id));
+        mem_file.mark_initialized(self.mem_file_path(id));
+
+        Ok(VmSnapshot {
+            snapshot,
+            mem_file,
+            configuration_data: entry.configuration_data.clone(),
+        }
+        .into_configuration(move_method, enable_diff_snapshots, resume_vm))
+    }
+
+    /// Remove the snapshot with the given ID from the catalog, together with all diff snapshots that depend on it,
+    /// and delete their files. The IDs of all removed snapshots are returned.
+    pub async fn remove(&mut self, id: u64) -> Result<Vec<u64>, SnapshotCatalogError> {
+        if self.get(id).is_none() {
+            return Err(SnapshotCatalogError::NotFound(id));
+        }
+
+        let removed_ids = self.dependents(HashSet::from([id]));
+        self.remove_all(&removed_ids).await?;
+        Ok(sorted(removed_ids))
+    }
+
+    /// Prune whole generations of snapshots according to the given [SnapshotRetentionPolicy], deleting their files.
+    /// The IDs of all removed snapshots are returned.
+    pub async fn prune(&mut self, policy: SnapshotRetentionPolicy) -> Result<Vec<u64>, SnapshotCatalogError> {
+        let pruned_roots = self.pruned_roots(policy, now());
+
+        if pruned_roots.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let removed_ids = self.dependents(pruned_roots);
+        self.remove_all(&removed_ids).await?;
+        Ok(sorted(removed_ids))
+    }
+
+    // selects the full snapshots of all generations that the given policy prunes at the given time
+    fn pruned_roots(&self, policy: SnapshotRetentionPolicy, now: u64) -> HashSet<u64> {
+        let mut generations = self
+            .entries
+            .iter()
+            .filter(|entry| entry.snapshot_type == SnapshotType::Full)
+            .map(|entry| (entry.created_at, entry.id))
+            .collect::<Vec<_>>();
+        generations.sort_unstable_by(|a, b| b.cmp(a));
+
+        generations
+            .into_iter()
+            .enumerate()
+            .skip(1)
+            .filter(|(index, (created_at, _))| {
+                policy
+                    .keep_generations
+                    .is_some_and(|keep_generations| *index >= keep_generations)
+                    || policy
+                        .max_age
+                        .is_some_and(|max_age| now.saturating_sub(*created_at) > max_age.as_secs())
+            })
+            .map(|(_, (_, id))| id)
+            .collect()
+    }
+
+    // extends the given IDs with all diff snapshots transitively depending on them
+    fn dependents(&self, mut ids: HashSet<u64>) -> HashSet<u64> {
+        loop {
+            let previous_len = ids.len();
+
+            for entry in &self.entries {
+                if entry.snapshot_type == SnapshotType::Diff && entry.parent.is_some_and(|parent| ids.contains(&parent))
+                {
+                    ids.insert(entry.id);
+                }
+            }
+
+            if ids.len() == previous_len {
+                return ids;
+            }
+        }
+    }
+
+    async fn remove_all(&mut self, ids: &HashSet<u64>) -> Result<(), SnapshotCatalogError> {
+        self.entries.retain(|entry| !ids.contains(&entry.id));
+
+        // full snapshots flattened from a removed diff snapshot remain restorable, but lose their lineage
+        for entry in &mut self.entries {
+            if entry.parent.is_some_and(|parent| ids.contains(&parent)) {
+                entry.parent = None;
+            }
+        }
+
+        self.persist().await?;
+
+        for id in ids {
+            for path in [self.snapshot_path(*id), self.mem_file_path(*id)] {
+                if self
+                    .runtime
+                    .fs_exists(&path)
+                    .await
+                    .map_err(SnapshotCatalogError::FilesystemError)?
+                {
+                    self.runtime
+                        .fs_remove_file(&path)
+                        .await
+                        .map_err(SnapshotCatalogError::FilesystemError)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn persist(&self) -> Result<(), SnapshotCatalogError> {
+        let manifest = ReprManifest {
+            version: SNAPSHOT_CATALOG_MANIFEST_VERSION,
+            next_id: self.next_id,
+            entries: self.entries.iter().map(to_repr).collect(),
+        };
+        let manifest_json = serde_json::to_string_pretty(&manifest).map_err(SnapshotCatalogError::SerdeError)?;
+
+        // the manifest is replaced atomically, so that it is never observed half-written
+        let temporary_path = self.directory.join(format!("{SNAPSHOT_CATALOG_MANIFEST_NAME}.tmp"));
+        self.runtime
+            .fs_write(&temporary_path, manifest_json)
+            .await
+            .map_err(SnapshotCatalogError::FilesystemError)?;
+        self.runtime
+            .fs_rename(&temporary_path, &self.directory.join(SNAPSHOT_CATALOG_MANIFEST_NAME))
+            .await
+            .map_err(SnapshotCatalogError::FilesystemError)
+    }
+}
+
+fn to_repr(entry: &SnapshotCatalogEntry) -> ReprSnapshotCatalogEntry {
+    ReprSnapshotCatalogEntry {
+        id: entry.id,
+        parent: entry.parent,
+        snapshot_type: entry.snapshot_type,
+        firecracker_version: entry.firecracker_version.clone(),
+        created_at: entry.created_at,
+        configuration_data: ReprVmConfigurationData::new(&entry.configuration_data),
+    }
+}
+
+fn from_repr(repr: ReprSnapshotCatalogEntry) -> SnapshotCatalogEntry {
+    SnapshotCatalogEntry {
+        id: repr.id,
+        parent: repr.parent,
+        snapshot_type: repr.snapshot_type,
+        firecracker_version: repr.firecracker_version,
+        created_at: repr.created_at,
+        configuration_data: repr.configuration_data.into_data(),
+    }
+}
+
+async fn rename_or_copy<R: Runtime>(runtime: &R, from: &Path, to: &Path) -> Result<(), SnapshotCatalogError> {
+    if runtime.fs_rename(from, to).await.is_ok() {
+        return Ok(());
+    }
+
+    runtime
+        .fs_copy(from, to)
+        .await
+        .map_err(SnapshotCatalogError::FilesystemError)?;
+    runtime
+        .fs_remove_file(from)
+        .await
+        .map_err(SnapshotCatalogError::FilesystemError)
+}
+
+fn sorted(ids: HashSet<u64>) -> Vec<u64> {
+    let mut ids = ids.into_iter().collect::<Vec<_>>();
+    ids.sort_unstable();
+    ids
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, path::PathBuf, time::Duration};
+
+    use assert_matches::assert_matches;
+
+    use crate::{
+        runtime::tokio::TokioRuntime,
+        vm::{
+            configuration::VmConfigurationData,
+            models::{BootSource, MachineConfiguration, SnapshotType},
+        },
+        vmm::resource::{MovedVmmResource, VmmResourceMoveMethod},
+    };
+
+    use super::{SnapshotCatalog, SnapshotCatalogEntry, SnapshotCatalogError, SnapshotRetentionPolicy};
+
+    // two generations: full snapshot 1 with diffs 2 and 3 on top of it, full snapshot 4 flattened from diff 3 with
+    // diff 5 on top of it, and diff 6 whose parent is missing
+    fn catalog() -> SnapshotCatalog<TokioRuntime> {
+        let entries = [
+            (1, None, SnapshotType::Full, 100),
+            (2, Some(1), SnapshotType::Diff, 200),
+            (3, Some(2), SnapshotType::Diff, 300),
+            (4, Some(3), SnapshotType::Full, 400),
+            (5, Some(4), SnapshotType::Diff, 500),
+            (6, Some(7), SnapshotType::Diff, 600),
+        ];
+
+        SnapshotCatalog {
+            directory: PathBuf::new(),
+            runtime: TokioRuntime,
+            next_id: 8,
+            entries: entries
+                .into_iter()
+                .map(|(id, parent, snapshot_type, created_at)| SnapshotCatalogEntry {
+                    id,
+                    parent,
+                    snapshot_type,
+                    firecracker_version: "1.10.0".to_owned(),
+                    created_at,
+                    configuration_data: configuration_data(),
+                })
+                .collect(),
+        }
+    }
+
+    fn configuration_data() -> VmConfigurationData {
+        VmConfigurationData {
+            boot_source: BootSource {
+                kernel_image: MovedVmmResource::new("/kernel", VmmResourceMoveMethod::Copy),
+                boot_args: None,
+                initrd: None,
+            },
+            drives: Vec::new(),
+            machine_configuration: MachineConfiguration {
+                vcpu_count: 1,
+                mem_size_mib: 128,
+                smt: None,
+                track_dirty_pages: None,
+                huge_pages: None,
+            },
+            cpu_template: None,
+            network_interfaces: Vec::new(),
+            balloon_device: None,
+            vsock_device: None,
+            logger_system: None,
+            metrics_system: None,
+            mmds_configuration: None,
+            entropy_device: None,
+        }
+    }
+
+    fn chain_ids(catalog: &SnapshotCatalog<TokioRuntime>, id: u64) -> Vec<u64> {
+        catalog.chain(id).unwrap().into_iter().map(|entry| entry.id).collect()
+    }
+
+    #[test]
+    fn chain_leads_from_nearest_full_snapshot() {
+        let catalog = catalog();
+        assert_eq!(chain_ids(&catalog, 1), [1]);
+        assert_eq!(chain_ids(&catalog, 3), [1, 2, 3]);
+        assert_eq!(chain_ids(&catalog, 4), [4]);
+        assert_eq!(chain_ids(&catalog, 5), [4, 5]);
+    }
+
+    #[test]
+    fn chain_without_full_snapshot_is_broken() {
+        let catalog = catalog();
+        assert_matches!(catalog.chain(6), Err(SnapshotCatalogError::BrokenChain(6)));
+        assert_matches!(catalog.chain(7), Err(SnapshotCatalogError::NotFound(7)));
+    }
+
+    #[test]
+    fn dependents_include_transitive_diffs_only() {
+        let catalog = catalog();
+        assert_eq!(catalog.dependents(HashSet::from([1])), HashSet::from([1, 2, 3]));
+        assert_eq!(catalog.dependents(HashSet::from([4])), HashSet::from([4, 5]));
+    }
+
+    #[test]
+    fn retention_never_prunes_newest_generation() {
+        let catalog = catalog();
+        assert!(catalog.pruned_roots(SnapshotRetentionPolicy::new(), 1000).is_empty());
+        assert!(catalog
+            .pruned_roots(SnapshotRetentionPolicy::new().keep_generations(2), 1000)
+            .is_empty());
+        assert_eq!(
+            catalog.pruned_roots(SnapshotRetentionPolicy::new().keep_generations(0), 1000),
+            HashSet::from([1])
+        );
+        assert_eq!(
+            catalog.pruned_roots(SnapshotRetentionPolicy::new().max_age(Duration::from_secs(1)), 1000),
+            HashSet::from([1])
+        );
+    }
+
+    #[test]
+    fn retention_by_age_keeps_recent_generations() {
+        let catalog = catalog();
+        assert!(catalog
+            .pruned_roots(SnapshotRetentionPolicy::new().max_age(Duration::from_secs(900)), 1000)
+            .is_empty());
+        assert_eq!(
+            catalog.pruned_roots(SnapshotRetentionPolicy::new().max_age(Duration::from_secs(899)), 1000),
+            HashSet::from([1])
+        );
+    }
+}
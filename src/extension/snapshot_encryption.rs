@@ -0,0 +1,523 @@
+//! Encryption at rest for snapshot and memory files. An encrypted file starts with a header made up of the
+//! [ENCRYPTED_FILE_MAGIC], the length of the key ID as a big-endian u16, the key ID itself and a random 19-byte
+//! nonce prefix. The header is followed by the plaintext encrypted with XChaCha20-Poly1305 in the STREAM construction,
+//! in chunks of [ENCRYPTED_CHUNK_SIZE] bytes that are each authenticated alongside the header, with the last chunk
+//! being shorter (potentially empty) so that truncation is detected.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use chacha20poly1305::{
+    aead::{
+        rand_core::RngCore,
+        stream::{DecryptorBE32, EncryptorBE32},
+        KeyInit, OsRng,
+    },
+    XChaCha20Poly1305,
+};
+use futures_util::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use zeroize::Zeroize;
+
+use crate::{
+    process_spawner::ProcessSpawner,
+    runtime::Runtime,
+    vm::{
+        api::{VmApi, VmApiError},
+        configuration::VmConfiguration,
+        models::{CreateSnapshot, MemoryBackendType},
+        snapshot::VmSnapshot,
+        Vm, VmError,
+    },
+    vmm::{
+        executor::VmmExecutor,
+        ownership::{downgrade_owner, ChangeOwnerError},
+        resource::MovedVmmResource,
+    },
+};
+
+/// The magic bytes at the start of every encrypted file.
+pub const ENCRYPTED_FILE_MAGIC: &[u8; 8] = b"FCTSENC1";
+
+/// The size of the plaintext chunks that are individually encrypted and authenticated.
+pub const ENCRYPTED_CHUNK_SIZE: usize = 64 * 1024;
+
+const NONCE_PREFIX_SIZE: usize = 19;
+const TAG_SIZE: usize = 16;
+/// The permission bits of decrypted files, which are only accessible to their owner.
+const PLAINTEXT_FILE_MODE: u32 = 0o600;
+
+/// A 256-bit key used to encrypt and decrypt snapshot and memory files. The key is zeroed when dropped and isn't
+/// printed by its [Debug](std::fmt::Debug) implementation.
+#[derive(Clone)]
+pub struct SnapshotEncryptionKey([u8; 32]);
+
+impl SnapshotEncryptionKey {
+    /// Create a key from the given raw bytes.
+    pub fn new(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    /// Generate a random key from the operating system's random number generator.
+    pub fn generate() -> Self {
+        let mut bytes = [0; 32];
+        OsRng.fill_bytes(&mut bytes);
+        Self(bytes)
+    }
+}
+
+impl std::fmt::Debug for SnapshotEncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SnapshotEncryptionKey(..)")
+    }
+}
+
+impl Drop for SnapshotEncryptionKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+/// An error that can be emitted by a [SnapshotKeyProvider].
+#[derive(Debug)]
+pub enum SnapshotKeyProviderError {
+    UnknownKey(String),
+    Other(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl std::error::Error for SnapshotKeyProviderError {}
+
+impl std::fmt::Display for SnapshotKeyProviderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotKeyProviderError::UnknownKey(key_id) => write!(f, "The key with the ID {key_id} is unknown"),
+            SnapshotKeyProviderError::Other(err) => write!(f, "Another error occurred: {err}"),
+        }
+    }
+}
+
+/// A provider of [SnapshotEncryptionKey]s, such as a key management service. Every encrypted file records the ID of
+/// the key it was encrypted with, so that keys can be rotated while files encrypted with older keys remain readable.
+pub trait SnapshotKeyProvider: Send + Sync {
+    /// Get the ID and the [SnapshotEncryptionKey] that new files should be encrypted with.
+    fn encryption_key(
+        &self,
+    ) -> impl Future<Output = Result<(String, SnapshotEncryptionKey), SnapshotKeyProviderError>> + Send;
+
+    /// Get the [SnapshotEncryptionKey] with the given ID that a file was encrypted with.
+    fn decryption_key(
+        &self,
+        key_id: &str,
+    ) -> impl Future<Output = Result<SnapshotEncryptionKey, SnapshotKeyProviderError>> + Send;
+}
+
+/// A [SnapshotKeyProvider] holding a fixed set of keys in memory, one of which is used for encryption while the
+/// others, for example rotated-out keys, are only used for decryption.
+#[derive(Debug, Clone)]
+pub struct StaticSnapshotKeyProvider {
+    current_key_id: String,
+    keys: HashMap<String, SnapshotEncryptionKey>,
+}
+
+impl StaticSnapshotKeyProvider {
+    /// Create a [StaticSnapshotKeyProvider] encrypting with the given key.
+    pub fn new(key_id: impl Into<String>, key: SnapshotEncryptionKey) -> Self {
+        let current_key_id = key_id.into();
+        let mut keys = HashMap::new();
+        keys.insert(current_key_id.clone(), key);
+
+        Self { current_key_id, keys }
+    }
+
+    /// Add a key that is only used to decrypt files that were encrypted with it.
+    pub fn retired_key(mut self, key_id: impl Into<String>, key: SnapshotEncryptionKey) -> Self {
+        self.keys.insert(key_id.into(), key);
+        self
+    }
+}
+
+impl SnapshotKeyProvider for StaticSnapshotKeyProvider {
+    async fn encryption_key(&self) -> Result<(String, SnapshotEncryptionKey), SnapshotKeyProviderError> {
+        let key = self.decryption_key(&self.current_key_id).await?;
+        Ok((self.current_key_id.clone(), key))
+    }
+
+    async fn decryption_key(&self, key_id: &str) -> Result<SnapshotEncryptionKey, SnapshotKeyProviderError> {
+        self.keys
+            .get(key_id)
+            .cloned()
+            .ok_or_else(|| SnapshotKeyProviderError::UnknownKey(key_id.to_owned()))
+    }
+}
+
+/// An error that can be emitted when encrypting or decrypting snapshot and memory files.
+#[derive(Debug)]
+pub enum SnapshotEncryptionError {
+    SnapshotError(VmApiError),
+    StartError(VmError),
+    NotRestoredFromSnapshot,
+    ResourceUninitialized,
+    KeyProviderError(SnapshotKeyProviderError),
+    FilesystemError(std::io::Error),
+    ChangeOwnerError(ChangeOwnerError),
+    InvalidHeader,
+    KeyIdTooLong,
+    EncryptionFailed,
+    AuthenticationFailed,
+}
+
+impl std::error::Error for SnapshotEncryptionError {}
+
+impl std::fmt::Display for SnapshotEncryptionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotEncryptionError::SnapshotError(err) => write!(f, "Creating the snapshot failed: {err}"),
+            SnapshotEncryptionError::StartError(err) => write!(f, "Starting the restored VM failed: {err}"),
+            SnapshotEncryptionError::NotRestoredFromSnapshot => {
+                write!(f, "The VM isn't configured to be restored from a snapshot")
+            }
+            SnapshotEncryptionError::ResourceUninitialized => {
+                write!(f, "A snapshot resource of the VM wasn't initialized, so the VM wasn't prepared")
+            }
+            SnapshotEncryptionError::KeyProviderError(err) => write!(f, "The key provider failed: {err}"),
+            SnapshotEncryptionError::FilesystemError(err) => {
+                write!(f, "A filesystem operation backed by the runtime failed: {err}")
+            }
+            SnapshotEncryptionError::ChangeOwnerError(err) => {
+                write!(f, "An ownership change of a decrypted file failed: {err}")
+            }
+            SnapshotEncryptionError::InvalidHeader => write!(f, "The header of an encrypted file was invalid"),
+            SnapshotEncryptionError::KeyIdTooLong => write!(f, "The key ID exceeds 65535 bytes"),
+            SnapshotEncryptionError::EncryptionFailed => write!(f, "Encrypting a chunk of a file failed"),
+            SnapshotEncryptionError::AuthenticationFailed => write!(
+                f,
+                "An encrypted file failed authentication, so it was tampered with, truncated or encrypted with a different key"
+            ),
+        }
+    }
+}
+
+/// An extension that keeps the snapshot and memory files of a [Vm] encrypted at rest, so that plaintext guest memory
+/// only exists on disk between the VMM producing or consuming it and the extension encrypting or removing it.
+pub trait SnapshotEncryptionExt {
+    /// Create a snapshot via [VmApi::api_create_snapshot] and encrypt its snapshot and memory files in place with the
+    /// encryption key of the given [SnapshotKeyProvider] as soon as the VMM has written them. If the encryption fails,
+    /// the plaintext files are removed.
+    fn api_create_encrypted_snapshot<K: SnapshotKeyProvider>(
+        &mut self,
+        create_snapshot: CreateSnapshot,
+        key_provider: &K,
+    ) -> impl Future<Output = Result<VmSnapshot, SnapshotEncryptionError>> + Send;
+
+    /// Start a prepared [Vm] that is restored from an encrypted snapshot, for example one created by
+    /// [SnapshotEncryptionExt::api_create_encrypted_snapshot]. The snapshot and memory files at the effective paths of
+    /// the [LoadSnapshot](crate::vm::models::LoadSnapshot) are decrypted right before the VMM loads them and the
+    /// plaintext files are removed as soon as it has, regardless of whether the start succeeded. A moved file is
+    /// decrypted in place, while an unmoved file is decrypted next to its encrypted source, which is kept intact.
+    /// A memory file is only decrypted when the [MemoryBackendType::File] backend is used.
+    fn start_decrypted<K: SnapshotKeyProvider>(
+        &mut self,
+        socket_wait_timeout: Duration,
+        key_provider: &K,
+    ) -> impl Future<Output = Result<(), SnapshotEncryptionError>> + Send;
+}
+
+impl<E: VmmExecutor, S: ProcessSpawner, R: Runtime> SnapshotEncryptionExt for Vm<E, S, R> {
+    async fn api_create_encrypted_snapshot<K: SnapshotKeyProvider>(
+        &mut self,
+        create_snapshot: CreateSnapshot,
+        key_provider: &K,
+    ) -> Result<VmSnapshot, SnapshotEncryptionError> {
+        let snapshot = self
+            .api_create_snapshot(create_snapshot)
+            .await
+            .map_err(SnapshotEncryptionError::SnapshotError)?;
+
+        if let Err(err) = futures_util::try_join!(
+            encrypt_file(&self.runtime, snapshot.snapshot.effective_path(), key_provider),
+            encrypt_file(&self.runtime, snapshot.mem_file.effective_path(), key_provider)
+        ) {
+            snapshot.remove(&self.runtime).await;
+            return Err(err);
+        }
+
+        Ok(snapshot)
+    }
+
+    async fn start_decrypted<K: SnapshotKeyProvider>(
+        &mut self,
+        socket_wait_timeout: Duration,
+        key_provider: &K,
+    ) -> Result<(), SnapshotEncryptionError> {
+        let VmConfiguration::RestoredFromSnapshot { load_snapshot, data: _ } = self.configuration_mut() else {
+            return Err(SnapshotEncryptionError::NotRestoredFromSnapshot);
+        };
+
+        let mut resources = vec![&mut load_snapshot.snapshot];
+        if load_snapshot.mem_backend.backend_type == MemoryBackendType::File {
+            resources.push(&mut load_snapshot.mem_backend.backend);
+        }
+
+        let mut decryptions = Vec::with_capacity(resources.len());
+        for resource in resources {
+            decryptions.push(plan_decryption(resource)?);
+        }
+
+        let mut plaintext_paths = Vec::with_capacity(decryptions.len());
+        let mut result = Ok(());
+
+        for (encrypted_path, plaintext_path) in decryptions {
+            plaintext_paths.push(plaintext_path.clone());
+            result = decrypt_file(&self.runtime, &encrypted_path, &plaintext_path, key_provider).await;
+
+            if result.is_ok() {
+                result = downgrade_owner(&plaintext_path, self.ownership_model)
+                    .map_err(SnapshotEncryptionError::ChangeOwnerError);
+            }
+
+            if result.is_err() {
+                break;
+            }
+        }
+
+        if result.is_ok() {
+            result = self
+                .start(socket_wait_timeout)
+                .await
+                .map_err(SnapshotEncryptionError::StartError);
+        }
+
+        // the VMM has read the snapshot and mapped the memory file by now, so neither is needed on disk anymore
+        for plaintext_path in plaintext_paths {
+            let _ = self.runtime.fs_remove_file(&plaintext_path).await;
+        }
+
+        result
+    }
+}
+
+// an unmoved resource is its own encrypted source, so it's redirected to a plaintext file next to it, while a moved
+// resource is a copy or link that can be replaced by its plaintext
+fn plan_decryption(resource: &mut MovedVmmResource) -> Result<(PathBuf, PathBuf), SnapshotEncryptionError> {
+    let effective_path = resource
+        .effective_path_checked()
+        .ok_or(SnapshotEncryptionError::ResourceUninitialized)?
+        .to_owned();
+
+    if effective_path != resource.source_path() {
+        return Ok((effective_path.clone(), effective_path));
+    }
+
+    let plaintext_path = append_extension(&effective_path, "decrypted");
+    resource.mark_initialized(plaintext_path.clone(), plaintext_path.clone());
+    Ok((effective_path, plaintext_path))
+}
+
+/// Encrypt the file at the given path in place with the encryption key of the given [SnapshotKeyProvider]. The
+/// ciphertext is written next to the file and then renamed over it, so that the file is never partially encrypted.
+pub async fn encrypt_file<R: Runtime, K: SnapshotKeyProvider>(
+    runtime: &R,
+    path: &Path,
+    key_provider: &K,
+) -> Result<(), SnapshotEncryptionError> {
+    let (key_id, key) = key_provider
+        .encryption_key()
+        .await
+        .map_err(SnapshotEncryptionError::KeyProviderError)?;
+    let key_id_length = u16::try_from(key_id.len()).map_err(|_| SnapshotEncryptionError::KeyIdTooLong)?;
+
+    let mut nonce_prefix = [0; NONCE_PREFIX_SIZE];
+    OsRng.fill_bytes(&mut nonce_prefix);
+    let mut header = ENCRYPTED_FILE_MAGIC.to_vec();
+    header.extend_from_slice(&key_id_length.to_be_bytes());
+    header.extend_from_slice(key_id.as_bytes());
+    header.extend_from_slice(&nonce_prefix);
+
+    let mut encryptor = EncryptorBE32::from_aead(XChaCha20Poly1305::new(&key.0.into()), &nonce_prefix.into());
+    let temporary_path = append_extension(path, "encrypting");
+
+    let result = async {
+        let mut reader = runtime
+            .fs_open_file_for_read(path)
+            .await
+            .map_err(SnapshotEncryptionError::FilesystemError)?;
+        let mut writer = runtime
+            .fs_open_file_for_write(&temporary_path)
+            .await
+            .map_err(SnapshotEncryptionError::FilesystemError)?;
+        writer
+            .write_all(&header)
+            .await
+            .map_err(SnapshotEncryptionError::FilesystemError)?;
+
+        let mut buf = Vec::with_capacity(ENCRYPTED_CHUNK_SIZE + TAG_SIZE);
+        loop {
+            buf.resize(ENCRYPTED_CHUNK_SIZE, 0);
+            let length = read_full(&mut reader, &mut buf).await?;
+            buf.truncate(length);
+
+            if length < ENCRYPTED_CHUNK_SIZE {
+                encryptor
+                    .encrypt_last_in_place(&header, &mut buf)
+                    .map_err(|_| SnapshotEncryptionError::EncryptionFailed)?;
+                writer
+                    .write_all(&buf)
+                    .await
+                    .map_err(SnapshotEncryptionError::FilesystemError)?;
+                break;
+            }
+
+            encryptor
+                .encrypt_next_in_place(&header, &mut buf)
+                .map_err(|_| SnapshotEncryptionError::EncryptionFailed)?;
+            writer
+                .write_all(&buf)
+                .await
+                .map_err(SnapshotEncryptionError::FilesystemError)?;
+        }
+
+        writer.close().await.map_err(SnapshotEncryptionError::FilesystemError)
+    }
+    .await;
+
+    match result {
+        Ok(()) => runtime
+            .fs_rename(&temporary_path, path)
+            .await
+            .map_err(SnapshotEncryptionError::FilesystemError),
+        Err(err) => {
+            let _ = runtime.fs_remove_file(&temporary_path).await;
+            Err(err)
+        }
+    }
+}
+
+/// Decrypt the encrypted file at the given source path into the given destination path, which may be the same path,
+/// with the decryption key of the given [SnapshotKeyProvider] whose ID is recorded in the file. The plaintext is
+/// written next to the destination path with mode 0600 and only renamed to it once the whole file has been
+/// authenticated.
+pub async fn decrypt_file<R: Runtime, K: SnapshotKeyProvider>(
+    runtime: &R,
+    source_path: &Path,
+    destination_path: &Path,
+    key_provider: &K,
+) -> Result<(), SnapshotEncryptionError> {
+    let mut reader = runtime
+        .fs_open_file_for_read(source_path)
+        .await
+        .map_err(SnapshotEncryptionError::FilesystemError)?;
+
+    let mut header = vec![0; ENCRYPTED_FILE_MAGIC.len() + 2];
+    read_exact(&mut reader, &mut header).await?;
+    if &header[..ENCRYPTED_FILE_MAGIC.len()] != ENCRYPTED_FILE_MAGIC {
+        return Err(SnapshotEncryptionError::InvalidHeader);
+    }
+
+    let key_id_length = u16::from_be_bytes([header[header.len() - 2], header[header.len() - 1]]) as usize;
+    header.resize(header.len() + key_id_length + NONCE_PREFIX_SIZE, 0);
+    let prefix_length = ENCRYPTED_FILE_MAGIC.len() + 2;
+    read_exact(&mut reader, &mut header[prefix_length..]).await?;
+
+    let key_id = std::str::from_utf8(&header[prefix_length..prefix_length + key_id_length])
+        .map_err(|_| SnapshotEncryptionError::InvalidHeader)?;
+    let key = key_provider
+        .decryption_key(key_id)
+        .await
+        .map_err(SnapshotEncryptionError::KeyProviderError)?;
+    let nonce_prefix: [u8; NONCE_PREFIX_SIZE] = header[header.len() - NONCE_PREFIX_SIZE..]
+        .try_into()
+        .expect("Nonce prefix had an unexpected length");
+
+    let mut decryptor = DecryptorBE32::from_aead(XChaCha20Poly1305::new(&key.0.into()), &nonce_prefix.into());
+    let temporary_path = append_extension(destination_path, "decrypting");
+
+    // the plaintext must never be readable by others, so a stale temporary file, whose mode can't be trusted, is
+    // replaced with a new one that is only accessible to its owner
+    if runtime.fs_exists(&temporary_path).await.unwrap_or(false) {
+        runtime
+            .fs_remove_file(&temporary_path)
+            .await
+            .map_err(SnapshotEncryptionError::FilesystemError)?;
+    }
+
+    let result = async {
+        let mut writer = runtime
+            .fs_create_new_file_for_write(&temporary_path, PLAINTEXT_FILE_MODE)
+            .await
+            .map_err(SnapshotEncryptionError::FilesystemError)?;
+
+        let mut buf = Vec::with_capacity(ENCRYPTED_CHUNK_SIZE + TAG_SIZE);
+        loop {
+            buf.resize(ENCRYPTED_CHUNK_SIZE + TAG_SIZE, 0);
+            let length = read_full(&mut reader, &mut buf).await?;
+            buf.truncate(length);
+
+            if length < ENCRYPTED_CHUNK_SIZE + TAG_SIZE {
+                decryptor
+                    .decrypt_last_in_place(&header, &mut buf)
+                    .map_err(|_| SnapshotEncryptionError::AuthenticationFailed)?;
+                writer
+                    .write_all(&buf)
+                    .await
+                    .map_err(SnapshotEncryptionError::FilesystemError)?;
+                break;
+            }
+
+            decryptor
+                .decrypt_next_in_place(&header, &mut buf)
+                .map_err(|_| SnapshotEncryptionError::AuthenticationFailed)?;
+            writer
+                .write_all(&buf)
+                .await
+                .map_err(SnapshotEncryptionError::FilesystemError)?;
+        }
+
+        writer.close().await.map_err(SnapshotEncryptionError::FilesystemError)
+    }
+    .await;
+
+    match result {
+        Ok(()) => runtime
+            .fs_rename(&temporary_path, destination_path)
+            .await
+            .map_err(SnapshotEncryptionError::FilesystemError),
+        Err(err) => {
+            let _ = runtime.fs_remove_file(&temporary_path).await;
+            Err(err)
+        }
+    }
+}
+
+async fn read_full<T: AsyncRead + Unpin>(reader: &mut T, buf: &mut [u8]) -> Result<usize, SnapshotEncryptionError> {
+    let mut length = 0;
+
+    while length < buf.len() {
+        match reader
+            .read(&mut buf[length..])
+            .await
+            .map_err(SnapshotEncryptionError::FilesystemError)?
+        {
+            0 => break,
+            read => length += read,
+        }
+    }
+
+    Ok(length)
+}
+
+async fn read_exact<T: AsyncRead + Unpin>(reader: &mut T, buf: &mut [u8]) -> Result<(), SnapshotEncryptionError> {
+    match read_full(reader, buf).await? == buf.len() {
+        true => Ok(()),
+        false => Err(SnapshotEncryptionError::InvalidHeader),
+    }
+}
+
+fn append_extension(path: &Path, extension: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".");
+    path.push(extension);
+    PathBuf::from(path)
+}
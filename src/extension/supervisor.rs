@@ -0,0 +1,391 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use futures_channel::mpsc;
+use futures_util::lock::{Mutex as AsyncMutex, MutexGuard as AsyncMutexGuard};
+
+use crate::{
+    process_spawner::ProcessSpawner,
+    runtime::{Runtime, RuntimeTask},
+    vm::{configuration::VmConfiguration, snapshot::VmSnapshot, Vm, VmError, VmState},
+    vmm::{
+        executor::VmmExecutor, id::VmmId, installation::VmmInstallation, ownership::VmmOwnershipModel,
+        resource::VmmResourceMoveMethod,
+    },
+};
+
+use super::util::tear_down;
+
+/// The policy that determines whether a [Vm] supervised by a [VmSupervisor] is restarted after its VMM exits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum VmRestartPolicy {
+    /// Never restart the [Vm], only clean it up.
+    Never,
+    /// Only restart the [Vm] when it is [VmState::Crashed].
+    #[default]
+    OnFailure,
+    /// Restart the [Vm] both when it is [VmState::Crashed] and [VmState::Exited].
+    Always,
+}
+
+/// The options for supervising a single [Vm] with a [VmSupervisor].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VmSupervisorOptions {
+    policy: VmRestartPolicy,
+    socket_wait_timeout: Duration,
+    poll_interval: Duration,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    max_restarts: u32,
+    restart_window: Duration,
+}
+
+impl VmSupervisorOptions {
+    /// Create options with the given [VmRestartPolicy] and timeout for the API socket to become available when
+    /// restarting. By default, the [Vm] is polled every 100 milliseconds, restarts are delayed by an exponential
+    /// backoff from 100 milliseconds to 30 seconds, and supervision gives up after 5 restarts within 60 seconds.
+    pub fn new(policy: VmRestartPolicy, socket_wait_timeout: Duration) -> Self {
+        Self {
+            policy,
+            socket_wait_timeout,
+            poll_interval: Duration::from_millis(100),
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+            max_restarts: 5,
+            restart_window: Duration::from_secs(60),
+        }
+    }
+
+    /// Set the interval at which the state of the [Vm] is polled.
+    pub fn poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Set the delay before the first restart, which doubles with every consecutive restart up to the given maximum.
+    /// The delay is reset once the [Vm] has been running for longer than the restart window.
+    pub fn backoff(mut self, initial_backoff: Duration, max_backoff: Duration) -> Self {
+        self.initial_backoff = initial_backoff;
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// Give up supervising the [Vm] once the given amount of restarts has been attempted within the given window.
+    pub fn max_restarts(mut self, max_restarts: u32, restart_window: Duration) -> Self {
+        self.max_restarts = max_restarts;
+        self.restart_window = restart_window;
+        self
+    }
+}
+
+/// An event reported by a [VmSupervisor] about one of its supervised [Vm]s.
+#[derive(Debug)]
+pub enum VmSupervisorEvent {
+    /// The VMM exited with the given [VmState] and was cleaned up, with the error of the cleanup if it failed.
+    Exited {
+        id: VmmId,
+        state: VmState,
+        cleanup_error: Option<VmError>,
+    },
+    /// The [Vm] was prepared and started again after the given backoff, with the total amount of restarts.
+    Restarted {
+        id: VmmId,
+        restarts: u32,
+        backoff: Duration,
+    },
+    /// Preparing or starting the [Vm] again failed, which counts as an attempted restart.
+    RestartFailed { id: VmmId, error: VmError },
+    /// Supervision was given up, either because of the [VmRestartPolicy] or the maximum amount of restarts.
+    GaveUp { id: VmmId, restarts: u32 },
+}
+
+/// A supervisor that watches [Vm]s sharing the same [ProcessSpawner], [Runtime], [VmmOwnershipModel] and
+/// [VmmInstallation], cleans each of them up once its VMM has exited, and prepares and starts it again according to
+/// its [VmRestartPolicy]. Every exit and restart is reported as a [VmSupervisorEvent].
+#[derive(Debug)]
+pub struct VmSupervisor<S: ProcessSpawner, R: Runtime> {
+    process_spawner: S,
+    runtime: R,
+    ownership_model: VmmOwnershipModel,
+    installation: Arc<VmmInstallation>,
+    event_sender: Option<mpsc::UnboundedSender<VmSupervisorEvent>>,
+}
+
+impl<S: ProcessSpawner, R: Runtime> VmSupervisor<S, R> {
+    /// Create a [VmSupervisor] whose [Vm]s are restarted with the given components.
+    pub fn new(
+        process_spawner: S,
+        runtime: R,
+        ownership_model: VmmOwnershipModel,
+        installation: Arc<VmmInstallation>,
+    ) -> Self {
+        Self {
+            process_spawner,
+            runtime,
+            ownership_model,
+            installation,
+            event_sender: None,
+        }
+    }
+
+    /// Report every [VmSupervisorEvent] to the given sender.
+    pub fn event_sender(mut self, event_sender: mpsc::UnboundedSender<VmSupervisorEvent>) -> Self {
+        self.event_sender = Some(event_sender);
+        self
+    }
+
+    /// Start supervising the given [Vm] under the given [VmmId] in a background task. Restarts prepare a new [Vm] with
+    /// the [VmmExecutor] returned by the given closure and the given restart [VmConfiguration], which should be the
+    /// original configuration the [Vm] was prepared with (before preparation modified its resources).
+    pub fn supervise<E: VmmExecutor + 'static>(
+        &self,
+        id: VmmId,
+        vm: Vm<E, S, R>,
+        restart_configuration: VmConfiguration,
+        executor_factory: impl Fn() -> E + Send + Sync + 'static,
+        options: VmSupervisorOptions,
+    ) -> SupervisedVm<E, S, R> {
+        let shared = Arc::new(SupervisedShared {
+            id: id.clone(),
+            vm: AsyncMutex::new(vm),
+            restart_configuration: Mutex::new(restart_configuration),
+            stopped: AtomicBool::new(false),
+            restarts: AtomicU32::new(0),
+        });
+
+        let task = self.runtime.spawn_task(supervise(
+            shared.clone(),
+            SupervisorContext {
+                process_spawner: self.process_spawner.clone(),
+                runtime: self.runtime.clone(),
+                ownership_model: self.ownership_model,
+                installation: self.installation.clone(),
+                event_sender: self.event_sender.clone(),
+                executor_factory: Box::new(executor_factory),
+                options,
+            },
+        ));
+
+        SupervisedVm { id, shared, task }
+    }
+}
+
+/// A handle to a [Vm] supervised by a [VmSupervisor]. Since the [VmRestartPolicy] applies to any exit, supervision
+/// should be stopped via [SupervisedVm::stop] before the [Vm] is intentionally shut down.
+pub struct SupervisedVm<E: VmmExecutor, S: ProcessSpawner, R: Runtime> {
+    id: VmmId,
+    shared: Arc<SupervisedShared<E, S, R>>,
+    task: R::Task<()>,
+}
+
+struct SupervisedShared<E: VmmExecutor, S: ProcessSpawner, R: Runtime> {
+    id: VmmId,
+    vm: AsyncMutex<Vm<E, S, R>>,
+    restart_configuration: Mutex<VmConfiguration>,
+    stopped: AtomicBool,
+    restarts: AtomicU32,
+}
+
+struct SupervisorContext<E: VmmExecutor, S: ProcessSpawner, R: Runtime> {
+    process_spawner: S,
+    runtime: R,
+    ownership_model: VmmOwnershipModel,
+    installation: Arc<VmmInstallation>,
+    event_sender: Option<mpsc::UnboundedSender<VmSupervisorEvent>>,
+    executor_factory: Box<dyn Fn() -> E + Send + Sync>,
+    options: VmSupervisorOptions,
+}
+
+impl<E: VmmExecutor, S: ProcessSpawner, R: Runtime> SupervisedVm<E, S, R> {
+    /// Get the [VmmId] the [Vm] is supervised under.
+    pub fn id(&self) -> &VmmId {
+        &self.id
+    }
+
+    /// Get the amount of restarts attempted so far.
+    pub fn restarts(&self) -> u32 {
+        self.shared.restarts.load(Ordering::Acquire)
+    }
+
+    /// Lock the current [Vm] for exclusive access, which pauses supervision until the lock is released. After a
+    /// restart, the lock gives access to the new [Vm].
+    pub async fn lock(&self) -> AsyncMutexGuard<'_, Vm<E, S, R>> {
+        self.shared.vm.lock().await
+    }
+
+    /// Replace the [VmConfiguration] used for subsequent restarts.
+    pub fn set_restart_configuration(&self, restart_configuration: VmConfiguration) {
+        *self.shared.lock_restart_configuration() = restart_configuration;
+    }
+
+    /// Restore subsequent restarts from the given [VmSnapshot], such as the last one created of the [Vm], moving its
+    /// files with the given [VmmResourceMoveMethod] which must keep the original files intact.
+    pub fn set_restart_snapshot(&self, snapshot: VmSnapshot, move_method: VmmResourceMoveMethod) {
+        self.set_restart_configuration(snapshot.into_configuration(move_method, None, Some(true)));
+    }
+
+    /// Stop supervising the [Vm], waiting for an ongoing restart to finish, and return the current [Vm].
+    pub async fn stop(self) -> Vm<E, S, R> {
+        self.shared.stopped.store(true, Ordering::Release);
+        self.task.join().await;
+
+        match Arc::try_unwrap(self.shared) {
+            Ok(shared) => shared.vm.into_inner(),
+            Err(_) => unreachable!("The supervision task still held the VM after being joined"),
+        }
+    }
+}
+
+impl<E: VmmExecutor, S: ProcessSpawner, R: Runtime> SupervisedShared<E, S, R> {
+    fn lock_restart_configuration(&self) -> std::sync::MutexGuard<'_, VmConfiguration> {
+        self.restart_configuration
+            .lock()
+            .expect("Restart configuration mutex was poisoned")
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Acquire)
+    }
+}
+
+impl<E: VmmExecutor, S: ProcessSpawner, R: Runtime> SupervisorContext<E, S, R> {
+    fn report(&self, event: VmSupervisorEvent) {
+        if let Some(ref event_sender) = self.event_sender {
+            let _ = event_sender.unbounded_send(event);
+        }
+    }
+
+    /// Sleep for the given duration in slices of the poll interval, returning false if supervision was stopped.
+    async fn sleep<F: Fn() -> bool>(&self, duration: Duration, is_stopped: F) -> bool {
+        let deadline = Instant::now() + duration;
+
+        loop {
+            if is_stopped() {
+                return false;
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return true;
+            }
+
+            self.runtime.sleep(remaining.min(self.options.poll_interval)).await;
+        }
+    }
+}
+
+async fn supervise<E: VmmExecutor, S: ProcessSpawner, R: Runtime>(
+    shared: Arc<SupervisedShared<E, S, R>>,
+    context: SupervisorContext<E, S, R>,
+) {
+    let mut restart_times = VecDeque::<Instant>::new();
+    let mut backoff = context.options.initial_backoff;
+    let mut running_since = Instant::now();
+
+    loop {
+        if !context
+            .sleep(context.options.poll_interval, || shared.is_stopped())
+            .await
+        {
+            return;
+        }
+
+        let mut vm = shared.vm.lock().await;
+        let state = vm.state();
+
+        if !matches!(state, VmState::Exited | VmState::Crashed(_)) {
+            continue;
+        }
+
+        let cleanup_error = vm.cleanup().await.err();
+        drop(vm);
+        context.report(VmSupervisorEvent::Exited {
+            id: shared.id.clone(),
+            state,
+            cleanup_error,
+        });
+
+        let should_restart = match context.options.policy {
+            VmRestartPolicy::Never => false,
+            VmRestartPolicy::OnFailure => matches!(state, VmState::Crashed(_)),
+            VmRestartPolicy::Always => true,
+        };
+
+        if running_since.elapsed() > context.options.restart_window {
+            backoff = context.options.initial_backoff;
+        }
+
+        loop {
+            let now = Instant::now();
+            while restart_times
+                .front()
+                .is_some_and(|time| now.duration_since(*time) > context.options.restart_window)
+            {
+                restart_times.pop_front();
+            }
+
+            if !should_restart || restart_times.len() >= context.options.max_restarts as usize {
+                context.report(VmSupervisorEvent::GaveUp {
+                    id: shared.id.clone(),
+                    restarts: shared.restarts.load(Ordering::Acquire),
+                });
+                return;
+            }
+
+            if !context.sleep(backoff, || shared.is_stopped()).await {
+                return;
+            }
+
+            restart_times.push_back(Instant::now());
+            let restarts = shared.restarts.fetch_add(1, Ordering::AcqRel) + 1;
+            let restart_backoff = backoff;
+            backoff = (backoff * 2).min(context.options.max_backoff);
+
+            let configuration = shared.lock_restart_configuration().clone();
+            match restart(&context, configuration).await {
+                Ok(new_vm) => {
+                    *shared.vm.lock().await = new_vm;
+                    running_since = Instant::now();
+                    context.report(VmSupervisorEvent::Restarted {
+                        id: shared.id.clone(),
+                        restarts,
+                        backoff: restart_backoff,
+                    });
+                    break;
+                }
+                Err(error) => context.report(VmSupervisorEvent::RestartFailed {
+                    id: shared.id.clone(),
+                    error,
+                }),
+            }
+        }
+    }
+}
+
+async fn restart<E: VmmExecutor, S: ProcessSpawner, R: Runtime>(
+    context: &SupervisorContext<E, S, R>,
+    configuration: VmConfiguration,
+) -> Result<Vm<E, S, R>, VmError> {
+    let mut vm = Vm::prepare(
+        (context.executor_factory)(),
+        context.process_spawner.clone(),
+        context.runtime.clone(),
+        context.ownership_model,
+        context.installation.clone(),
+        configuration,
+    )
+    .await?;
+
+    if let Err(err) = vm.start(context.options.socket_wait_timeout).await {
+        let _ = tear_down(&mut vm).await;
+        return Err(err);
+    }
+
+    Ok(vm)
+}
@@ -0,0 +1,684 @@
+use std::{
+    future::{poll_fn, Future},
+    io::Read,
+    ops::Range,
+    os::{
+        fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd},
+        unix::net::UnixStream,
+    },
+    path::PathBuf,
+    pin::pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::Poll,
+};
+
+use futures_util::future::{select, Either};
+use serde::Deserialize;
+
+use crate::{
+    runtime::{Runtime, RuntimeAsyncFd, RuntimeListener, RuntimeTask},
+    syscall::ReadOnlyMapping,
+    vm::models::{MemoryBackend, MemoryBackendType},
+    vmm::{
+        ownership::{downgrade_owner, VmmOwnershipModel},
+        resource::{MovedVmmResource, ProducedVmmResource, VmmResourceError, VmmResourceMoveMethod},
+    },
+};
+
+const HANDSHAKE_BUFFER_SIZE: usize = 64 * 1024;
+const PREFETCH_CHUNK_SIZE: u64 = 2 * 1024 * 1024;
+// UFFDIO_ZEROPAGE is only supported for regular pages, not for huge pages
+const SMALL_PAGE_SIZE: u64 = 4096;
+
+// the layout of struct uffd_msg from linux/userfaultfd.h
+const UFFD_MSG_SIZE: usize = 32;
+const UFFD_MSG_BUFFER_LENGTH: usize = 64;
+const UFFD_EVENT_PAGEFAULT: u8 = 0x12;
+const UFFD_EVENT_REMOVE: u8 = 0x15;
+const UFFD_EVENT_UNMAP: u8 = 0x16;
+
+// the errno values returned by UFFDIO_COPY and UFFDIO_ZEROPAGE once the guest memory is gone
+const ENOENT: i32 = 2;
+const ESRCH: i32 = 3;
+
+/// An error that can be emitted by the userfaultfd handler extension.
+#[derive(Debug)]
+pub enum UffdHandlerError {
+    CannotBind(std::io::Error),
+    CannotRemoveSocket(std::io::Error),
+    SocketResourceError(VmmResourceError),
+    MemFileError(std::io::Error),
+    EmptyMemFile,
+    HandshakeError(std::io::Error),
+    MissingUffd,
+    InvalidMappings(serde_json::Error),
+    MappingOutOfBounds { offset: u64, size: u64 },
+    FaultOutOfBounds(u64),
+    UffdError(std::io::Error),
+    TaskJoinFailed,
+}
+
+impl std::error::Error for UffdHandlerError {}
+
+impl std::fmt::Display for UffdHandlerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UffdHandlerError::CannotBind(err) => write!(f, "Could not bind the handshake socket: {err}"),
+            UffdHandlerError::CannotRemoveSocket(err) => write!(f, "Could not remove the handshake socket: {err}"),
+            UffdHandlerError::SocketResourceError(err) => {
+                write!(f, "Could not initialize the handshake socket resource: {err}")
+            }
+            UffdHandlerError::MemFileError(err) => write!(f, "Could not map the snapshot memory file: {err}"),
+            UffdHandlerError::EmptyMemFile => write!(f, "The snapshot memory file is empty and can't be mapped"),
+            UffdHandlerError::HandshakeError(err) => write!(f, "The handshake with the VMM failed: {err}"),
+            UffdHandlerError::MissingUffd => write!(f, "The VMM didn't send a userfaultfd during the handshake"),
+            UffdHandlerError::InvalidMappings(err) => {
+                write!(f, "The guest memory mappings sent by the VMM are invalid: {err}")
+            }
+            UffdHandlerError::MappingOutOfBounds { offset, size } => write!(
+                f,
+                "The guest memory mapping of size {size} at offset {offset} exceeds the snapshot memory file"
+            ),
+            UffdHandlerError::FaultOutOfBounds(address) => write!(
+                f,
+                "The page fault at address {address:#x} lies outside of every guest memory mapping"
+            ),
+            UffdHandlerError::UffdError(err) => write!(f, "An operation on the userfaultfd failed: {err}"),
+            UffdHandlerError::TaskJoinFailed => write!(f, "Joining on the handler task via the runtime failed"),
+        }
+    }
+}
+
+/// The strategy a [UffdHandler] uses to populate guest memory from the snapshot memory file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum UffdFaultStrategy {
+    /// Only serve the pages the guest faults on, as it faults on them, which keeps restores fast and the resident
+    /// memory of the guest minimal.
+    #[default]
+    Lazy,
+    /// Copy all guest memory from the snapshot memory file in chunks right after the handshake, while still serving
+    /// faults in between the chunks, so that the guest rarely faults after having been resumed.
+    EagerPrefetch,
+}
+
+/// The options of a [UffdHandler].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct UffdHandlerOptions {
+    strategy: UffdFaultStrategy,
+    detect_zero_pages: bool,
+}
+
+impl UffdHandlerOptions {
+    /// Create options with the lazy [UffdFaultStrategy] and no zero-page detection.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the [UffdFaultStrategy] of the handler.
+    pub fn strategy(mut self, strategy: UffdFaultStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Whether pages that only contain zeroes in the snapshot memory file should be served as zero pages instead of
+    /// being copied, which avoids allocating memory for them until the guest writes to them. Pages of huge page
+    /// regions are always copied. By default, this is disabled.
+    pub fn detect_zero_pages(mut self, detect_zero_pages: bool) -> Self {
+        self.detect_zero_pages = detect_zero_pages;
+        self
+    }
+}
+
+/// A snapshot of the counters of a [UffdHandler].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct UffdHandlerStats {
+    /// The amount of page faults the guest has raised.
+    pub page_faults: u64,
+    /// The amount of pages copied from the snapshot memory file, both on faults and when prefetching.
+    pub copied_pages: u64,
+    /// The amount of pages served as zero pages, since they were detected to only contain zeroes or were removed by
+    /// the guest.
+    pub zero_pages: u64,
+    /// The amount of pages copied in advance by the [UffdFaultStrategy::EagerPrefetch] strategy.
+    pub prefetched_pages: u64,
+    /// The amount of memory ranges the guest has removed, typically by inflating its balloon device.
+    pub removed_ranges: u64,
+}
+
+/// A userfaultfd page fault handler serving the guest memory of a single VM restored from a snapshot with the
+/// [MemoryBackendType::Uffd] memory backend. The handler binds a Unix socket that Firecracker connects to when loading
+/// the snapshot, receives the userfaultfd and the guest memory mappings over it, and then serves page faults from the
+/// snapshot memory file until the VMM exits. Dropping the handler detaches its task, so [UffdHandler::stop] or
+/// [UffdHandler::join] should be called to remove the socket.
+pub struct UffdHandler<R: Runtime> {
+    socket: ProducedVmmResource,
+    shared: Arc<UffdHandlerShared>,
+    task: R::Task<Result<(), UffdHandlerError>>,
+    runtime: R,
+}
+
+#[derive(Default)]
+struct UffdHandlerShared {
+    page_faults: AtomicU64,
+    copied_pages: AtomicU64,
+    zero_pages: AtomicU64,
+    prefetched_pages: AtomicU64,
+    removed_ranges: AtomicU64,
+}
+
+impl<R: Runtime> UffdHandler<R> {
+    /// Map the snapshot memory file at the given path and bind the handshake socket at the given path, whose parent
+    /// directory is made accessible to the VMM according to the given [VmmOwnershipModel]. A stale socket left behind
+    /// at the path is removed before binding.
+    pub async fn bind(
+        socket_path: impl Into<PathBuf>,
+        mem_file_path: impl Into<PathBuf>,
+        ownership_model: VmmOwnershipModel,
+        runtime: R,
+        options: UffdHandlerOptions,
+    ) -> Result<Self, UffdHandlerError>
+    where
+        R::File: AsRawFd,
+        R::UnixStream: AsRawFd,
+    {
+        let mem_file_path = mem_file_path.into();
+        let mem_file_size = runtime
+            .fs_metadata(&mem_file_path)
+            .await
+            .map_err(UffdHandlerError::MemFileError)?
+            .len();
+
+        // mmap rejects zero-length mappings with EINVAL, which wouldn't tell what is actually wrong
+        if mem_file_size == 0 {
+            return Err(UffdHandlerError::EmptyMemFile);
+        }
+
+        let mem_file = runtime
+            .fs_open_file_for_read(&mem_file_path)
+            .await
+            .map_err(UffdHandlerError::MemFileError)?;
+        // the mapping stays valid after the file is closed
+        let mem_file = crate::syscall::mmap_read_only(
+            unsafe { BorrowedFd::borrow_raw(mem_file.as_raw_fd()) },
+            mem_file_size as usize,
+        )
+        .map_err(UffdHandlerError::MemFileError)?;
+
+        let mut socket = ProducedVmmResource::new(socket_path);
+        socket
+            .initialize_with_same_path(ownership_model, runtime.clone())
+            .await
+            .map_err(UffdHandlerError::SocketResourceError)?;
+        socket.unlink();
+
+        if runtime.fs_exists(socket.effective_path()).await.unwrap_or(false) {
+            runtime
+                .fs_remove_file(socket.effective_path())
+                .await
+                .map_err(UffdHandlerError::CannotBind)?;
+        }
+
+        let listener = runtime
+            .unix_bind(socket.effective_path())
+            .map_err(UffdHandlerError::CannotBind)?;
+        downgrade_owner(socket.effective_path(), ownership_model)
+            .map_err(|err| UffdHandlerError::SocketResourceError(VmmResourceError::ChangeOwnerError(err)))?;
+
+        let shared = Arc::new(UffdHandlerShared::default());
+        let task = runtime.spawn_task(serve(runtime.clone(), listener, mem_file, options, shared.clone()));
+
+        Ok(Self {
+            socket,
+            shared,
+            task,
+            runtime,
+        })
+    }
+
+    /// Get the [ProducedVmmResource] of the handshake socket, which is unlinked since the handler removes it.
+    pub fn socket(&self) -> &ProducedVmmResource {
+        &self.socket
+    }
+
+    /// Create a [MemoryBackend] pointing the VMM to the handshake socket, to be used in the
+    /// [LoadSnapshot](crate::vm::models::LoadSnapshot) of the restored VM. A bound socket can't be copied, so the
+    /// [VmmResourceMoveMethod] should hard link it when a jailed executor needs to move it into the jail.
+    pub fn memory_backend(&self, move_method: VmmResourceMoveMethod) -> MemoryBackend {
+        MemoryBackend {
+            backend_type: MemoryBackendType::Uffd,
+            backend: MovedVmmResource::new(self.socket.effective_path(), move_method),
+        }
+    }
+
+    /// Get a snapshot of the counters of this handler.
+    pub fn stats(&self) -> UffdHandlerStats {
+        UffdHandlerStats {
+            page_faults: self.shared.page_faults.load(Ordering::Acquire),
+            copied_pages: self.shared.copied_pages.load(Ordering::Acquire),
+            zero_pages: self.shared.zero_pages.load(Ordering::Acquire),
+            prefetched_pages: self.shared.prefetched_pages.load(Ordering::Acquire),
+            removed_ranges: self.shared.removed_ranges.load(Ordering::Acquire),
+        }
+    }
+
+    /// Wait until the VMM has exited and the handler has stopped serving page faults, then remove the handshake
+    /// socket. The error that stopped the handler, if any, is returned.
+    pub async fn join(self) -> Result<(), UffdHandlerError> {
+        let result = self.task.join().await.unwrap_or(Err(UffdHandlerError::TaskJoinFailed));
+        remove_socket(&self.runtime, &self.socket).await?;
+        result
+    }
+
+    /// Stop serving page faults right away and remove the handshake socket. Any guest still using the handler will
+    /// hang on its next page fault, so this should only be called once the VMM has exited or been killed.
+    pub async fn stop(self) -> Result<(), UffdHandlerError> {
+        self.task.cancel().await;
+        remove_socket(&self.runtime, &self.socket).await
+    }
+}
+
+async fn remove_socket<R: Runtime>(runtime: &R, socket: &ProducedVmmResource) -> Result<(), UffdHandlerError> {
+    runtime
+        .fs_remove_file(socket.effective_path())
+        .await
+        .map_err(UffdHandlerError::CannotRemoveSocket)
+}
+
+#[derive(Deserialize)]
+struct ReprGuestRegionUffdMapping {
+    base_host_virt_addr: u64,
+    size: u64,
+    offset: u64,
+    // older Firecracker versions only send the page size in KiB
+    #[serde(default)]
+    page_size: Option<u64>,
+    #[serde(default)]
+    page_size_kib: Option<u64>,
+}
+
+struct GuestRegion {
+    base: u64,
+    size: u64,
+    offset: u64,
+    page_size: u64,
+}
+
+enum FillOutcome {
+    Filled,
+    // another fill has already populated the range
+    Present,
+    // pending non-cooperative events must be read before the fill can be retried
+    Retry,
+    Gone,
+}
+
+async fn serve<R: Runtime>(
+    runtime: R,
+    listener: R::UnixListener,
+    mem_file: ReadOnlyMapping,
+    options: UffdHandlerOptions,
+    shared: Arc<UffdHandlerShared>,
+) -> Result<(), UffdHandlerError>
+where
+    R::UnixStream: AsRawFd,
+{
+    let runtime_stream = listener.accept().await.map_err(UffdHandlerError::HandshakeError)?;
+    drop(listener);
+
+    // receiving the userfaultfd requires recvmsg on the raw socket, so the stream is taken out of the runtime
+    let mut stream = UnixStream::from(
+        unsafe { BorrowedFd::borrow_raw(runtime_stream.as_raw_fd()) }
+            .try_clone_to_owned()
+            .map_err(UffdHandlerError::HandshakeError)?,
+    );
+    drop(runtime_stream);
+    stream.set_nonblocking(true).map_err(UffdHandlerError::HandshakeError)?;
+    let async_stream = runtime
+        .create_async_fd(OwnedFd::from(
+            stream.try_clone().map_err(UffdHandlerError::HandshakeError)?,
+        ))
+        .map_err(UffdHandlerError::HandshakeError)?;
+
+    let mut buf = vec![0; HANDSHAKE_BUFFER_SIZE];
+    let (length, uffd) = loop {
+        match crate::syscall::recv_with_fd(stream.as_fd(), &mut buf) {
+            Ok((0, _)) => {
+                return Err(UffdHandlerError::HandshakeError(std::io::Error::from(
+                    std::io::ErrorKind::UnexpectedEof,
+                )))
+            }
+            Ok(received) => break received,
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => async_stream
+                .readable()
+                .await
+                .map_err(UffdHandlerError::HandshakeError)?,
+            Err(err) => return Err(UffdHandlerError::HandshakeError(err)),
+        }
+    };
+    let uffd = uffd.ok_or(UffdHandlerError::MissingUffd)?;
+
+    let regions = serde_json::from_slice::<Vec<ReprGuestRegionUffdMapping>>(&buf[..length])
+        .map_err(UffdHandlerError::InvalidMappings)?
+        .into_iter()
+        .map(|mapping| GuestRegion {
+            base: mapping.base_host_virt_addr,
+            size: mapping.size,
+            offset: mapping.offset,
+            page_size: mapping
+                .page_size
+                .or(mapping.page_size_kib.map(|page_size_kib| page_size_kib * 1024))
+                .unwrap_or(SMALL_PAGE_SIZE),
+        })
+        .collect::<Vec<_>>();
+
+    for region in &regions {
+        if region.offset.saturating_add(region.size) > mem_file.as_slice().len() as u64 {
+            return Err(UffdHandlerError::MappingOutOfBounds {
+                offset: region.offset,
+                size: region.size,
+            });
+        }
+    }
+
+    let async_uffd = runtime
+        .create_async_fd(uffd.try_clone().map_err(UffdHandlerError::UffdError)?)
+        .map_err(UffdHandlerError::UffdError)?;
+    let mut server = UffdServer {
+        uffd_reader: std::fs::File::from(uffd.try_clone().map_err(UffdHandlerError::UffdError)?),
+        uffd,
+        regions,
+        mem_file,
+        detect_zero_pages: options.detect_zero_pages,
+        removed_ranges: Vec::new(),
+        deferred_faults: Vec::new(),
+        zero_buffer: Vec::new(),
+        shared,
+    };
+
+    if options.strategy == UffdFaultStrategy::EagerPrefetch && !server.prefetch().await? {
+        return Ok(());
+    }
+
+    loop {
+        if !server.handle_events()? {
+            return Ok(());
+        }
+
+        match select(pin!(async_uffd.readable()), pin!(async_stream.readable())).await {
+            Either::Left((result, _)) => result.map_err(UffdHandlerError::UffdError)?,
+            Either::Right((result, _)) => {
+                result.map_err(UffdHandlerError::HandshakeError)?;
+
+                // the VMM never sends anything after the handshake, so a readable stream means it has exited
+                match stream.read(&mut buf) {
+                    Ok(0) => return Ok(()),
+                    Ok(_) => {}
+                    Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {}
+                    Err(err) => return Err(UffdHandlerError::HandshakeError(err)),
+                }
+            }
+        }
+    }
+}
+
+struct UffdServer {
+    uffd: OwnedFd,
+    uffd_reader: std::fs::File,
+    regions: Vec<GuestRegion>,
+    mem_file: ReadOnlyMapping,
+    detect_zero_pages: bool,
+    removed_ranges: Vec<Range<u64>>,
+    // faults whose resolution must be retried after the pending non-cooperative events have been read
+    deferred_faults: Vec<u64>,
+    zero_buffer: Vec<u8>,
+    shared: Arc<UffdHandlerShared>,
+}
+
+impl UffdServer {
+    // read and handle all pending events, returning false once the guest memory is gone
+    fn handle_events(&mut self) -> Result<bool, UffdHandlerError> {
+        let mut buf = [0; UFFD_MSG_SIZE * UFFD_MSG_BUFFER_LENGTH];
+
+        loop {
+            let length = match self.uffd_reader.read(&mut buf) {
+                Ok(0) => return Ok(false),
+                Ok(length) => length,
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(UffdHandlerError::UffdError(err)),
+            };
+
+            for msg in buf[..length].chunks_exact(UFFD_MSG_SIZE) {
+                let arg = |index: usize| u64::from_ne_bytes(msg[8 + index * 8..16 + index * 8].try_into().unwrap());
+
+                match msg[0] {
+                    UFFD_EVENT_PAGEFAULT => {
+                        self.shared.page_faults.fetch_add(1, Ordering::AcqRel);
+                        self.deferred_faults.push(arg(1));
+                    }
+                    UFFD_EVENT_REMOVE | UFFD_EVENT_UNMAP => {
+                        self.shared.removed_ranges.fetch_add(1, Ordering::AcqRel);
+                        self.removed_ranges.push(arg(0)..arg(1));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let faults = std::mem::take(&mut self.deferred_faults);
+        for (index, address) in faults.iter().enumerate() {
+            match self.serve_fault(*address)? {
+                FillOutcome::Filled | FillOutcome::Present => {}
+                FillOutcome::Retry => {
+                    self.deferred_faults.extend_from_slice(&faults[index..]);
+                    break;
+                }
+                FillOutcome::Gone => return Ok(false),
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn serve_fault(&mut self, address: u64) -> Result<FillOutcome, UffdHandlerError> {
+        let Some(region) = self
+            .regions
+            .iter()
+            .find(|region| (region.base..region.base + region.size).contains(&address))
+        else {
+            return Err(UffdHandlerError::FaultOutOfBounds(address));
+        };
+
+        let page = address & !(region.page_size - 1);
+        let page_size = region.page_size;
+        let offset = (region.offset + page - region.base) as usize;
+
+        if self.removed_ranges.iter().any(|range| range.contains(&page)) {
+            return self.fill_zero(page, page_size);
+        }
+
+        let src = &self.mem_file.as_slice()[offset..offset + page_size as usize];
+        if self.detect_zero_pages && page_size == SMALL_PAGE_SIZE && src.iter().all(|byte| *byte == 0) {
+            return self.fill_zero(page, page_size);
+        }
+
+        self.fill(page, offset, page_size, false)
+    }
+
+    // copy all regions in chunks, yielding to the runtime after every chunk and returning false once the guest memory
+    // is gone
+    async fn prefetch(&mut self) -> Result<bool, UffdHandlerError> {
+        let chunks = self
+            .regions
+            .iter()
+            .flat_map(|region| {
+                let chunk_size = PREFETCH_CHUNK_SIZE.max(region.page_size);
+                (0..region.size.div_ceil(chunk_size)).map(move |index| {
+                    let start = index * chunk_size;
+                    (
+                        region.base + start,
+                        region.offset + start,
+                        chunk_size.min(region.size - start),
+                        region.page_size,
+                    )
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for (address, offset, size, page_size) in chunks {
+            if !self.handle_events()? {
+                return Ok(false);
+            }
+
+            if self
+                .removed_ranges
+                .iter()
+                .any(|range| range.start < address + size && address < range.end)
+            {
+                continue;
+            }
+
+            // zero pages are left out, so that faults on them are served as zero pages later
+            let mut runs = Vec::new();
+            if self.detect_zero_pages && page_size == SMALL_PAGE_SIZE {
+                let mut run_start = None;
+
+                for page_offset in (0..size).step_by(page_size as usize) {
+                    let start = (offset + page_offset) as usize;
+                    let is_zero = self.mem_file.as_slice()[start..start + page_size as usize]
+                        .iter()
+                        .all(|byte| *byte == 0);
+
+                    match (is_zero, run_start) {
+                        (false, None) => run_start = Some(page_offset),
+                        (true, Some(run_offset)) => {
+                            runs.push((run_offset, page_offset - run_offset));
+                            run_start = None;
+                        }
+                        _ => {}
+                    }
+                }
+
+                if let Some(run_offset) = run_start {
+                    runs.push((run_offset, size - run_offset));
+                }
+            } else {
+                runs.push((0, size));
+            }
+
+            for (run_offset, run_size) in runs {
+                if let FillOutcome::Gone =
+                    self.fill(address + run_offset, (offset + run_offset) as usize, run_size, true)?
+                {
+                    return Ok(false);
+                }
+            }
+
+            yield_now().await;
+        }
+
+        Ok(true)
+    }
+
+    fn fill(
+        &mut self,
+        address: u64,
+        offset: usize,
+        size: u64,
+        prefetch: bool,
+    ) -> Result<FillOutcome, UffdHandlerError> {
+        let src = &self.mem_file.as_slice()[offset..offset + size as usize];
+        let page_size = self.page_size_at(address);
+
+        match self.check(
+            crate::syscall::uffdio_copy(self.uffd.as_fd(), address, src),
+            address,
+            size,
+        )? {
+            FillOutcome::Filled => {
+                self.shared.copied_pages.fetch_add(size / page_size, Ordering::AcqRel);
+
+                if prefetch {
+                    self.shared
+                        .prefetched_pages
+                        .fetch_add(size / page_size, Ordering::AcqRel);
+                }
+
+                Ok(FillOutcome::Filled)
+            }
+            // some pages of a prefetched run were already faulted in, so the remaining ones are copied one by one
+            FillOutcome::Present if prefetch && size > page_size => {
+                for page_offset in (0..size).step_by(page_size as usize) {
+                    if let FillOutcome::Gone =
+                        self.fill(address + page_offset, offset + page_offset as usize, page_size, true)?
+                    {
+                        return Ok(FillOutcome::Gone);
+                    }
+                }
+
+                Ok(FillOutcome::Filled)
+            }
+            outcome => Ok(outcome),
+        }
+    }
+
+    fn fill_zero(&mut self, address: u64, page_size: u64) -> Result<FillOutcome, UffdHandlerError> {
+        let result = if page_size == SMALL_PAGE_SIZE {
+            crate::syscall::uffdio_zeropage(self.uffd.as_fd(), address, page_size)
+        } else {
+            self.zero_buffer.resize(page_size as usize, 0);
+            crate::syscall::uffdio_copy(self.uffd.as_fd(), address, &self.zero_buffer)
+        };
+
+        let outcome = self.check(result, address, page_size)?;
+        if let FillOutcome::Filled = outcome {
+            self.shared.zero_pages.fetch_add(1, Ordering::AcqRel);
+        }
+
+        Ok(outcome)
+    }
+
+    fn check(
+        &self,
+        result: Result<(), std::io::Error>,
+        address: u64,
+        size: u64,
+    ) -> Result<FillOutcome, UffdHandlerError> {
+        match result {
+            Ok(()) => Ok(FillOutcome::Filled),
+            // only the faulting threads need to be woken up, since they may still be waiting for the range
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+                crate::syscall::uffdio_wake(self.uffd.as_fd(), address, size).map_err(UffdHandlerError::UffdError)?;
+                Ok(FillOutcome::Present)
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => Ok(FillOutcome::Retry),
+            Err(err) if matches!(err.raw_os_error(), Some(ENOENT) | Some(ESRCH)) => Ok(FillOutcome::Gone),
+            Err(err) => Err(UffdHandlerError::UffdError(err)),
+        }
+    }
+
+    fn page_size_at(&self, address: u64) -> u64 {
+        self.regions
+            .iter()
+            .find(|region| (region.base..region.base + region.size).contains(&address))
+            .map(|region| region.page_size)
+            .unwrap_or(SMALL_PAGE_SIZE)
+    }
+}
+
+// yield to the runtime once, so that copying all guest memory doesn't starve the other tasks on the same thread
+fn yield_now() -> impl Future<Output = ()> {
+    let mut yielded = false;
+
+    poll_fn(move |cx| {
+        if yielded {
+            return Poll::Ready(());
+        }
+
+        yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    })
+}
@@ -0,0 +1,112 @@
+use std::time::Duration;
+
+use crate::{
+    process_spawner::ProcessSpawner,
+    runtime::Runtime,
+    vm::{
+        shutdown::{VmShutdownAction, VmShutdownMethod},
+        Vm, VmError, VmState,
+    },
+    vmm::executor::VmmExecutor,
+};
+
+#[cfg(feature = "snapshot-extension-util")]
+use {
+    crate::{
+        vm::{
+            api::{VmApi, VmApiError},
+            models::{CreateSnapshot, SnapshotType},
+            snapshot::VmSnapshot,
+        },
+        vmm::{
+            ownership::{downgrade_owner, ChangeOwnerError, VmmOwnershipModel},
+            resource::ProducedVmmResource,
+        },
+    },
+    std::path::Path,
+};
+
+/// Kill the [Vm] if its VMM is still running or paused and clean it up, returning the error of the cleanup. This is
+/// used for VMs that failed midway or are no longer needed, where a graceful shutdown isn't worth waiting for.
+pub(crate) async fn tear_down<E: VmmExecutor, S: ProcessSpawner, R: Runtime>(
+    vm: &mut Vm<E, S, R>,
+) -> Result<(), VmError> {
+    if matches!(vm.state(), VmState::Running | VmState::Paused) {
+        let _ = vm
+            .shutdown(VmShutdownAction {
+                method: VmShutdownMethod::Kill,
+                timeout: Some(Duration::from_secs(1)),
+                graceful: false,
+            })
+            .await;
+    }
+
+    vm.cleanup().await
+}
+
+#[cfg(feature = "snapshot-extension-util")]
+#[derive(Debug)]
+pub(crate) enum SnapshotStagingError {
+    Storage(std::io::Error),
+    ChangeOwner(ChangeOwnerError),
+    Pause(VmApiError),
+    Snapshot(VmApiError),
+}
+
+/// Pause the [Vm] and create a full snapshot of it with the given file names inside the given directory as seen by
+/// the VMM, which is resolved to its effective path, i.e. inside the jail for jailed VMs, before being created. If a
+/// host directory is given, the snapshot is moved into it unless it was already written there, since a jail is removed
+/// when its VM is cleaned up. The VM is left paused if staging the snapshot succeeds and is resumed if it fails.
+#[cfg(feature = "snapshot-extension-util")]
+pub(crate) async fn stage_snapshot<E: VmmExecutor, S: ProcessSpawner, R: Runtime>(
+    vm: &mut Vm<E, S, R>,
+    runtime: &R,
+    ownership_model: VmmOwnershipModel,
+    directory: &Path,
+    snapshot_file_name: &str,
+    mem_file_name: &str,
+    host_directory: Option<&Path>,
+) -> Result<VmSnapshot, SnapshotStagingError> {
+    let effective_directory = vm.local_to_effective_path(directory);
+    runtime
+        .fs_create_dir_all(&effective_directory)
+        .await
+        .map_err(SnapshotStagingError::Storage)?;
+    downgrade_owner(&effective_directory, ownership_model).map_err(SnapshotStagingError::ChangeOwner)?;
+
+    vm.api_pause().await.map_err(SnapshotStagingError::Pause)?;
+    let result = async {
+        let mut snapshot = vm
+            .api_create_snapshot(CreateSnapshot {
+                snapshot_type: Some(SnapshotType::Full),
+                snapshot: ProducedVmmResource::new(directory.join(snapshot_file_name)),
+                mem_file: ProducedVmmResource::new(directory.join(mem_file_name)),
+            })
+            .await
+            .map_err(SnapshotStagingError::Snapshot)?;
+
+        if let Some(host_directory) = host_directory.filter(|host_directory| *host_directory != effective_directory) {
+            runtime
+                .fs_create_dir_all(host_directory)
+                .await
+                .map_err(SnapshotStagingError::Storage)?;
+            snapshot
+                .rename(
+                    host_directory.join(snapshot_file_name),
+                    host_directory.join(mem_file_name),
+                    runtime,
+                )
+                .await
+                .map_err(SnapshotStagingError::Storage)?;
+        }
+
+        Ok(snapshot)
+    }
+    .await;
+
+    if result.is_err() {
+        let _ = vm.api_resume().await;
+    }
+
+    result
+}
@@ -0,0 +1,380 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use futures_channel::oneshot;
+use futures_util::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    process_spawner::ProcessSpawner,
+    runtime::{Runtime, RuntimeListener, RuntimeTask},
+    vm::Vm,
+    vmm::executor::VmmExecutor,
+};
+
+use super::vsock_stream::connect;
+
+/// An error that can be emitted by the vsock port forwarding extension.
+#[derive(Debug)]
+pub enum VsockForwardError {
+    VsockNotConfigured,
+    CannotBind(std::io::Error),
+    CannotRemoveSocket(std::io::Error),
+}
+
+impl std::error::Error for VsockForwardError {}
+
+impl std::fmt::Display for VsockForwardError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VsockForwardError::VsockNotConfigured => write!(f, "A vsock device was not configured for this VM"),
+            VsockForwardError::CannotBind(err) => write!(f, "Could not bind the host listener: {err}"),
+            VsockForwardError::CannotRemoveSocket(err) => {
+                write!(f, "Could not remove the Unix socket of the host listener: {err}")
+            }
+        }
+    }
+}
+
+/// The host address that a [VsockForwarder] listens on.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum VsockForwardAddress {
+    /// A TCP address. Binding to port 0 lets the OS pick a free port, which is then reported by
+    /// [VsockForwarder::local_address].
+    Tcp(SocketAddr),
+    /// A Unix socket path. A stale socket left behind at this path is removed before binding.
+    Unix(PathBuf),
+}
+
+/// The options of a [VsockForwarder].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VsockForwardOptions {
+    address: VsockForwardAddress,
+    guest_port: u32,
+    max_connections: Option<usize>,
+}
+
+impl VsockForwardOptions {
+    /// Create options that forward connections accepted on the given host address to the given guest vsock port.
+    pub fn new(address: VsockForwardAddress, guest_port: u32) -> Self {
+        Self {
+            address,
+            guest_port,
+            max_connections: None,
+        }
+    }
+
+    /// Limit the amount of concurrently forwarded connections: connections accepted beyond this limit are closed
+    /// right away. By default, the amount isn't limited.
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = Some(max_connections);
+        self
+    }
+}
+
+/// The byte counters of a single connection forwarded by a [VsockForwarder].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct VsockForwardConnectionStats {
+    /// The sequential ID of the connection, unique within its [VsockForwarder].
+    pub id: u64,
+    /// The amount of bytes forwarded from the host client to the guest.
+    pub bytes_to_guest: u64,
+    /// The amount of bytes forwarded from the guest to the host client.
+    pub bytes_from_guest: u64,
+}
+
+/// A snapshot of the counters of a [VsockForwarder].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct VsockForwardStats {
+    /// The amount of connections that were accepted within the connection limit.
+    pub forwarded_connections: u64,
+    /// The amount of connections that were closed right away because of the connection limit.
+    pub rejected_connections: u64,
+    /// The amount of connections that were closed because the connection to the guest port couldn't be established.
+    pub failed_connections: u64,
+    /// The total amount of bytes forwarded from host clients to the guest, including by closed connections.
+    pub bytes_to_guest: u64,
+    /// The total amount of bytes forwarded from the guest to host clients, including by closed connections.
+    pub bytes_from_guest: u64,
+    /// The counters of the connections that are currently being forwarded.
+    pub active_connections: Vec<VsockForwardConnectionStats>,
+}
+
+/// A port forwarding proxy that accepts connections on a host TCP address or Unix socket and splices each of them to a
+/// guest vsock port, established via the vsock device of the VM. Dropping the forwarder detaches its tasks, so
+/// [VsockForwarder::stop] should be called to stop forwarding.
+pub struct VsockForwarder<R: Runtime> {
+    local_address: VsockForwardAddress,
+    shared: Arc<ForwarderShared<R>>,
+    accept_task: R::Task<()>,
+    runtime: R,
+}
+
+struct ForwarderShared<R: Runtime> {
+    runtime: R,
+    socket_path: PathBuf,
+    guest_port: u32,
+    max_connections: Option<usize>,
+    next_connection_id: AtomicU64,
+    rejected_connections: AtomicU64,
+    failed_connections: AtomicU64,
+    bytes_to_guest: AtomicU64,
+    bytes_from_guest: AtomicU64,
+    connections: Mutex<HashMap<u64, ForwardedConnection<R>>>,
+}
+
+struct ForwardedConnection<R: Runtime> {
+    counters: Arc<ConnectionCounters>,
+    task: R::Task<()>,
+    done_receiver: oneshot::Receiver<()>,
+}
+
+#[derive(Default)]
+struct ConnectionCounters {
+    bytes_to_guest: AtomicU64,
+    bytes_from_guest: AtomicU64,
+    finished: AtomicBool,
+}
+
+impl<R: Runtime> VsockForwarder<R> {
+    /// Get the host address this forwarder is bound to, with the actual port if TCP port 0 was requested.
+    pub fn local_address(&self) -> &VsockForwardAddress {
+        &self.local_address
+    }
+
+    /// Get the guest vsock port this forwarder forwards connections to.
+    pub fn guest_port(&self) -> u32 {
+        self.shared.guest_port
+    }
+
+    /// Get a snapshot of the counters of this forwarder.
+    pub fn stats(&self) -> VsockForwardStats {
+        let mut connections = self.shared.lock_connections();
+        connections.retain(|_, connection| !connection.counters.finished.load(Ordering::Acquire));
+
+        let mut active_connections = connections
+            .iter()
+            .map(|(id, connection)| VsockForwardConnectionStats {
+                id: *id,
+                bytes_to_guest: connection.counters.bytes_to_guest.load(Ordering::Acquire),
+                bytes_from_guest: connection.counters.bytes_from_guest.load(Ordering::Acquire),
+            })
+            .collect::<Vec<_>>();
+        active_connections.sort_by_key(|connection| connection.id);
+
+        VsockForwardStats {
+            forwarded_connections: self.shared.next_connection_id.load(Ordering::Acquire),
+            rejected_connections: self.shared.rejected_connections.load(Ordering::Acquire),
+            failed_connections: self.shared.failed_connections.load(Ordering::Acquire),
+            bytes_to_guest: self.shared.bytes_to_guest.load(Ordering::Acquire),
+            bytes_from_guest: self.shared.bytes_from_guest.load(Ordering::Acquire),
+            active_connections,
+        }
+    }
+
+    /// Gracefully stop the forwarder: new connections stop being accepted right away, while the connections that are
+    /// being forwarded are given the given timeout to complete, after which they are closed. The Unix socket of the
+    /// forwarder, if any, is removed.
+    pub async fn stop(self, timeout: Duration) -> Result<(), VsockForwardError> {
+        self.accept_task.cancel().await;
+
+        let connections = std::mem::take(&mut *self.shared.lock_connections());
+        let deadline = Instant::now() + timeout;
+
+        for connection in connections.into_values() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+
+            if self.runtime.timeout(remaining, connection.done_receiver).await.is_err() {
+                connection.task.cancel().await;
+            }
+        }
+
+        if let VsockForwardAddress::Unix(ref socket_path) = self.local_address {
+            self.runtime
+                .fs_remove_file(socket_path)
+                .await
+                .map_err(VsockForwardError::CannotRemoveSocket)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<R: Runtime> ForwarderShared<R> {
+    fn lock_connections(&self) -> std::sync::MutexGuard<'_, HashMap<u64, ForwardedConnection<R>>> {
+        self.connections.lock().expect("Connections mutex was poisoned")
+    }
+}
+
+/// An extension that forwards connections accepted on a host TCP address or Unix socket to a guest vsock port, which
+/// allows reaching guest services with host tools such as browsers or curl without a guest network.
+pub trait VsockForwardExt<R: Runtime> {
+    /// Start a [VsockForwarder] with the given [VsockForwardOptions].
+    fn vsock_forward(
+        &self,
+        options: VsockForwardOptions,
+    ) -> impl Future<Output = Result<VsockForwarder<R>, VsockForwardError>> + Send;
+}
+
+impl<E: VmmExecutor, S: ProcessSpawner, R: Runtime> VsockForwardExt<R> for Vm<E, S, R> {
+    async fn vsock_forward(&self, options: VsockForwardOptions) -> Result<VsockForwarder<R>, VsockForwardError> {
+        let socket_path = self
+            .configuration()
+            .data()
+            .vsock_device
+            .as_ref()
+            .ok_or(VsockForwardError::VsockNotConfigured)?
+            .uds
+            .effective_path()
+            .to_owned();
+
+        let shared = Arc::new(ForwarderShared {
+            runtime: self.runtime.clone(),
+            socket_path,
+            guest_port: options.guest_port,
+            max_connections: options.max_connections,
+            next_connection_id: AtomicU64::new(0),
+            rejected_connections: AtomicU64::new(0),
+            failed_connections: AtomicU64::new(0),
+            bytes_to_guest: AtomicU64::new(0),
+            bytes_from_guest: AtomicU64::new(0),
+            connections: Mutex::new(HashMap::new()),
+        });
+
+        let (local_address, accept_task) = match options.address {
+            VsockForwardAddress::Tcp(address) => {
+                let (listener, local_address) =
+                    self.runtime.tcp_bind(address).map_err(VsockForwardError::CannotBind)?;
+                (
+                    VsockForwardAddress::Tcp(local_address),
+                    self.runtime.spawn_task(accept_connections(listener, shared.clone())),
+                )
+            }
+            VsockForwardAddress::Unix(socket_path) => {
+                if self.runtime.fs_exists(&socket_path).await.unwrap_or(false) {
+                    self.runtime
+                        .fs_remove_file(&socket_path)
+                        .await
+                        .map_err(VsockForwardError::CannotBind)?;
+                }
+
+                let listener = self
+                    .runtime
+                    .unix_bind(&socket_path)
+                    .map_err(VsockForwardError::CannotBind)?;
+                (
+                    VsockForwardAddress::Unix(socket_path),
+                    self.runtime.spawn_task(accept_connections(listener, shared.clone())),
+                )
+            }
+        };
+
+        Ok(VsockForwarder {
+            local_address,
+            shared,
+            accept_task,
+            runtime: self.runtime.clone(),
+        })
+    }
+}
+
+async fn accept_connections<L: RuntimeListener, R: Runtime>(listener: L, shared: Arc<ForwarderShared<R>>) {
+    while let Ok(stream) = listener.accept().await {
+        let mut connections = shared.lock_connections();
+        connections.retain(|_, connection| !connection.counters.finished.load(Ordering::Acquire));
+
+        if shared
+            .max_connections
+            .is_some_and(|max_connections| connections.len() >= max_connections)
+        {
+            shared.rejected_connections.fetch_add(1, Ordering::AcqRel);
+            continue;
+        }
+
+        let id = shared.next_connection_id.fetch_add(1, Ordering::AcqRel);
+        let counters = Arc::new(ConnectionCounters::default());
+        let (done_sender, done_receiver) = oneshot::channel();
+        let task = shared.runtime.spawn_task(forward_connection(
+            stream,
+            shared.clone(),
+            counters.clone(),
+            done_sender,
+        ));
+        connections.insert(
+            id,
+            ForwardedConnection {
+                counters,
+                task,
+                done_receiver,
+            },
+        );
+    }
+}
+
+async fn forward_connection<S: AsyncRead + AsyncWrite + Unpin, R: Runtime>(
+    stream: S,
+    shared: Arc<ForwarderShared<R>>,
+    counters: Arc<ConnectionCounters>,
+    // dropped when the forwarding completes, which notifies a graceful stop
+    _done_sender: oneshot::Sender<()>,
+) {
+    match connect(&shared.runtime, &shared.socket_path, shared.guest_port).await {
+        Ok(vsock_stream) => {
+            let (client_reader, client_writer) = stream.split();
+            let (guest_reader, guest_writer) = vsock_stream.split();
+
+            // an error in either direction closes the whole connection, while EOF only half-closes it
+            let _ = futures_util::future::try_join(
+                splice(
+                    client_reader,
+                    guest_writer,
+                    &counters.bytes_to_guest,
+                    &shared.bytes_to_guest,
+                ),
+                splice(
+                    guest_reader,
+                    client_writer,
+                    &counters.bytes_from_guest,
+                    &shared.bytes_from_guest,
+                ),
+            )
+            .await;
+        }
+        Err(_) => {
+            shared.failed_connections.fetch_add(1, Ordering::AcqRel);
+        }
+    }
+
+    counters.finished.store(true, Ordering::Release);
+}
+
+/// Copy data from the reader to the writer until the reader reaches EOF, closing the writer afterwards so that the
+/// other side observes the EOF too.
+pub(crate) async fn splice(
+    mut reader: impl AsyncRead + Unpin,
+    mut writer: impl AsyncWrite + Unpin,
+    connection_counter: &AtomicU64,
+    total_counter: &AtomicU64,
+) -> Result<(), std::io::Error> {
+    let mut buf = [0; 8192];
+
+    loop {
+        let read = reader.read(&mut buf).await?;
+
+        if read == 0 {
+            return writer.close().await;
+        }
+
+        connection_counter.fetch_add(read as u64, Ordering::AcqRel);
+        total_counter.fetch_add(read as u64, Ordering::AcqRel);
+        writer.write_all(&buf[..read]).await?;
+    }
+}
@@ -0,0 +1,151 @@
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+};
+
+use futures_util::Stream;
+
+use crate::{
+    process_spawner::ProcessSpawner,
+    runtime::{Runtime, RuntimeListener},
+    vm::Vm,
+    vmm::{
+        executor::VmmExecutor,
+        ownership::{downgrade_owner, ChangeOwnerError},
+    },
+};
+
+/// An error that can be emitted by the vsock listener extension.
+#[derive(Debug)]
+pub enum VsockListenerError {
+    VsockNotConfigured,
+    VsockResourceUninitialized,
+    CannotBind(std::io::Error),
+    ChangeOwnerError(ChangeOwnerError),
+    CannotAccept(std::io::Error),
+    CannotRemoveSocket(std::io::Error),
+}
+
+impl std::error::Error for VsockListenerError {}
+
+impl std::fmt::Display for VsockListenerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VsockListenerError::VsockNotConfigured => write!(f, "A vsock device was not configured for this VM"),
+            VsockListenerError::VsockResourceUninitialized => {
+                write!(f, "The Unix socket resource of the vsock device was not initialized")
+            }
+            VsockListenerError::CannotBind(err) => write!(f, "Could not bind the vsock listener socket: {err}"),
+            VsockListenerError::ChangeOwnerError(err) => {
+                write!(f, "Could not change the owner of the vsock listener socket: {err}")
+            }
+            VsockListenerError::CannotAccept(err) => write!(f, "Could not accept a vsock connection: {err}"),
+            VsockListenerError::CannotRemoveSocket(err) => {
+                write!(f, "Could not remove the vsock listener socket: {err}")
+            }
+        }
+    }
+}
+
+/// A listener for connections initiated by the guest to a host vsock port. Firecracker forwards such connections
+/// to the "<uds_path>_<port>" Unix socket, where "uds_path" is the path of the Unix socket of the vsock device, so
+/// the listener binds to that socket on the host. The socket isn't removed when the listener is dropped:
+/// [VsockListener::cleanup] should be called to remove it, and otherwise it is removed by [Vm::cleanup].
+pub struct VsockListener<R: Runtime> {
+    listener: R::UnixListener,
+    socket_path: PathBuf,
+    port: u32,
+    runtime: R,
+}
+
+impl<R: Runtime> VsockListener<R> {
+    /// Get the host path of the Unix socket this listener is bound to.
+    pub fn socket_path(&self) -> &Path {
+        &self.socket_path
+    }
+
+    /// Get the host vsock port this listener accepts connections on.
+    pub fn port(&self) -> u32 {
+        self.port
+    }
+
+    /// Accept the next connection initiated by the guest.
+    pub async fn accept(&self) -> Result<R::UnixStream, VsockListenerError> {
+        self.listener.accept().await.map_err(VsockListenerError::CannotAccept)
+    }
+
+    /// Get a [Stream] of the connections initiated by the guest from now on.
+    pub fn incoming(&self) -> impl Stream<Item = Result<R::UnixStream, VsockListenerError>> + Send + '_ {
+        futures_util::stream::unfold(
+            self,
+            |listener| async move { Some((listener.accept().await, listener)) },
+        )
+    }
+
+    /// Stop listening and remove the Unix socket of this listener. Established connections remain open.
+    pub async fn cleanup(self) -> Result<(), VsockListenerError> {
+        drop(self.listener);
+        self.runtime
+            .fs_remove_file(&self.socket_path)
+            .await
+            .map_err(VsockListenerError::CannotRemoveSocket)
+    }
+}
+
+/// An extension that allows the host to accept connections initiated by guest applications over the Firecracker vsock
+/// device, with the guest connecting to the host's CID (2) and the given port.
+pub trait VsockListenerExt<R: Runtime> {
+    /// Bind a [VsockListener] for the given host port. The socket is created next to the effective path of the vsock
+    /// device's Unix socket, which is inside the jail for jailed VMs, and its owner is changed to that of the VMM
+    /// process according to the [VmmOwnershipModel](crate::vmm::ownership::VmmOwnershipModel) of the VM, so that the
+    /// VMM can connect to it. A stale socket left behind at the same path is removed first.
+    fn vsock_listen(&self, port: u32) -> impl Future<Output = Result<VsockListener<R>, VsockListenerError>> + Send;
+}
+
+impl<E: VmmExecutor, S: ProcessSpawner, R: Runtime> VsockListenerExt<R> for Vm<E, S, R> {
+    async fn vsock_listen(&self, port: u32) -> Result<VsockListener<R>, VsockListenerError> {
+        let mut socket_path = self
+            .configuration()
+            .data()
+            .vsock_device
+            .as_ref()
+            .ok_or(VsockListenerError::VsockNotConfigured)?
+            .uds
+            .effective_path_checked()
+            .ok_or(VsockListenerError::VsockResourceUninitialized)?
+            .as_os_str()
+            .to_owned();
+        socket_path.push(format!("_{port}"));
+        let socket_path = PathBuf::from(socket_path);
+
+        if self.runtime.fs_exists(&socket_path).await.unwrap_or(false) {
+            self.runtime
+                .fs_remove_file(&socket_path)
+                .await
+                .map_err(VsockListenerError::CannotBind)?;
+        }
+
+        let listener = self
+            .runtime
+            .unix_bind(&socket_path)
+            .map_err(VsockListenerError::CannotBind)?;
+
+        if let Err(err) = downgrade_owner(&socket_path, self.ownership_model) {
+            drop(listener);
+            let _ = self.runtime.fs_remove_file(&socket_path).await;
+            return Err(VsockListenerError::ChangeOwnerError(err));
+        }
+
+        self.vsock_listener_sockets
+            .lock()
+            .expect("Vsock listener sockets mutex was poisoned")
+            .push(socket_path.clone());
+
+        Ok(VsockListener {
+            listener,
+            socket_path,
+            port,
+            runtime: self.runtime.clone(),
+        })
+    }
+}
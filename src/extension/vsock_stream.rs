@@ -0,0 +1,228 @@
+use std::{
+    future::Future,
+    path::Path,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use futures_io::{AsyncRead, AsyncWrite};
+use futures_util::{AsyncReadExt, AsyncWriteExt};
+
+use crate::{process_spawner::ProcessSpawner, runtime::Runtime, vm::Vm, vmm::executor::VmmExecutor};
+
+/// The maximum length of the "OK <port>\n" response to a CONNECT command, with the port being a 32-bit integer.
+const MAX_HANDSHAKE_RESPONSE_LENGTH: usize = 14;
+
+/// An error that can be emitted by the raw vsock stream extension.
+#[derive(Debug)]
+pub enum VsockStreamError {
+    VsockNotConfigured,
+    CannotConnect(std::io::Error),
+    HandshakeFailed(std::io::Error),
+    ConnectionRefused { guest_port: u32 },
+    InvalidHandshake { response: String },
+    RetryTimedOut(Box<VsockStreamError>),
+}
+
+impl std::error::Error for VsockStreamError {}
+
+impl std::fmt::Display for VsockStreamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VsockStreamError::VsockNotConfigured => write!(f, "A vsock device was not configured for this VM"),
+            VsockStreamError::CannotConnect(err) => write!(f, "Could not connect to the vsock socket: {err}"),
+            VsockStreamError::HandshakeFailed(err) => write!(f, "An I/O error occurred during the handshake: {err}"),
+            VsockStreamError::ConnectionRefused { guest_port } => {
+                write!(f, "The connection to guest port {guest_port} was refused")
+            }
+            VsockStreamError::InvalidHandshake { response } => {
+                write!(f, "The handshake response \"{response}\" is invalid")
+            }
+            VsockStreamError::RetryTimedOut(err) => {
+                write!(f, "Connecting with retries timed out, the last error being: {err}")
+            }
+        }
+    }
+}
+
+/// A raw bidirectional byte stream to a guest vsock port, established over the Unix socket of the vsock device after
+/// the Firecracker CONNECT handshake. The stream doesn't impose any protocol on the transferred data.
+pub struct VsockStream<R: Runtime> {
+    stream: R::UnixStream,
+    guest_port: u32,
+    host_port: u32,
+}
+
+impl<R: Runtime> VsockStream<R> {
+    /// Get the guest port this stream is connected to.
+    pub fn guest_port(&self) -> u32 {
+        self.guest_port
+    }
+
+    /// Get the host-side port that Firecracker assigned to this connection, as reported in the handshake.
+    pub fn host_port(&self) -> u32 {
+        self.host_port
+    }
+
+    /// Get the underlying Unix socket stream of the runtime.
+    pub fn into_inner(self) -> R::UnixStream {
+        self.stream
+    }
+}
+
+impl<R: Runtime> std::fmt::Debug for VsockStream<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VsockStream")
+            .field("guest_port", &self.guest_port)
+            .field("host_port", &self.host_port)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<R: Runtime> AsyncRead for VsockStream<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl<R: Runtime> AsyncWrite for VsockStream<R> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_close(cx)
+    }
+}
+
+/// An extension that allows making raw byte stream connections to guest applications over the Firecracker vsock
+/// device, for applications that speak a protocol other than HTTP or gRPC.
+pub trait VsockStreamExt<R: Runtime> {
+    /// Connect to the given guest port, performing the CONNECT handshake.
+    fn vsock_connect(&self, guest_port: u32) -> impl Future<Output = Result<VsockStream<R>, VsockStreamError>> + Send;
+
+    /// Connect to the given guest port, retrying at the given interval while the connection can't be made or is
+    /// refused (such as when the guest is still booting or its application hasn't started listening yet), until the
+    /// given timeout elapses.
+    fn vsock_connect_with_retry(
+        &self,
+        guest_port: u32,
+        retry_interval: Duration,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<VsockStream<R>, VsockStreamError>> + Send;
+}
+
+impl<E: VmmExecutor, S: ProcessSpawner, R: Runtime> VsockStreamExt<R> for Vm<E, S, R> {
+    async fn vsock_connect(&self, guest_port: u32) -> Result<VsockStream<R>, VsockStreamError> {
+        connect(&self.runtime, get_socket_path(self)?, guest_port).await
+    }
+
+    async fn vsock_connect_with_retry(
+        &self,
+        guest_port: u32,
+        retry_interval: Duration,
+        timeout: Duration,
+    ) -> Result<VsockStream<R>, VsockStreamError> {
+        let socket_path = get_socket_path(self)?;
+        let deadline = Instant::now() + timeout;
+        let mut last_error = None;
+
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self
+                .runtime
+                .timeout(remaining, connect(&self.runtime, socket_path, guest_port))
+                .await
+            {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(
+                    err @ (VsockStreamError::CannotConnect(_)
+                    | VsockStreamError::HandshakeFailed(_)
+                    | VsockStreamError::ConnectionRefused { .. }),
+                )) => last_error = Some(err),
+                Ok(Err(err)) => return Err(err),
+                // an attempt cut short by the deadline says nothing about why connecting fails, so the error of the
+                // previous attempt is reported instead if there was one
+                Err(_) => {}
+            };
+
+            if Instant::now() + retry_interval >= deadline {
+                let error = last_error.unwrap_or_else(|| {
+                    VsockStreamError::CannotConnect(std::io::Error::from(std::io::ErrorKind::TimedOut))
+                });
+                return Err(VsockStreamError::RetryTimedOut(Box::new(error)));
+            }
+
+            self.runtime.sleep(retry_interval).await;
+        }
+    }
+}
+
+pub(crate) fn get_socket_path<E: VmmExecutor, S: ProcessSpawner, R: Runtime>(
+    vm: &Vm<E, S, R>,
+) -> Result<&Path, VsockStreamError> {
+    Ok(vm
+        .configuration()
+        .data()
+        .vsock_device
+        .as_ref()
+        .ok_or(VsockStreamError::VsockNotConfigured)?
+        .uds
+        .effective_path())
+}
+
+pub(crate) async fn connect<R: Runtime>(
+    runtime: &R,
+    socket_path: &Path,
+    guest_port: u32,
+) -> Result<VsockStream<R>, VsockStreamError> {
+    let mut stream = runtime
+        .unix_connect(socket_path)
+        .await
+        .map_err(VsockStreamError::CannotConnect)?;
+    stream
+        .write_all(format!("CONNECT {guest_port}\n").as_bytes())
+        .await
+        .map_err(VsockStreamError::HandshakeFailed)?;
+
+    // the response is read byte by byte, so that no data sent by the guest right after the handshake is consumed
+    let mut response = Vec::new();
+    let mut byte = [0];
+
+    loop {
+        match stream.read(&mut byte).await {
+            // Firecracker closes the connection without a response when nothing listens on the guest port
+            Ok(0) if response.is_empty() => return Err(VsockStreamError::ConnectionRefused { guest_port }),
+            Ok(0) => break,
+            Ok(_) if byte[0] == b'\n' => break,
+            Ok(_) => response.push(byte[0]),
+            Err(err) if err.kind() == std::io::ErrorKind::ConnectionReset => {
+                return Err(VsockStreamError::ConnectionRefused { guest_port })
+            }
+            Err(err) => return Err(VsockStreamError::HandshakeFailed(err)),
+        }
+
+        if response.len() > MAX_HANDSHAKE_RESPONSE_LENGTH {
+            break;
+        }
+    }
+
+    let response = String::from_utf8_lossy(&response).into_owned();
+    let Some(host_port) = response
+        .strip_prefix("OK ")
+        .and_then(|host_port| host_port.parse().ok())
+    else {
+        return Err(VsockStreamError::InvalidHandshake { response });
+    };
+
+    Ok(VsockStream {
+        stream,
+        guest_port,
+        host_port,
+    })
+}
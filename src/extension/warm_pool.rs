@@ -0,0 +1,334 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use futures_channel::mpsc;
+use futures_util::{future::join_all, StreamExt};
+
+use crate::{
+    process_spawner::ProcessSpawner,
+    runtime::{Runtime, RuntimeTask},
+    vm::{
+        configuration::{InitMethod, VmConfiguration, VmConfigurationData},
+        shutdown::{VmShutdownAction, VmShutdownError, VmShutdownOutcome},
+        snapshot::VmSnapshot,
+        Vm, VmError, VmState,
+    },
+    vmm::{
+        executor::VmmExecutor, installation::VmmInstallation, ownership::VmmOwnershipModel,
+        resource::VmmResourceMoveMethod,
+    },
+};
+
+use super::util::tear_down;
+
+/// The interval at which a [WarmPool] is polled for a ready [Vm] while one is being taken.
+const TAKE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// An error that can be emitted by a [WarmPool].
+#[derive(Debug)]
+pub enum WarmPoolError {
+    TakeTimeout,
+    Stopped,
+}
+
+impl std::error::Error for WarmPoolError {}
+
+impl std::fmt::Display for WarmPoolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WarmPoolError::TakeTimeout => write!(f, "No ready VM became available in the warm pool in time"),
+            WarmPoolError::Stopped => write!(f, "The warm pool was stopped"),
+        }
+    }
+}
+
+/// The template that every [Vm] of a [WarmPool] is produced from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WarmPoolTemplate {
+    /// Boot new [Vm]s with the given [InitMethod] and [VmConfigurationData]. Since every [Vm] receives the same
+    /// paths, [InitMethod::ViaJsonConfiguration] should only be used with jailed [VmmExecutor]s.
+    New {
+        init_method: InitMethod,
+        data: VmConfigurationData,
+    },
+    /// Restore and resume [Vm]s from the given [VmSnapshot], moving its files into each [Vm] with the given
+    /// [VmmResourceMoveMethod], which must keep the original files intact.
+    Snapshot {
+        snapshot: VmSnapshot,
+        move_method: VmmResourceMoveMethod,
+    },
+}
+
+impl WarmPoolTemplate {
+    fn configuration(&self) -> VmConfiguration {
+        match self {
+            WarmPoolTemplate::New { init_method, data } => VmConfiguration::New {
+                init_method: init_method.clone(),
+                data: data.clone(),
+            },
+            WarmPoolTemplate::Snapshot { snapshot, move_method } => {
+                snapshot.clone().into_configuration(*move_method, None, Some(true))
+            }
+        }
+    }
+}
+
+/// The options of a [WarmPool].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WarmPoolOptions {
+    size: usize,
+    socket_wait_timeout: Duration,
+    health_check_interval: Duration,
+}
+
+impl WarmPoolOptions {
+    /// Create options for a [WarmPool] that keeps the given amount of [Vm]s ready, starting each of them with the
+    /// given timeout for the API socket to become available. By default, idle [Vm]s are checked every second.
+    pub fn new(size: usize, socket_wait_timeout: Duration) -> Self {
+        Self {
+            size,
+            socket_wait_timeout,
+            health_check_interval: Duration::from_secs(1),
+        }
+    }
+
+    /// Set the interval at which idle [Vm]s are checked for having exited or crashed and failed refills are retried.
+    pub fn health_check_interval(mut self, health_check_interval: Duration) -> Self {
+        self.health_check_interval = health_check_interval;
+        self
+    }
+}
+
+/// A report of the health of a [WarmPool].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct WarmPoolHealth {
+    /// The amount of [Vm]s the [WarmPool] tries to keep ready.
+    pub size: usize,
+    /// The amount of [Vm]s that are ready to be taken.
+    pub ready: usize,
+    /// The amount of [Vm]s that are currently being prepared and started.
+    pub booting: usize,
+    /// The amount of [Vm]s that were taken out of the [WarmPool].
+    pub taken: u64,
+    /// The amount of idle [Vm]s that exited or crashed and were therefore evicted and cleaned up.
+    pub evicted: u64,
+    /// The amount of [Vm]s that couldn't be prepared or started.
+    pub failed_boots: u64,
+}
+
+/// A pool that keeps a number of [Vm]s prepared and started in advance from a [WarmPoolTemplate], refilling itself
+/// in the background after every take, so that ready [Vm]s can be handed out without any boot latency. Dropping the
+/// pool detaches its refill task and leaves its ready [Vm]s running, so [WarmPool::shutdown] should be called instead.
+pub struct WarmPool<E: VmmExecutor, S: ProcessSpawner, R: Runtime> {
+    shared: Arc<PoolShared<E, S, R>>,
+    refill_sender: mpsc::UnboundedSender<()>,
+    refill_task: R::Task<()>,
+}
+
+struct PoolShared<E: VmmExecutor, S: ProcessSpawner, R: Runtime> {
+    process_spawner: S,
+    runtime: R,
+    ownership_model: VmmOwnershipModel,
+    installation: Arc<VmmInstallation>,
+    executor_factory: Box<dyn Fn() -> E + Send + Sync>,
+    template: WarmPoolTemplate,
+    options: WarmPoolOptions,
+    ready: Mutex<VecDeque<Vm<E, S, R>>>,
+    booting: AtomicUsize,
+    taken: AtomicU64,
+    evicted: AtomicU64,
+    failed_boots: AtomicU64,
+}
+
+impl<E: VmmExecutor + 'static, S: ProcessSpawner, R: Runtime> WarmPool<E, S, R> {
+    /// Start a [WarmPool] whose [Vm]s are produced from the given [WarmPoolTemplate] and components, with each [Vm]
+    /// receiving the [VmmExecutor] returned by the given closure. The closure must return executors with unique
+    /// socket paths and IDs. The pool starts being filled in the background right away.
+    pub fn start(
+        process_spawner: S,
+        runtime: R,
+        ownership_model: VmmOwnershipModel,
+        installation: Arc<VmmInstallation>,
+        executor_factory: impl Fn() -> E + Send + Sync + 'static,
+        template: WarmPoolTemplate,
+        options: WarmPoolOptions,
+    ) -> Self {
+        let shared = Arc::new(PoolShared {
+            process_spawner,
+            runtime: runtime.clone(),
+            ownership_model,
+            installation,
+            executor_factory: Box::new(executor_factory),
+            template,
+            options,
+            ready: Mutex::new(VecDeque::new()),
+            booting: AtomicUsize::new(0),
+            taken: AtomicU64::new(0),
+            evicted: AtomicU64::new(0),
+            failed_boots: AtomicU64::new(0),
+        });
+
+        let (refill_sender, refill_receiver) = mpsc::unbounded();
+        let refill_task = runtime.spawn_task(refill(shared.clone(), refill_receiver));
+
+        Self {
+            shared,
+            refill_sender,
+            refill_task,
+        }
+    }
+
+    /// Take a ready [Vm] out of the [WarmPool], waiting up to the given timeout for one to become ready. Idle [Vm]s
+    /// that have exited or crashed are evicted along the way, and a refill is requested once a [Vm] was taken.
+    pub async fn take(&self, timeout: Duration) -> Result<Vm<E, S, R>, WarmPoolError> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            if self.refill_sender.is_closed() {
+                return Err(WarmPoolError::Stopped);
+            }
+
+            let vm = self.shared.lock_ready().pop_front();
+
+            if let Some(mut vm) = vm {
+                if vm.state() == VmState::Running {
+                    self.shared.taken.fetch_add(1, Ordering::AcqRel);
+                    let _ = self.refill_sender.unbounded_send(());
+                    return Ok(vm);
+                }
+
+                evict(&self.shared, vm).await;
+                let _ = self.refill_sender.unbounded_send(());
+                continue;
+            }
+
+            if Instant::now() >= deadline {
+                return Err(WarmPoolError::TakeTimeout);
+            }
+
+            self.shared.runtime.sleep(TAKE_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Get a [WarmPoolHealth] report of the [WarmPool].
+    pub fn health(&self) -> WarmPoolHealth {
+        WarmPoolHealth {
+            size: self.shared.options.size,
+            ready: self.shared.lock_ready().len(),
+            booting: self.shared.booting.load(Ordering::Acquire),
+            taken: self.shared.taken.load(Ordering::Acquire),
+            evicted: self.shared.evicted.load(Ordering::Acquire),
+            failed_boots: self.shared.failed_boots.load(Ordering::Acquire),
+        }
+    }
+
+    /// Stop refilling the [WarmPool], waiting for [Vm]s that are currently booting, then shut down all ready [Vm]s
+    /// with the given sequence of [VmShutdownAction]s and clean them up, returning the shutdown result of each one
+    /// that was still running or paused.
+    pub async fn shutdown(
+        self,
+        actions: impl IntoIterator<Item = VmShutdownAction>,
+    ) -> Vec<Result<VmShutdownOutcome, VmShutdownError>> {
+        drop(self.refill_sender);
+        self.refill_task.join().await;
+
+        let actions = actions.into_iter().collect::<Vec<_>>();
+        let vms = self.shared.lock_ready().drain(..).collect::<Vec<_>>();
+
+        join_all(vms.into_iter().map(|mut vm| {
+            let actions = actions.clone();
+
+            async move {
+                let result = match vm.state() {
+                    VmState::Running | VmState::Paused => Some(vm.shutdown(actions).await),
+                    _ => None,
+                };
+                let _ = vm.cleanup().await;
+                result
+            }
+        }))
+        .await
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+}
+
+impl<E: VmmExecutor, S: ProcessSpawner, R: Runtime> PoolShared<E, S, R> {
+    fn lock_ready(&self) -> std::sync::MutexGuard<'_, VecDeque<Vm<E, S, R>>> {
+        self.ready.lock().expect("Ready VMs mutex was poisoned")
+    }
+}
+
+async fn refill<E: VmmExecutor + 'static, S: ProcessSpawner, R: Runtime>(
+    shared: Arc<PoolShared<E, S, R>>,
+    mut refill_receiver: mpsc::UnboundedReceiver<()>,
+) {
+    loop {
+        let idle_vms = shared.lock_ready().drain(..).collect::<Vec<_>>();
+        for mut vm in idle_vms {
+            match vm.state() {
+                VmState::Running => shared.lock_ready().push_back(vm),
+                _ => evict(&shared, vm).await,
+            }
+        }
+
+        let deficit = shared.options.size.saturating_sub(shared.lock_ready().len());
+        shared.booting.store(deficit, Ordering::Release);
+        join_all((0..deficit).map(|_| boot(&shared))).await;
+
+        // the pool is stopped when the sender is dropped, while timeouts trigger periodic health checks
+        if let Ok(None) = shared
+            .runtime
+            .timeout(shared.options.health_check_interval, refill_receiver.next())
+            .await
+        {
+            return;
+        }
+
+        // coalesce refill requests of multiple takes into a single round
+        while refill_receiver.try_recv().is_ok() {}
+    }
+}
+
+async fn boot<E: VmmExecutor, S: ProcessSpawner, R: Runtime>(shared: &PoolShared<E, S, R>) {
+    let result = async {
+        let mut vm = Vm::prepare(
+            (shared.executor_factory)(),
+            shared.process_spawner.clone(),
+            shared.runtime.clone(),
+            shared.ownership_model,
+            shared.installation.clone(),
+            shared.template.configuration(),
+        )
+        .await?;
+
+        if let Err(err) = vm.start(shared.options.socket_wait_timeout).await {
+            let _ = tear_down(&mut vm).await;
+            return Err(err);
+        }
+
+        Ok::<_, VmError>(vm)
+    }
+    .await;
+
+    match result {
+        Ok(vm) => shared.lock_ready().push_back(vm),
+        Err(_) => {
+            shared.failed_boots.fetch_add(1, Ordering::AcqRel);
+        }
+    }
+
+    shared.booting.fetch_sub(1, Ordering::AcqRel);
+}
+
+async fn evict<E: VmmExecutor, S: ProcessSpawner, R: Runtime>(shared: &PoolShared<E, S, R>, mut vm: Vm<E, S, R>) {
+    let _ = tear_down(&mut vm).await;
+    shared.evicted.fetch_add(1, Ordering::AcqRel);
+}
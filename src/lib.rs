@@ -24,6 +24,9 @@
 //! 6. The extension layer, enabled via various features ending with `-extension`. These small extensions, each typically spanning under
 //!    500 lines of code, provide various real-world utilities useful for a microVM-based application.
 //!
+//! Outside of the layers, the `testing` feature provides a mock of the Firecracker Management API and fake "firecracker" and
+//! "jailer" binaries, allowing code built on any layer to be tested on hosts without KVM or a Firecracker installation.
+//!
 //! Each higher layer is more opinionated and high-level than its predecessor, while offering more useful features. Depending on the needs
 //! of your application or library, you should decide which layers make sense for your use-case. Enabling the VM layer and all necessary
 //! extensions is usually a good start.
@@ -46,6 +49,10 @@ pub mod process_spawner;
 #[cfg_attr(docsrs, doc(cfg(feature = "vm")))]
 pub mod vm;
 
+#[cfg(feature = "testing")]
+#[cfg_attr(docsrs, doc(cfg(feature = "testing")))]
+pub mod testing;
+
 #[cfg(not(any(feature = "syscall-nix", feature = "syscall-rustix")))]
 compile_error!("Either \"syscall-nix\" or \"syscall-rustix\" must be enabled to provide syscalls");
 
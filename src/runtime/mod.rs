@@ -8,8 +8,9 @@
 
 use std::{
     future::Future,
+    net::SocketAddr,
     os::fd::OwnedFd,
-    path::Path,
+    path::{Path, PathBuf},
     process::{ExitStatus, Stdio},
     time::Duration,
 };
@@ -37,6 +38,10 @@ pub trait Runtime: Clone + Send + Sync + 'static {
     type File: AsyncRead + AsyncWrite + Send + Unpin;
     type AsyncFd: RuntimeAsyncFd;
     type Child: RuntimeChild;
+    type UnixListener: RuntimeListener<Stream = Self::UnixStream>;
+    type UnixStream: AsyncRead + AsyncWrite + Send + Unpin + 'static;
+    type TcpListener: RuntimeListener<Stream = Self::TcpStream>;
+    type TcpStream: AsyncRead + AsyncWrite + Send + Unpin + 'static;
 
     #[cfg(feature = "vmm-process")]
     #[cfg_attr(docsrs, doc(cfg(feature = "vmm-process")))]
@@ -56,6 +61,8 @@ pub trait Runtime: Clone + Send + Sync + 'static {
         F: Future<Output = O> + Send,
         O: Send;
 
+    fn sleep(&self, duration: Duration) -> impl Future<Output = ()> + Send;
+
     fn fs_exists(&self, path: &Path) -> impl Future<Output = Result<bool, std::io::Error>> + Send;
 
     fn fs_remove_file(&self, path: &Path) -> impl Future<Output = Result<(), std::io::Error>> + Send;
@@ -76,6 +83,8 @@ pub trait Runtime: Clone + Send + Sync + 'static {
 
     fn fs_remove_dir_all(&self, path: &Path) -> impl Future<Output = Result<(), std::io::Error>> + Send;
 
+    fn fs_remove_dir(&self, path: &Path) -> impl Future<Output = Result<(), std::io::Error>> + Send;
+
     fn fs_copy(
         &self,
         source_path: &Path,
@@ -84,6 +93,12 @@ pub trait Runtime: Clone + Send + Sync + 'static {
 
     fn fs_chown_all(&self, path: &Path, uid: u32, gid: u32) -> impl Future<Output = Result<(), std::io::Error>> + Send;
 
+    fn fs_fast_copy(
+        &self,
+        source_path: &Path,
+        destination_path: &Path,
+    ) -> impl Future<Output = Result<FastCopyStrategy, std::io::Error>> + Send;
+
     fn fs_hard_link(
         &self,
         source_path: &Path,
@@ -92,6 +107,33 @@ pub trait Runtime: Clone + Send + Sync + 'static {
 
     fn fs_open_file_for_read(&self, path: &Path) -> impl Future<Output = Result<Self::File, std::io::Error>> + Send;
 
+    fn fs_open_file_for_write(&self, path: &Path) -> impl Future<Output = Result<Self::File, std::io::Error>> + Send;
+
+    fn fs_create_new_file_for_write(
+        &self,
+        path: &Path,
+        mode: u32,
+    ) -> impl Future<Output = Result<Self::File, std::io::Error>> + Send;
+
+    fn fs_read_dir(&self, path: &Path) -> impl Future<Output = Result<Vec<PathBuf>, std::io::Error>> + Send;
+
+    fn fs_metadata(&self, path: &Path) -> impl Future<Output = Result<std::fs::Metadata, std::io::Error>> + Send;
+
+    fn unix_bind(&self, path: &Path) -> Result<Self::UnixListener, std::io::Error>;
+
+    fn unix_connect(&self, path: &Path) -> impl Future<Output = Result<Self::UnixStream, std::io::Error>> + Send;
+
+    fn tcp_bind(&self, address: SocketAddr) -> Result<(Self::TcpListener, SocketAddr), std::io::Error>;
+
+    fn tcp_connect(&self, address: SocketAddr) -> impl Future<Output = Result<Self::TcpStream, std::io::Error>> + Send;
+
+    /// Resolve the given host name and port into [SocketAddr]s via the system resolver.
+    fn tcp_resolve(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> impl Future<Output = Result<Vec<SocketAddr>, std::io::Error>> + Send;
+
     fn create_async_fd(&self, fd: OwnedFd) -> Result<Self::AsyncFd, std::io::Error>;
 
     fn spawn_child(
@@ -108,6 +150,17 @@ pub trait Runtime: Clone + Send + Sync + 'static {
     ) -> impl Future<Output = Result<std::process::Output, std::io::Error>> + Send;
 }
 
+/// The strategy that [Runtime::fs_fast_copy] ended up using to copy a file, from the fastest to the slowest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FastCopyStrategy {
+    /// The file was cloned via a reflink (FICLONE), sharing all of its extents copy-on-write with the source file.
+    Reflink,
+    /// The data segments of the file were copied inside the kernel via copy_file_range, preserving its holes.
+    CopyFileRange,
+    /// The data segments of the file were copied through a userspace buffer, preserving its holes.
+    ReadWrite,
+}
+
 /// An async task that is detached on drop, can be cancelled and joined on.
 pub trait RuntimeTask<O: Send + 'static>: Send {
     fn cancel(self) -> impl Future<Output = Option<O>> + Send;
@@ -116,17 +169,28 @@ pub trait RuntimeTask<O: Send + 'static>: Send {
 }
 
 /// An async file descriptor in the runtime that can be polled for the "readable" interest. Used by
-/// the detached (pidfd) backend in process handles.
+/// the detached (pidfd) backend in process handles. Readiness may be edge-triggered, so a caller waiting on
+/// the same file descriptor repeatedly should read from it until it would block before waiting again.
 pub trait RuntimeAsyncFd: Send {
     fn readable(&self) -> impl Future<Output = Result<(), std::io::Error>> + Send;
 }
 
+/// An async socket listener in the runtime that accepts incoming connections as streams. Used by extensions that
+/// serve connections on the host.
+pub trait RuntimeListener: Send + Sync + 'static {
+    type Stream: AsyncRead + AsyncWrite + Send + Unpin + 'static;
+
+    fn accept(&self) -> impl Future<Output = Result<Self::Stream, std::io::Error>> + Send;
+}
+
 /// An async child process in the runtime. Used by the attached backend in process handles.
 pub trait RuntimeChild: Sized + Send + Sync + std::fmt::Debug {
     type Stdout: AsyncRead + Unpin + Send;
     type Stderr: AsyncRead + Unpin + Send;
     type Stdin: AsyncWrite + Unpin + Send;
 
+    fn id(&self) -> Option<u32>;
+
     fn try_wait(&mut self) -> Result<Option<ExitStatus>, std::io::Error>;
 
     fn wait(&mut self) -> impl Future<Output = Result<ExitStatus, std::io::Error>> + Send;
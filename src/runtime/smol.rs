@@ -3,8 +3,9 @@
 
 use std::{
     future::Future,
+    net::{SocketAddr, ToSocketAddrs},
     os::unix::prelude::OwnedFd,
-    path::Path,
+    path::{Path, PathBuf},
     pin::Pin,
     process::Stdio,
     sync::Arc,
@@ -12,11 +13,15 @@ use std::{
     time::{Duration, Instant},
 };
 
+use async_fs::unix::OpenOptionsExt;
 use async_io::Timer;
 use async_process::{Child, ChildStderr, ChildStdin, ChildStdout};
 use pin_project_lite::pin_project;
 
-use super::{util::chown_all_blocking, Runtime, RuntimeAsyncFd, RuntimeChild, RuntimeTask};
+use super::{
+    util::{chown_all_blocking, fast_copy_blocking},
+    FastCopyStrategy, Runtime, RuntimeAsyncFd, RuntimeChild, RuntimeListener, RuntimeTask,
+};
 
 #[derive(Clone)]
 enum MaybeStaticExecutor {
@@ -43,6 +48,10 @@ impl Runtime for SmolRuntime {
     type File = async_fs::File;
     type AsyncFd = SmolRuntimeAsyncFd;
     type Child = SmolRuntimeChild;
+    type UnixListener = SmolRuntimeUnixListener;
+    type UnixStream = async_io::Async<std::os::unix::net::UnixStream>;
+    type TcpListener = SmolRuntimeTcpListener;
+    type TcpStream = async_io::Async<std::net::TcpStream>;
 
     #[cfg(feature = "vmm-process")]
     fn get_hyper_client_sockets_backend(&self) -> hyper_client_sockets::Backend {
@@ -56,7 +65,7 @@ impl Runtime for SmolRuntime {
     {
         let task = match self.0 {
             MaybeStaticExecutor::NonStatic(ref executor) => executor.spawn(future),
-            MaybeStaticExecutor::Static(ref executor) => executor.spawn(future),
+            MaybeStaticExecutor::Static(executor) => executor.spawn(future),
         };
 
         SmolRuntimeTask(Some(task))
@@ -73,6 +82,10 @@ impl Runtime for SmolRuntime {
         }
     }
 
+    async fn sleep(&self, duration: Duration) {
+        Timer::after(duration).await;
+    }
+
     fn fs_exists(&self, path: &Path) -> impl Future<Output = Result<bool, std::io::Error>> + Send {
         let path = path.to_owned();
         blocking::unblock(move || std::fs::exists(&path))
@@ -110,6 +123,10 @@ impl Runtime for SmolRuntime {
         async_fs::remove_dir_all(path)
     }
 
+    fn fs_remove_dir(&self, path: &Path) -> impl Future<Output = Result<(), std::io::Error>> + Send {
+        async_fs::remove_dir(path)
+    }
+
     async fn fs_copy(&self, source_path: &Path, destination_path: &Path) -> Result<(), std::io::Error> {
         async_fs::copy(source_path, destination_path).await.map(|_| ())
     }
@@ -119,6 +136,16 @@ impl Runtime for SmolRuntime {
         blocking::unblock(move || chown_all_blocking(&path, uid, gid))
     }
 
+    fn fs_fast_copy(
+        &self,
+        source_path: &Path,
+        destination_path: &Path,
+    ) -> impl Future<Output = Result<FastCopyStrategy, std::io::Error>> + Send {
+        let source_path = source_path.to_owned();
+        let destination_path = destination_path.to_owned();
+        blocking::unblock(move || fast_copy_blocking(&source_path, &destination_path))
+    }
+
     fn fs_hard_link(
         &self,
         source_path: &Path,
@@ -133,6 +160,64 @@ impl Runtime for SmolRuntime {
         open_options.open(path)
     }
 
+    fn fs_open_file_for_write(&self, path: &Path) -> impl Future<Output = Result<Self::File, std::io::Error>> + Send {
+        let mut open_options = async_fs::OpenOptions::new();
+        open_options.write(true).create(true).truncate(true);
+        open_options.open(path)
+    }
+
+    fn fs_create_new_file_for_write(
+        &self,
+        path: &Path,
+        mode: u32,
+    ) -> impl Future<Output = Result<Self::File, std::io::Error>> + Send {
+        let mut open_options = async_fs::OpenOptions::new();
+        open_options.write(true).create_new(true).mode(mode);
+        open_options.open(path)
+    }
+
+    fn fs_read_dir(&self, path: &Path) -> impl Future<Output = Result<Vec<PathBuf>, std::io::Error>> + Send {
+        let path = path.to_owned();
+        blocking::unblock(move || {
+            std::fs::read_dir(&path)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect()
+        })
+    }
+
+    fn fs_metadata(&self, path: &Path) -> impl Future<Output = Result<std::fs::Metadata, std::io::Error>> + Send {
+        async_fs::metadata(path)
+    }
+
+    fn unix_bind(&self, path: &Path) -> Result<Self::UnixListener, std::io::Error> {
+        Ok(SmolRuntimeUnixListener(async_io::Async::<
+            std::os::unix::net::UnixListener,
+        >::bind(path)?))
+    }
+
+    fn unix_connect(&self, path: &Path) -> impl Future<Output = Result<Self::UnixStream, std::io::Error>> + Send {
+        async_io::Async::<std::os::unix::net::UnixStream>::connect(path.to_owned())
+    }
+
+    fn tcp_bind(&self, address: SocketAddr) -> Result<(Self::TcpListener, SocketAddr), std::io::Error> {
+        let listener = async_io::Async::<std::net::TcpListener>::bind(address)?;
+        let local_address = listener.get_ref().local_addr()?;
+        Ok((SmolRuntimeTcpListener(listener), local_address))
+    }
+
+    fn tcp_connect(&self, address: SocketAddr) -> impl Future<Output = Result<Self::TcpStream, std::io::Error>> + Send {
+        async_io::Async::<std::net::TcpStream>::connect(address)
+    }
+
+    fn tcp_resolve(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> impl Future<Output = Result<Vec<SocketAddr>, std::io::Error>> + Send {
+        let host = host.to_owned();
+        blocking::unblock(move || Ok((host, port).to_socket_addrs()?.collect()))
+    }
+
     fn create_async_fd(&self, fd: OwnedFd) -> Result<Self::AsyncFd, std::io::Error> {
         Ok(SmolRuntimeAsyncFd(async_io::Async::new(fd)?))
     }
@@ -237,6 +322,26 @@ impl RuntimeAsyncFd for SmolRuntimeAsyncFd {
     }
 }
 
+pub struct SmolRuntimeUnixListener(async_io::Async<std::os::unix::net::UnixListener>);
+
+impl RuntimeListener for SmolRuntimeUnixListener {
+    type Stream = async_io::Async<std::os::unix::net::UnixStream>;
+
+    async fn accept(&self) -> Result<Self::Stream, std::io::Error> {
+        Ok(self.0.accept().await?.0)
+    }
+}
+
+pub struct SmolRuntimeTcpListener(async_io::Async<std::net::TcpListener>);
+
+impl RuntimeListener for SmolRuntimeTcpListener {
+    type Stream = async_io::Async<std::net::TcpStream>;
+
+    async fn accept(&self) -> Result<Self::Stream, std::io::Error> {
+        Ok(self.0.accept().await?.0)
+    }
+}
+
 #[derive(Debug)]
 pub struct SmolRuntimeChild {
     child: Child,
@@ -252,6 +357,10 @@ impl RuntimeChild for SmolRuntimeChild {
 
     type Stdin = ChildStdin;
 
+    fn id(&self) -> Option<u32> {
+        Some(self.child.id())
+    }
+
     fn try_wait(&mut self) -> Result<Option<std::process::ExitStatus>, std::io::Error> {
         self.child.try_status()
     }
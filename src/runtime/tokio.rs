@@ -1,6 +1,13 @@
 //! A runtime implementation using Tokio's different features for all of its components.
 
-use std::{future::Future, os::fd::OwnedFd, path::Path, process::Stdio, time::Duration};
+use std::{
+    future::Future,
+    net::SocketAddr,
+    os::fd::OwnedFd,
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::Duration,
+};
 
 use tokio::{
     io::unix::AsyncFd,
@@ -9,7 +16,10 @@ use tokio::{
 };
 use tokio_util::compat::{Compat, TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
 
-use super::{util::chown_all_blocking, Runtime, RuntimeAsyncFd, RuntimeChild, RuntimeTask};
+use super::{
+    util::{chown_all_blocking, fast_copy_blocking},
+    FastCopyStrategy, Runtime, RuntimeAsyncFd, RuntimeChild, RuntimeListener, RuntimeTask,
+};
 
 #[derive(Clone)]
 pub struct TokioRuntime;
@@ -20,6 +30,10 @@ impl Runtime for TokioRuntime {
     type File = Compat<tokio::fs::File>;
     type AsyncFd = TokioRuntimeAsyncFd;
     type Child = TokioRuntimeChild;
+    type UnixListener = TokioRuntimeUnixListener;
+    type UnixStream = Compat<tokio::net::UnixStream>;
+    type TcpListener = TokioRuntimeTcpListener;
+    type TcpStream = Compat<tokio::net::TcpStream>;
 
     #[cfg(feature = "vmm-process")]
     fn get_hyper_client_sockets_backend(&self) -> hyper_client_sockets::Backend {
@@ -42,6 +56,10 @@ impl Runtime for TokioRuntime {
         tokio::time::timeout(duration, future)
     }
 
+    fn sleep(&self, duration: Duration) -> impl Future<Output = ()> + Send {
+        tokio::time::sleep(duration)
+    }
+
     fn fs_exists(&self, path: &Path) -> impl Future<Output = Result<bool, std::io::Error>> + Send {
         tokio::fs::try_exists(path)
     }
@@ -78,6 +96,10 @@ impl Runtime for TokioRuntime {
         tokio::fs::remove_dir_all(path)
     }
 
+    fn fs_remove_dir(&self, path: &Path) -> impl Future<Output = Result<(), std::io::Error>> + Send {
+        tokio::fs::remove_dir(path)
+    }
+
     async fn fs_copy(&self, source_path: &Path, destination_path: &Path) -> Result<(), std::io::Error> {
         tokio::fs::copy(source_path, destination_path).await.map(|_| ())
     }
@@ -90,6 +112,19 @@ impl Runtime for TokioRuntime {
         }
     }
 
+    async fn fs_fast_copy(
+        &self,
+        source_path: &Path,
+        destination_path: &Path,
+    ) -> Result<FastCopyStrategy, std::io::Error> {
+        let source_path = source_path.to_owned();
+        let destination_path = destination_path.to_owned();
+        match tokio::task::spawn_blocking(move || fast_copy_blocking(&source_path, &destination_path)).await {
+            Ok(result) => result,
+            Err(_) => Err(std::io::Error::other("fast_copy_blocking task panicked")),
+        }
+    }
+
     fn fs_hard_link(
         &self,
         source_path: &Path,
@@ -105,6 +140,59 @@ impl Runtime for TokioRuntime {
         Ok(file.compat())
     }
 
+    async fn fs_create_new_file_for_write(&self, path: &Path, mode: u32) -> Result<Self::File, std::io::Error> {
+        let mut open_options = tokio::fs::OpenOptions::new();
+        open_options.write(true).create_new(true).mode(mode);
+        let file = open_options.open(path).await?;
+        Ok(file.compat())
+    }
+
+    async fn fs_open_file_for_write(&self, path: &Path) -> Result<Self::File, std::io::Error> {
+        let mut open_options = tokio::fs::OpenOptions::new();
+        open_options.write(true).create(true).truncate(true);
+        let file = open_options.open(path).await?;
+        Ok(file.compat())
+    }
+
+    async fn fs_read_dir(&self, path: &Path) -> Result<Vec<PathBuf>, std::io::Error> {
+        let mut read_dir = tokio::fs::read_dir(path).await?;
+        let mut paths = Vec::new();
+        while let Some(entry) = read_dir.next_entry().await? {
+            paths.push(entry.path());
+        }
+        Ok(paths)
+    }
+
+    fn fs_metadata(&self, path: &Path) -> impl Future<Output = Result<std::fs::Metadata, std::io::Error>> + Send {
+        tokio::fs::metadata(path)
+    }
+
+    fn unix_bind(&self, path: &Path) -> Result<Self::UnixListener, std::io::Error> {
+        Ok(TokioRuntimeUnixListener(tokio::net::UnixListener::bind(path)?))
+    }
+
+    async fn unix_connect(&self, path: &Path) -> Result<Self::UnixStream, std::io::Error> {
+        Ok(tokio::net::UnixStream::connect(path).await?.compat())
+    }
+
+    fn tcp_bind(&self, address: SocketAddr) -> Result<(Self::TcpListener, SocketAddr), std::io::Error> {
+        let listener = std::net::TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        let local_address = listener.local_addr()?;
+        Ok((
+            TokioRuntimeTcpListener(tokio::net::TcpListener::from_std(listener)?),
+            local_address,
+        ))
+    }
+
+    async fn tcp_connect(&self, address: SocketAddr) -> Result<Self::TcpStream, std::io::Error> {
+        Ok(tokio::net::TcpStream::connect(address).await?.compat())
+    }
+
+    async fn tcp_resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, std::io::Error> {
+        Ok(tokio::net::lookup_host((host, port)).await?.collect())
+    }
+
     fn create_async_fd(&self, fd: OwnedFd) -> Result<Self::AsyncFd, std::io::Error> {
         Ok(TokioRuntimeAsyncFd(AsyncFd::new(fd)?))
     }
@@ -151,10 +239,7 @@ impl<O: Send + 'static> RuntimeTask<O> for TokioRuntimeTask<O> {
     }
 
     async fn join(self) -> Option<O> {
-        match self.0.await {
-            Ok(output) => Some(output),
-            Err(_) => None,
-        }
+        self.0.await.ok()
     }
 }
 
@@ -163,11 +248,31 @@ pub struct TokioRuntimeAsyncFd(AsyncFd<OwnedFd>);
 impl RuntimeAsyncFd for TokioRuntimeAsyncFd {
     async fn readable(&self) -> Result<(), std::io::Error> {
         let mut guard = self.0.readable().await?;
-        guard.retain_ready();
+        guard.clear_ready();
         Ok(())
     }
 }
 
+pub struct TokioRuntimeUnixListener(tokio::net::UnixListener);
+
+impl RuntimeListener for TokioRuntimeUnixListener {
+    type Stream = Compat<tokio::net::UnixStream>;
+
+    async fn accept(&self) -> Result<Self::Stream, std::io::Error> {
+        Ok(self.0.accept().await?.0.compat())
+    }
+}
+
+pub struct TokioRuntimeTcpListener(tokio::net::TcpListener);
+
+impl RuntimeListener for TokioRuntimeTcpListener {
+    type Stream = Compat<tokio::net::TcpStream>;
+
+    async fn accept(&self) -> Result<Self::Stream, std::io::Error> {
+        Ok(self.0.accept().await?.0.compat())
+    }
+}
+
 #[derive(Debug)]
 pub struct TokioRuntimeChild {
     child: Child,
@@ -183,6 +288,10 @@ impl RuntimeChild for TokioRuntimeChild {
 
     type Stdin = Compat<ChildStdin>;
 
+    fn id(&self) -> Option<u32> {
+        self.child.id()
+    }
+
     fn try_wait(&mut self) -> Result<Option<std::process::ExitStatus>, std::io::Error> {
         self.child.try_wait()
     }
@@ -1,6 +1,14 @@
-use std::{future::Future, path::Path};
+use std::{
+    fs::File,
+    future::Future,
+    os::{
+        fd::AsFd,
+        unix::fs::{FileExt, MetadataExt},
+    },
+    path::Path,
+};
 
-use super::{Runtime, RuntimeTask};
+use super::{FastCopyStrategy, Runtime, RuntimeTask};
 
 #[cfg(feature = "vmm-process")]
 use std::pin::Pin;
@@ -41,6 +49,27 @@ impl<O: Send + 'static, R: Runtime> RuntimeTaskSet<O, R> {
 
         Some(Ok(()))
     }
+
+    /// Wait for all tasks to complete, unlike [RuntimeTaskSet::wait] not returning early once one of them has failed,
+    /// which is needed when the effects of all tasks must be settled before reacting to the failure. The first
+    /// failure in the order of spawning, or [None] if joining a task failed first, is returned.
+    pub async fn wait_all(self) -> Option<Result<(), O>> {
+        let mut outcome = Some(Ok(()));
+
+        for task in self.tasks {
+            let result = task.join().await;
+
+            if let Some(Ok(())) = outcome {
+                match result {
+                    Some(Ok(())) => {}
+                    Some(Err(err)) => outcome = Some(Err(err)),
+                    None => outcome = None,
+                }
+            }
+        }
+
+        outcome
+    }
 }
 
 /// A simple utility that performs recursive chown syscalls on the given directory's [Path] to
@@ -58,9 +87,97 @@ pub fn chown_all_blocking(path: &Path, uid: u32, gid: u32) -> Result<(), std::io
         }
     }
 
+    // files that already have the requested owner are skipped, so that read-only mounts inside the path, such as
+    // read-only bind mounts of moved resources, only fail the operation if their owner actually needs to change
+    let metadata = std::fs::metadata(path)?;
+    if metadata.uid() == uid && metadata.gid() == gid {
+        return Ok(());
+    }
+
     crate::syscall::chown(path, uid, gid)
 }
 
+/// A utility that copies the file at the source [Path] to the destination [Path] as fast as the filesystem allows,
+/// trying a reflink first and otherwise copying only the data segments found via SEEK_DATA and SEEK_HOLE, so that
+/// holes in sparse files are preserved. The data segments are copied via copy_file_range, falling back to a userspace
+/// buffer if the kernel or filesystem doesn't support it, for example across filesystems on older kernels. Filesystems
+/// that don't report holes are copied as a single data segment. Like [chown_all_blocking], this operation is blocking.
+///
+/// This is used with blocking threads by the Tokio and Smol runtime implementations to implement
+/// [Runtime::fs_fast_copy], and is public for usage by third-party runtimes too.
+pub fn fast_copy_blocking(source_path: &Path, destination_path: &Path) -> Result<FastCopyStrategy, std::io::Error> {
+    let source = File::open(source_path)?;
+    let metadata = source.metadata()?;
+    let destination = File::create(destination_path)?;
+    destination.set_permissions(metadata.permissions())?;
+
+    if crate::syscall::ficlone(destination.as_fd(), source.as_fd()).is_ok() {
+        return Ok(FastCopyStrategy::Reflink);
+    }
+
+    let len = metadata.len();
+    destination.set_len(len)?;
+    let mut strategy = FastCopyStrategy::CopyFileRange;
+    let mut offset = 0;
+
+    while offset < len {
+        let data_start = match crate::syscall::seek_data(source.as_fd(), offset) {
+            Ok(Some(data_start)) => data_start,
+            Ok(None) => break,
+            Err(_) => offset,
+        };
+        let data_end = crate::syscall::seek_hole(source.as_fd(), data_start)
+            .unwrap_or(len)
+            .min(len);
+
+        copy_range_blocking(&source, &destination, data_start, data_end, &mut strategy)?;
+        offset = data_end;
+    }
+
+    Ok(strategy)
+}
+
+fn copy_range_blocking(
+    source: &File,
+    destination: &File,
+    start: u64,
+    end: u64,
+    strategy: &mut FastCopyStrategy,
+) -> Result<(), std::io::Error> {
+    const COPY_FILE_RANGE_CHUNK_SIZE: u64 = 1024 * 1024 * 1024;
+    const READ_WRITE_CHUNK_SIZE: u64 = 1024 * 1024;
+
+    let mut offset = start;
+    let mut buf = Vec::new();
+
+    while offset < end {
+        if *strategy == FastCopyStrategy::CopyFileRange {
+            match crate::syscall::copy_file_range(
+                source.as_fd(),
+                offset,
+                destination.as_fd(),
+                offset,
+                (end - offset).min(COPY_FILE_RANGE_CHUNK_SIZE) as usize,
+            ) {
+                Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                Ok(copied) => {
+                    offset += copied as u64;
+                    continue;
+                }
+                Err(_) => *strategy = FastCopyStrategy::ReadWrite,
+            }
+        }
+
+        let chunk_size = (end - offset).min(READ_WRITE_CHUNK_SIZE) as usize;
+        buf.resize(chunk_size, 0);
+        source.read_exact_at(&mut buf, offset)?;
+        destination.write_all_at(&buf, offset)?;
+        offset += chunk_size as u64;
+    }
+
+    Ok(())
+}
+
 /// A [hyper::rt::Executor] implementation that is agnostic over any [Runtime] by simply using [Runtime::spawn_task]
 /// internally.
 #[cfg(feature = "vmm-process")]
@@ -75,3 +192,62 @@ impl<R: Runtime> hyper::rt::Executor<Pin<Box<dyn Future<Output = ()> + Send>>> f
         self.0.spawn_task(future);
     }
 }
+
+/// A [hyper::rt::Read] and [hyper::rt::Write] implementation that wraps any [futures_io] stream, such as the TCP
+/// streams of a [Runtime], so that hyper can perform HTTP over it.
+#[cfg(feature = "vmm-process")]
+#[cfg_attr(docsrs, doc(cfg(feature = "vmm-process")))]
+#[derive(Debug)]
+pub struct RuntimeHyperIo<T>(pub T);
+
+#[cfg(feature = "vmm-process")]
+#[cfg_attr(docsrs, doc(cfg(feature = "vmm-process")))]
+impl<T: futures_io::AsyncRead + Unpin> hyper::rt::Read for RuntimeHyperIo<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        mut buf: hyper::rt::ReadBufCursor<'_>,
+    ) -> std::task::Poll<Result<(), std::io::Error>> {
+        // reading into an initialized buffer first avoids having to fill hyper's uninitialized one unsafely
+        let mut read_buf = [0; HYPER_IO_READ_BUFFER_SIZE];
+        let length = buf.remaining().min(read_buf.len());
+
+        match Pin::new(&mut self.0).poll_read(cx, &mut read_buf[..length]) {
+            std::task::Poll::Ready(Ok(read)) => {
+                buf.put_slice(&read_buf[..read]);
+                std::task::Poll::Ready(Ok(()))
+            }
+            std::task::Poll::Ready(Err(err)) => std::task::Poll::Ready(Err(err)),
+            std::task::Poll::Pending => std::task::Poll::Pending,
+        }
+    }
+}
+
+#[cfg(feature = "vmm-process")]
+#[cfg_attr(docsrs, doc(cfg(feature = "vmm-process")))]
+impl<T: futures_io::AsyncWrite + Unpin> hyper::rt::Write for RuntimeHyperIo<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<Result<usize, std::io::Error>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.0).poll_close(cx)
+    }
+}
+
+#[cfg(feature = "vmm-process")]
+const HYPER_IO_READ_BUFFER_SIZE: usize = 16 * 1024;
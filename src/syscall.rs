@@ -1,12 +1,58 @@
 #![allow(unused)]
 
+// the userfaultfd ioctl structures from linux/userfaultfd.h, shared by both implementations
+#[repr(C)]
+struct UffdioRange {
+    start: u64,
+    len: u64,
+}
+
+#[repr(C)]
+struct UffdioCopy {
+    dst: u64,
+    src: u64,
+    len: u64,
+    mode: u64,
+    copy: i64,
+}
+
+#[repr(C)]
+struct UffdioZeropage {
+    range: UffdioRange,
+    mode: u64,
+    zeropage: i64,
+}
+
+/// A private read-only memory mapping of a whole file, which is unmapped on drop.
+pub struct ReadOnlyMapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// the mapping is read-only and never aliased mutably, so it can be shared between threads
+unsafe impl Send for ReadOnlyMapping {}
+unsafe impl Sync for ReadOnlyMapping {}
+
+impl ReadOnlyMapping {
+    #[inline]
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl Drop for ReadOnlyMapping {
+    fn drop(&mut self) {
+        let _ = unsafe { munmap(self.ptr, self.len) };
+    }
+}
+
 #[cfg(all(feature = "syscall-nix", not(feature = "syscall-rustix")))]
 mod imp_nix {
     #![allow(unused)]
 
     use std::{
-        os::fd::{FromRawFd, OwnedFd, RawFd},
-        path::Path,
+        os::fd::{BorrowedFd, FromRawFd, IntoRawFd, OwnedFd, RawFd},
+        path::{Path, PathBuf},
     };
 
     use nix::sys::stat::Mode;
@@ -55,6 +101,255 @@ mod imp_nix {
 
         Ok(())
     }
+
+    #[inline]
+    pub fn kill(pid: i32, signal: i32) -> Result<(), std::io::Error> {
+        let ret = unsafe { nix::libc::kill(pid, signal) };
+
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    pub type Termios = nix::sys::termios::Termios;
+
+    #[inline]
+    pub fn openpty() -> Result<(OwnedFd, OwnedFd, PathBuf), std::io::Error> {
+        use nix::fcntl::OFlag;
+
+        let master = nix::pty::posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY | OFlag::O_NONBLOCK | OFlag::O_CLOEXEC)
+            .map_err(std::io::Error::from)?;
+        nix::pty::grantpt(&master).map_err(std::io::Error::from)?;
+        nix::pty::unlockpt(&master).map_err(std::io::Error::from)?;
+        let slave_path = PathBuf::from(nix::pty::ptsname_r(&master).map_err(std::io::Error::from)?);
+        let slave = nix::fcntl::open(
+            &slave_path,
+            OFlag::O_RDWR | OFlag::O_NOCTTY | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )
+        .map_err(std::io::Error::from)?;
+
+        Ok((
+            unsafe { OwnedFd::from_raw_fd(master.into_raw_fd()) },
+            unsafe { OwnedFd::from_raw_fd(slave) },
+            slave_path,
+        ))
+    }
+
+    #[inline]
+    pub fn tcgetattr(fd: BorrowedFd) -> Result<Termios, std::io::Error> {
+        nix::sys::termios::tcgetattr(fd).map_err(std::io::Error::from)
+    }
+
+    #[inline]
+    pub fn tcsetattr(fd: BorrowedFd, termios: &Termios) -> Result<(), std::io::Error> {
+        nix::sys::termios::tcsetattr(fd, nix::sys::termios::SetArg::TCSANOW, termios).map_err(std::io::Error::from)
+    }
+
+    #[inline]
+    pub fn make_raw(termios: &mut Termios, read_timeout_deciseconds: Option<u8>) {
+        use nix::sys::termios::SpecialCharacterIndices;
+
+        nix::sys::termios::cfmakeraw(termios);
+
+        if let Some(read_timeout_deciseconds) = read_timeout_deciseconds {
+            termios.control_chars[SpecialCharacterIndices::VMIN as usize] = 0;
+            termios.control_chars[SpecialCharacterIndices::VTIME as usize] = read_timeout_deciseconds;
+        }
+    }
+
+    #[inline]
+    pub fn recv_with_fd(socket: BorrowedFd, buf: &mut [u8]) -> Result<(usize, Option<OwnedFd>), std::io::Error> {
+        use nix::sys::socket::{ControlMessageOwned, MsgFlags};
+        use std::os::fd::AsRawFd;
+
+        let mut iov = [std::io::IoSliceMut::new(buf)];
+        let mut cmsg_buffer = nix::cmsg_space!([RawFd; 1]);
+        let msg = nix::sys::socket::recvmsg::<()>(
+            socket.as_raw_fd(),
+            &mut iov,
+            Some(&mut cmsg_buffer),
+            MsgFlags::MSG_CMSG_CLOEXEC,
+        )
+        .map_err(std::io::Error::from)?;
+
+        let mut fd = None;
+        for cmsg in msg.cmsgs().map_err(std::io::Error::from)? {
+            if let ControlMessageOwned::ScmRights(raw_fds) = cmsg {
+                for raw_fd in raw_fds {
+                    let owned_fd = unsafe { OwnedFd::from_raw_fd(raw_fd) };
+                    fd.get_or_insert(owned_fd);
+                }
+            }
+        }
+
+        Ok((msg.bytes, fd))
+    }
+
+    #[inline]
+    pub fn mmap_read_only(fd: BorrowedFd, len: usize) -> Result<super::ReadOnlyMapping, std::io::Error> {
+        use std::os::fd::AsRawFd;
+
+        let ptr = unsafe {
+            nix::libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                nix::libc::PROT_READ,
+                nix::libc::MAP_PRIVATE,
+                fd.as_raw_fd(),
+                0,
+            )
+        };
+
+        if ptr == nix::libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(super::ReadOnlyMapping { ptr: ptr.cast(), len })
+    }
+
+    #[inline]
+    pub unsafe fn munmap(ptr: *mut u8, len: usize) -> Result<(), std::io::Error> {
+        if nix::libc::munmap(ptr.cast(), len) < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    // _IOWR(0xAA, 0x03, struct uffdio_copy)
+    const UFFDIO_COPY: u64 = 0xc028aa03;
+    // _IOWR(0xAA, 0x04, struct uffdio_zeropage)
+    const UFFDIO_ZEROPAGE: u64 = 0xc020aa04;
+    // _IOR(0xAA, 0x02, struct uffdio_range)
+    const UFFDIO_WAKE: u64 = 0x8010aa02;
+
+    #[inline]
+    pub fn uffdio_copy(uffd: BorrowedFd, dst: u64, src: &[u8]) -> Result<(), std::io::Error> {
+        use std::os::fd::AsRawFd;
+
+        let mut copy = super::UffdioCopy {
+            dst,
+            src: src.as_ptr() as u64,
+            len: src.len() as u64,
+            mode: 0,
+            copy: 0,
+        };
+
+        if unsafe { nix::libc::ioctl(uffd.as_raw_fd(), UFFDIO_COPY as _, &mut copy) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    #[inline]
+    pub fn uffdio_zeropage(uffd: BorrowedFd, dst: u64, len: u64) -> Result<(), std::io::Error> {
+        use std::os::fd::AsRawFd;
+
+        let mut zeropage = super::UffdioZeropage {
+            range: super::UffdioRange { start: dst, len },
+            mode: 0,
+            zeropage: 0,
+        };
+
+        if unsafe { nix::libc::ioctl(uffd.as_raw_fd(), UFFDIO_ZEROPAGE as _, &mut zeropage) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    #[inline]
+    pub fn uffdio_wake(uffd: BorrowedFd, start: u64, len: u64) -> Result<(), std::io::Error> {
+        use std::os::fd::AsRawFd;
+
+        let mut range = super::UffdioRange { start, len };
+
+        if unsafe { nix::libc::ioctl(uffd.as_raw_fd(), UFFDIO_WAKE as _, &mut range) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    #[inline]
+    pub fn punch_hole(fd: BorrowedFd, offset: u64, len: u64) -> Result<(), std::io::Error> {
+        use std::os::fd::AsRawFd;
+
+        nix::fcntl::fallocate(
+            fd.as_raw_fd(),
+            nix::fcntl::FallocateFlags::FALLOC_FL_PUNCH_HOLE | nix::fcntl::FallocateFlags::FALLOC_FL_KEEP_SIZE,
+            offset as nix::libc::off_t,
+            len as nix::libc::off_t,
+        )
+        .map_err(|_| std::io::Error::last_os_error())
+    }
+
+    #[inline]
+    pub fn ficlone(destination: BorrowedFd, source: BorrowedFd) -> Result<(), std::io::Error> {
+        use std::os::fd::AsRawFd;
+
+        // FICLONE isn't wrapped in nix, so a libc-wrapped ioctl is needed
+        let ret = unsafe { nix::libc::ioctl(destination.as_raw_fd(), nix::libc::FICLONE, source.as_raw_fd()) };
+
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    #[inline]
+    pub fn copy_file_range(
+        source: BorrowedFd,
+        source_offset: u64,
+        destination: BorrowedFd,
+        destination_offset: u64,
+        len: usize,
+    ) -> Result<usize, std::io::Error> {
+        let mut source_offset = source_offset as i64;
+        let mut destination_offset = destination_offset as i64;
+
+        nix::fcntl::copy_file_range(
+            source,
+            Some(&mut source_offset),
+            destination,
+            Some(&mut destination_offset),
+            len,
+        )
+        .map_err(|_| std::io::Error::last_os_error())
+    }
+
+    #[inline]
+    pub fn seek_data(fd: BorrowedFd, offset: u64) -> Result<Option<u64>, std::io::Error> {
+        use std::os::fd::AsRawFd;
+
+        match nix::unistd::lseek(
+            fd.as_raw_fd(),
+            offset as nix::libc::off_t,
+            nix::unistd::Whence::SeekData,
+        ) {
+            Ok(offset) => Ok(Some(offset as u64)),
+            Err(nix::errno::Errno::ENXIO) => Ok(None),
+            Err(_) => Err(std::io::Error::last_os_error()),
+        }
+    }
+
+    #[inline]
+    pub fn seek_hole(fd: BorrowedFd, offset: u64) -> Result<u64, std::io::Error> {
+        use std::os::fd::AsRawFd;
+
+        nix::unistd::lseek(
+            fd.as_raw_fd(),
+            offset as nix::libc::off_t,
+            nix::unistd::Whence::SeekHole,
+        )
+        .map(|offset| offset as u64)
+        .map_err(|_| std::io::Error::last_os_error())
+    }
 }
 
 #[cfg(feature = "syscall-rustix")]
@@ -62,8 +357,12 @@ mod imp_rustix {
     #![allow(unused)]
 
     use std::{
-        os::fd::{BorrowedFd, OwnedFd, RawFd},
-        path::Path,
+        ffi::OsString,
+        os::{
+            fd::{BorrowedFd, OwnedFd, RawFd},
+            unix::ffi::OsStringExt,
+        },
+        path::{Path, PathBuf},
     };
 
     use rustix::fs::Mode;
@@ -119,6 +418,226 @@ mod imp_rustix {
         rustix::process::pidfd_send_signal(unsafe { BorrowedFd::borrow_raw(fd) }, rustix::process::Signal::Kill)
             .map_err(|errno| std::io::Error::from_raw_os_error(errno.raw_os_error()))
     }
+
+    #[inline]
+    pub fn kill(pid: i32, signal: i32) -> Result<(), std::io::Error> {
+        let pid = rustix::process::Pid::from_raw(pid).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "The provided PID for kill was not positive",
+            )
+        })?;
+        let signal = rustix::process::Signal::from_raw(signal).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "The provided signal for kill is unknown",
+            )
+        })?;
+        rustix::process::kill_process(pid, signal)
+            .map_err(|errno| std::io::Error::from_raw_os_error(errno.raw_os_error()))
+    }
+
+    pub type Termios = rustix::termios::Termios;
+
+    #[inline]
+    pub fn openpty() -> Result<(OwnedFd, OwnedFd, PathBuf), std::io::Error> {
+        use rustix::pty::OpenptFlags;
+
+        let master = rustix::pty::openpt(OpenptFlags::RDWR | OpenptFlags::NOCTTY | OpenptFlags::CLOEXEC)
+            .map_err(|errno| std::io::Error::from_raw_os_error(errno.raw_os_error()))?;
+        rustix::pty::grantpt(&master).map_err(|errno| std::io::Error::from_raw_os_error(errno.raw_os_error()))?;
+        rustix::pty::unlockpt(&master).map_err(|errno| std::io::Error::from_raw_os_error(errno.raw_os_error()))?;
+        let slave_path = rustix::pty::ptsname(&master, Vec::new())
+            .map_err(|errno| std::io::Error::from_raw_os_error(errno.raw_os_error()))?;
+        let flags = rustix::fs::fcntl_getfl(&master)
+            .map_err(|errno| std::io::Error::from_raw_os_error(errno.raw_os_error()))?;
+        rustix::fs::fcntl_setfl(&master, flags | rustix::fs::OFlags::NONBLOCK)
+            .map_err(|errno| std::io::Error::from_raw_os_error(errno.raw_os_error()))?;
+
+        let slave_path = PathBuf::from(OsString::from_vec(slave_path.into_bytes()));
+        let slave = rustix::fs::open(
+            &slave_path,
+            rustix::fs::OFlags::RDWR | rustix::fs::OFlags::NOCTTY | rustix::fs::OFlags::CLOEXEC,
+            Mode::empty(),
+        )
+        .map_err(|errno| std::io::Error::from_raw_os_error(errno.raw_os_error()))?;
+
+        Ok((master, slave, slave_path))
+    }
+
+    #[inline]
+    pub fn tcgetattr(fd: BorrowedFd) -> Result<Termios, std::io::Error> {
+        rustix::termios::tcgetattr(fd).map_err(|errno| std::io::Error::from_raw_os_error(errno.raw_os_error()))
+    }
+
+    #[inline]
+    pub fn tcsetattr(fd: BorrowedFd, termios: &Termios) -> Result<(), std::io::Error> {
+        rustix::termios::tcsetattr(fd, rustix::termios::OptionalActions::Now, termios)
+            .map_err(|errno| std::io::Error::from_raw_os_error(errno.raw_os_error()))
+    }
+
+    #[inline]
+    pub fn make_raw(termios: &mut Termios, read_timeout_deciseconds: Option<u8>) {
+        use rustix::termios::SpecialCodeIndex;
+
+        termios.make_raw();
+
+        if let Some(read_timeout_deciseconds) = read_timeout_deciseconds {
+            termios.special_codes[SpecialCodeIndex::VMIN] = 0;
+            termios.special_codes[SpecialCodeIndex::VTIME] = read_timeout_deciseconds;
+        }
+    }
+
+    #[inline]
+    pub fn recv_with_fd(socket: BorrowedFd, buf: &mut [u8]) -> Result<(usize, Option<OwnedFd>), std::io::Error> {
+        use rustix::net::{RecvAncillaryBuffer, RecvAncillaryMessage, RecvFlags};
+
+        let mut iov = [std::io::IoSliceMut::new(buf)];
+        let mut space = [0; rustix::cmsg_space!(ScmRights(1))];
+        let mut cmsg_buffer = RecvAncillaryBuffer::new(&mut space);
+        let msg = rustix::net::recvmsg(socket, &mut iov, &mut cmsg_buffer, RecvFlags::CMSG_CLOEXEC)
+            .map_err(|errno| std::io::Error::from_raw_os_error(errno.raw_os_error()))?;
+
+        let mut fd = None;
+        for cmsg in cmsg_buffer.drain() {
+            if let RecvAncillaryMessage::ScmRights(owned_fds) = cmsg {
+                for owned_fd in owned_fds {
+                    fd.get_or_insert(owned_fd);
+                }
+            }
+        }
+
+        Ok((msg.bytes, fd))
+    }
+
+    #[inline]
+    pub fn mmap_read_only(fd: BorrowedFd, len: usize) -> Result<super::ReadOnlyMapping, std::io::Error> {
+        unsafe {
+            rustix::mm::mmap(
+                std::ptr::null_mut(),
+                len,
+                rustix::mm::ProtFlags::READ,
+                rustix::mm::MapFlags::PRIVATE,
+                fd,
+                0,
+            )
+        }
+        .map(|ptr| super::ReadOnlyMapping { ptr: ptr.cast(), len })
+        .map_err(|errno| std::io::Error::from_raw_os_error(errno.raw_os_error()))
+    }
+
+    #[inline]
+    pub unsafe fn munmap(ptr: *mut u8, len: usize) -> Result<(), std::io::Error> {
+        rustix::mm::munmap(ptr.cast(), len).map_err(|errno| std::io::Error::from_raw_os_error(errno.raw_os_error()))
+    }
+
+    #[inline]
+    pub fn uffdio_copy(uffd: BorrowedFd, dst: u64, src: &[u8]) -> Result<(), std::io::Error> {
+        use rustix::ioctl::{ReadWriteOpcode, Updater};
+
+        let mut copy = super::UffdioCopy {
+            dst,
+            src: src.as_ptr() as u64,
+            len: src.len() as u64,
+            mode: 0,
+            copy: 0,
+        };
+
+        unsafe {
+            rustix::ioctl::ioctl(
+                uffd,
+                Updater::<ReadWriteOpcode<0xAA, 0x03, super::UffdioCopy>, _>::new(&mut copy),
+            )
+        }
+        .map_err(|errno| std::io::Error::from_raw_os_error(errno.raw_os_error()))
+    }
+
+    #[inline]
+    pub fn uffdio_zeropage(uffd: BorrowedFd, dst: u64, len: u64) -> Result<(), std::io::Error> {
+        use rustix::ioctl::{ReadWriteOpcode, Updater};
+
+        let mut zeropage = super::UffdioZeropage {
+            range: super::UffdioRange { start: dst, len },
+            mode: 0,
+            zeropage: 0,
+        };
+
+        unsafe {
+            rustix::ioctl::ioctl(
+                uffd,
+                Updater::<ReadWriteOpcode<0xAA, 0x04, super::UffdioZeropage>, _>::new(&mut zeropage),
+            )
+        }
+        .map_err(|errno| std::io::Error::from_raw_os_error(errno.raw_os_error()))
+    }
+
+    #[inline]
+    pub fn uffdio_wake(uffd: BorrowedFd, start: u64, len: u64) -> Result<(), std::io::Error> {
+        use rustix::ioctl::{ReadOpcode, Updater};
+
+        let mut range = super::UffdioRange { start, len };
+
+        unsafe {
+            rustix::ioctl::ioctl(
+                uffd,
+                Updater::<ReadOpcode<0xAA, 0x02, super::UffdioRange>, _>::new(&mut range),
+            )
+        }
+        .map_err(|errno| std::io::Error::from_raw_os_error(errno.raw_os_error()))
+    }
+
+    #[inline]
+    pub fn punch_hole(fd: BorrowedFd, offset: u64, len: u64) -> Result<(), std::io::Error> {
+        rustix::fs::fallocate(
+            fd,
+            rustix::fs::FallocateFlags::PUNCH_HOLE | rustix::fs::FallocateFlags::KEEP_SIZE,
+            offset,
+            len,
+        )
+        .map_err(|errno| std::io::Error::from_raw_os_error(errno.raw_os_error()))
+    }
+
+    #[inline]
+    pub fn ficlone(destination: BorrowedFd, source: BorrowedFd) -> Result<(), std::io::Error> {
+        rustix::fs::ioctl_ficlone(destination, source)
+            .map_err(|errno| std::io::Error::from_raw_os_error(errno.raw_os_error()))
+    }
+
+    #[inline]
+    pub fn copy_file_range(
+        source: BorrowedFd,
+        source_offset: u64,
+        destination: BorrowedFd,
+        destination_offset: u64,
+        len: usize,
+    ) -> Result<usize, std::io::Error> {
+        let mut source_offset = source_offset;
+        let mut destination_offset = destination_offset;
+
+        rustix::fs::copy_file_range(
+            source,
+            Some(&mut source_offset),
+            destination,
+            Some(&mut destination_offset),
+            len,
+        )
+        .map_err(|errno| std::io::Error::from_raw_os_error(errno.raw_os_error()))
+    }
+
+    #[inline]
+    pub fn seek_data(fd: BorrowedFd, offset: u64) -> Result<Option<u64>, std::io::Error> {
+        match rustix::fs::seek(fd, rustix::fs::SeekFrom::Data(offset as i64)) {
+            Ok(offset) => Ok(Some(offset)),
+            Err(rustix::io::Errno::NXIO) => Ok(None),
+            Err(errno) => Err(std::io::Error::from_raw_os_error(errno.raw_os_error())),
+        }
+    }
+
+    #[inline]
+    pub fn seek_hole(fd: BorrowedFd, offset: u64) -> Result<u64, std::io::Error> {
+        rustix::fs::seek(fd, rustix::fs::SeekFrom::Hole(offset as i64))
+            .map_err(|errno| std::io::Error::from_raw_os_error(errno.raw_os_error()))
+    }
 }
 
 #[cfg(feature = "syscall-rustix")]
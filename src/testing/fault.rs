@@ -0,0 +1,160 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::vmm::arguments::command_modifier::CommandModifier;
+
+use super::MOCK_FAULTS_ARGUMENT;
+
+/// A fault that a [MockApiServer](super::server::MockApiServer) injects when it receives a request matching the
+/// fault's route and, optionally, its HTTP method. Faults are checked in the order they were added, and only the
+/// first matching fault is applied to a request.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct MockFault {
+    route: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    method: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    times: Option<u32>,
+    #[serde(default)]
+    skip: u32,
+    action: MockFaultAction,
+}
+
+impl MockFault {
+    /// Create a fault that applies the given [MockFaultAction] to every request made to the given route (for example,
+    /// "/drives/rootfs"), regardless of its HTTP method.
+    pub fn new(route: impl Into<String>, action: MockFaultAction) -> Self {
+        Self {
+            route: route.into(),
+            method: None,
+            times: None,
+            skip: 0,
+            action,
+        }
+    }
+
+    /// Only apply the fault to requests with the given HTTP method.
+    pub fn method(mut self, method: impl Into<String>) -> Self {
+        self.method = Some(method.into().to_uppercase());
+        self
+    }
+
+    /// Only apply the fault the given amount of times, after which matching requests are handled normally.
+    pub fn times(mut self, times: u32) -> Self {
+        self.times = Some(times);
+        self
+    }
+
+    /// Let the given amount of matching requests through before starting to apply the fault.
+    pub fn skip(mut self, skip: u32) -> Self {
+        self.skip = skip;
+        self
+    }
+
+    /// Try to apply this fault to a request with the given method and route, returning the [MockFaultAction] if the fault
+    /// matched and isn't exhausted or skipped.
+    pub(super) fn try_apply(&mut self, method: &str, route: &str) -> Option<MockFaultAction> {
+        if self.route != route {
+            return None;
+        }
+
+        if let Some(ref fault_method) = self.method {
+            if !fault_method.eq_ignore_ascii_case(method) {
+                return None;
+            }
+        }
+
+        if self.skip > 0 {
+            self.skip -= 1;
+            return None;
+        }
+
+        match self.times {
+            Some(0) => None,
+            Some(ref mut times) => {
+                *times -= 1;
+                Some(self.action.clone())
+            }
+            None => Some(self.action.clone()),
+        }
+    }
+}
+
+/// The action performed by a [MockApiServer](super::server::MockApiServer) when a [MockFault] is applied.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MockFaultAction {
+    /// Respond with the given status code and fault message instead of handling the request.
+    ErrorResponse { status_code: u16, fault_message: String },
+    /// Delay the handling of the request by the given amount of milliseconds.
+    Delay { millis: u64 },
+    /// Close the connection without sending any response.
+    CloseConnection,
+    /// Close the connection without sending any response and make the emulated VMM exit with the given exit code,
+    /// as if it had crashed.
+    Crash { exit_code: i32 },
+}
+
+/// A [CommandModifier] that passes a set of [MockFault]s to the fake "firecracker" binary. When used with a jailed
+/// executor, this modifier should be applied last so that the faults are passed through the fake "jailer" binary.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MockFaultCommandModifier {
+    faults: Vec<MockFault>,
+}
+
+impl MockFaultCommandModifier {
+    pub fn new(faults: impl IntoIterator<Item = MockFault>) -> Self {
+        Self {
+            faults: faults.into_iter().collect(),
+        }
+    }
+}
+
+impl CommandModifier for MockFaultCommandModifier {
+    fn apply(&self, _binary_path: &mut PathBuf, arguments: &mut Vec<String>) {
+        arguments.push(MOCK_FAULTS_ARGUMENT.to_string());
+        arguments.push(serde_json::to_string(&self.faults).expect("MockFault serialization cannot fail"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::vmm::arguments::command_modifier::CommandModifier;
+
+    use super::{MockFault, MockFaultAction, MockFaultCommandModifier};
+
+    #[test]
+    fn fault_matches_route_and_method() {
+        let mut fault = MockFault::new("/vm", MockFaultAction::CloseConnection).method("patch");
+        assert_eq!(fault.try_apply("PUT", "/vm"), None);
+        assert_eq!(fault.try_apply("PATCH", "/boot-source"), None);
+        assert_eq!(fault.try_apply("PATCH", "/vm"), Some(MockFaultAction::CloseConnection));
+        assert_eq!(fault.try_apply("PATCH", "/vm"), Some(MockFaultAction::CloseConnection));
+    }
+
+    #[test]
+    fn fault_respects_skip_and_times() {
+        let mut fault = MockFault::new("/actions", MockFaultAction::Delay { millis: 1 })
+            .skip(1)
+            .times(2);
+        assert_eq!(fault.try_apply("PUT", "/actions"), None);
+        assert!(fault.try_apply("PUT", "/actions").is_some());
+        assert!(fault.try_apply("PUT", "/actions").is_some());
+        assert_eq!(fault.try_apply("PUT", "/actions"), None);
+    }
+
+    #[test]
+    fn command_modifier_appends_faults() {
+        let faults = vec![MockFault::new("/", MockFaultAction::Crash { exit_code: 1 })];
+        let mut binary_path = PathBuf::from("/opt/firecracker");
+        let mut arguments = vec!["--api-sock".to_string(), "/tmp/socket".to_string()];
+        MockFaultCommandModifier::new(faults.clone()).apply(&mut binary_path, &mut arguments);
+
+        assert_eq!(binary_path, PathBuf::from("/opt/firecracker"));
+        assert_eq!(arguments[2], "--mock-faults");
+        assert_eq!(serde_json::from_str::<Vec<MockFault>>(&arguments[3]).unwrap(), faults);
+    }
+}
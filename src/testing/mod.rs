@@ -0,0 +1,29 @@
+//! Provides facilities for testing code built on fctools on hosts that have neither KVM nor a Firecracker installation.
+//! These are:
+//! - [MockApiServer](server::MockApiServer), an in-process mock of the Firecracker Management API served over HTTP on a
+//!   Unix socket. It tracks the state of an emulated VM, validates requests against it and can be scripted to inject faults.
+//! - [MockFault](fault::MockFault), a scripted fault and the [MockFaultCommandModifier](fault::MockFaultCommandModifier)
+//!   that passes faults to a fake binary through its arguments.
+//! - The "fctools-fake-firecracker" and "fctools-fake-jailer" binaries built from this crate with the `testing` feature.
+//!   They accept the same arguments as their real counterparts, serve a [MockApiServer](server::MockApiServer) and emulate
+//!   a serial console over stdio, so that both the unrestricted and the jailed VMM executors can be driven end-to-end.
+//!   A [VmmInstallation](crate::vmm::installation::VmmInstallation) pointing to them can be used like a real one.
+
+pub mod fault;
+
+pub mod server;
+
+/// The Firecracker version reported by the [MockApiServer](server::MockApiServer) and the fake binaries by default.
+pub const MOCK_FIRECRACKER_VERSION: &str = "1.10.1";
+
+/// The argument through which the fake "jailer" binary passes the path of the jail to the fake "firecracker" binary,
+/// in place of performing a chroot.
+pub const MOCK_JAIL_ROOT_ARGUMENT: &str = "--mock-jail-root";
+
+/// The argument through which [MockFault](fault::MockFault)s are passed to the fake "firecracker" binary as JSON.
+pub const MOCK_FAULTS_ARGUMENT: &str = "--mock-faults";
+
+/// The guest vsock port on which the [MockApiServer](server::MockApiServer) emulates an echo server inside the guest.
+/// Host-initiated connections to this port are accepted and all data sent over them is echoed back, while connections
+/// to any other port are refused.
+pub const MOCK_VSOCK_ECHO_PORT: u32 = 7000;
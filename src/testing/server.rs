@@ -0,0 +1,1306 @@
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex, MutexGuard,
+    },
+    thread::JoinHandle,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use http::{Request, Response, StatusCode};
+use http_body_util::{BodyExt, Full};
+use hyper::{body::Incoming, server::conn::http1, service::service_fn};
+use hyper_util::rt::TokioIo;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+};
+
+use super::{
+    fault::{MockFault, MockFaultAction},
+    MOCK_FIRECRACKER_VERSION, MOCK_VSOCK_ECHO_PORT,
+};
+
+const NOT_SUPPORTED_AFTER_START: &str = "The requested operation is not supported after starting the microVM.";
+const NOT_SUPPORTED_BEFORE_START: &str = "The requested operation is not supported before starting the microVM.";
+const MIB: u64 = 1024 * 1024;
+// Firecracker assigns host-side ports to host-initiated connections starting from 2^30
+const MOCK_VSOCK_FIRST_HOST_PORT: u32 = 1 << 30;
+
+/// An error that can occur when starting a [MockApiServer].
+#[derive(Debug)]
+pub enum MockApiServerError {
+    RuntimeBuildError(std::io::Error),
+    BindError(std::io::Error),
+    ThreadSpawnError(std::io::Error),
+    ConfigurationFileError(std::io::Error),
+    ConfigurationSerdeError(serde_json::Error),
+    ConfigurationRejected { route: String, fault_message: String },
+}
+
+impl std::error::Error for MockApiServerError {}
+
+impl std::fmt::Display for MockApiServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MockApiServerError::RuntimeBuildError(err) => {
+                write!(f, "Building the Tokio runtime of the mock API server failed: {err}")
+            }
+            MockApiServerError::BindError(err) => write!(f, "Binding the mock API server's socket failed: {err}"),
+            MockApiServerError::ThreadSpawnError(err) => {
+                write!(f, "Spawning the thread of the mock API server failed: {err}")
+            }
+            MockApiServerError::ConfigurationFileError(err) => {
+                write!(
+                    f,
+                    "Reading the configuration file or a file it references failed: {err}"
+                )
+            }
+            MockApiServerError::ConfigurationSerdeError(err) => {
+                write!(f, "Deserializing the configuration file failed: {err}")
+            }
+            MockApiServerError::ConfigurationRejected { route, fault_message } => {
+                write!(
+                    f,
+                    "The configuration for the \"{route}\" route was rejected: {fault_message}"
+                )
+            }
+        }
+    }
+}
+
+/// The operating state of the VM emulated by a [MockApiServer].
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MockVmState {
+    /// The VM hasn't been started or restored from a snapshot yet.
+    #[default]
+    NotStarted,
+    /// The VM is running.
+    Running,
+    /// The VM is paused.
+    Paused,
+}
+
+/// Everything that a [MockApiServer] has been configured with, alongside the state of its emulated VM. Device
+/// configurations are stored as the JSON values that were accepted by the server.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct MockVmmState {
+    pub vm_state: MockVmState,
+    pub boot_source: Option<Value>,
+    pub drives: BTreeMap<String, Value>,
+    pub machine_configuration: Value,
+    pub cpu_template: Option<Value>,
+    pub network_interfaces: BTreeMap<String, Value>,
+    pub balloon_device: Option<Value>,
+    pub vsock_device: Option<Value>,
+    pub logger_system: Option<Value>,
+    pub metrics_system: Option<Value>,
+    pub mmds_configuration: Option<Value>,
+    pub mmds: Option<Value>,
+    pub entropy_device: Option<Value>,
+    /// Whether the VM was restored from a snapshot instead of being booted.
+    pub restored_from_snapshot: bool,
+    /// The log of all requests that were handled by the server, in the order of their completion.
+    #[serde(skip)]
+    pub requests: Vec<MockApiRequest>,
+}
+
+impl Default for MockVmmState {
+    fn default() -> Self {
+        Self {
+            vm_state: MockVmState::NotStarted,
+            boot_source: None,
+            drives: BTreeMap::new(),
+            machine_configuration: serde_json::json!({
+                "vcpu_count": 1,
+                "mem_size_mib": 128,
+                "smt": false,
+                "track_dirty_pages": false,
+                "huge_pages": "None"
+            }),
+            cpu_template: None,
+            network_interfaces: BTreeMap::new(),
+            balloon_device: None,
+            vsock_device: None,
+            logger_system: None,
+            metrics_system: None,
+            mmds_configuration: None,
+            mmds: None,
+            entropy_device: None,
+            restored_from_snapshot: false,
+            requests: Vec::new(),
+        }
+    }
+}
+
+/// A request handled by a [MockApiServer].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MockApiRequest {
+    pub method: String,
+    pub route: String,
+    /// The status code of the response, or [None] if the connection was closed due to a [MockFault].
+    pub status_code: Option<u16>,
+}
+
+/// An event that occurred in the VMM emulated by a [MockApiServer].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MockVmmEvent {
+    /// The VM was booted.
+    Started,
+    /// The VM was paused.
+    Paused,
+    /// The VM was resumed.
+    Resumed,
+    /// A snapshot of the VM was created.
+    SnapshotCreated,
+    /// The VM was restored from a snapshot.
+    SnapshotLoaded,
+    /// The VMM should exit with the given exit code, either due to a Ctrl+Alt+Del or a [MockFaultAction::Crash].
+    Exited(i32),
+}
+
+/// An in-process mock of the Firecracker Management API that serves HTTP over a Unix socket on a dedicated thread,
+/// thus being usable regardless of the async runtime of the caller. The server tracks the state of an emulated VM
+/// and validates requests against it similarly to Firecracker: pre-boot resources can only be configured before
+/// starting, referenced files must exist, snapshots can only be created when paused and so on. Snapshots are
+/// written to disk in a format that only a [MockApiServer] understands, alongside sparse memory files.
+#[derive(Debug)]
+pub struct MockApiServer {
+    socket_path: Option<PathBuf>,
+    jail_root: Option<PathBuf>,
+    id: String,
+    firecracker_version: String,
+    faults: Vec<MockFault>,
+    config_path: Option<PathBuf>,
+    log_path: Option<PathBuf>,
+    metrics_path: Option<PathBuf>,
+    metadata_path: Option<PathBuf>,
+    ctrl_alt_del_delay: Duration,
+}
+
+impl Default for MockApiServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockApiServer {
+    /// Create a [MockApiServer] with no socket, only able to boot from a configuration file.
+    pub fn new() -> Self {
+        Self {
+            socket_path: None,
+            jail_root: None,
+            id: "anonymous-instance".to_string(),
+            firecracker_version: MOCK_FIRECRACKER_VERSION.to_string(),
+            faults: Vec::new(),
+            config_path: None,
+            log_path: None,
+            metrics_path: None,
+            metadata_path: None,
+            ctrl_alt_del_delay: Duration::from_millis(50),
+        }
+    }
+
+    /// Serve the API on a Unix socket bound at the given path.
+    pub fn socket_path(mut self, socket_path: impl Into<PathBuf>) -> Self {
+        self.socket_path = Some(socket_path.into());
+        self
+    }
+
+    /// Resolve all paths, including that of the socket, relative to the given jail root directory, emulating a chroot.
+    pub fn jail_root(mut self, jail_root: impl Into<PathBuf>) -> Self {
+        self.jail_root = Some(jail_root.into());
+        self
+    }
+
+    pub fn id(mut self, id: impl Into<String>) -> Self {
+        self.id = id.into();
+        self
+    }
+
+    pub fn firecracker_version(mut self, firecracker_version: impl Into<String>) -> Self {
+        self.firecracker_version = firecracker_version.into();
+        self
+    }
+
+    pub fn fault(mut self, fault: MockFault) -> Self {
+        self.faults.push(fault);
+        self
+    }
+
+    pub fn faults(mut self, faults: impl IntoIterator<Item = MockFault>) -> Self {
+        self.faults.extend(faults);
+        self
+    }
+
+    /// Configure and boot the VM from the given Firecracker JSON configuration file when starting.
+    pub fn config_path(mut self, config_path: impl Into<PathBuf>) -> Self {
+        self.config_path = Some(config_path.into());
+        self
+    }
+
+    pub fn log_path(mut self, log_path: impl Into<PathBuf>) -> Self {
+        self.log_path = Some(log_path.into());
+        self
+    }
+
+    pub fn metrics_path(mut self, metrics_path: impl Into<PathBuf>) -> Self {
+        self.metrics_path = Some(metrics_path.into());
+        self
+    }
+
+    /// Load the initial contents of the MMDS data store from the given JSON file.
+    pub fn metadata_path(mut self, metadata_path: impl Into<PathBuf>) -> Self {
+        self.metadata_path = Some(metadata_path.into());
+        self
+    }
+
+    /// The emulated time it takes for the guest to shut down after receiving a Ctrl+Alt+Del, 50ms by default.
+    pub fn ctrl_alt_del_delay(mut self, ctrl_alt_del_delay: Duration) -> Self {
+        self.ctrl_alt_del_delay = ctrl_alt_del_delay;
+        self
+    }
+
+    /// Apply the initial configuration, bind the socket and start serving requests on a dedicated thread. The socket
+    /// is guaranteed to be bound once this function returns.
+    pub fn start(self) -> Result<MockApiServerHandle, MockApiServerError> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(MockApiServerError::RuntimeBuildError)?;
+        let (event_tx, event_rx) = mpsc::channel();
+
+        let vmm = Arc::new(MockVmm {
+            state: Mutex::new(MockVmmState::default()),
+            faults: Mutex::new(self.faults),
+            jail_root: self.jail_root,
+            id: self.id,
+            firecracker_version: self.firecracker_version,
+            ctrl_alt_del_delay: self.ctrl_alt_del_delay,
+            event_tx,
+            exited: Arc::new(AtomicBool::new(false)),
+            vsock_task: Mutex::new(None),
+        });
+
+        let listener = runtime.block_on(async {
+            if let Some(log_path) = self.log_path {
+                vmm.apply_initial("/logger", serde_json::json!({ "log_path": log_path }))?;
+            }
+
+            if let Some(metrics_path) = self.metrics_path {
+                vmm.apply_initial("/metrics", serde_json::json!({ "metrics_path": metrics_path }))?;
+            }
+
+            if let Some(metadata_path) = self.metadata_path {
+                let content = std::fs::read_to_string(vmm.resolve(&metadata_path))
+                    .map_err(MockApiServerError::ConfigurationFileError)?;
+                let value = serde_json::from_str(&content).map_err(MockApiServerError::ConfigurationSerdeError)?;
+                vmm.apply_initial("/mmds", value)?;
+            }
+
+            if let Some(config_path) = self.config_path {
+                vmm.apply_configuration_file(&config_path)?;
+            }
+
+            match self.socket_path {
+                Some(socket_path) => UnixListener::bind(vmm.resolve(&socket_path))
+                    .map(Some)
+                    .map_err(MockApiServerError::BindError),
+                None => Ok(None),
+            }
+        })?;
+
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
+        let thread_vmm = vmm.clone();
+        let thread = std::thread::Builder::new()
+            .name("fctools-mock-api-server".to_string())
+            .spawn(move || {
+                runtime.block_on(async move {
+                    tokio::select! {
+                        _ = serve(listener, thread_vmm) => {},
+                        _ = shutdown_rx => {},
+                    }
+                });
+            })
+            .map_err(MockApiServerError::ThreadSpawnError)?;
+
+        Ok(MockApiServerHandle {
+            vmm,
+            event_rx,
+            shutdown_tx: Some(shutdown_tx),
+            thread: Some(thread),
+        })
+    }
+}
+
+/// A handle to a started [MockApiServer] that allows inspecting its state, scripting faults and receiving
+/// [MockVmmEvent]s. Dropping the handle stops the server.
+#[derive(Debug)]
+pub struct MockApiServerHandle {
+    vmm: Arc<MockVmm>,
+    event_rx: mpsc::Receiver<MockVmmEvent>,
+    shutdown_tx: Option<tokio::sync::oneshot::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MockApiServerHandle {
+    /// Get a copy of the current [MockVmmState].
+    pub fn state(&self) -> MockVmmState {
+        self.vmm.lock_state().clone()
+    }
+
+    /// Add a [MockFault] that will be checked after all previously added faults.
+    pub fn add_fault(&self, fault: MockFault) {
+        self.vmm.faults.lock().expect("Faults mutex was poisoned").push(fault);
+    }
+
+    /// Block the current thread until the next [MockVmmEvent] occurs. Returns [None] if the server has stopped.
+    pub fn recv_event(&self) -> Option<MockVmmEvent> {
+        self.event_rx.recv().ok()
+    }
+
+    /// Block the current thread until the next [MockVmmEvent] occurs or the timeout elapses.
+    pub fn recv_event_timeout(&self, timeout: Duration) -> Option<MockVmmEvent> {
+        self.event_rx.recv_timeout(timeout).ok()
+    }
+
+    /// Get the next [MockVmmEvent] if one has already occurred, without blocking.
+    pub fn try_recv_event(&self) -> Option<MockVmmEvent> {
+        self.event_rx.try_recv().ok()
+    }
+
+    /// Stop serving requests and wait for the server thread to finish.
+    pub fn shutdown(mut self) {
+        self.shutdown_internal();
+    }
+
+    fn shutdown_internal(&mut self) {
+        if let Some(shutdown_tx) = self.shutdown_tx.take() {
+            let _ = shutdown_tx.send(());
+        }
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for MockApiServerHandle {
+    fn drop(&mut self) {
+        self.shutdown_internal();
+    }
+}
+
+#[derive(Debug)]
+struct MockVmm {
+    state: Mutex<MockVmmState>,
+    faults: Mutex<Vec<MockFault>>,
+    jail_root: Option<PathBuf>,
+    id: String,
+    firecracker_version: String,
+    ctrl_alt_del_delay: Duration,
+    event_tx: mpsc::Sender<MockVmmEvent>,
+    exited: Arc<AtomicBool>,
+    vsock_task: Mutex<Option<tokio::task::JoinHandle<()>>>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct MockSnapshot {
+    firecracker_version: String,
+    state: MockVmmState,
+}
+
+type HandlerResult = Result<Option<Value>, String>;
+
+async fn serve(listener: Option<UnixListener>, vmm: Arc<MockVmm>) {
+    let Some(listener) = listener else {
+        return std::future::pending().await;
+    };
+
+    loop {
+        let Ok((stream, _)) = listener.accept().await else {
+            continue;
+        };
+        let vmm = vmm.clone();
+
+        tokio::spawn(async move {
+            let _ = http1::Builder::new()
+                .serve_connection(
+                    TokioIo::new(stream),
+                    service_fn(move |request| serve_request(vmm.clone(), request)),
+                )
+                .await;
+        });
+    }
+}
+
+async fn serve_request(vmm: Arc<MockVmm>, request: Request<Incoming>) -> Result<Response<Full<Bytes>>, std::io::Error> {
+    if vmm.exited.load(Ordering::Acquire) {
+        return Err(std::io::Error::other("The mock VMM has exited"));
+    }
+
+    let method = request.method().as_str().to_owned();
+    let route = request.uri().path().to_owned();
+    let body = request
+        .into_body()
+        .collect()
+        .await
+        .map_err(std::io::Error::other)?
+        .to_bytes();
+
+    let fault_action = vmm
+        .faults
+        .lock()
+        .expect("Faults mutex was poisoned")
+        .iter_mut()
+        .find_map(|fault| fault.try_apply(&method, &route));
+
+    let result = match fault_action {
+        Some(MockFaultAction::ErrorResponse {
+            status_code,
+            fault_message,
+        }) => Err((
+            StatusCode::from_u16(status_code).unwrap_or(StatusCode::BAD_REQUEST),
+            fault_message,
+        )),
+        Some(MockFaultAction::CloseConnection) => {
+            vmm.record(method, route, None);
+            return Err(std::io::Error::other("Connection closed by a mock fault"));
+        }
+        Some(MockFaultAction::Crash { exit_code }) => {
+            vmm.record(method, route, None);
+            vmm.exit(exit_code);
+            return Err(std::io::Error::other("Mock VMM crashed due to a mock fault"));
+        }
+        Some(MockFaultAction::Delay { millis }) => {
+            tokio::time::sleep(Duration::from_millis(millis)).await;
+            vmm.handle(&method, &route, &body)
+                .map_err(|fault_message| (StatusCode::BAD_REQUEST, fault_message))
+        }
+        None => vmm
+            .handle(&method, &route, &body)
+            .map_err(|fault_message| (StatusCode::BAD_REQUEST, fault_message)),
+    };
+
+    let response = match result {
+        Ok(Some(value)) => Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Full::new(Bytes::from(value.to_string()))),
+        Ok(None) => Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Full::new(Bytes::new())),
+        Err((status_code, fault_message)) => Response::builder()
+            .status(status_code)
+            .header("Content-Type", "application/json")
+            .body(Full::new(Bytes::from(
+                serde_json::json!({ "fault_message": fault_message }).to_string(),
+            ))),
+    }
+    .map_err(std::io::Error::other)?;
+
+    vmm.record(method, route, Some(response.status().as_u16()));
+    Ok(response)
+}
+
+async fn serve_vsock(listener: UnixListener) {
+    let mut host_port = MOCK_VSOCK_FIRST_HOST_PORT;
+
+    while let Ok((stream, _)) = listener.accept().await {
+        tokio::spawn(serve_vsock_connection(stream, host_port));
+        host_port = host_port.wrapping_add(1);
+    }
+}
+
+async fn serve_vsock_connection(stream: UnixStream, host_port: u32) {
+    // Only an echo server is emulated inside the guest, so connections to any other port are dropped after the CONNECT
+    // command, as Firecracker would do for a port that nothing listens on inside the guest.
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    if reader.read_line(&mut line).await.is_err() || line != format!("CONNECT {MOCK_VSOCK_ECHO_PORT}\n") {
+        return;
+    }
+
+    let mut stream = reader.into_inner();
+    if stream.write_all(format!("OK {host_port}\n").as_bytes()).await.is_err() {
+        return;
+    }
+
+    let (mut read_half, mut write_half) = stream.split();
+    let _ = tokio::io::copy(&mut read_half, &mut write_half).await;
+}
+
+impl MockVmm {
+    fn lock_state(&self) -> MutexGuard<'_, MockVmmState> {
+        self.state.lock().expect("State mutex was poisoned")
+    }
+
+    fn resolve(&self, path: impl AsRef<Path>) -> PathBuf {
+        let path = path.as_ref();
+        match self.jail_root {
+            Some(ref jail_root) => jail_root.join(path.strip_prefix("/").unwrap_or(path)),
+            None => path.to_owned(),
+        }
+    }
+
+    fn emit(&self, event: MockVmmEvent) {
+        let _ = self.event_tx.send(event);
+    }
+
+    fn exit(&self, exit_code: i32) {
+        if !self.exited.swap(true, Ordering::AcqRel) {
+            self.emit(MockVmmEvent::Exited(exit_code));
+        }
+    }
+
+    fn record(&self, method: String, route: String, status_code: Option<u16>) {
+        self.lock_state().requests.push(MockApiRequest {
+            method,
+            route,
+            status_code,
+        });
+    }
+
+    fn log(&self, state: &MockVmmState, message: &str) {
+        let Some(log_path) = state
+            .logger_system
+            .as_ref()
+            .and_then(|logger| field(logger, "log_path"))
+            .and_then(Value::as_str)
+        else {
+            return;
+        };
+
+        let log_path = self.resolve(log_path);
+        // writing to a FIFO without a reader would block the server, so only regular files receive log lines
+        if std::fs::metadata(&log_path).is_ok_and(|metadata| metadata.file_type().is_file()) {
+            if let Ok(mut file) = std::fs::OpenOptions::new().append(true).open(log_path) {
+                let _ = writeln!(file, "{} [{}:main] {message}", timestamp_ms(), self.id);
+            }
+        }
+    }
+
+    fn apply_initial(&self, route: &str, body: Value) -> Result<(), MockApiServerError> {
+        self.handle_parsed("PUT", route, Some(body))
+            .map(|_| ())
+            .map_err(|fault_message| MockApiServerError::ConfigurationRejected {
+                route: route.to_owned(),
+                fault_message,
+            })
+    }
+
+    fn apply_configuration_file(&self, config_path: &Path) -> Result<(), MockApiServerError> {
+        let content =
+            std::fs::read_to_string(self.resolve(config_path)).map_err(MockApiServerError::ConfigurationFileError)?;
+        let configuration: Value =
+            serde_json::from_str(&content).map_err(MockApiServerError::ConfigurationSerdeError)?;
+
+        for (key, route) in [
+            ("logger", "/logger"),
+            ("metrics", "/metrics"),
+            ("boot-source", "/boot-source"),
+            ("machine-config", "/machine-config"),
+            ("cpu-config", "/cpu-config"),
+        ] {
+            if let Some(value) = field(&configuration, key) {
+                self.apply_initial(route, value.clone())?;
+            }
+        }
+
+        if let Some(drives) = field(&configuration, "drives").and_then(Value::as_array) {
+            for drive in drives {
+                let drive_id = field(drive, "drive_id").and_then(Value::as_str).unwrap_or_default();
+                self.apply_initial(&format!("/drives/{drive_id}"), drive.clone())?;
+            }
+        }
+
+        if let Some(network_interfaces) = field(&configuration, "network-interfaces").and_then(Value::as_array) {
+            for network_interface in network_interfaces {
+                let iface_id = field(network_interface, "iface_id")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default();
+                self.apply_initial(&format!("/network-interfaces/{iface_id}"), network_interface.clone())?;
+            }
+        }
+
+        for (key, route) in [
+            ("balloon", "/balloon"),
+            ("vsock", "/vsock"),
+            ("mmds-config", "/mmds/config"),
+            ("entropy", "/entropy"),
+        ] {
+            if let Some(value) = field(&configuration, key) {
+                self.apply_initial(route, value.clone())?;
+            }
+        }
+
+        self.apply_initial("/actions", serde_json::json!({ "action_type": "InstanceStart" }))
+    }
+
+    fn handle(&self, method: &str, route: &str, body: &[u8]) -> HandlerResult {
+        let body =
+            match body.iter().all(u8::is_ascii_whitespace) {
+                true => None,
+                false => Some(serde_json::from_slice(body).map_err(|err| {
+                    format!("An error occurred when deserializing the json body of a request: {err}.")
+                })?),
+            };
+
+        self.handle_parsed(method, route, body)
+    }
+
+    fn handle_parsed(&self, method: &str, route: &str, body: Option<Value>) -> HandlerResult {
+        let segments = route
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect::<Vec<_>>();
+
+        match (method, segments.as_slice()) {
+            ("GET", []) => self.get_info(),
+            ("GET", ["version"]) => Ok(Some(serde_json::json!({
+                "firecracker_version": self.firecracker_version
+            }))),
+            ("GET", ["machine-config"]) => Ok(Some(self.lock_state().machine_configuration.clone())),
+            ("PUT", ["machine-config"]) => self.put_machine_configuration(required(body)?),
+            ("PUT", ["boot-source"]) => self.put_boot_source(required(body)?),
+            ("PUT", ["drives", drive_id]) => self.put_drive(drive_id, required(body)?),
+            ("PATCH", ["drives", drive_id]) => self.patch_drive(drive_id, required(body)?),
+            ("PUT", ["network-interfaces", iface_id]) => self.put_network_interface(iface_id, required(body)?),
+            ("PATCH", ["network-interfaces", iface_id]) => self.patch_network_interface(iface_id, required(body)?),
+            ("PUT", ["cpu-config"]) => self.put_pre_boot(required(body)?, |state, body| {
+                state.cpu_template = Some(body);
+                Ok(())
+            }),
+            ("PUT", ["entropy"]) => self.put_pre_boot(required(body)?, |state, body| {
+                state.entropy_device = Some(body);
+                Ok(())
+            }),
+            ("PUT", ["balloon"]) => self.put_balloon(required(body)?),
+            ("GET", ["balloon"]) => self.get_balloon(),
+            ("PATCH", ["balloon"]) => self.patch_balloon(required(body)?),
+            ("GET", ["balloon", "statistics"]) => self.get_balloon_statistics(),
+            ("PATCH", ["balloon", "statistics"]) => self.patch_balloon_statistics(required(body)?),
+            ("PUT", ["vsock"]) => self.put_vsock(required(body)?),
+            ("PUT", ["logger"]) => self.put_logger(required(body)?),
+            ("PUT", ["metrics"]) => self.put_metrics(required(body)?),
+            ("PUT", ["mmds", "config"]) => self.put_mmds_configuration(required(body)?),
+            ("PUT", ["mmds"]) => {
+                self.lock_state().mmds = Some(required(body)?);
+                Ok(None)
+            }
+            ("PATCH", ["mmds"]) => self.patch_mmds(required(body)?),
+            ("GET", ["mmds"]) => Ok(Some(
+                self.lock_state()
+                    .mmds
+                    .clone()
+                    .unwrap_or_else(|| Value::Object(Default::default())),
+            )),
+            ("PUT", ["actions"]) => self.put_action(required(body)?),
+            ("PATCH", ["vm"]) => self.patch_vm(required(body)?),
+            ("PUT", ["snapshot", "create"]) => self.create_snapshot(required(body)?),
+            ("PUT", ["snapshot", "load"]) => self.load_snapshot(required(body)?),
+            _ => Err(format!("Invalid request method and/or path: {method} {route}.")),
+        }
+    }
+
+    fn get_info(&self) -> HandlerResult {
+        let state = match self.lock_state().vm_state {
+            MockVmState::NotStarted => "Not started",
+            MockVmState::Running => "Running",
+            MockVmState::Paused => "Paused",
+        };
+
+        Ok(Some(serde_json::json!({
+            "id": self.id,
+            "state": state,
+            "vmm_version": self.firecracker_version,
+            "app_name": "Firecracker"
+        })))
+    }
+
+    fn put_pre_boot(
+        &self,
+        body: Value,
+        apply: impl FnOnce(&mut MockVmmState, Value) -> Result<(), String>,
+    ) -> HandlerResult {
+        let mut state = self.lock_state();
+        ensure_not_started(&state)?;
+        apply(&mut state, body)?;
+        Ok(None)
+    }
+
+    fn put_machine_configuration(&self, body: Value) -> HandlerResult {
+        self.put_pre_boot(body, |state, body| {
+            let vcpu_count = required_u64(&body, "vcpu_count")?;
+            if !(1..=32).contains(&vcpu_count) {
+                return Err(
+                    "The vCPU number is invalid! The vCPU number can only be 1 or an even number when SMT is enabled."
+                        .to_string(),
+                );
+            }
+
+            if required_u64(&body, "mem_size_mib")? == 0 {
+                return Err("The memory size (MiB) is invalid.".to_string());
+            }
+
+            state.machine_configuration = body;
+            Ok(())
+        })
+    }
+
+    fn put_boot_source(&self, body: Value) -> HandlerResult {
+        self.put_pre_boot(body, |state, body| {
+            self.ensure_file_exists(required_str(&body, "kernel_image_path")?, "kernel")?;
+
+            if let Some(initrd_path) = field(&body, "initrd_path").and_then(Value::as_str) {
+                self.ensure_file_exists(initrd_path, "initrd")?;
+            }
+
+            state.boot_source = Some(body);
+            Ok(())
+        })
+    }
+
+    fn put_drive(&self, drive_id: &str, body: Value) -> HandlerResult {
+        self.put_pre_boot(body, |state, body| {
+            ensure_id_matches(drive_id, required_str(&body, "drive_id")?)?;
+
+            match field(&body, "socket").and_then(Value::as_str) {
+                Some(socket) => self.ensure_file_exists(socket, "vhost-user socket")?,
+                None => self.ensure_file_exists(required_str(&body, "path_on_host")?, "block device")?,
+            }
+
+            if field(&body, "is_root_device").and_then(Value::as_bool) == Some(true)
+                && state.drives.iter().any(|(other_drive_id, other_drive)| {
+                    other_drive_id != drive_id
+                        && field(other_drive, "is_root_device").and_then(Value::as_bool) == Some(true)
+                })
+            {
+                return Err("A root block device already exists!".to_string());
+            }
+
+            state.drives.insert(drive_id.to_owned(), body);
+            Ok(())
+        })
+    }
+
+    fn patch_drive(&self, drive_id: &str, body: Value) -> HandlerResult {
+        let mut state = self.lock_state();
+        ensure_started(&state)?;
+        ensure_id_matches(drive_id, required_str(&body, "drive_id")?)?;
+
+        if let Some(path_on_host) = field(&body, "path_on_host").and_then(Value::as_str) {
+            self.ensure_file_exists(path_on_host, "block device")?;
+        }
+
+        let drive = state
+            .drives
+            .get_mut(drive_id)
+            .ok_or_else(|| format!("Invalid block device ID: {drive_id}."))?;
+        merge_fields(drive, body);
+        Ok(None)
+    }
+
+    fn put_network_interface(&self, iface_id: &str, body: Value) -> HandlerResult {
+        self.put_pre_boot(body, |state, body| {
+            ensure_id_matches(iface_id, required_str(&body, "iface_id")?)?;
+            let host_dev_name = required_str(&body, "host_dev_name")?;
+
+            if state.network_interfaces.iter().any(|(other_iface_id, other_iface)| {
+                other_iface_id != iface_id
+                    && field(other_iface, "host_dev_name").and_then(Value::as_str) == Some(host_dev_name)
+            }) {
+                return Err(format!("The host device name {host_dev_name} is already in use."));
+            }
+
+            state.network_interfaces.insert(iface_id.to_owned(), body);
+            Ok(())
+        })
+    }
+
+    fn patch_network_interface(&self, iface_id: &str, body: Value) -> HandlerResult {
+        let mut state = self.lock_state();
+        ensure_started(&state)?;
+        ensure_id_matches(iface_id, required_str(&body, "iface_id")?)?;
+
+        let network_interface = state
+            .network_interfaces
+            .get_mut(iface_id)
+            .ok_or_else(|| format!("Invalid network interface ID: {iface_id}."))?;
+        merge_fields(network_interface, body);
+        Ok(None)
+    }
+
+    fn put_balloon(&self, body: Value) -> HandlerResult {
+        self.put_pre_boot(body, |state, body| {
+            let amount_mib = required_u64(&body, "amount_mib")?;
+            if amount_mib > mem_size_mib(state) {
+                return Err("Amount of pages requested is too large.".to_string());
+            }
+
+            state.balloon_device = Some(body);
+            Ok(())
+        })
+    }
+
+    fn get_balloon(&self) -> HandlerResult {
+        self.lock_state()
+            .balloon_device
+            .clone()
+            .map(Some)
+            .ok_or_else(|| "No balloon device was configured.".to_string())
+    }
+
+    fn patch_balloon(&self, body: Value) -> HandlerResult {
+        let mut state = self.lock_state();
+        ensure_started(&state)?;
+        let amount_mib = required_u64(&body, "amount_mib")?;
+        if amount_mib > mem_size_mib(&state) {
+            return Err("Amount of pages requested is too large.".to_string());
+        }
+
+        let balloon_device = state
+            .balloon_device
+            .as_mut()
+            .ok_or_else(|| "No balloon device was configured.".to_string())?;
+        merge_fields(balloon_device, body);
+        Ok(None)
+    }
+
+    fn get_balloon_statistics(&self) -> HandlerResult {
+        let state = self.lock_state();
+        ensure_started(&state)?;
+        let balloon_device = state
+            .balloon_device
+            .as_ref()
+            .ok_or_else(|| "No balloon device was configured.".to_string())?;
+
+        if field(balloon_device, "stats_polling_interval_s")
+            .and_then(Value::as_u64)
+            .unwrap_or_default()
+            == 0
+        {
+            return Err("Statistics for the balloon device are not enabled".to_string());
+        }
+
+        let amount_mib = required_u64(balloon_device, "amount_mib")?;
+        let free_memory = (mem_size_mib(&state) - amount_mib) * MIB;
+
+        Ok(Some(serde_json::json!({
+            "target_pages": amount_mib * 256,
+            "actual_pages": amount_mib * 256,
+            "target_mib": amount_mib,
+            "actual_mib": amount_mib,
+            "swap_in": 0,
+            "swap_out": 0,
+            "major_faults": 0,
+            "minor_faults": 0,
+            "free_memory": free_memory,
+            "available_memory": free_memory,
+            "disk_caches": 0
+        })))
+    }
+
+    fn patch_balloon_statistics(&self, body: Value) -> HandlerResult {
+        let mut state = self.lock_state();
+        ensure_started(&state)?;
+        let stats_polling_interval_s = required_u64(&body, "stats_polling_interval_s")?;
+
+        let balloon_device = state
+            .balloon_device
+            .as_mut()
+            .ok_or_else(|| "No balloon device was configured.".to_string())?;
+        let previous_interval = field(balloon_device, "stats_polling_interval_s")
+            .and_then(Value::as_u64)
+            .unwrap_or_default();
+
+        if (previous_interval == 0) != (stats_polling_interval_s == 0) {
+            return Err("Cannot enable or disable the statistics after boot.".to_string());
+        }
+
+        merge_fields(balloon_device, body);
+        Ok(None)
+    }
+
+    fn put_vsock(&self, body: Value) -> HandlerResult {
+        self.put_pre_boot(body, |state, body| {
+            if required_u64(&body, "guest_cid")? < 3 {
+                return Err("Invalid guest CID: the CID must be at least 3.".to_string());
+            }
+
+            self.bind_vsock(required_str(&body, "uds_path")?)?;
+            state.vsock_device = Some(body);
+            Ok(())
+        })
+    }
+
+    fn bind_vsock(&self, uds_path: &str) -> Result<(), String> {
+        let listener = UnixListener::bind(self.resolve(uds_path))
+            .map_err(|err| format!("Cannot create backend for vsock device: {err}"))?;
+        let mut vsock_task = self.vsock_task.lock().expect("Vsock task mutex was poisoned");
+
+        if let Some(previous_task) = vsock_task.replace(tokio::spawn(serve_vsock(listener))) {
+            previous_task.abort();
+        }
+
+        Ok(())
+    }
+
+    fn put_logger(&self, body: Value) -> HandlerResult {
+        self.put_pre_boot(body, |state, body| {
+            if let Some(log_path) = field(&body, "log_path").and_then(Value::as_str) {
+                self.ensure_file_exists(log_path, "log")?;
+            }
+
+            state.logger_system = Some(body);
+            Ok(())
+        })
+    }
+
+    fn put_metrics(&self, body: Value) -> HandlerResult {
+        self.put_pre_boot(body, |state, body| {
+            self.ensure_file_exists(required_str(&body, "metrics_path")?, "metrics")?;
+            state.metrics_system = Some(body);
+            Ok(())
+        })
+    }
+
+    fn put_mmds_configuration(&self, body: Value) -> HandlerResult {
+        self.put_pre_boot(body, |state, body| {
+            let network_interfaces = field(&body, "network_interfaces")
+                .and_then(Value::as_array)
+                .ok_or_else(|| missing_field("network_interfaces"))?;
+
+            if network_interfaces.is_empty() {
+                return Err("The list of network interface IDs that allow forwarding MMDS requests is empty.".to_string());
+            }
+
+            if network_interfaces.iter().any(|iface_id| {
+                iface_id
+                    .as_str()
+                    .map_or(true, |iface_id| !state.network_interfaces.contains_key(iface_id))
+            }) {
+                return Err("The list of network interface IDs provided contains at least one ID that does not correspond to any existing network interface.".to_string());
+            }
+
+            state.mmds_configuration = Some(body);
+            Ok(())
+        })
+    }
+
+    fn patch_mmds(&self, body: Value) -> HandlerResult {
+        let mut state = self.lock_state();
+        let mmds = state
+            .mmds
+            .as_mut()
+            .ok_or_else(|| "The MMDS data store is not initialized.".to_string())?;
+        merge_patch(mmds, &body);
+        Ok(None)
+    }
+
+    fn put_action(&self, body: Value) -> HandlerResult {
+        let mut state = self.lock_state();
+
+        match required_str(&body, "action_type")? {
+            "InstanceStart" => {
+                ensure_not_started(&state)?;
+                if state.boot_source.is_none() {
+                    return Err("Cannot start microvm without kernel configuration.".to_string());
+                }
+
+                state.vm_state = MockVmState::Running;
+                self.log(&state, "Artificially kick devices.");
+                self.emit(MockVmmEvent::Started);
+            }
+            "FlushMetrics" => {
+                ensure_started(&state)?;
+                let metrics_path = state
+                    .metrics_system
+                    .as_ref()
+                    .and_then(|metrics| field(metrics, "metrics_path"))
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| "The metrics system is not initialized.".to_string())?;
+                let metrics_path = self.resolve(metrics_path);
+
+                if std::fs::metadata(&metrics_path).is_ok_and(|metadata| !metadata.file_type().is_fifo()) {
+                    let mut file = std::fs::OpenOptions::new()
+                        .append(true)
+                        .open(metrics_path)
+                        .map_err(|err| format!("Cannot flush metrics: {err}"))?;
+                    writeln!(file, "{}", serde_json::json!({ "utc_timestamp_ms": timestamp_ms() }))
+                        .map_err(|err| format!("Cannot flush metrics: {err}"))?;
+                }
+            }
+            "SendCtrlAltDel" => {
+                ensure_started(&state)?;
+                let event_tx = self.event_tx.clone();
+                let exited = self.exited.clone();
+                let delay = self.ctrl_alt_del_delay;
+                self.log(&state, "Received Ctrl+Alt+Del, shutting down.");
+
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    if !exited.swap(true, Ordering::AcqRel) {
+                        let _ = event_tx.send(MockVmmEvent::Exited(0));
+                    }
+                });
+            }
+            action_type => {
+                return Err(format!(
+                    "An error occurred when deserializing the json body of a request: unknown variant `{action_type}`."
+                ))
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn patch_vm(&self, body: Value) -> HandlerResult {
+        let mut state = self.lock_state();
+        ensure_started(&state)?;
+
+        match required_str(&body, "state")? {
+            "Paused" => {
+                state.vm_state = MockVmState::Paused;
+                self.emit(MockVmmEvent::Paused);
+            }
+            "Resumed" => {
+                state.vm_state = MockVmState::Running;
+                self.emit(MockVmmEvent::Resumed);
+            }
+            vm_state => {
+                return Err(format!(
+                    "An error occurred when deserializing the json body of a request: unknown variant `{vm_state}`."
+                ))
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn create_snapshot(&self, body: Value) -> HandlerResult {
+        let state = self.lock_state();
+        ensure_started(&state)?;
+        if state.vm_state != MockVmState::Paused {
+            return Err("Cannot create a snapshot of a microVM that is not paused.".to_string());
+        }
+
+        if field(&body, "snapshot_type").and_then(Value::as_str) == Some("Diff")
+            && field(&state.machine_configuration, "track_dirty_pages").and_then(Value::as_bool) != Some(true)
+        {
+            return Err("Diff snapshots are not enabled: dirty page tracking must be enabled.".to_string());
+        }
+
+        let snapshot_path = self.resolve(required_str(&body, "snapshot_path")?);
+        let mem_file_path = self.resolve(required_str(&body, "mem_file_path")?);
+
+        let snapshot = MockSnapshot {
+            firecracker_version: self.firecracker_version.clone(),
+            state: state.clone(),
+        };
+        let snapshot_json =
+            serde_json::to_string(&snapshot).map_err(|err| format!("Cannot save the microVM state: {err}"))?;
+        std::fs::write(&snapshot_path, snapshot_json).map_err(|err| format!("Cannot save the microVM state: {err}"))?;
+
+        let mem_file = std::fs::File::create(&mem_file_path).map_err(|err| format!("Cannot save the memory: {err}"))?;
+        mem_file
+            .set_len(mem_size_mib(&state) * MIB)
+            .map_err(|err| format!("Cannot save the memory: {err}"))?;
+
+        self.log(&state, "Snapshot created.");
+        self.emit(MockVmmEvent::SnapshotCreated);
+        Ok(None)
+    }
+
+    fn load_snapshot(&self, body: Value) -> HandlerResult {
+        let mut state = self.lock_state();
+        ensure_not_started(&state)?;
+        if state.boot_source.is_some()
+            || !state.drives.is_empty()
+            || !state.network_interfaces.is_empty()
+            || state.vsock_device.is_some()
+            || state.balloon_device.is_some()
+        {
+            return Err(
+                "Loading a microVM snapshot not allowed after configuring boot-specific resources.".to_string(),
+            );
+        }
+
+        let snapshot_path = self.resolve(required_str(&body, "snapshot_path")?);
+        let snapshot_json =
+            std::fs::read_to_string(snapshot_path).map_err(|err| format!("Cannot load the snapshot: {err}"))?;
+        let mut snapshot: MockSnapshot =
+            serde_json::from_str(&snapshot_json).map_err(|err| format!("Cannot load the snapshot: {err}"))?;
+
+        let (backend_type, backend_path) = match field(&body, "mem_backend") {
+            Some(mem_backend) => (
+                required_str(mem_backend, "backend_type")?,
+                required_str(mem_backend, "backend_path")?,
+            ),
+            None => ("File", required_str(&body, "mem_file_path")?),
+        };
+        match backend_type {
+            "File" => self.ensure_file_exists(backend_path, "memory")?,
+            "Uffd" => {
+                std::os::unix::net::UnixStream::connect(self.resolve(backend_path))
+                    .map_err(|err| format!("Cannot connect to the UFFD handler: {err}"))?;
+            }
+            backend_type => {
+                return Err(format!(
+                    "An error occurred when deserializing the json body of a request: unknown variant `{backend_type}`."
+                ))
+            }
+        }
+
+        if let Some(network_overrides) = field(&body, "network_overrides").and_then(Value::as_array) {
+            for network_override in network_overrides {
+                let iface_id = required_str(network_override, "iface_id")?;
+                let host_dev_name = required_str(network_override, "host_dev_name")?;
+                let network_interface = snapshot
+                    .state
+                    .network_interfaces
+                    .get_mut(iface_id)
+                    .ok_or_else(|| format!("Invalid network override: unknown interface {iface_id}."))?;
+                network_interface["host_dev_name"] = Value::String(host_dev_name.to_owned());
+            }
+        }
+
+        if let Some(vsock_override) = field(&body, "vsock_override") {
+            let uds_path = required_str(vsock_override, "uds_path")?;
+            let vsock_device = snapshot
+                .state
+                .vsock_device
+                .as_mut()
+                .ok_or_else(|| "Invalid vsock override: the snapshot has no vsock device.".to_string())?;
+            vsock_device["uds_path"] = Value::String(uds_path.to_owned());
+        }
+
+        if let Some(ref vsock_device) = snapshot.state.vsock_device {
+            self.bind_vsock(required_str(vsock_device, "uds_path")?)?;
+        }
+
+        let logger_system = state.logger_system.take();
+        let metrics_system = state.metrics_system.take();
+        let requests = std::mem::take(&mut state.requests);
+        *state = MockVmmState {
+            logger_system,
+            metrics_system,
+            requests,
+            restored_from_snapshot: true,
+            ..snapshot.state
+        };
+
+        if field(&body, "enable_diff_snapshots").and_then(Value::as_bool) == Some(true) {
+            state.machine_configuration["track_dirty_pages"] = Value::Bool(true);
+        }
+
+        state.vm_state = match field(&body, "resume_vm").and_then(Value::as_bool) {
+            Some(true) => MockVmState::Running,
+            _ => MockVmState::Paused,
+        };
+
+        self.log(&state, "Snapshot loaded.");
+        self.emit(MockVmmEvent::SnapshotLoaded);
+        Ok(None)
+    }
+
+    fn ensure_file_exists(&self, path: &str, description: &str) -> Result<(), String> {
+        std::fs::metadata(self.resolve(path))
+            .map(|_| ())
+            .map_err(|err| format!("The {description} file at {path} cannot be opened: {err}"))
+    }
+}
+
+fn ensure_not_started(state: &MockVmmState) -> Result<(), String> {
+    match state.vm_state {
+        MockVmState::NotStarted => Ok(()),
+        _ => Err(NOT_SUPPORTED_AFTER_START.to_string()),
+    }
+}
+
+fn ensure_started(state: &MockVmmState) -> Result<(), String> {
+    match state.vm_state {
+        MockVmState::NotStarted => Err(NOT_SUPPORTED_BEFORE_START.to_string()),
+        _ => Ok(()),
+    }
+}
+
+fn ensure_id_matches(route_id: &str, body_id: &str) -> Result<(), String> {
+    if route_id != body_id {
+        return Err(format!(
+            "The id from the path [{route_id}] does not match the id from the body [{body_id}]!"
+        ));
+    }
+
+    Ok(())
+}
+
+fn mem_size_mib(state: &MockVmmState) -> u64 {
+    field(&state.machine_configuration, "mem_size_mib")
+        .and_then(Value::as_u64)
+        .unwrap_or_default()
+}
+
+fn required(body: Option<Value>) -> Result<Value, String> {
+    body.ok_or_else(|| "The request body is empty.".to_string())
+}
+
+fn field<'a>(value: &'a Value, name: &str) -> Option<&'a Value> {
+    value.get(name).filter(|value| !value.is_null())
+}
+
+fn missing_field(name: &str) -> String {
+    format!("An error occurred when deserializing the json body of a request: missing field `{name}`.")
+}
+
+fn required_str<'a>(value: &'a Value, name: &str) -> Result<&'a str, String> {
+    field(value, name)
+        .and_then(Value::as_str)
+        .ok_or_else(|| missing_field(name))
+}
+
+fn required_u64(value: &Value, name: &str) -> Result<u64, String> {
+    field(value, name)
+        .and_then(Value::as_u64)
+        .ok_or_else(|| missing_field(name))
+}
+
+fn merge_fields(target: &mut Value, update: Value) {
+    if let (Some(target), Value::Object(update)) = (target.as_object_mut(), update) {
+        target.extend(update.into_iter().filter(|(_, value)| !value.is_null()));
+    }
+}
+
+fn merge_patch(target: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+
+    if !target.is_object() {
+        *target = Value::Object(Default::default());
+    }
+
+    let target = target.as_object_mut().expect("Target was ensured to be an object");
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(key);
+        } else {
+            merge_patch(target.entry(key.clone()).or_insert(Value::Null), value);
+        }
+    }
+}
+
+fn timestamp_ms() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis())
+        .unwrap_or_default()
+}
@@ -446,7 +446,12 @@ pub(super) async fn init_restored_from_snapshot<E: VmmExecutor, S: ProcessSpawne
         send_api_request(vm, "/metrics", "PUT", Some(metrics_system)).await?;
     }
 
-    send_api_request(vm, "/snapshot/load", "PUT", Some(&load_snapshot)).await
+    let resume_vm = load_snapshot.resume_vm;
+    send_api_request(vm, "/snapshot/load", "PUT", Some(&load_snapshot)).await?;
+
+    // Firecracker leaves the restored VM paused unless it was explicitly requested to be resumed
+    vm.is_paused = resume_vm != Some(true);
+    Ok(())
 }
 
 async fn send_api_request<E: VmmExecutor, S: ProcessSpawner, R: Runtime>(
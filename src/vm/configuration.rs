@@ -1,8 +1,18 @@
 use std::path::PathBuf;
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-use crate::vmm::resource::VmmResourceReferences;
+use crate::vmm::resource::{CreatedVmmResource, MovedVmmResource, ProducedVmmResource, VmmResourceReferences};
+
+#[cfg(any(
+    feature = "snapshot-catalog-extension",
+    feature = "snapshot-bundle-extension",
+    feature = "migration-extension"
+))]
+use crate::vmm::resource::{CreatedVmmResourceType, VmmResourceMoveMethod};
+
+#[cfg(any(feature = "fork-extension", feature = "migration-extension"))]
+use super::models::{NetworkOverride, VsockOverride};
 
 use super::models::{
     BalloonDevice, BootSource, CpuTemplate, Drive, EntropyDevice, LoadSnapshot, LoggerSystem, MachineConfiguration,
@@ -13,6 +23,7 @@ use super::models::{
 /// the same amount of features for both new and restored VMs, and this layer abstracts away most snapshot-related
 /// work.
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[allow(clippy::large_enum_variant)]
 pub enum VmConfiguration {
     /// The VM is new, thus its initialization process is controlled.
     New {
@@ -71,13 +82,69 @@ impl VmConfiguration {
             VmConfiguration::New { init_method: _, data } => data,
         };
 
-        references.moved_resources.push(&mut data.boot_source.kernel_image);
+        data.push_resource_references(&mut references);
+        references
+    }
+
+    // pass the network interfaces and vsock device of the data to the VMM as overrides when loading the snapshot, so
+    // that changes made to them after the snapshot was created take effect
+    #[cfg(any(feature = "fork-extension", feature = "migration-extension"))]
+    pub(crate) fn override_devices(&mut self) {
+        if let VmConfiguration::RestoredFromSnapshot { load_snapshot, data } = self {
+            load_snapshot.network_overrides = data
+                .network_interfaces
+                .iter()
+                .map(|network_interface| NetworkOverride {
+                    iface_id: network_interface.iface_id.clone(),
+                    host_dev_name: network_interface.host_dev_name.clone(),
+                })
+                .collect();
+            load_snapshot.vsock_override = data.vsock_device.as_ref().map(|vsock_device| VsockOverride {
+                uds_path: vsock_device.uds.local_path().to_owned(),
+            });
+        }
+    }
+}
+
+/// The full data of various devices associated with a VM. Even when restoring from a snapshot, this information
+/// is required for initialization to proceed.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct VmConfigurationData {
+    #[serde(rename = "boot-source")]
+    pub boot_source: BootSource,
+    pub drives: Vec<Drive>,
+    #[serde(rename = "machine-config")]
+    pub machine_configuration: MachineConfiguration,
+    #[serde(rename = "cpu-config")]
+    pub cpu_template: Option<CpuTemplate>,
+    #[serde(rename = "network-interfaces")]
+    pub network_interfaces: Vec<NetworkInterface>,
+    pub balloon_device: Option<BalloonDevice>,
+    pub vsock_device: Option<VsockDevice>,
+    pub logger_system: Option<LoggerSystem>,
+    pub metrics_system: Option<MetricsSystem>,
+    #[serde(rename = "mmds-config")]
+    pub mmds_configuration: Option<MmdsConfiguration>,
+    pub entropy_device: Option<EntropyDevice>,
+}
+
+impl VmConfigurationData {
+    /// Create a set of [VmmResourceReferences] from only the resources inside this [VmConfigurationData], in the same
+    /// order as they appear in the [VmmResourceReferences] of a [VmConfiguration] containing it.
+    pub fn resource_references(&mut self) -> VmmResourceReferences<'_> {
+        let mut references = VmmResourceReferences::new();
+        self.push_resource_references(&mut references);
+        references
+    }
+
+    fn push_resource_references<'res>(&'res mut self, references: &mut VmmResourceReferences<'res>) {
+        references.moved_resources.push(&mut self.boot_source.kernel_image);
 
-        if let Some(ref mut initrd) = data.boot_source.initrd {
+        if let Some(ref mut initrd) = self.boot_source.initrd {
             references.moved_resources.push(initrd);
         }
 
-        for drive in &mut data.drives {
+        for drive in &mut self.drives {
             if let Some(ref mut block) = drive.block {
                 references.moved_resources.push(block);
             }
@@ -87,44 +154,109 @@ impl VmConfiguration {
             }
         }
 
-        if let Some(ref mut vsock_device) = data.vsock_device {
+        if let Some(ref mut vsock_device) = self.vsock_device {
             references.produced_resources.push(&mut vsock_device.uds);
         }
 
-        if let Some(ref mut logger_system) = data.logger_system {
+        if let Some(ref mut logger_system) = self.logger_system {
             if let Some(ref mut logs) = logger_system.logs {
                 references.created_resources.push(logs);
             }
         }
 
-        if let Some(ref mut metrics_system) = data.metrics_system {
+        if let Some(ref mut metrics_system) = self.metrics_system {
             references.created_resources.push(&mut metrics_system.metrics);
         }
+    }
 
-        references
+    /// Reset all resources inside this [VmConfigurationData] to their uninitialized state, which is needed when the
+    /// data outlives the VM that its resources were initialized for, for example when it is persisted with a snapshot.
+    pub fn into_uninitialized(mut self) -> Self {
+        let resource_references = self.resource_references();
+
+        for moved_resource in resource_references.moved_resources {
+            *moved_resource =
+                MovedVmmResource::new(moved_resource.source_path().to_owned(), moved_resource.move_method());
+        }
+
+        for created_resource in resource_references.created_resources {
+            *created_resource =
+                CreatedVmmResource::new(created_resource.local_path().to_owned(), created_resource.r#type());
+        }
+
+        for produced_resource in resource_references.produced_resources {
+            *produced_resource = ProducedVmmResource::new(produced_resource.local_path().to_owned());
+        }
+
+        self
     }
 }
 
-/// The full data of various devices associated with a VM. Even when restoring from a snapshot, this information
-/// is required for initialization to proceed.
-#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
-pub struct VmConfigurationData {
-    #[serde(rename = "boot-source")]
-    pub boot_source: BootSource,
-    pub drives: Vec<Drive>,
-    #[serde(rename = "machine-config")]
-    pub machine_configuration: MachineConfiguration,
-    #[serde(rename = "cpu-config")]
-    pub cpu_template: Option<CpuTemplate>,
-    #[serde(rename = "network-interfaces")]
-    pub network_interfaces: Vec<NetworkInterface>,
-    pub balloon_device: Option<BalloonDevice>,
-    pub vsock_device: Option<VsockDevice>,
-    pub logger_system: Option<LoggerSystem>,
-    pub metrics_system: Option<MetricsSystem>,
-    #[serde(rename = "mmds-config")]
-    pub mmds_configuration: Option<MmdsConfiguration>,
-    pub entropy_device: Option<EntropyDevice>,
+/// The lossless serialized form of a [VmConfigurationData] used when persisting it outside of a VM. The regular
+/// serialized form only contains the local paths of resources, so moved resources are serialized with their source
+/// paths instead, and the move methods and created resource types are stored in the order of resource references.
+#[cfg(any(
+    feature = "snapshot-catalog-extension",
+    feature = "snapshot-bundle-extension",
+    feature = "migration-extension"
+))]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ReprVmConfigurationData {
+    configuration_data: VmConfigurationData,
+    moved_resources: Vec<VmmResourceMoveMethod>,
+    created_resources: Vec<CreatedVmmResourceType>,
+}
+
+#[cfg(any(
+    feature = "snapshot-catalog-extension",
+    feature = "snapshot-bundle-extension",
+    feature = "migration-extension"
+))]
+impl ReprVmConfigurationData {
+    pub(crate) fn new(data: &VmConfigurationData) -> Self {
+        let mut configuration_data = data.clone();
+        let mut moved_resources = Vec::new();
+        let mut created_resources = Vec::new();
+
+        let resource_references = configuration_data.resource_references();
+        for moved_resource in resource_references.moved_resources {
+            moved_resources.push(moved_resource.move_method());
+            let source_path = moved_resource.source_path().to_owned();
+            moved_resource.mark_initialized(source_path.clone(), source_path);
+        }
+
+        for created_resource in resource_references.created_resources {
+            created_resources.push(created_resource.r#type());
+        }
+
+        Self {
+            configuration_data,
+            moved_resources,
+            created_resources,
+        }
+    }
+
+    pub(crate) fn into_data(mut self) -> VmConfigurationData {
+        let resource_references = self.configuration_data.resource_references();
+
+        for (moved_resource, move_method) in resource_references
+            .moved_resources
+            .into_iter()
+            .zip(self.moved_resources)
+        {
+            *moved_resource = MovedVmmResource::new(moved_resource.source_path().to_owned(), move_method);
+        }
+
+        for (created_resource, r#type) in resource_references
+            .created_resources
+            .into_iter()
+            .zip(self.created_resources)
+        {
+            *created_resource = CreatedVmmResource::new(created_resource.local_path().to_owned(), r#type);
+        }
+
+        self.configuration_data
+    }
 }
 
 /// A method of initialization used when booting a new (not restored from snapshot) VM.
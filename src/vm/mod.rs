@@ -8,13 +8,14 @@ use crate::{
     process_spawner::ProcessSpawner,
     runtime::{util::RuntimeHyperExecutor, Runtime},
     vmm::{
+        console::VmmConsole,
         executor::{process_handle::ProcessHandlePipes, VmmExecutor},
         installation::VmmInstallation,
         ownership::{upgrade_owner, ChangeOwnerError, VmmOwnershipModel},
-        process::{VmmProcess, VmmProcessError, VmmProcessState},
+        process::{VmmProcess, VmmProcessDescriptor, VmmProcessError, VmmProcessState},
     },
 };
-use api::VmApiError;
+use api::{VmApi, VmApiError};
 use bytes::Bytes;
 use configuration::{InitMethod, VmConfiguration};
 use http::Uri;
@@ -38,10 +39,12 @@ pub mod snapshot;
 pub struct Vm<E: VmmExecutor, S: ProcessSpawner, R: Runtime> {
     vmm_process: VmmProcess<E, S, R>,
     process_spawner: S,
-    ownership_model: VmmOwnershipModel,
+    pub(crate) ownership_model: VmmOwnershipModel,
     pub(crate) runtime: R,
     is_paused: bool,
     configuration: VmConfiguration,
+    #[cfg(feature = "vsock-listener-extension")]
+    pub(crate) vsock_listener_sockets: std::sync::Mutex<Vec<PathBuf>>,
 }
 
 /// The high-level state of a [Vm]. Unlike the state of a [VmmProcess], this state tracks the virtual machine and its operating state,
@@ -184,9 +187,59 @@ impl<E: VmmExecutor, S: ProcessSpawner, R: Runtime> Vm<E, S, R> {
             runtime,
             is_paused: false,
             configuration,
+            #[cfg(feature = "vsock-listener-extension")]
+            vsock_listener_sockets: std::sync::Mutex::new(Vec::new()),
         })
     }
 
+    /// Recover a [Vm] that is already running, for example after the control process has restarted, by attaching to
+    /// its [VmmProcess] via the given [VmmProcessDescriptor]. The [VmmExecutor] and [VmConfiguration] must be equal to
+    /// the ones the [Vm] was originally prepared with, so that all resource paths can be restored without any
+    /// filesystem operations being performed. Whether the [Vm] is paused is queried from the API server, which also
+    /// ensures that the API server is reachable.
+    pub async fn recover(
+        executor: E,
+        process_spawner: S,
+        runtime: R,
+        installation: Arc<VmmInstallation>,
+        mut configuration: VmConfiguration,
+        descriptor: &VmmProcessDescriptor,
+    ) -> Result<Self, VmError> {
+        if executor.get_socket_path(installation.as_ref()).is_none() {
+            return Err(VmError::DisabledApiSocketIsUnsupported);
+        }
+
+        let vmm_process = VmmProcess::attach(
+            executor,
+            process_spawner.clone(),
+            runtime.clone(),
+            installation,
+            descriptor,
+            configuration.resource_references(),
+        )
+        .map_err(VmError::ProcessError)?;
+
+        let mut vm = Self {
+            vmm_process,
+            process_spawner,
+            ownership_model: descriptor.ownership_model,
+            runtime,
+            is_paused: false,
+            configuration,
+            #[cfg(feature = "vsock-listener-extension")]
+            vsock_listener_sockets: std::sync::Mutex::new(Vec::new()),
+        };
+        vm.is_paused = vm.api_get_info().await.map_err(VmError::ApiError)?.is_paused;
+        Ok(vm)
+    }
+
+    /// Create a [VmmProcessDescriptor] of this [Vm]'s underlying [VmmProcess] that can be persisted and later passed
+    /// to [Vm::recover] alongside the same [VmConfiguration].
+    pub fn descriptor(&mut self) -> Result<VmmProcessDescriptor, VmError> {
+        self.ensure_paused_or_running().map_err(VmError::StateCheckError)?;
+        self.vmm_process.descriptor().map_err(VmError::ProcessError)
+    }
+
     /// Retrieve the [VmState] of the [Vm], based on internal tracking and that being done by the [VmmProcess].
     pub fn state(&mut self) -> VmState {
         match self.vmm_process.state() {
@@ -289,6 +342,21 @@ impl<E: VmmExecutor, S: ProcessSpawner, R: Runtime> Vm<E, S, R> {
     /// Clean up the full environment of this [Vm] after it being [VmState::Exited] or [VmState::Crashed].
     pub async fn cleanup(&mut self) -> Result<(), VmError> {
         self.ensure_exited_or_crashed().map_err(VmError::StateCheckError)?;
+
+        // vsock listener sockets are created next to the vsock device's socket, which isn't inside a jail that is
+        // removed as a whole for unrestricted VMs, so any that weren't cleaned up by their listeners are removed here
+        #[cfg(feature = "vsock-listener-extension")]
+        for socket_path in std::mem::take(
+            self.vsock_listener_sockets
+                .get_mut()
+                .expect("Vsock listener sockets mutex was poisoned"),
+        ) {
+            match self.runtime.fs_remove_file(&socket_path).await {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(VmError::FilesystemError(err)),
+                _ => {}
+            }
+        }
+
         self.vmm_process
             .cleanup(self.configuration.resource_references())
             .await
@@ -301,11 +369,23 @@ impl<E: VmmExecutor, S: ProcessSpawner, R: Runtime> Vm<E, S, R> {
         self.vmm_process.take_pipes().map_err(VmError::ProcessError)
     }
 
+    /// Get the [VmmConsole] hub of the underlying process, which is created on first use by taking out its
+    /// [ProcessHandlePipes] and is therefore mutually exclusive with [Vm::take_pipes].
+    pub fn console(&mut self) -> Result<VmmConsole<R>, VmError> {
+        self.ensure_paused_or_running().map_err(VmError::StateCheckError)?;
+        self.vmm_process.console().map_err(VmError::ProcessError)
+    }
+
     /// Get a shared reference to the [Vm]'s [VmConfiguration].
     pub fn configuration(&self) -> &VmConfiguration {
         &self.configuration
     }
 
+    #[cfg(feature = "snapshot-encryption-extension")]
+    pub(crate) fn configuration_mut(&mut self) -> &mut VmConfiguration {
+        &mut self.configuration
+    }
+
     /// Translates the given local resource path to an effective resource path.
     pub fn local_to_effective_path(&self, local_path: impl Into<PathBuf>) -> PathBuf {
         self.vmm_process.local_to_effective_path(local_path)
@@ -1,4 +1,4 @@
-use std::net::Ipv4Addr;
+use std::{net::Ipv4Addr, path::PathBuf};
 
 use serde::{Deserialize, Serialize};
 
@@ -63,7 +63,7 @@ pub struct UpdateBalloonStatistics {
     pub stats_polling_interval_s: u16,
 }
 
-#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 pub struct BootSource {
     #[serde(rename = "kernel_image_path")]
     pub kernel_image: MovedVmmResource,
@@ -74,7 +74,7 @@ pub struct BootSource {
     pub initrd: Option<MovedVmmResource>,
 }
 
-#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 pub struct Drive {
     pub drive_id: String,
     pub is_root_device: bool,
@@ -131,7 +131,7 @@ pub struct TokenBucket {
     pub refill_time: u64,
 }
 
-#[derive(Serialize, Debug, Clone, PartialEq, Eq, Default)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Default)]
 pub struct LoggerSystem {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(rename = "log_path")]
@@ -165,7 +165,7 @@ pub enum HugePages {
     Hugetlbfs2M,
 }
 
-#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 pub struct MetricsSystem {
     #[serde(rename = "metrics_path")]
     pub metrics: CreatedVmmResource,
@@ -228,6 +228,9 @@ pub enum SnapshotType {
     Diff,
 }
 
+/// The network_overrides are only accepted by Firecracker 1.12.0 and newer, and the vsock_override only by later
+/// releases still. Older releases reject the entire request if either is set, so both must be left empty for them,
+/// which [LoadSnapshot::new] does.
 #[derive(Serialize, Debug, Clone, PartialEq, Eq)]
 pub struct LoadSnapshot {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -237,6 +240,34 @@ pub struct LoadSnapshot {
     pub snapshot: MovedVmmResource,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub resume_vm: Option<bool>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub network_overrides: Vec<NetworkOverride>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vsock_override: Option<VsockOverride>,
+}
+
+impl LoadSnapshot {
+    pub fn new(mem_backend: MemoryBackend, snapshot: MovedVmmResource) -> Self {
+        Self {
+            enable_diff_snapshots: None,
+            mem_backend,
+            snapshot,
+            resume_vm: None,
+            network_overrides: Vec::new(),
+            vsock_override: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct NetworkOverride {
+    pub iface_id: String,
+    pub host_dev_name: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct VsockOverride {
+    pub uds_path: PathBuf,
 }
 
 #[derive(Serialize, Debug, Clone, PartialEq, Eq)]
@@ -268,7 +299,7 @@ pub(crate) enum ReprUpdatedState {
     Resumed,
 }
 
-#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 pub struct VsockDevice {
     pub guest_cid: u32,
     #[serde(rename = "uds_path")]
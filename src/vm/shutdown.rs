@@ -1,11 +1,13 @@
 use std::{process::ExitStatus, time::Duration};
 
-use futures_util::AsyncWriteExt;
-
 use crate::{
     process_spawner::ProcessSpawner,
     runtime::Runtime,
-    vmm::{executor::VmmExecutor, process::VmmProcessError},
+    vmm::{
+        console::VmmConsoleError,
+        executor::VmmExecutor,
+        process::{VmmProcessError, VmmProcessState},
+    },
 };
 
 use super::{
@@ -25,9 +27,9 @@ pub enum VmShutdownMethod {
     /// Performs a graceful shutdown by sending Ctrl+Alt+Del to the VM. Only supported on x86_64 CPUs and recommended
     /// as a primary option.
     CtrlAltDel,
-    /// Performs a shutdown by taking the VMM process's stdin pipe and writing the provided byte sequence to it. The byte
-    /// sequence can, for example, be "systemctl reboot\n". Recommended as a backup option on ARM CPUs with no Ctrl+Alt+Del
-    /// support.
+    /// Performs a shutdown by writing the provided byte sequence to the VMM process's stdin pipe via its console hub. The
+    /// byte sequence can, for example, be "systemctl reboot\n". Recommended as a backup option on ARM CPUs with no
+    /// Ctrl+Alt+Del support.
     WriteToSerial(Vec<u8>),
 }
 
@@ -47,21 +49,25 @@ impl VmShutdownMethod {
                 .send_ctrl_alt_del()
                 .await
                 .map_err(VmShutdownError::SendCtrlAltDelError)?,
-            VmShutdownMethod::WriteToSerial(bytes) => {
-                let mut pipes = vm.vmm_process.take_pipes().map_err(VmShutdownError::TakePipesError)?;
-                pipes
-                    .stdin
-                    .write_all(bytes)
-                    .await
-                    .map_err(VmShutdownError::SerialError)?;
-                pipes.stdin.flush().await.map_err(VmShutdownError::SerialError)?
-            }
+            VmShutdownMethod::WriteToSerial(bytes) => vm
+                .vmm_process
+                .console()
+                .map_err(VmShutdownError::TakePipesError)?
+                .write(bytes)
+                .await
+                .map_err(VmShutdownError::SerialError)?,
         }
 
-        vm.vmm_process
-            .wait_for_exit()
-            .await
-            .map_err(VmShutdownError::WaitForExitError)
+        // the process may have already exited right after being told to, in which case it can no longer be waited on
+        match vm.vmm_process.state() {
+            VmmProcessState::Exited => Ok(ExitStatus::default()),
+            VmmProcessState::Crashed(exit_status) => Ok(exit_status),
+            _ => vm
+                .vmm_process
+                .wait_for_exit()
+                .await
+                .map_err(VmShutdownError::WaitForExitError),
+        }
     }
 }
 
@@ -101,7 +107,7 @@ pub enum VmShutdownError {
     PauseError(VmApiError),
     SendCtrlAltDelError(VmmProcessError),
     TakePipesError(VmmProcessError),
-    SerialError(std::io::Error),
+    SerialError(VmmConsoleError),
 }
 
 impl std::error::Error for VmShutdownError {}
@@ -125,9 +131,9 @@ impl std::fmt::Display for VmShutdownError {
             VmShutdownError::SendCtrlAltDelError(err) => write!(f, "Sending Ctrl+Alt+Del to the VM failed: {err}"),
             VmShutdownError::TakePipesError(err) => write!(
                 f,
-                "Taking the pipes from the VM to perform a serial write failed: {err}"
+                "Getting the console of the VM to perform a serial write failed: {err}"
             ),
-            VmShutdownError::SerialError(err) => write!(f, "Performing a serial write to stdin failed: {err}"),
+            VmShutdownError::SerialError(err) => write!(f, "Performing a serial write via the console failed: {err}"),
         }
     }
 }
@@ -1,7 +1,7 @@
 use std::path::PathBuf;
 
 use crate::{
-    runtime::Runtime,
+    runtime::{FastCopyStrategy, Runtime},
     vmm::resource::{MovedVmmResource, ProducedVmmResource, VmmResourceMoveMethod},
 };
 
@@ -19,17 +19,18 @@ pub struct VmSnapshot {
 }
 
 impl VmSnapshot {
+    /// Copy the snapshot and memory file to the given new paths via [Runtime::fs_fast_copy], returning the
+    /// [FastCopyStrategy] that was used for each of them respectively.
     pub async fn copy<R: Runtime>(
         &mut self,
         new_snapshot_path: impl Into<PathBuf>,
         new_mem_file_path: impl Into<PathBuf>,
         runtime: &R,
-    ) -> Result<(), std::io::Error> {
+    ) -> Result<(FastCopyStrategy, FastCopyStrategy), std::io::Error> {
         futures_util::try_join!(
             self.snapshot.copy(new_snapshot_path, runtime),
             self.mem_file.copy(new_mem_file_path, runtime)
         )
-        .map(|_| ())
     }
 
     pub async fn rename<R: Runtime>(
@@ -58,15 +59,15 @@ impl VmSnapshot {
         let mem_file = MovedVmmResource::new(self.mem_file.effective_path(), move_method);
         let snapshot = MovedVmmResource::new(self.snapshot.effective_path(), move_method);
 
-        let load_snapshot = LoadSnapshot {
-            enable_diff_snapshots,
-            mem_backend: MemoryBackend {
+        let mut load_snapshot = LoadSnapshot::new(
+            MemoryBackend {
                 backend_type: MemoryBackendType::File,
                 backend: mem_file,
             },
             snapshot,
-            resume_vm,
-        };
+        );
+        load_snapshot.enable_diff_snapshots = enable_diff_snapshots;
+        load_snapshot.resume_vm = resume_vm;
 
         VmConfiguration::RestoredFromSnapshot {
             load_snapshot,
@@ -294,7 +294,7 @@ mod tests {
     #[test]
     fn metadata_path_can_be_set() {
         let mut resource = MovedVmmResource::new("/tmp/metadata.txt", VmmResourceMoveMethod::Rename);
-        let _ = resource.initialize_with_same_path(VmmOwnershipModel::Shared, DirectProcessSpawner, TokioRuntime);
+        drop(resource.initialize_with_same_path(VmmOwnershipModel::Shared, DirectProcessSpawner, TokioRuntime));
         check_without_config(new().metadata(resource), ["--metadata", "/tmp/metadata.txt"]);
     }
 
@@ -0,0 +1,506 @@
+//! Provides a [VmmConsole] hub that multiplexes the serial console of a VMM process, exposed over its
+//! [ProcessHandlePipes], between many readers and writers.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures_channel::{mpsc, oneshot};
+use futures_util::{lock::Mutex as AsyncMutex, AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+use crate::runtime::{Runtime, RuntimeChild};
+
+use super::executor::process_handle::ProcessHandlePipes;
+
+/// The default amount of lines kept in the scrollback of a [VmmConsole].
+pub const DEFAULT_SCROLLBACK_CAPACITY: usize = 1000;
+
+/// The maximum length of a line in bytes. Output that reaches it without a newline is broken into a separate line, so
+/// that a VMM that never prints a newline can't make the console buffer an unbounded amount of it.
+pub const MAX_LINE_LENGTH: usize = 64 * 1024;
+
+/// A console hub built on top of the [ProcessHandlePipes] of a VMM process. A background task reads the stdout
+/// (serial console) and stderr pipes of the process line by line, broadcasting the lines to all subscribers and
+/// keeping a bounded scrollback of them, while writes to the stdin pipe are serialized. The [VmmConsole] is a
+/// cheaply cloneable handle, so it can be shared between multiple loggers, watchers and writers.
+pub struct VmmConsole<R: Runtime> {
+    inner: Arc<VmmConsoleInner<R>>,
+}
+
+struct VmmConsoleInner<R: Runtime> {
+    runtime: R,
+    stdin: AsyncMutex<<R::Child as RuntimeChild>::Stdin>,
+    state: Mutex<VmmConsoleState>,
+}
+
+struct VmmConsoleState {
+    scrollback: VecDeque<VmmConsoleLine>,
+    scrollback_capacity: usize,
+    unfinished_line: String,
+    // incremented on every clear, so that the readers also discard the parts of lines they have buffered
+    clears: u64,
+    subscribers: Vec<mpsc::Sender<VmmConsoleLine>>,
+    raw_subscribers: Vec<mpsc::Sender<Vec<u8>>>,
+    waiters: Vec<(String, oneshot::Sender<String>)>,
+    closed: bool,
+}
+
+/// A single line of output read by a [VmmConsole].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct VmmConsoleLine {
+    /// The [VmmConsoleSource] of the line.
+    pub source: VmmConsoleSource,
+    /// The content of the line without the trailing line terminator.
+    pub content: String,
+}
+
+/// The pipe that a [VmmConsoleLine] was read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum VmmConsoleSource {
+    /// The stdout pipe, which carries the serial console output of the guest.
+    Stdout,
+    /// The stderr pipe, which carries diagnostic output of the VMM itself.
+    Stderr,
+}
+
+/// An error that can be emitted by a [VmmConsole].
+#[derive(Debug)]
+pub enum VmmConsoleError {
+    Timeout,
+    Closed,
+    WriteFailed(std::io::Error),
+}
+
+impl std::error::Error for VmmConsoleError {}
+
+impl std::fmt::Display for VmmConsoleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VmmConsoleError::Timeout => write!(f, "Waiting for a pattern in the console output timed out"),
+            VmmConsoleError::Closed => write!(f, "The console output was closed by the VMM process"),
+            VmmConsoleError::WriteFailed(err) => write!(f, "Writing to the console input failed: {err}"),
+        }
+    }
+}
+
+impl<R: Runtime> VmmConsole<R> {
+    /// Create a [VmmConsole] from the given [ProcessHandlePipes], spawning its background reader tasks onto the
+    /// given [Runtime] and keeping up to the given amount of lines in the scrollback.
+    pub fn new(pipes: ProcessHandlePipes<R::Child>, runtime: R, scrollback_capacity: usize) -> Self {
+        let inner = Arc::new(VmmConsoleInner {
+            runtime: runtime.clone(),
+            stdin: AsyncMutex::new(pipes.stdin),
+            state: Mutex::new(VmmConsoleState {
+                scrollback: VecDeque::with_capacity(scrollback_capacity),
+                scrollback_capacity,
+                unfinished_line: String::new(),
+                clears: 0,
+                subscribers: Vec::new(),
+                raw_subscribers: Vec::new(),
+                waiters: Vec::new(),
+                closed: false,
+            }),
+        });
+
+        runtime.spawn_task(read_pipe(pipes.stdout, VmmConsoleSource::Stdout, inner.clone()));
+        runtime.spawn_task(read_pipe(pipes.stderr, VmmConsoleSource::Stderr, inner.clone()));
+
+        Self { inner }
+    }
+
+    /// Subscribe to all lines that will be read by the [VmmConsole] from now on. The returned [mpsc::Receiver] can
+    /// buffer up to the given amount of lines: a subscriber that lags further behind will miss lines. The receiver
+    /// is closed once the VMM process closes its stdout pipe.
+    pub fn subscribe(&self, capacity: usize) -> mpsc::Receiver<VmmConsoleLine> {
+        let (sender, receiver) = mpsc::channel(capacity);
+        let mut state = self.lock_state();
+
+        if !state.closed {
+            state.subscribers.push(sender);
+        }
+
+        receiver
+    }
+
+    /// Atomically get a copy of the scrollback and subscribe to all lines read afterwards, so that no line is either
+    /// missed or seen twice when combining the two. The returned [mpsc::Receiver] behaves like the one returned by
+    /// [VmmConsole::subscribe].
+    pub fn subscribe_with_scrollback(&self, capacity: usize) -> (Vec<VmmConsoleLine>, mpsc::Receiver<VmmConsoleLine>) {
+        let (sender, receiver) = mpsc::channel(capacity);
+        let mut state = self.lock_state();
+        let scrollback = state.scrollback.iter().cloned().collect();
+
+        if !state.closed {
+            state.subscribers.push(sender);
+        }
+
+        (scrollback, receiver)
+    }
+
+    /// Subscribe to the raw serial output (stdout) that will be read by the [VmmConsole] from now on, as unmodified
+    /// chunks of bytes that preserve prompts, control characters and escape sequences, making the subscription
+    /// suitable for forwarding to an interactive terminal. When requested, the serial output kept in the scrollback
+    /// is replayed as the first chunk. The returned [mpsc::Receiver] can buffer up to the given amount of chunks and
+    /// behaves like the one returned by [VmmConsole::subscribe] otherwise.
+    pub fn subscribe_raw(&self, capacity: usize, replay_scrollback: bool) -> mpsc::Receiver<Vec<u8>> {
+        let (mut sender, receiver) = mpsc::channel(capacity);
+        let mut state = self.lock_state();
+
+        if replay_scrollback {
+            let mut replay = Vec::new();
+
+            for line in state
+                .scrollback
+                .iter()
+                .filter(|line| line.source == VmmConsoleSource::Stdout)
+            {
+                replay.extend_from_slice(line.content.as_bytes());
+                replay.extend_from_slice(b"\r\n");
+            }
+
+            replay.extend_from_slice(state.unfinished_line.as_bytes());
+
+            // a fresh channel always has room for one message per sender, so this can't fail due to capacity
+            if !replay.is_empty() {
+                let _ = sender.try_send(replay);
+            }
+        }
+
+        if !state.closed {
+            state.raw_subscribers.push(sender);
+        }
+
+        receiver
+    }
+
+    /// Get a copy of the lines currently kept in the scrollback, from oldest to newest.
+    pub fn scrollback(&self) -> Vec<VmmConsoleLine> {
+        self.lock_state().scrollback.iter().cloned().collect()
+    }
+
+    /// Clear the scrollback, so that subsequent calls to [VmmConsole::wait_for] only match output that is read
+    /// afterwards.
+    pub fn clear_scrollback(&self) {
+        let mut state = self.lock_state();
+        state.scrollback.clear();
+        state.unfinished_line.clear();
+        state.clears += 1;
+    }
+
+    /// Whether the VMM process has closed its stdout pipe, meaning no more serial output will be read.
+    pub fn is_closed(&self) -> bool {
+        self.lock_state().closed
+    }
+
+    /// Wait until a line of serial output (stdout) containing the given pattern is read, or until the given timeout
+    /// elapses. Lines in the scrollback and the unfinished last line (such as a "login: " prompt that isn't terminated
+    /// until the user responds) are also matched. The matching line is returned.
+    pub async fn wait_for(&self, pattern: impl Into<String>, timeout: Duration) -> Result<String, VmmConsoleError> {
+        let pattern = pattern.into();
+
+        let receiver = {
+            let mut state = self.lock_state();
+
+            if let Some(line) = state
+                .scrollback
+                .iter()
+                .filter(|line| line.source == VmmConsoleSource::Stdout)
+                .map(|line| &line.content)
+                .chain(std::iter::once(&state.unfinished_line))
+                .find(|content| content.contains(&pattern))
+            {
+                return Ok(line.clone());
+            }
+
+            if state.closed {
+                return Err(VmmConsoleError::Closed);
+            }
+
+            let (sender, receiver) = oneshot::channel();
+            state.waiters.push((pattern, sender));
+            receiver
+        };
+
+        match self.inner.runtime.timeout(timeout, receiver).await {
+            Ok(Ok(line)) => Ok(line),
+            Ok(Err(_)) => Err(VmmConsoleError::Closed),
+            Err(_) => Err(VmmConsoleError::Timeout),
+        }
+    }
+
+    /// Write the given bytes to the stdin pipe (serial console input) of the VMM process and flush them. Concurrent
+    /// writes are serialized so that they are never interleaved.
+    pub async fn write(&self, bytes: impl AsRef<[u8]>) -> Result<(), VmmConsoleError> {
+        let mut stdin = self.inner.stdin.lock().await;
+        stdin
+            .write_all(bytes.as_ref())
+            .await
+            .map_err(VmmConsoleError::WriteFailed)?;
+        stdin.flush().await.map_err(VmmConsoleError::WriteFailed)
+    }
+
+    /// A shorthand to write the given line, terminated with "\n", to the serial console input.
+    pub async fn write_line(&self, line: impl AsRef<str>) -> Result<(), VmmConsoleError> {
+        self.write(format!("{}\n", line.as_ref())).await
+    }
+
+    fn lock_state(&self) -> std::sync::MutexGuard<'_, VmmConsoleState> {
+        self.inner.state.lock().expect("Console state mutex was poisoned")
+    }
+}
+
+impl<R: Runtime> Clone for VmmConsole<R> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<R: Runtime> std::fmt::Debug for VmmConsole<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VmmConsole").finish_non_exhaustive()
+    }
+}
+
+impl VmmConsoleState {
+    fn push_line(&mut self, line: VmmConsoleLine) {
+        // lines are dropped for subscribers that are lagging behind, and disconnected subscribers are removed
+        self.subscribers
+            .retain_mut(|sender| match sender.try_send(line.clone()) {
+                Ok(()) => true,
+                Err(err) => !err.is_disconnected(),
+            });
+
+        if self.scrollback_capacity > 0 {
+            if self.scrollback.len() == self.scrollback_capacity {
+                self.scrollback.pop_front();
+            }
+
+            self.scrollback.push_back(line);
+        }
+    }
+
+    fn notify_waiters(&mut self, content: &str) {
+        let mut index = 0;
+
+        while index < self.waiters.len() {
+            if content.contains(self.waiters[index].0.as_str()) {
+                let (_, sender) = self.waiters.swap_remove(index);
+                let _ = sender.send(content.to_owned());
+            } else {
+                index += 1;
+            }
+        }
+    }
+}
+
+async fn read_pipe<P: AsyncRead + Unpin, R: Runtime>(
+    mut pipe: P,
+    source: VmmConsoleSource,
+    inner: Arc<VmmConsoleInner<R>>,
+) {
+    let mut buf = [0; 4096];
+    let mut unfinished_line = Vec::new();
+    let mut clears = 0;
+
+    loop {
+        let read = match pipe.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(read) => read,
+        };
+
+        let mut state = inner.state.lock().expect("Console state mutex was poisoned");
+
+        if state.clears != clears {
+            clears = state.clears;
+            unfinished_line.clear();
+        }
+
+        if source == VmmConsoleSource::Stdout {
+            state
+                .raw_subscribers
+                .retain_mut(|sender| match sender.try_send(buf[..read].to_vec()) {
+                    Ok(()) => true,
+                    Err(err) => !err.is_disconnected(),
+                });
+        }
+
+        for byte in &buf[..read] {
+            if *byte != b'\n' {
+                unfinished_line.push(*byte);
+
+                if unfinished_line.len() == MAX_LINE_LENGTH {
+                    finish_line(&mut state, source, &mut unfinished_line);
+                }
+
+                continue;
+            }
+
+            if unfinished_line.last() == Some(&b'\r') {
+                unfinished_line.pop();
+            }
+
+            finish_line(&mut state, source, &mut unfinished_line);
+        }
+
+        if source == VmmConsoleSource::Stdout {
+            let content = String::from_utf8_lossy(&unfinished_line).into_owned();
+            state.notify_waiters(&content);
+            state.unfinished_line = content;
+        }
+    }
+
+    let mut state = inner.state.lock().expect("Console state mutex was poisoned");
+
+    if !unfinished_line.is_empty() {
+        let content = String::from_utf8_lossy(&unfinished_line).into_owned();
+        state.push_line(VmmConsoleLine { source, content });
+    }
+
+    // only the closure of the serial output closes the console, the closure of stderr is irrelevant to consumers
+    if source == VmmConsoleSource::Stdout {
+        state.unfinished_line.clear();
+        state.subscribers.clear();
+        state.raw_subscribers.clear();
+        state.waiters.clear();
+        state.closed = true;
+    }
+}
+
+fn finish_line(state: &mut VmmConsoleState, source: VmmConsoleSource, unfinished_line: &mut Vec<u8>) {
+    let content = String::from_utf8_lossy(unfinished_line).into_owned();
+    unfinished_line.clear();
+
+    if source == VmmConsoleSource::Stdout {
+        state.notify_waiters(&content);
+    }
+
+    state.push_line(VmmConsoleLine { source, content });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{process::Stdio, time::Duration};
+
+    use futures_util::StreamExt;
+
+    use crate::{
+        runtime::{tokio::TokioRuntime, Runtime, RuntimeChild},
+        vmm::executor::process_handle::ProcessHandlePipes,
+    };
+
+    use super::{VmmConsole, VmmConsoleError, VmmConsoleSource, MAX_LINE_LENGTH};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn spawn_console(script: &str, scrollback_capacity: usize) -> VmmConsole<TokioRuntime> {
+        let mut command = std::process::Command::new("sh");
+        command.arg("-c").arg(script);
+        let mut child = TokioRuntime
+            .spawn_child(command, Stdio::piped(), Stdio::piped(), Stdio::piped())
+            .unwrap();
+        let pipes = ProcessHandlePipes {
+            stdout: child.take_stdout().unwrap(),
+            stderr: child.take_stderr().unwrap(),
+            stdin: child.take_stdin().unwrap(),
+        };
+        tokio::spawn(async move { child.wait().await });
+
+        VmmConsole::new(pipes, TokioRuntime, scrollback_capacity)
+    }
+
+    #[tokio::test]
+    async fn console_matches_unfinished_prompt_and_serializes_writes() {
+        let console = spawn_console(r#"printf 'host login: '; read name; echo "welcome $name""#, 10);
+        assert_eq!(console.wait_for("login:", TIMEOUT).await.unwrap(), "host login: ");
+
+        let mut subscriber = console.subscribe(10);
+        console.write_line("root").await.unwrap();
+        assert_eq!(
+            console.wait_for("welcome", TIMEOUT).await.unwrap(),
+            "host login: welcome root"
+        );
+        assert_eq!(subscriber.next().await.unwrap().content, "host login: welcome root");
+        assert!(subscriber.next().await.is_none());
+        assert!(console.is_closed());
+        assert_matches::assert_matches!(console.wait_for("never", TIMEOUT).await, Err(VmmConsoleError::Closed));
+    }
+
+    #[tokio::test]
+    async fn console_broadcasts_to_subscribers_with_bounded_scrollback() {
+        let console = spawn_console("read _; echo first; echo second >&2; echo third; read _", 2);
+        let mut subscribers = [console.subscribe(10), console.subscribe(10)];
+        console.write_line("").await.unwrap();
+        console.wait_for("third", TIMEOUT).await.unwrap();
+
+        for subscriber in &mut subscribers {
+            let mut lines = Vec::new();
+            for _ in 0..3 {
+                lines.push(subscriber.next().await.unwrap());
+            }
+
+            lines.sort_by_key(|line| line.source);
+            assert_eq!(lines[0].content, "first");
+            assert_eq!(lines[1].content, "third");
+            assert_eq!(lines[2].content, "second");
+            assert_eq!(lines[2].source, VmmConsoleSource::Stderr);
+        }
+
+        assert_eq!(console.scrollback().len(), 2);
+        console.clear_scrollback();
+        assert_matches::assert_matches!(
+            console.wait_for("first", Duration::from_millis(50)).await,
+            Err(VmmConsoleError::Timeout)
+        );
+        console.write_line("").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn console_clear_discards_unfinished_line() {
+        let console = spawn_console("printf before; read _; echo after", 10);
+        console.wait_for("before", TIMEOUT).await.unwrap();
+        console.clear_scrollback();
+
+        let mut subscriber = console.subscribe(10);
+        console.write_line("").await.unwrap();
+        assert_eq!(subscriber.next().await.unwrap().content, "after");
+        assert!(subscriber.next().await.is_none());
+        assert!(console.scrollback().iter().all(|line| line.content == "after"));
+    }
+
+    #[tokio::test]
+    async fn console_raw_subscription_replays_scrollback_and_preserves_bytes() {
+        let console = spawn_console(
+            r#"echo booted; printf 'prompt\033[0m> '; read _; printf 'done\r\n'"#,
+            10,
+        );
+        console.wait_for("> ", TIMEOUT).await.unwrap();
+
+        let mut subscriber = console.subscribe_raw(10, true);
+        assert_eq!(subscriber.next().await.unwrap(), b"booted\r\nprompt\x1b[0m> ");
+        console.write_line("").await.unwrap();
+
+        let mut output = Vec::new();
+        while let Some(chunk) = subscriber.next().await {
+            output.extend(chunk);
+        }
+        assert_eq!(output, b"done\r\n");
+    }
+
+    #[tokio::test]
+    async fn console_breaks_lines_exceeding_max_length() {
+        let console = spawn_console(
+            &format!("read _; head -c {} /dev/zero | tr '\\0' a; echo", MAX_LINE_LENGTH + 5),
+            10,
+        );
+        let mut subscriber = console.subscribe(10);
+        console.write_line("").await.unwrap();
+
+        assert_eq!(subscriber.next().await.unwrap().content, "a".repeat(MAX_LINE_LENGTH));
+        assert_eq!(subscriber.next().await.unwrap().content, "aaaaa");
+        assert!(subscriber.next().await.is_none());
+    }
+}
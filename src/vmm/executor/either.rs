@@ -71,6 +71,17 @@ impl<J: JailRenamer + 'static> VmmExecutor for EitherVmmExecutor<J> {
         }
     }
 
+    fn recover(
+        &mut self,
+        installation: &VmmInstallation,
+        resource_references: VmmResourceReferences<'_>,
+    ) -> Result<(), VmmExecutorError> {
+        match self {
+            EitherVmmExecutor::Unrestricted(executor) => executor.recover(installation, resource_references),
+            EitherVmmExecutor::Jailed(executor) => executor.recover(installation, resource_references),
+        }
+    }
+
     async fn cleanup<S: ProcessSpawner, R: Runtime>(
         &mut self,
         context: VmmExecutorContext<S, R>,
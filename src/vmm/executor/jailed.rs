@@ -7,13 +7,14 @@ use crate::{
     runtime::{util::RuntimeTaskSet, Runtime, RuntimeChild},
     vmm::{
         arguments::{command_modifier::CommandModifier, jailer::JailerArguments, VmmApiSocket, VmmArguments},
+        id::VmmId,
         installation::VmmInstallation,
         ownership::{downgrade_owner_recursively, upgrade_owner, PROCESS_GID, PROCESS_UID},
-        resource::VmmResourceReferences,
+        resource::{VmmResourceJournal, VmmResourceReferences},
     },
 };
 
-use super::{process_handle::ProcessHandle, VmmExecutor, VmmExecutorContext, VmmExecutorError};
+use super::{process_handle::ProcessHandle, roll_back_preparation, VmmExecutor, VmmExecutorContext, VmmExecutorError};
 
 /// A [VmmExecutor] that uses the "jailer" binary for maximum security and isolation, dropping privileges to then
 /// run "firecracker". This executor, due to jailer design, can only run as root, even though the "firecracker"
@@ -45,24 +46,12 @@ impl<J: JailRenamer + 'static> JailedVmmExecutor<J> {
         self.command_modifier_chain.extend(command_modifiers);
         self
     }
-}
-
-impl<J: JailRenamer + 'static> VmmExecutor for JailedVmmExecutor<J> {
-    fn get_socket_path(&self, installation: &VmmInstallation) -> Option<PathBuf> {
-        match &self.vmm_arguments.api_socket {
-            VmmApiSocket::Disabled => None,
-            VmmApiSocket::Enabled(socket_path) => Some(self.get_paths(installation).1.jail_join(socket_path)),
-        }
-    }
 
-    fn local_to_effective_path(&self, installation: &VmmInstallation, local_path: PathBuf) -> PathBuf {
-        self.get_paths(installation).1.jail_join(&local_path)
-    }
-
-    async fn prepare<S: ProcessSpawner, R: Runtime>(
+    async fn prepare_journaled<S: ProcessSpawner, R: Runtime>(
         &mut self,
         context: VmmExecutorContext<S, R>,
         mut resource_references: VmmResourceReferences<'_>,
+        journal: VmmResourceJournal,
     ) -> Result<(), VmmExecutorError> {
         // Create jail and delete previous one if necessary
         let (chroot_base_dir, jail_path) = self.get_paths(context.installation.as_ref());
@@ -88,12 +77,31 @@ impl<J: JailRenamer + 'static> VmmExecutor for JailedVmmExecutor<J> {
                 .map_err(VmmExecutorError::FilesystemError)?;
         }
 
-        context
-            .runtime
-            .fs_create_dir_all(&jail_path)
+        // The directory containing all jails of the same binary is shared with other VMMs, so only the directories
+        // belonging to this jail are journaled and thus removed on rollback
+        if let Some(jail_parent_path) = jail_path.parent().and_then(|path| path.parent()) {
+            context
+                .runtime
+                .fs_create_dir_all(jail_parent_path)
+                .await
+                .map_err(VmmExecutorError::FilesystemError)?;
+        }
+
+        journal
+            .create_dir_all(&jail_path, &context.runtime)
             .await
             .map_err(VmmExecutorError::FilesystemError)?;
 
+        // Rename moved resources for the jail up front, so that no tasks are left running if renaming fails
+        let mut moved_resources = Vec::with_capacity(resource_references.moved_resources.len());
+        for moved_resource in resource_references.moved_resources {
+            let local_path = self
+                .jail_renamer
+                .rename_for_jail(moved_resource.source_path())
+                .map_err(VmmExecutorError::JailRenamerFailed)?;
+            moved_resources.push((moved_resource, local_path));
+        }
+
         let mut task_set = RuntimeTaskSet::new(context.runtime.clone());
 
         // Ensure socket parent directory exists so that the firecracker process can bind inside of it
@@ -102,12 +110,13 @@ impl<J: JailRenamer + 'static> VmmExecutor for JailedVmmExecutor<J> {
                 let socket_parent_dir = socket_parent_dir.to_owned();
                 let jail_path = jail_path.clone();
                 let runtime = context.runtime.clone();
+                let journal = journal.clone();
 
                 task_set.spawn(async move {
                     let expanded_path = jail_path.jail_join(&socket_parent_dir);
 
-                    runtime
-                        .fs_create_dir_all(&expanded_path)
+                    journal
+                        .create_dir_all(&expanded_path, &runtime)
                         .await
                         .map_err(VmmExecutorError::FilesystemError)
                 });
@@ -126,30 +135,28 @@ impl<J: JailRenamer + 'static> VmmExecutor for JailedVmmExecutor<J> {
         for created_resource in resource_references.created_resources {
             task_set.spawn(
                 created_resource
-                    .initialize(
+                    .initialize_journaled(
                         jail_path.jail_join(created_resource.local_path()),
                         context.ownership_model,
                         context.runtime.clone(),
+                        journal.clone(),
                     )
                     .map_err(VmmExecutorError::ResourceError),
             );
         }
 
         // Apply moved resources
-        for moved_resource in resource_references.moved_resources {
-            let local_path = self
-                .jail_renamer
-                .rename_for_jail(moved_resource.source_path())
-                .map_err(VmmExecutorError::JailRenamerFailed)?;
+        for (moved_resource, local_path) in moved_resources {
             let effective_path = jail_path.jail_join(&local_path);
             task_set.spawn(
                 moved_resource
-                    .initialize(
+                    .initialize_journaled(
                         effective_path,
                         local_path,
                         context.ownership_model,
                         context.process_spawner.clone(),
                         context.runtime.clone(),
+                        journal.clone(),
                     )
                     .map_err(VmmExecutorError::ResourceError),
             );
@@ -159,16 +166,21 @@ impl<J: JailRenamer + 'static> VmmExecutor for JailedVmmExecutor<J> {
         for produced_resource in resource_references.produced_resources {
             task_set.spawn(
                 produced_resource
-                    .initialize(
+                    .initialize_journaled(
                         jail_path.jail_join(produced_resource.local_path()),
                         context.ownership_model,
                         context.runtime.clone(),
+                        journal.clone(),
                     )
                     .map_err(VmmExecutorError::ResourceError),
             );
         }
 
-        task_set.wait().await.unwrap_or(Err(VmmExecutorError::TaskJoinFailed))?;
+        // All tasks are awaited even if one fails, so that the journal is complete when rolling back
+        task_set
+            .wait_all()
+            .await
+            .unwrap_or(Err(VmmExecutorError::TaskJoinFailed))?;
 
         downgrade_owner_recursively(&jail_path, context.ownership_model, &context.runtime)
             .await
@@ -176,6 +188,38 @@ impl<J: JailRenamer + 'static> VmmExecutor for JailedVmmExecutor<J> {
 
         Ok(())
     }
+}
+
+impl<J: JailRenamer + 'static> VmmExecutor for JailedVmmExecutor<J> {
+    fn get_socket_path(&self, installation: &VmmInstallation) -> Option<PathBuf> {
+        match &self.vmm_arguments.api_socket {
+            VmmApiSocket::Disabled => None,
+            VmmApiSocket::Enabled(socket_path) => Some(self.get_paths(installation).1.jail_join(socket_path)),
+        }
+    }
+
+    fn local_to_effective_path(&self, installation: &VmmInstallation, local_path: PathBuf) -> PathBuf {
+        self.get_paths(installation).1.jail_join(&local_path)
+    }
+
+    async fn prepare<S: ProcessSpawner, R: Runtime>(
+        &mut self,
+        context: VmmExecutorContext<S, R>,
+        resource_references: VmmResourceReferences<'_>,
+    ) -> Result<(), VmmExecutorError> {
+        let journal = VmmResourceJournal::new();
+        let ownership_model = context.ownership_model;
+        let process_spawner = context.process_spawner.clone();
+        let runtime = context.runtime.clone();
+
+        match self
+            .prepare_journaled(context, resource_references, journal.clone())
+            .await
+        {
+            Ok(()) => Ok(()),
+            Err(err) => Err(roll_back_preparation(err, &journal, ownership_model, &process_spawner, &runtime).await),
+        }
+    }
 
     async fn invoke<S: ProcessSpawner, R: Runtime>(
         &mut self,
@@ -207,15 +251,7 @@ impl<J: JailRenamer + 'static> VmmExecutor for JailedVmmExecutor<J> {
 
         if self.jailer_arguments.daemonize || self.jailer_arguments.exec_in_new_pid_ns {
             let (_, jail_path) = self.get_paths(&context.installation);
-            let pid_file_path = jail_path.join(format!(
-                "{}.pid",
-                context
-                    .installation
-                    .firecracker_path
-                    .file_name()
-                    .and_then(|f| f.to_str())
-                    .unwrap_or("firecracker")
-            ));
+            let pid_file_path = get_pid_file_path(&jail_path, &context.installation);
 
             let exit_status = process.wait().await.map_err(VmmExecutorError::ProcessWaitError)?;
             if !exit_status.success() {
@@ -245,13 +281,54 @@ impl<J: JailRenamer + 'static> VmmExecutor for JailedVmmExecutor<J> {
         }
     }
 
+    fn recover(
+        &mut self,
+        installation: &VmmInstallation,
+        resource_references: VmmResourceReferences<'_>,
+    ) -> Result<(), VmmExecutorError> {
+        let (_, jail_path) = self.get_paths(installation);
+        let created_resources = resource_references
+            .created_resources
+            .into_iter()
+            .chain(self.vmm_arguments.logs.as_mut())
+            .chain(self.vmm_arguments.metrics.as_mut());
+
+        for created_resource in created_resources {
+            created_resource.mark_initialized(jail_path.jail_join(created_resource.local_path()));
+        }
+
+        // the jail renamer is deterministic, so the same local paths as during preparation are produced
+        for moved_resource in resource_references.moved_resources {
+            let local_path = self
+                .jail_renamer
+                .rename_for_jail(moved_resource.source_path())
+                .map_err(VmmExecutorError::JailRenamerFailed)?;
+            moved_resource.mark_initialized(jail_path.jail_join(&local_path), local_path);
+        }
+
+        for produced_resource in resource_references.produced_resources {
+            produced_resource.mark_initialized(jail_path.jail_join(produced_resource.local_path()));
+        }
+
+        Ok(())
+    }
+
     async fn cleanup<S: ProcessSpawner, R: Runtime>(
         &mut self,
         context: VmmExecutorContext<S, R>,
-        _resource_references: VmmResourceReferences<'_>,
+        resource_references: VmmResourceReferences<'_>,
     ) -> Result<(), VmmExecutorError> {
         let (_, jail_path) = self.get_paths(&context.installation);
 
+        // bind mounts need to be undone before anything else touches the jail, since both changing the owner of and
+        // removing the jail would otherwise reach through the mounts into the sources
+        for moved_resource in resource_references.moved_resources {
+            moved_resource
+                .unmount(context.process_spawner.clone(), context.runtime.clone())
+                .await
+                .map_err(VmmExecutorError::ResourceError)?;
+        }
+
         upgrade_owner(
             &jail_path,
             context.ownership_model,
@@ -279,17 +356,11 @@ impl<R: JailRenamer + 'static> JailedVmmExecutor<R> {
             .jailer_arguments
             .chroot_base_dir
             .clone()
-            .unwrap_or(PathBuf::from("/srv/jailer"));
+            .unwrap_or(PathBuf::from(DEFAULT_CHROOT_BASE_DIR));
 
         // example: /srv/jailer/firecracker/1/root
         let jail_path = chroot_base_dir
-            .join(
-                installation
-                    .firecracker_path
-                    .file_name()
-                    .and_then(|f| f.to_str())
-                    .unwrap_or("firecracker"),
-            )
+            .join(get_firecracker_file_name(installation))
             .join(self.jailer_arguments.jail_id.as_ref())
             .join("root");
 
@@ -297,6 +368,86 @@ impl<R: JailRenamer + 'static> JailedVmmExecutor<R> {
     }
 }
 
+const DEFAULT_CHROOT_BASE_DIR: &str = "/srv/jailer";
+
+fn get_firecracker_file_name(installation: &VmmInstallation) -> &str {
+    installation
+        .firecracker_path
+        .file_name()
+        .and_then(|f| f.to_str())
+        .unwrap_or("firecracker")
+}
+
+fn get_pid_file_path(jail_path: &Path, installation: &VmmInstallation) -> PathBuf {
+    jail_path.join(format!("{}.pid", get_firecracker_file_name(installation)))
+}
+
+/// A jail that was found by [scan_jails] inside a jailer chroot base directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScannedJail {
+    /// The ID of the jail, equal to the one that was passed to the jailer.
+    pub jail_id: VmmId,
+    /// The host path to the root directory of the jail.
+    pub jail_path: PathBuf,
+    /// The PID of the VMM process that is still running inside the jail. This is only known when the jailer
+    /// was configured to daemonize or to exec into a new PID namespace, since only then is a PID file written.
+    pub pid: Option<i32>,
+}
+
+/// Scan the given jailer chroot base directory (or "/srv/jailer" if none is given, same as with the jailer) for jails
+/// of VMMs that use the given [VmmInstallation]. This is useful for finding VMMs that are still running after the
+/// control process has restarted, in order to attach to them or to clean up their jails.
+pub async fn scan_jails<R: Runtime>(
+    chroot_base_dir: Option<&Path>,
+    installation: &VmmInstallation,
+    runtime: &R,
+) -> Result<Vec<ScannedJail>, std::io::Error> {
+    let jails_path = chroot_base_dir
+        .unwrap_or(Path::new(DEFAULT_CHROOT_BASE_DIR))
+        .join(get_firecracker_file_name(installation));
+
+    if !runtime.fs_exists(&jails_path).await? {
+        return Ok(Vec::new());
+    }
+
+    let mut scanned_jails = Vec::new();
+
+    for path in runtime.fs_read_dir(&jails_path).await? {
+        let Some(jail_id) = path
+            .file_name()
+            .and_then(|file_name| file_name.to_str())
+            .and_then(|file_name| VmmId::new(file_name).ok())
+        else {
+            continue;
+        };
+
+        let jail_path = path.join("root");
+        if !runtime.fs_exists(&jail_path).await? {
+            continue;
+        }
+
+        let mut pid = None;
+        if let Ok(pid_string) = runtime
+            .fs_read_to_string(&get_pid_file_path(&jail_path, installation))
+            .await
+        {
+            if let Ok(parsed_pid) = pid_string.trim_end().parse::<i32>() {
+                if runtime.fs_exists(&PathBuf::from(format!("/proc/{parsed_pid}"))).await? {
+                    pid = Some(parsed_pid);
+                }
+            }
+        }
+
+        scanned_jails.push(ScannedJail {
+            jail_id,
+            jail_path,
+            pid,
+        });
+    }
+
+    Ok(scanned_jails)
+}
+
 /// An error that can be emitted by a [JailRenamer].
 #[derive(Debug)]
 pub enum JailRenamerError {
@@ -371,7 +522,7 @@ mod tests {
 
     #[test]
     fn flat_jail_renamer_moves_correctly() {
-        let renamer = FlatJailRenamer::default();
+        let renamer = FlatJailRenamer;
         assert_renamer(&renamer, "/opt/file", "/file");
         assert_renamer(&renamer, "/tmp/some_path.txt", "/some_path.txt");
         assert_renamer(&renamer, "/some/complex/outside/path/filename.ext4", "/filename.ext4");
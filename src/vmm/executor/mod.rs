@@ -40,6 +40,11 @@ pub enum VmmExecutorError {
     JailRenamerFailed(JailRenamerError),
     ProcessExitedWithIncorrectStatus(ExitStatus),
     ParseIntError(ParseIntError),
+    RollbackFailed {
+        error: Box<VmmExecutorError>,
+        rollback_error: VmmResourceError,
+    },
+    RecoveryUnsupported,
     Other(Box<dyn std::error::Error + Send>),
 }
 
@@ -72,6 +77,13 @@ impl std::fmt::Display for VmmExecutorError {
                 write!(f, "A watched process exited with a non-zero exit status: {exit_status}")
             }
             VmmExecutorError::ParseIntError(err) => write!(f, "Parsing an integer from a string failed: {err}"),
+            VmmExecutorError::RollbackFailed { error, rollback_error } => write!(
+                f,
+                "Rolling back the preparation after it failed with \"{error}\" failed: {rollback_error}"
+            ),
+            VmmExecutorError::RecoveryUnsupported => {
+                write!(f, "Recovering an already invoked VMM isn't supported by this executor")
+            }
             VmmExecutorError::Other(err) => write!(f, "Another error occurred: {err}"),
         }
     }
@@ -86,7 +98,8 @@ pub trait VmmExecutor: Send + Sync {
     /// Transform a given local resource path to an effective resource path.
     fn local_to_effective_path(&self, installation: &VmmInstallation, local_path: PathBuf) -> PathBuf;
 
-    /// Prepare all transient resources for the VMM invocation.
+    /// Prepare all transient resources for the VMM invocation. If the preparation fails partway, the filesystem
+    /// operations it had already performed should be rolled back before the error is returned.
     fn prepare<S: ProcessSpawner, R: Runtime>(
         &mut self,
         context: VmmExecutorContext<S, R>,
@@ -102,6 +115,18 @@ pub trait VmmExecutor: Send + Sync {
         config_path: Option<PathBuf>,
     ) -> impl Future<Output = Result<ProcessHandle<R>, VmmExecutorError>> + Send;
 
+    /// Recover the state of the given resources for a VMM that was already prepared and invoked by an equally
+    /// configured executor, without performing any filesystem operations. This is used when reattaching to a
+    /// VMM process after the control process has restarted. Executors that don't support recovery keep the default
+    /// implementation, which returns [VmmExecutorError::RecoveryUnsupported].
+    fn recover(
+        &mut self,
+        _installation: &VmmInstallation,
+        _resource_references: VmmResourceReferences<'_>,
+    ) -> Result<(), VmmExecutorError> {
+        Err(VmmExecutorError::RecoveryUnsupported)
+    }
+
     /// Clean up all transient resources of the VMM invocation.
     fn cleanup<S: ProcessSpawner, R: Runtime>(
         &mut self,
@@ -116,3 +141,22 @@ pub struct VmmExecutorContext<S: ProcessSpawner, R: Runtime> {
     pub runtime: R,
     pub ownership_model: VmmOwnershipModel,
 }
+
+// roll back the operations recorded by a failed preparation, returning the error of the preparation unless the
+// rollback itself fails
+#[cfg(any(feature = "unrestricted-vmm-executor", feature = "jailed-vmm-executor"))]
+async fn roll_back_preparation<S: ProcessSpawner, R: Runtime>(
+    error: VmmExecutorError,
+    journal: &super::resource::VmmResourceJournal,
+    ownership_model: VmmOwnershipModel,
+    process_spawner: &S,
+    runtime: &R,
+) -> VmmExecutorError {
+    match journal.roll_back(ownership_model, process_spawner, runtime).await {
+        Ok(()) => error,
+        Err(rollback_error) => VmmExecutorError::RollbackFailed {
+            error: Box::new(error),
+            rollback_error,
+        },
+    }
+}
@@ -56,6 +56,7 @@ enum ProcessHandleInner<R: Runtime> {
         pipes_dropped: bool,
     },
     Pidfd {
+        pid: i32,
         raw_pidfd: RawFd,
         exited_rx: futures_channel::oneshot::Receiver<ExitStatus>,
         exited: Option<ExitStatus>,
@@ -94,12 +95,30 @@ impl<R: Runtime> ProcessHandle<R> {
         });
 
         Ok(Self(ProcessHandleInner::Pidfd {
+            pid,
             raw_pidfd,
             exited_rx,
             exited: None,
         }))
     }
 
+    /// Get the PID of the process, if it is still known. The PID of an attached child process is no longer known
+    /// after it has been waited on.
+    pub fn pid(&self) -> Option<i32> {
+        match self.0 {
+            ProcessHandleInner::Child {
+                ref process,
+                pipes_dropped: _,
+            } => process.id().and_then(|pid| pid.try_into().ok()),
+            ProcessHandleInner::Pidfd {
+                pid,
+                raw_pidfd: _,
+                exited_rx: _,
+                exited: _,
+            } => Some(pid),
+        }
+    }
+
     /// Send a SIGKILL signal to the process.
     pub fn send_sigkill(&mut self) -> Result<(), std::io::Error> {
         match self.0 {
@@ -108,6 +127,7 @@ impl<R: Runtime> ProcessHandle<R> {
                 pipes_dropped: _,
             } => process.kill(),
             ProcessHandleInner::Pidfd {
+                pid: _,
                 raw_pidfd,
                 exited_rx: _,
                 exited,
@@ -129,6 +149,7 @@ impl<R: Runtime> ProcessHandle<R> {
                 pipes_dropped: _,
             } => process.wait().await,
             ProcessHandleInner::Pidfd {
+                pid: _,
                 raw_pidfd: _,
                 ref mut exited_rx,
                 ref mut exited,
@@ -154,6 +175,7 @@ impl<R: Runtime> ProcessHandle<R> {
                 pipes_dropped: _,
             } => process.try_wait(),
             ProcessHandleInner::Pidfd {
+                pid: _,
                 raw_pidfd: _,
                 ref mut exited_rx,
                 ref mut exited,
@@ -177,6 +199,7 @@ impl<R: Runtime> ProcessHandle<R> {
     pub fn get_pipes(&mut self) -> Result<ProcessHandlePipes<R::Child>, ProcessHandlePipesError> {
         match self.0 {
             ProcessHandleInner::Pidfd {
+                pid: _,
                 raw_pidfd: _,
                 exited_rx: _,
                 exited: _,
@@ -10,11 +10,11 @@ use crate::{
         id::VmmId,
         installation::VmmInstallation,
         ownership::upgrade_owner,
-        resource::VmmResourceReferences,
+        resource::{VmmResourceJournal, VmmResourceReferences},
     },
 };
 
-use super::{process_handle::ProcessHandle, VmmExecutor, VmmExecutorContext, VmmExecutorError};
+use super::{process_handle::ProcessHandle, roll_back_preparation, VmmExecutor, VmmExecutorContext, VmmExecutorError};
 
 /// A [VmmExecutor] that uses the "firecracker" binary directly, without jailing it or ensuring it doesn't run as root.
 /// This [VmmExecutor] allows rootless execution, given that the user has been granted access to /dev/kvm, but using
@@ -56,24 +56,12 @@ impl UnrestrictedVmmExecutor {
         self.id = Some(id);
         self
     }
-}
 
-impl VmmExecutor for UnrestrictedVmmExecutor {
-    fn get_socket_path(&self, _installation: &VmmInstallation) -> Option<PathBuf> {
-        match &self.vmm_arguments.api_socket {
-            VmmApiSocket::Disabled => None,
-            VmmApiSocket::Enabled(path) => Some(path.clone()),
-        }
-    }
-
-    fn local_to_effective_path(&self, _installation: &VmmInstallation, local_path: PathBuf) -> PathBuf {
-        local_path
-    }
-
-    async fn prepare<S: ProcessSpawner, R: Runtime>(
+    async fn prepare_journaled<S: ProcessSpawner, R: Runtime>(
         &mut self,
         context: VmmExecutorContext<S, R>,
         mut resource_references: VmmResourceReferences<'_>,
+        journal: VmmResourceJournal,
     ) -> Result<(), VmmExecutorError> {
         let mut task_set = RuntimeTaskSet::new(context.runtime.clone());
 
@@ -127,7 +115,12 @@ impl VmmExecutor for UnrestrictedVmmExecutor {
         for created_resource in resource_references.created_resources {
             task_set.spawn(
                 created_resource
-                    .initialize_with_same_path::<R>(context.ownership_model, context.runtime.clone())
+                    .initialize_journaled(
+                        created_resource.local_path().to_owned(),
+                        context.ownership_model,
+                        context.runtime.clone(),
+                        journal.clone(),
+                    )
                     .map_err(VmmExecutorError::ResourceError),
             );
         }
@@ -135,13 +128,53 @@ impl VmmExecutor for UnrestrictedVmmExecutor {
         for produced_resource in resource_references.produced_resources {
             task_set.spawn(
                 produced_resource
-                    .initialize_with_same_path::<R>(context.ownership_model, context.runtime.clone())
+                    .initialize_journaled(
+                        produced_resource.local_path().to_owned(),
+                        context.ownership_model,
+                        context.runtime.clone(),
+                        journal.clone(),
+                    )
                     .map_err(VmmExecutorError::ResourceError),
             );
         }
 
-        task_set.wait().await.unwrap_or(Err(VmmExecutorError::TaskJoinFailed))?;
-        Ok(())
+        // All tasks are awaited even if one fails, so that the journal is complete when rolling back
+        task_set
+            .wait_all()
+            .await
+            .unwrap_or(Err(VmmExecutorError::TaskJoinFailed))
+    }
+}
+
+impl VmmExecutor for UnrestrictedVmmExecutor {
+    fn get_socket_path(&self, _installation: &VmmInstallation) -> Option<PathBuf> {
+        match &self.vmm_arguments.api_socket {
+            VmmApiSocket::Disabled => None,
+            VmmApiSocket::Enabled(path) => Some(path.clone()),
+        }
+    }
+
+    fn local_to_effective_path(&self, _installation: &VmmInstallation, local_path: PathBuf) -> PathBuf {
+        local_path
+    }
+
+    async fn prepare<S: ProcessSpawner, R: Runtime>(
+        &mut self,
+        context: VmmExecutorContext<S, R>,
+        resource_references: VmmResourceReferences<'_>,
+    ) -> Result<(), VmmExecutorError> {
+        let journal = VmmResourceJournal::new();
+        let ownership_model = context.ownership_model;
+        let process_spawner = context.process_spawner.clone();
+        let runtime = context.runtime.clone();
+
+        match self
+            .prepare_journaled(context, resource_references, journal.clone())
+            .await
+        {
+            Ok(()) => Ok(()),
+            Err(err) => Err(roll_back_preparation(err, &journal, ownership_model, &process_spawner, &runtime).await),
+        }
     }
 
     async fn invoke<S: ProcessSpawner, R: Runtime>(
@@ -169,6 +202,33 @@ impl VmmExecutor for UnrestrictedVmmExecutor {
         Ok(ProcessHandle::with_child(child, self.pipes_to_null))
     }
 
+    fn recover(
+        &mut self,
+        _installation: &VmmInstallation,
+        resource_references: VmmResourceReferences<'_>,
+    ) -> Result<(), VmmExecutorError> {
+        let created_resources = resource_references
+            .created_resources
+            .into_iter()
+            .chain(self.vmm_arguments.logs.as_mut())
+            .chain(self.vmm_arguments.metrics.as_mut());
+
+        for moved_resource in resource_references.moved_resources {
+            let source_path = moved_resource.source_path().to_owned();
+            moved_resource.mark_initialized(source_path.clone(), source_path);
+        }
+
+        for created_resource in created_resources {
+            created_resource.mark_initialized(created_resource.local_path().to_owned());
+        }
+
+        for produced_resource in resource_references.produced_resources {
+            produced_resource.mark_initialized(produced_resource.local_path().to_owned());
+        }
+
+        Ok(())
+    }
+
     async fn cleanup<S: ProcessSpawner, R: Runtime>(
         &mut self,
         context: VmmExecutorContext<S, R>,
@@ -14,6 +14,9 @@
 //! The `unrestricted-vmm-executor`, `jailed-vmm-executor` and `either-vmm-executor` features enable the
 //! respective default implementations of VMM executors.
 //!
+//! The `vmm-executor` feature also provides a console hub that multiplexes the serial console of a VMM process
+//! between many readers and writers.
+//!
 //! With the `vmm-process` feature, a VMM process abstraction that works on top of a VMM executor
 //! and provides additional useful functionality like an HTTP connection pool is additionally available.
 
@@ -31,6 +34,10 @@ pub mod ownership;
 #[cfg_attr(docsrs, doc(cfg(feature = "vmm-executor")))]
 pub mod executor;
 
+#[cfg(feature = "vmm-executor")]
+#[cfg_attr(docsrs, doc(cfg(feature = "vmm-executor")))]
+pub mod console;
+
 #[cfg(feature = "vmm-process")]
 #[cfg_attr(docsrs, doc(cfg(feature = "vmm-process")))]
 pub mod process;
@@ -16,6 +16,7 @@ pub(crate) static PROCESS_GID: LazyLock<u32> = LazyLock::new(crate::syscall::get
 /// The model used for managing the ownership of resources between the controlling process
 /// (the Rust application using fctools) and the VMM process ("firecracker").
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "vm", derive(serde::Serialize, serde::Deserialize))]
 pub enum VmmOwnershipModel {
     /// The resources are fully shared between control and VMM processes. Either both run
     /// as root or both are run rootlessly. The latter scenario is incompatible with jailing,
@@ -51,7 +52,7 @@ impl VmmOwnershipModel {
     }
 
     #[inline]
-    fn is_upgrade(&self) -> bool {
+    pub(crate) fn is_upgrade(&self) -> bool {
         matches!(
             self,
             VmmOwnershipModel::UpgradedTemporarily | VmmOwnershipModel::UpgradedPermanently
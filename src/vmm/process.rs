@@ -18,6 +18,7 @@ use crate::{
 };
 
 use super::{
+    console::{VmmConsole, DEFAULT_SCROLLBACK_CAPACITY},
     executor::{
         process_handle::{ProcessHandle, ProcessHandlePipes, ProcessHandlePipesError},
         VmmExecutorContext,
@@ -36,10 +37,29 @@ pub struct VmmProcess<E: VmmExecutor, S: ProcessSpawner, R: Runtime> {
     runtime: R,
     installation: Arc<VmmInstallation>,
     process_handle: Option<ProcessHandle<R>>,
+    console: Option<VmmConsole<R>>,
     state: VmmProcessState,
     hyper_client: OnceCell<Client<HyperUnixConnector, Full<Bytes>>>,
 }
 
+/// A descriptor of a started [VmmProcess] that contains all information needed to attach to it again via
+/// [VmmProcess::attach], for example after the control process has restarted. The descriptor should be persisted
+/// by the control process (it is serializable when the `vm` feature is enabled) right after the [VmmProcess] has
+/// been started.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "vm", derive(serde::Serialize, serde::Deserialize))]
+pub struct VmmProcessDescriptor {
+    /// The PID of the "firecracker" process.
+    pub pid: i32,
+    /// The effective path of the API server socket, if one is enabled.
+    pub socket_path: Option<PathBuf>,
+    /// The effective path that the root of the VMM's filesystem view corresponds to: the jail path for jailed
+    /// VMMs and "/" for unrestricted ones.
+    pub root_path: PathBuf,
+    /// The [VmmOwnershipModel] that the process was started with.
+    pub ownership_model: VmmOwnershipModel,
+}
+
 /// The state of a [VmmProcess].
 /// Keep in mind: the [VmmProcess] lifecycle is not that of the VM! If the process has
 /// started without a config file, API requests will need to be issued first in order
@@ -93,6 +113,12 @@ pub enum VmmProcessError {
     ProcessWaitFailed(std::io::Error),
     ExecutorError(VmmExecutorError),
     ProcessHandlePipesError(ProcessHandlePipesError),
+    PidfdAllocationError(std::io::Error),
+    PidUnavailable,
+    DescriptorMismatch {
+        descriptor_path: Option<PathBuf>,
+        executor_path: Option<PathBuf>,
+    },
 }
 
 impl std::error::Error for VmmProcessError {}
@@ -129,6 +155,17 @@ impl std::fmt::Display for VmmProcessError {
             VmmProcessError::ProcessHandlePipesError(err) => {
                 write!(f, "Getting the pipes from the process handle failed: {err}")
             }
+            VmmProcessError::PidfdAllocationError(err) => {
+                write!(f, "Allocating a pidfd to attach to the process failed: {err}")
+            }
+            VmmProcessError::PidUnavailable => write!(f, "The PID of the process is no longer known"),
+            VmmProcessError::DescriptorMismatch {
+                descriptor_path,
+                executor_path,
+            } => write!(
+                f,
+                "The path {descriptor_path:?} from the descriptor doesn't match the path {executor_path:?} produced by the executor"
+            ),
         }
     }
 }
@@ -143,7 +180,6 @@ impl<E: VmmExecutor, S: ProcessSpawner, R: Runtime> VmmProcess<E, S, R> {
         ownership_model: VmmOwnershipModel,
         installation: Arc<VmmInstallation>,
     ) -> Self {
-        let installation = installation.into();
         Self {
             executor,
             ownership_model,
@@ -151,11 +187,60 @@ impl<E: VmmExecutor, S: ProcessSpawner, R: Runtime> VmmProcess<E, S, R> {
             runtime,
             installation,
             process_handle: None,
+            console: None,
             state: VmmProcessState::AwaitingPrepare,
             hyper_client: OnceCell::new(),
         }
     }
 
+    /// Attach to an already running [VmmProcess] described by the given [VmmProcessDescriptor], which was previously
+    /// prepared and started by an executor configured equally to the given [VmmExecutor]. No filesystem operations are
+    /// performed: the given [VmmResourceReferences] are only marked as initialized at the paths they were given when
+    /// preparing. The resulting [VmmProcess] will be in [VmmProcessState::Started] and is controlled via a pidfd, so
+    /// its pipes can't be taken.
+    pub fn attach(
+        mut executor: E,
+        process_spawner: S,
+        runtime: R,
+        installation: Arc<VmmInstallation>,
+        descriptor: &VmmProcessDescriptor,
+        resource_references: VmmResourceReferences<'_>,
+    ) -> Result<Self, VmmProcessError> {
+        let socket_path = executor.get_socket_path(installation.as_ref());
+        if socket_path != descriptor.socket_path {
+            return Err(VmmProcessError::DescriptorMismatch {
+                descriptor_path: descriptor.socket_path.clone(),
+                executor_path: socket_path,
+            });
+        }
+
+        let root_path = executor.local_to_effective_path(installation.as_ref(), PathBuf::from("/"));
+        if root_path != descriptor.root_path {
+            return Err(VmmProcessError::DescriptorMismatch {
+                descriptor_path: Some(descriptor.root_path.clone()),
+                executor_path: Some(root_path),
+            });
+        }
+
+        executor
+            .recover(installation.as_ref(), resource_references)
+            .map_err(VmmProcessError::ExecutorError)?;
+        let process_handle = ProcessHandle::with_pidfd(descriptor.pid, runtime.clone())
+            .map_err(VmmProcessError::PidfdAllocationError)?;
+
+        Ok(Self {
+            executor,
+            ownership_model: descriptor.ownership_model,
+            process_spawner,
+            runtime,
+            installation,
+            process_handle: Some(process_handle),
+            console: None,
+            state: VmmProcessState::Started,
+            hyper_client: OnceCell::new(),
+        })
+    }
+
     /// Prepare the [VmmProcess] environment. Allowed in [VmmProcessState::AwaitingPrepare], will result in [VmmProcessState::AwaitingStart].
     pub async fn prepare(&mut self, resource_references: VmmResourceReferences<'_>) -> Result<(), VmmProcessError> {
         self.ensure_state(VmmProcessState::AwaitingPrepare)?;
@@ -230,6 +315,41 @@ impl<E: VmmExecutor, S: ProcessSpawner, R: Runtime> VmmProcess<E, S, R> {
             .map_err(VmmProcessError::ProcessHandlePipesError)
     }
 
+    /// Create a [VmmProcessDescriptor] for this [VmmProcess] that can be persisted and later used to attach to it via
+    /// [VmmProcess::attach]. Allowed in [VmmProcessState::Started].
+    pub fn descriptor(&mut self) -> Result<VmmProcessDescriptor, VmmProcessError> {
+        self.ensure_state(VmmProcessState::Started)?;
+        let pid = self
+            .process_handle
+            .as_ref()
+            .expect("No process handle after having started cannot happen")
+            .pid()
+            .ok_or(VmmProcessError::PidUnavailable)?;
+
+        Ok(VmmProcessDescriptor {
+            pid,
+            socket_path: self.get_socket_path(),
+            root_path: self.local_to_effective_path("/"),
+            ownership_model: self.ownership_model,
+        })
+    }
+
+    /// Get the [VmmConsole] hub of the underlying process, creating it on first use by taking out the pipes of the
+    /// process. The console is therefore mutually exclusive with [VmmProcess::take_pipes]. Allowed in
+    /// [VmmProcessState::Started].
+    pub fn console(&mut self) -> Result<VmmConsole<R>, VmmProcessError> {
+        self.ensure_state(VmmProcessState::Started)?;
+
+        if let Some(ref console) = self.console {
+            return Ok(console.clone());
+        }
+
+        let pipes = self.take_pipes()?;
+        let console = VmmConsole::new(pipes, self.runtime.clone(), DEFAULT_SCROLLBACK_CAPACITY);
+        self.console = Some(console.clone());
+        Ok(console)
+    }
+
     /// Gets the outer path to the API server socket, if one has been configured, via the executor.
     pub fn get_socket_path(&self) -> Option<PathBuf> {
         self.executor.get_socket_path(self.installation.as_ref())
@@ -1,16 +1,22 @@
 use std::{
+    cmp::Reverse,
     future::Future,
     path::{Path, PathBuf},
+    process::ExitStatus,
+    sync::{Arc, Mutex},
 };
 
 use crate::{
     process_spawner::ProcessSpawner,
-    runtime::Runtime,
+    runtime::{FastCopyStrategy, Runtime, RuntimeChild},
     vmm::ownership::{downgrade_owner, VmmOwnershipModel},
 };
 
 use super::ownership::{upgrade_owner, ChangeOwnerError};
 
+#[cfg(feature = "resource-source-extension")]
+use crate::extension::resource_source::{resolve_source_deduplicated, ResourceSource};
+
 /// An error that can be produced by an operation on a VMM resource.
 #[derive(Debug)]
 pub enum VmmResourceError {
@@ -18,6 +24,10 @@ pub enum VmmResourceError {
     MkfifoError(std::io::Error),
     ChangeOwnerError(ChangeOwnerError),
     SourcePathMissing(PathBuf),
+    MountProcessSpawnFailed(std::io::Error),
+    MountProcessWaitFailed(std::io::Error),
+    MountProcessExitedWithWrongStatus(ExitStatus),
+    SourceResolutionFailed(Box<dyn std::error::Error + Send + Sync>),
 }
 
 impl std::error::Error for VmmResourceError {}
@@ -33,10 +43,45 @@ impl std::fmt::Display for VmmResourceError {
             VmmResourceError::SourcePathMissing(path) => {
                 write!(f, "The source path of a resource is missing: {}", path.display())
             }
+            VmmResourceError::MountProcessSpawnFailed(err) => {
+                write!(f, "Spawning a mount or umount process failed: {err}")
+            }
+            VmmResourceError::MountProcessWaitFailed(err) => {
+                write!(
+                    f,
+                    "Waiting on the completion of a mount or umount process failed: {err}"
+                )
+            }
+            VmmResourceError::MountProcessExitedWithWrongStatus(exit_status) => {
+                write!(
+                    f,
+                    "The mount or umount process exited with a non-zero exit status: {exit_status}"
+                )
+            }
+            VmmResourceError::SourceResolutionFailed(err) => {
+                write!(f, "Resolving the source of a resource failed: {err}")
+            }
         }
     }
 }
 
+// the resolution of a source is implemented by the extension, which is only hooked into the initialization here
+#[cfg(feature = "resource-source-extension")]
+async fn resolve_source(source: Option<ResourceSourceSlot>) -> Result<(), VmmResourceError> {
+    let Some(ResourceSourceSlot(source)) = source else {
+        return Ok(());
+    };
+
+    resolve_source_deduplicated(source.as_ref())
+        .await
+        .map_err(|err| VmmResourceError::SourceResolutionFailed(Box::new(err)))
+}
+
+#[cfg(not(feature = "resource-source-extension"))]
+async fn resolve_source(_source: Option<ResourceSourceSlot>) -> Result<(), VmmResourceError> {
+    Ok(())
+}
+
 /// A set of mutable references to VMM resources of all three types. Through these references, the resources
 /// should be initialized by a VMM executor.
 pub struct VmmResourceReferences<'res> {
@@ -56,6 +101,146 @@ impl<'res> VmmResourceReferences<'res> {
     }
 }
 
+impl Default for VmmResourceReferences<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A journal of the filesystem operations performed while initializing VMM resources, which allows a VMM executor to
+/// undo a partially failed preparation via [VmmResourceJournal::roll_back]. Clones of a journal share the same
+/// recorded operations, so a clone can be handed to every concurrently spawned initialization.
+#[derive(Debug, Clone, Default)]
+pub struct VmmResourceJournal(Arc<Mutex<Vec<VmmResourceOperation>>>);
+
+/// A filesystem operation recorded into a [VmmResourceJournal].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VmmResourceOperation {
+    /// A directory that didn't exist before was created at the path. Every created directory is recorded on its own,
+    /// parents before their children, so that rolling back only ever removes empty directories.
+    CreatedDirectory(PathBuf),
+    /// A file, named pipe, copy or link that didn't exist before was created at the path.
+    CreatedFile(PathBuf),
+    /// A file was renamed from the source path to the destination path.
+    Renamed {
+        source_path: PathBuf,
+        destination_path: PathBuf,
+    },
+    /// A file was bind mounted onto the path.
+    BindMounted(PathBuf),
+}
+
+impl VmmResourceJournal {
+    /// Create a new, empty journal.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record an operation into the journal. Operations that may partially succeed should be recorded before being
+    /// performed, since undoing an operation that didn't happen is a no-op.
+    pub fn record(&self, operation: VmmResourceOperation) {
+        self.0
+            .lock()
+            .expect("Resource journal mutex was poisoned")
+            .push(operation);
+    }
+
+    /// Get a copy of all operations recorded so far, in the order they were recorded in.
+    pub fn operations(&self) -> Vec<VmmResourceOperation> {
+        self.0.lock().expect("Resource journal mutex was poisoned").clone()
+    }
+
+    /// Create the directory at the given path along with all of its missing parents via the [Runtime], recording
+    /// every directory that was missing.
+    pub async fn create_dir_all<R: Runtime>(&self, path: &Path, runtime: &R) -> Result<(), std::io::Error> {
+        let mut missing_paths = Vec::new();
+
+        for ancestor_path in path.ancestors() {
+            if ancestor_path.as_os_str().is_empty() || runtime.fs_exists(ancestor_path).await? {
+                break;
+            }
+
+            missing_paths.push(ancestor_path.to_owned());
+        }
+
+        for missing_path in missing_paths.into_iter().rev() {
+            self.record(VmmResourceOperation::CreatedDirectory(missing_path));
+        }
+
+        runtime.fs_create_dir_all(path).await
+    }
+
+    /// Undo all recorded operations according to the ownership constraints defined by [VmmOwnershipModel] and
+    /// [ProcessSpawner], emptying the journal. Renames are undone first, then bind mounts, then created files and
+    /// lastly created directories from the deepest one upwards, each in reverse order of recording. Files and
+    /// directories that no longer exist are skipped, except for renamed files, and a failure to undo one operation
+    /// doesn't prevent the remaining ones from being undone, with the first failure being returned at the end.
+    /// Ownership changes aren't undone.
+    pub async fn roll_back<S: ProcessSpawner, R: Runtime>(
+        &self,
+        ownership_model: VmmOwnershipModel,
+        process_spawner: &S,
+        runtime: &R,
+    ) -> Result<(), VmmResourceError> {
+        let mut operations = std::mem::take(&mut *self.0.lock().expect("Resource journal mutex was poisoned"));
+        operations.reverse();
+
+        // concurrent initializations may record a created directory after a file was already renamed into it, so the
+        // order of undoing is determined by the kinds of operations rather than only by the order of recording
+        operations.sort_by_key(|operation| match operation {
+            VmmResourceOperation::Renamed { .. } => (0, Reverse(0)),
+            VmmResourceOperation::BindMounted(_) => (1, Reverse(0)),
+            VmmResourceOperation::CreatedFile(_) => (2, Reverse(0)),
+            VmmResourceOperation::CreatedDirectory(path) => (3, Reverse(path.components().count())),
+        });
+
+        let mut result = Ok(());
+
+        for operation in operations {
+            let operation_result = match operation {
+                // directories are removed non-recursively, so that anything not created by the journaled operations,
+                // such as the contents of directories shared with other VMMs, is never removed
+                VmmResourceOperation::CreatedDirectory(path) => {
+                    match upgrade_owner(&path, ownership_model, process_spawner, runtime).await {
+                        Ok(()) => ignore_not_found(runtime.fs_remove_dir(&path).await),
+                        Err(err) => Err(VmmResourceError::ChangeOwnerError(err)),
+                    }
+                }
+                VmmResourceOperation::CreatedFile(path) => ignore_not_found(runtime.fs_remove_file(&path).await),
+                VmmResourceOperation::Renamed {
+                    source_path,
+                    destination_path,
+                } => runtime
+                    .fs_rename(&destination_path, &source_path)
+                    .await
+                    .map_err(VmmResourceError::FilesystemError),
+                VmmResourceOperation::BindMounted(path) => {
+                    run_mount_process(
+                        "umount",
+                        vec![path.to_string_lossy().into_owned()],
+                        process_spawner,
+                        runtime,
+                    )
+                    .await
+                }
+            };
+
+            if result.is_ok() {
+                result = operation_result;
+            }
+        }
+
+        result
+    }
+}
+
+fn ignore_not_found(result: Result<(), std::io::Error>) -> Result<(), VmmResourceError> {
+    match result {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(VmmResourceError::FilesystemError(err)),
+        _ => Ok(()),
+    }
+}
+
 /// A VMM resource that is created by the control process for the VMM to use, for example a log or metrics file.
 /// The type of file that the resource should be is defined by the [CreatedVmmResourceType].
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -83,18 +268,38 @@ impl CreatedVmmResource {
         effective_path: PathBuf,
         ownership_model: VmmOwnershipModel,
         runtime: R,
+    ) -> impl Future<Output = Result<(), VmmResourceError>> + Send {
+        self.initialize_journaled(effective_path, ownership_model, runtime, VmmResourceJournal::new())
+    }
+
+    /// Initialize the created resource like [CreatedVmmResource::initialize], additionally recording the performed
+    /// filesystem operations into the given [VmmResourceJournal].
+    pub fn initialize_journaled<R: Runtime>(
+        &mut self,
+        effective_path: PathBuf,
+        ownership_model: VmmOwnershipModel,
+        runtime: R,
+        journal: VmmResourceJournal,
     ) -> impl Future<Output = Result<(), VmmResourceError>> + Send {
         self.effective_path = Some(effective_path.clone());
 
         let r#type = self.r#type;
         async move {
             if let Some(parent_path) = effective_path.parent() {
-                runtime
-                    .fs_create_dir_all(&parent_path)
+                journal
+                    .create_dir_all(parent_path, &runtime)
                     .await
                     .map_err(VmmResourceError::FilesystemError)?;
             }
 
+            if !runtime
+                .fs_exists(&effective_path)
+                .await
+                .map_err(VmmResourceError::FilesystemError)?
+            {
+                journal.record(VmmResourceOperation::CreatedFile(effective_path.clone()));
+            }
+
             match r#type {
                 CreatedVmmResourceType::File => {
                     runtime
@@ -122,6 +327,12 @@ impl CreatedVmmResource {
         self.initialize(self.local_path.clone(), ownership_model, runtime)
     }
 
+    /// Mark the resource as initialized to the given effective path without touching the filesystem, which is
+    /// needed when recovering a VMM whose resources were already initialized before.
+    pub fn mark_initialized(&mut self, effective_path: PathBuf) {
+        self.effective_path = Some(effective_path);
+    }
+
     /// Dispose of the resource by deleting it according to ownership constraints via [VmmOwnershipModel] and
     /// [ProcessSpawner].
     pub fn dispose<S: ProcessSpawner, R: Runtime>(
@@ -165,6 +376,7 @@ impl CreatedVmmResource {
 
 /// The type of file that a [CreatedVmmResource] is backed by.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "vm", derive(serde::Serialize, serde::Deserialize))]
 pub enum CreatedVmmResourceType {
     /// A plain-text file created normally.
     File,
@@ -183,6 +395,19 @@ impl serde::Serialize for CreatedVmmResource {
     }
 }
 
+/// A deserialized [CreatedVmmResource] is uninitialized, has the deserialized local path and is of the
+/// [CreatedVmmResourceType::File] type, since the type isn't part of the serialized form.
+#[cfg(feature = "vm")]
+#[cfg_attr(docsrs, doc(cfg(feature = "vm")))]
+impl<'de> serde::Deserialize<'de> for CreatedVmmResource {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        PathBuf::deserialize(deserializer).map(|path| Self::new(path, CreatedVmmResourceType::File))
+    }
+}
+
 /// A VMM resource that represents an already-existing file accessible by the control process that is
 /// moved for use by the VMM. The filesystem method to move the file is defined by the
 /// [VmmResourceMoveMethod]. A kernel image, an initrd and a block device for a VM are all examples of
@@ -193,8 +418,53 @@ pub struct MovedVmmResource {
     effective_path: Option<PathBuf>,
     local_path: Option<PathBuf>,
     move_method: VmmResourceMoveMethod,
+    copy_strategy: CopyStrategySlot,
+    source: Option<ResourceSourceSlot>,
+}
+
+// a source is fully described by its local path, which is the source path of the resource, so it's compared by that
+#[cfg(feature = "resource-source-extension")]
+#[derive(Debug, Clone)]
+struct ResourceSourceSlot(Arc<dyn ResourceSource>);
+
+// without the extension, a resource can't be constructed from a source, so its source is always empty
+#[cfg(not(feature = "resource-source-extension"))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ResourceSourceSlot {}
+
+#[cfg(feature = "resource-source-extension")]
+impl PartialEq for ResourceSourceSlot {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.local_path() == other.0.local_path()
+    }
 }
 
+#[cfg(feature = "resource-source-extension")]
+impl Eq for ResourceSourceSlot {}
+
+// the initialization future doesn't borrow the resource, so it reports the copy strategy through a shared slot, which
+// is a byproduct of initialization rather than part of the resource's identity and thus never affects equality
+#[derive(Debug, Clone, Default)]
+struct CopyStrategySlot(Arc<Mutex<Option<FastCopyStrategy>>>);
+
+impl CopyStrategySlot {
+    fn get(&self) -> Option<FastCopyStrategy> {
+        *self.0.lock().expect("Copy strategy mutex was poisoned")
+    }
+
+    fn set(&self, strategy: FastCopyStrategy) {
+        *self.0.lock().expect("Copy strategy mutex was poisoned") = Some(strategy);
+    }
+}
+
+impl PartialEq for CopyStrategySlot {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for CopyStrategySlot {}
+
 impl MovedVmmResource {
     /// Construct an uninitialized moved resource with the given source path and [VmmResourceMoveMethod].
     pub fn new(path: impl Into<PathBuf>, move_method: VmmResourceMoveMethod) -> Self {
@@ -203,6 +473,23 @@ impl MovedVmmResource {
             effective_path: None,
             local_path: None,
             move_method,
+            copy_strategy: CopyStrategySlot::default(),
+            source: None,
+        }
+    }
+
+    /// Construct an uninitialized moved resource that is lazily resolved from the given [ResourceSource] when it is
+    /// initialized, with the local path of the source as its source path, and the given [VmmResourceMoveMethod].
+    #[cfg(feature = "resource-source-extension")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "resource-source-extension")))]
+    pub fn from_source(source: impl ResourceSource, move_method: VmmResourceMoveMethod) -> Self {
+        Self {
+            source_path: source.local_path().to_owned(),
+            effective_path: None,
+            local_path: None,
+            move_method,
+            copy_strategy: CopyStrategySlot::default(),
+            source: Some(ResourceSourceSlot(Arc::new(source))),
         }
     }
 
@@ -216,21 +503,51 @@ impl MovedVmmResource {
         ownership_model: VmmOwnershipModel,
         process_spawner: S,
         runtime: R,
+    ) -> impl Future<Output = Result<(), VmmResourceError>> + Send {
+        self.initialize_journaled(
+            effective_path,
+            local_path,
+            ownership_model,
+            process_spawner,
+            runtime,
+            VmmResourceJournal::new(),
+        )
+    }
+
+    /// Initialize the resource like [MovedVmmResource::initialize], additionally recording the performed filesystem
+    /// operations into the given [VmmResourceJournal]. Files downloaded or otherwise produced by resolving the source
+    /// of the resource aren't recorded, since they are meant to be reused.
+    pub fn initialize_journaled<S: ProcessSpawner, R: Runtime>(
+        &mut self,
+        effective_path: PathBuf,
+        local_path: PathBuf,
+        ownership_model: VmmOwnershipModel,
+        process_spawner: S,
+        runtime: R,
+        journal: VmmResourceJournal,
     ) -> impl Future<Output = Result<(), VmmResourceError>> + Send {
         self.effective_path = Some(effective_path.clone());
         self.local_path = Some(local_path);
+        self.copy_strategy = CopyStrategySlot::default();
 
         let source_path = self.source_path.clone();
         let move_method = self.move_method;
+        let copy_strategy = self.copy_strategy.clone();
+        let source = self.source.clone();
 
         async move {
+            resolve_source(source).await?;
+
             if effective_path == source_path {
                 return Ok(());
             }
 
-            upgrade_owner(&source_path, ownership_model, &process_spawner, &runtime)
-                .await
-                .map_err(VmmResourceError::ChangeOwnerError)?;
+            // the source of a read-only bind mount is never modified, so its owner is left as is
+            if move_method != (VmmResourceMoveMethod::BindMount { read_only: true }) {
+                upgrade_owner(&source_path, ownership_model, &process_spawner, &runtime)
+                    .await
+                    .map_err(VmmResourceError::ChangeOwnerError)?;
+            }
 
             if !runtime
                 .fs_exists(&source_path)
@@ -241,12 +558,22 @@ impl MovedVmmResource {
             }
 
             if let Some(parent_path) = effective_path.parent() {
-                runtime
-                    .fs_create_dir_all(parent_path)
+                journal
+                    .create_dir_all(parent_path, &runtime)
                     .await
                     .map_err(VmmResourceError::FilesystemError)?;
             }
 
+            // a rename is only recorded once it has succeeded, since undoing it moves the file back
+            if move_method != VmmResourceMoveMethod::Rename
+                && !runtime
+                    .fs_exists(&effective_path)
+                    .await
+                    .map_err(VmmResourceError::FilesystemError)?
+            {
+                journal.record(VmmResourceOperation::CreatedFile(effective_path.clone()));
+            }
+
             match move_method {
                 VmmResourceMoveMethod::Copy => {
                     runtime
@@ -281,6 +608,43 @@ impl MovedVmmResource {
                         .fs_rename(&source_path, &effective_path)
                         .await
                         .map_err(VmmResourceError::FilesystemError)?;
+                    journal.record(VmmResourceOperation::Renamed {
+                        source_path: source_path.clone(),
+                        destination_path: effective_path.clone(),
+                    });
+                }
+                VmmResourceMoveMethod::FastCopy => {
+                    let strategy = runtime
+                        .fs_fast_copy(&source_path, &effective_path)
+                        .await
+                        .map_err(VmmResourceError::FilesystemError)?;
+                    copy_strategy.set(strategy);
+                }
+                VmmResourceMoveMethod::HardLinkOrFastCopy => {
+                    if runtime.fs_hard_link(&source_path, &effective_path).await.is_err() {
+                        let strategy = runtime
+                            .fs_fast_copy(&source_path, &effective_path)
+                            .await
+                            .map_err(VmmResourceError::FilesystemError)?;
+                        copy_strategy.set(strategy);
+                    }
+                }
+                VmmResourceMoveMethod::BindMount { read_only } => {
+                    runtime
+                        .fs_create_file(&effective_path)
+                        .await
+                        .map_err(VmmResourceError::FilesystemError)?;
+
+                    let mut arguments = vec!["--bind".to_string()];
+                    if read_only {
+                        arguments.push("-o".to_string());
+                        arguments.push("ro".to_string());
+                    }
+                    arguments.push(source_path.to_string_lossy().into_owned());
+                    arguments.push(effective_path.to_string_lossy().into_owned());
+
+                    run_mount_process("mount", arguments, &process_spawner, &runtime).await?;
+                    journal.record(VmmResourceOperation::BindMounted(effective_path.clone()));
                 }
             };
 
@@ -300,7 +664,10 @@ impl MovedVmmResource {
         self.local_path = Some(self.source_path.clone());
 
         let source_path = self.source_path.clone();
+        let source = self.source.clone();
         async move {
+            resolve_source(source).await?;
+
             upgrade_owner(&source_path, ownership_model, &process_spawner, &runtime)
                 .await
                 .map_err(VmmResourceError::ChangeOwnerError)?;
@@ -317,10 +684,74 @@ impl MovedVmmResource {
         }
     }
 
+    /// Mark the resource as initialized at the given effective and local paths without moving any files, which is
+    /// needed when recovering a VMM whose resources were already moved before.
+    pub fn mark_initialized(&mut self, effective_path: PathBuf, local_path: PathBuf) {
+        self.effective_path = Some(effective_path);
+        self.local_path = Some(local_path);
+    }
+
+    /// Undo the bind mount of a resource moved with [VmmResourceMoveMethod::BindMount] by spawning an elevated
+    /// "umount" process via the [ProcessSpawner], which must be done before the directory containing the effective
+    /// path is removed. For resources that weren't bind mounted or whose effective path no longer exists, this
+    /// no-ops. The returned future doesn't depend on &self and can be spawned on the [Runtime].
+    pub fn unmount<S: ProcessSpawner, R: Runtime>(
+        &self,
+        process_spawner: S,
+        runtime: R,
+    ) -> impl Future<Output = Result<(), VmmResourceError>> + Send {
+        let effective_path = match (self.move_method, &self.effective_path) {
+            (VmmResourceMoveMethod::BindMount { .. }, Some(effective_path)) if *effective_path != self.source_path => {
+                Some(effective_path.clone())
+            }
+            _ => None,
+        };
+
+        async move {
+            let Some(effective_path) = effective_path else {
+                return Ok(());
+            };
+
+            if !runtime
+                .fs_exists(&effective_path)
+                .await
+                .map_err(VmmResourceError::FilesystemError)?
+            {
+                return Ok(());
+            }
+
+            run_mount_process(
+                "umount",
+                vec![effective_path.to_string_lossy().into_owned()],
+                &process_spawner,
+                &runtime,
+            )
+            .await
+        }
+    }
+
     pub fn source_path(&self) -> &Path {
         self.source_path.as_path()
     }
 
+    pub fn move_method(&self) -> VmmResourceMoveMethod {
+        self.move_method
+    }
+
+    /// Get the [ResourceSource] that the resource is resolved from, if it was constructed from one.
+    #[cfg(feature = "resource-source-extension")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "resource-source-extension")))]
+    pub fn source(&self) -> Option<&dyn ResourceSource> {
+        self.source.as_ref().map(|source| source.0.as_ref())
+    }
+
+    /// Get the [FastCopyStrategy] that was used to move the resource with [VmmResourceMoveMethod::FastCopy] or
+    /// [VmmResourceMoveMethod::HardLinkOrFastCopy], once its initialization has completed. This is [None] if the
+    /// resource was moved in any other way, including by hard linking it.
+    pub fn copy_strategy(&self) -> Option<FastCopyStrategy> {
+        self.copy_strategy.get()
+    }
+
     pub fn effective_path_checked(&self) -> Option<&Path> {
         self.effective_path.as_deref()
     }
@@ -353,8 +784,22 @@ impl serde::Serialize for MovedVmmResource {
     }
 }
 
+/// A deserialized [MovedVmmResource] is uninitialized, has the deserialized path as its source path and is moved with
+/// [VmmResourceMoveMethod::Copy], since the move method isn't part of the serialized form.
+#[cfg(feature = "vm")]
+#[cfg_attr(docsrs, doc(cfg(feature = "vm")))]
+impl<'de> serde::Deserialize<'de> for MovedVmmResource {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        PathBuf::deserialize(deserializer).map(|path| Self::new(path, VmmResourceMoveMethod::Copy))
+    }
+}
+
 /// A set of methods of moving [MovedVmmResource] paths.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "vm", derive(serde::Serialize, serde::Deserialize))]
 pub enum VmmResourceMoveMethod {
     /// Copy, costly.
     Copy,
@@ -366,6 +811,37 @@ pub enum VmmResourceMoveMethod {
     HardLinkOrCopy,
     /// Fully move by renaming.
     Rename,
+    /// Reflink, falling back to copying only the data segments via copy_file_range and then via a userspace buffer,
+    /// which preserves holes in sparse files. See [Runtime::fs_fast_copy].
+    FastCopy,
+    /// Hard link or fast copy if hard linking failed.
+    HardLinkOrFastCopy,
+    /// Bind mount the source file onto the effective path, optionally read-only, which works across filesystems
+    /// without copying. The mount is performed by an elevated "mount" process spawned via the [ProcessSpawner], and
+    /// must be undone with [MovedVmmResource::unmount] before the effective path is removed. A read-only source is never
+    /// modified, so it must already be readable by the VMM and, with a downgrading [VmmOwnershipModel], already owned
+    /// by the VMM's user and group, while a writable source has its owner changed along with the rest of the jail like
+    /// any other resource.
+    BindMount { read_only: bool },
+}
+
+async fn run_mount_process<S: ProcessSpawner, R: Runtime>(
+    binary: &str,
+    arguments: Vec<String>,
+    process_spawner: &S,
+    runtime: &R,
+) -> Result<(), VmmResourceError> {
+    let mut process = process_spawner
+        .spawn(&PathBuf::from(binary), arguments, false, runtime)
+        .await
+        .map_err(VmmResourceError::MountProcessSpawnFailed)?;
+    let exit_status = process.wait().await.map_err(VmmResourceError::MountProcessWaitFailed)?;
+
+    if !exit_status.success() {
+        return Err(VmmResourceError::MountProcessExitedWithWrongStatus(exit_status));
+    }
+
+    Ok(())
 }
 
 /// A produced VMM resource represents a file created by the VMM process that is to be used by the
@@ -400,18 +876,30 @@ impl ProducedVmmResource {
         effective_path: PathBuf,
         ownership_model: VmmOwnershipModel,
         runtime: R,
+    ) -> impl Future<Output = Result<(), VmmResourceError>> + Send {
+        self.initialize_journaled(effective_path, ownership_model, runtime, VmmResourceJournal::new())
+    }
+
+    /// Initialize the produced resource like [ProducedVmmResource::initialize], additionally recording the created
+    /// parent directories into the given [VmmResourceJournal].
+    pub fn initialize_journaled<R: Runtime>(
+        &mut self,
+        effective_path: PathBuf,
+        ownership_model: VmmOwnershipModel,
+        runtime: R,
+        journal: VmmResourceJournal,
     ) -> impl Future<Output = Result<(), VmmResourceError>> + Send {
         let path = effective_path.clone();
         self.effective_path = Some(effective_path);
 
         async move {
             if let Some(parent_path) = path.parent() {
-                runtime
-                    .fs_create_dir_all(&parent_path)
+                journal
+                    .create_dir_all(parent_path, &runtime)
                     .await
                     .map_err(VmmResourceError::FilesystemError)?;
 
-                downgrade_owner(&parent_path, ownership_model).map_err(VmmResourceError::ChangeOwnerError)?;
+                downgrade_owner(parent_path, ownership_model).map_err(VmmResourceError::ChangeOwnerError)?;
             }
 
             Ok(())
@@ -427,6 +915,12 @@ impl ProducedVmmResource {
         self.initialize(self.local_path.clone(), ownership_model, runtime)
     }
 
+    /// Mark the resource as initialized to the given effective path without touching the filesystem, which is
+    /// needed when recovering a VMM that may have already produced the resource.
+    pub fn mark_initialized(&mut self, effective_path: PathBuf) {
+        self.effective_path = Some(effective_path);
+    }
+
     /// Dispose of the resource unless it has been unlinked, according to the given [VmmOwnershipModel]
     /// and [ProcessSpawner]. The returned future doesn't depend on &self and can be spawned on the
     /// [Runtime] to be persisted to the filesystem.
@@ -455,17 +949,18 @@ impl ProducedVmmResource {
         }
     }
 
-    /// Unlink and copy the resource to the given new effective path. The local path remains unchanged.
+    /// Unlink and copy the resource to the given new effective path via [Runtime::fs_fast_copy], returning the
+    /// [FastCopyStrategy] that was used. The local path remains unchanged.
     pub async fn copy<R: Runtime>(
         &mut self,
         new_effective_path: impl Into<PathBuf>,
         runtime: &R,
-    ) -> Result<(), std::io::Error> {
+    ) -> Result<FastCopyStrategy, std::io::Error> {
         let new_effective_path = new_effective_path.into();
-        runtime.fs_copy(self.effective_path(), &new_effective_path).await?;
+        let strategy = runtime.fs_fast_copy(self.effective_path(), &new_effective_path).await?;
         self.effective_path = Some(new_effective_path);
         self.unlink();
-        Ok(())
+        Ok(strategy)
     }
 
     /// Unlink and move/rename the resource to the given new effective path. The local path remains unchanged.
@@ -530,3 +1025,15 @@ impl serde::Serialize for ProducedVmmResource {
         self.local_path.serialize(serializer)
     }
 }
+
+/// A deserialized [ProducedVmmResource] is uninitialized, linked and has the deserialized local path.
+#[cfg(feature = "vm")]
+#[cfg_attr(docsrs, doc(cfg(feature = "vm")))]
+impl<'de> serde::Deserialize<'de> for ProducedVmmResource {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        PathBuf::deserialize(deserializer).map(Self::new)
+    }
+}
@@ -0,0 +1,321 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use fctools::{
+    extension::agent::{
+        client::{AgentCommand, AgentConnection, AgentError, AgentProcessEvent},
+        protocol::{
+            read_frame, write_frame, write_frame_blocking, AgentProtocolError, AgentRequest, AgentResponse,
+            AGENT_PROTOCOL_VERSION, MAX_MESSAGE_LENGTH,
+        },
+        server::AgentServer,
+    },
+    runtime::{tokio::TokioRuntime, Runtime},
+};
+use futures_util::AsyncWriteExt;
+use test_framework::get_tmp_path;
+use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+
+mod test_framework;
+
+#[tokio::test]
+async fn agent_binary_serves_protocol_over_unix_socket() {
+    let socket_path = get_tmp_path();
+    let mut agent = tokio::process::Command::new(env!("CARGO_BIN_EXE_fctools-agent"))
+        .arg("--unix")
+        .arg(&socket_path)
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+
+    let stream = loop {
+        if let Ok(stream) = TokioRuntime.unix_connect(&socket_path).await {
+            break stream;
+        }
+
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    };
+    let mut connection = AgentConnection::handshake(stream).await.unwrap();
+    let file_path = get_tmp_path();
+    connection
+        .put_file(&file_path, b"#!/bin/sh\necho \"$@\"\n".to_vec(), Some(0o755))
+        .await
+        .unwrap();
+
+    let stream = TokioRuntime.unix_connect(&socket_path).await.unwrap();
+    let output = AgentConnection::handshake(stream)
+        .await
+        .unwrap()
+        .exec(AgentCommand::new(file_path.to_str().unwrap()).args(["a", "b"]))
+        .await
+        .unwrap()
+        .wait_with_output()
+        .await
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"a b\n");
+
+    std::fs::remove_file(file_path).unwrap();
+    agent.kill().await.unwrap();
+}
+
+#[tokio::test]
+async fn agent_get_file_returns_content_and_reports_missing_files() {
+    let server = AgentServer::new();
+    let mut connection = connect(&server).await;
+    let file_path = get_tmp_path();
+    std::fs::write(&file_path, b"content").unwrap();
+
+    assert_eq!(connection.get_file(&file_path).await.unwrap(), b"content");
+    std::fs::remove_file(&file_path).unwrap();
+    assert!(matches!(
+        connection.get_file(&file_path).await,
+        Err(AgentError::Remote { .. })
+    ));
+}
+
+#[tokio::test]
+async fn agent_environment_is_shared_between_connections_and_inherited_by_processes() {
+    let server = AgentServer::new();
+    connect(&server)
+        .await
+        .set_environment(
+            BTreeMap::from([
+                ("AGENT_KEPT".to_string(), "kept".to_string()),
+                ("AGENT_REMOVED".to_string(), "removed".to_string()),
+            ]),
+            Vec::new(),
+        )
+        .await
+        .unwrap();
+
+    let mut connection = connect(&server).await;
+    connection
+        .set_environment(BTreeMap::new(), vec!["AGENT_REMOVED".to_string()])
+        .await
+        .unwrap();
+    let environment = connection.get_environment().await.unwrap();
+    assert_eq!(environment.get("AGENT_KEPT").unwrap(), "kept");
+    assert!(!environment.contains_key("AGENT_REMOVED"));
+
+    let output = connect(&server)
+        .await
+        .exec(AgentCommand::new("sh").arg("-c").arg("echo $AGENT_KEPT $AGENT_REMOVED"))
+        .await
+        .unwrap()
+        .wait_with_output()
+        .await
+        .unwrap();
+    assert_eq!(output.stdout, b"kept\n");
+}
+
+#[tokio::test]
+async fn agent_forwards_stdin_until_it_is_closed() {
+    let server = AgentServer::new();
+    let mut process = connect(&server).await.exec(AgentCommand::new("cat")).await.unwrap();
+    process.write_stdin(b"first ".to_vec()).await.unwrap();
+    process.write_stdin(b"second".to_vec()).await.unwrap();
+    process.close_stdin().await.unwrap();
+
+    let output = process.wait_with_output().await.unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"first second");
+}
+
+#[tokio::test]
+async fn agent_signals_executed_process() {
+    let server = AgentServer::new();
+    let mut process = connect(&server)
+        .await
+        .exec(AgentCommand::new("sleep").arg("10"))
+        .await
+        .unwrap();
+    process.signal(9).await.unwrap();
+
+    assert_signaled(process.next_event().await.unwrap().unwrap(), 9);
+    assert!(process.next_event().await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn agent_signals_arbitrary_process() {
+    let server = AgentServer::new();
+    let mut process = connect(&server)
+        .await
+        .exec(AgentCommand::new("sleep").arg("10"))
+        .await
+        .unwrap();
+
+    let mut connection = connect(&server).await;
+    connection.signal_process(process.pid(), 15).await.unwrap();
+    assert_signaled(process.next_event().await.unwrap().unwrap(), 15);
+
+    assert!(matches!(
+        connection.signal_process(i32::MAX, 15).await,
+        Err(AgentError::Remote { .. })
+    ));
+}
+
+#[tokio::test]
+async fn agent_rejects_unsupported_protocol_version() {
+    let mut stream = connect_raw(&AgentServer::new());
+    write_frame(
+        &mut stream,
+        AgentRequest::Hello {
+            version: AGENT_PROTOCOL_VERSION + 1,
+        },
+    )
+    .await
+    .unwrap();
+
+    assert!(matches!(
+        read_frame(&mut stream).await.unwrap(),
+        Some(AgentResponse::Error { .. })
+    ));
+    assert!(read_frame::<AgentResponse>(&mut stream).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn agent_rejects_requests_before_hello() {
+    let mut stream = connect_raw(&AgentServer::new());
+    write_frame(&mut stream, AgentRequest::GetEnvironment).await.unwrap();
+
+    assert!(matches!(
+        read_frame(&mut stream).await.unwrap(),
+        Some(AgentResponse::Error { .. })
+    ));
+    assert!(read_frame::<AgentResponse>(&mut stream).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn agent_rejects_requests_invalid_in_connection_state() {
+    let mut stream = connect(&AgentServer::new()).await.into_inner();
+
+    for request in [
+        AgentRequest::Hello {
+            version: AGENT_PROTOCOL_VERSION,
+        },
+        AgentRequest::Stdin { data: b"data".to_vec() },
+        AgentRequest::CloseStdin,
+        AgentRequest::Signal { signal: 15 },
+    ] {
+        write_frame(&mut stream, request).await.unwrap();
+        assert!(matches!(
+            read_frame(&mut stream).await.unwrap(),
+            Some(AgentResponse::Error { .. })
+        ));
+    }
+}
+
+#[tokio::test]
+async fn agent_rejects_requests_other_than_stdin_and_signal_during_exec() {
+    let server = AgentServer::new();
+    let mut stream = connect(&server).await.into_inner();
+    write_frame(
+        &mut stream,
+        AgentRequest::Exec {
+            program: "cat".to_string(),
+            args: Vec::new(),
+            env: BTreeMap::new(),
+            working_dir: None,
+        },
+    )
+    .await
+    .unwrap();
+    assert!(matches!(
+        read_frame(&mut stream).await.unwrap(),
+        Some(AgentResponse::Started { .. })
+    ));
+
+    write_frame(&mut stream, AgentRequest::GetEnvironment).await.unwrap();
+    assert!(matches!(
+        read_frame(&mut stream).await.unwrap(),
+        Some(AgentResponse::Error { .. })
+    ));
+
+    write_frame(&mut stream, AgentRequest::CloseStdin).await.unwrap();
+    assert!(matches!(
+        read_frame(&mut stream).await.unwrap(),
+        Some(AgentResponse::Exited { code: Some(0), .. })
+    ));
+}
+
+#[tokio::test]
+async fn agent_closes_connection_on_oversized_frame() {
+    let mut stream = connect(&AgentServer::new()).await.into_inner();
+    let mut header = (MAX_MESSAGE_LENGTH + 1).to_be_bytes().to_vec();
+    header.extend_from_slice(&0u32.to_be_bytes());
+    stream.write_all(&header).await.unwrap();
+
+    assert!(read_frame::<AgentResponse>(&mut stream).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn agent_client_reports_malformed_and_unexpected_responses() {
+    let (client, guest) = std::os::unix::net::UnixStream::pair().unwrap();
+    std::thread::spawn(move || {
+        let mut guest = guest;
+        let _ = std::io::Read::read(&mut guest, &mut [0; 64]);
+        write_frame_blocking(
+            &mut guest,
+            AgentResponse::Hello {
+                version: AGENT_PROTOCOL_VERSION + 1,
+            },
+        )
+        .unwrap();
+    });
+    assert!(matches!(
+        AgentConnection::handshake(into_async(client)).await,
+        Err(AgentError::VersionMismatch { .. })
+    ));
+
+    let (client, guest) = std::os::unix::net::UnixStream::pair().unwrap();
+    std::thread::spawn(move || {
+        let mut guest = guest;
+        let _ = std::io::Read::read(&mut guest, &mut [0; 64]);
+        let mut frame = 5u32.to_be_bytes().to_vec();
+        frame.extend_from_slice(&0u32.to_be_bytes());
+        frame.extend_from_slice(b"{bad}");
+        std::io::Write::write_all(&mut guest, &frame).unwrap();
+    });
+    assert!(matches!(
+        AgentConnection::handshake(into_async(client)).await,
+        Err(AgentError::ProtocolError(AgentProtocolError::SerdeError(_)))
+    ));
+
+    let (client, guest) = std::os::unix::net::UnixStream::pair().unwrap();
+    std::thread::spawn(move || {
+        let mut guest = guest;
+        let _ = std::io::Read::read(&mut guest, &mut [0; 64]);
+        write_frame_blocking(&mut guest, AgentResponse::Ok).unwrap();
+    });
+    assert!(matches!(
+        AgentConnection::handshake(into_async(client)).await,
+        Err(AgentError::UnexpectedResponse(AgentResponse::Ok))
+    ));
+}
+
+async fn connect(server: &AgentServer) -> AgentConnection<Compat<tokio::net::UnixStream>> {
+    AgentConnection::handshake(connect_raw(server)).await.unwrap()
+}
+
+fn connect_raw(server: &AgentServer) -> Compat<tokio::net::UnixStream> {
+    let (client, guest) = std::os::unix::net::UnixStream::pair().unwrap();
+    let server = server.clone();
+    std::thread::spawn(move || {
+        let writer = guest.try_clone().unwrap();
+        let _ = server.serve_connection(guest, writer);
+    });
+
+    into_async(client)
+}
+
+fn into_async(stream: std::os::unix::net::UnixStream) -> Compat<tokio::net::UnixStream> {
+    stream.set_nonblocking(true).unwrap();
+    tokio::net::UnixStream::from_std(stream).unwrap().compat()
+}
+
+fn assert_signaled(event: AgentProcessEvent, signal: i32) {
+    let AgentProcessEvent::Exited(status) = event else {
+        panic!("Expected an exit event, got {event:?}");
+    };
+    assert_eq!(status.signal, Some(signal));
+}
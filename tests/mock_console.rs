@@ -0,0 +1,119 @@
+use std::path::PathBuf;
+
+use assert_matches::assert_matches;
+use fctools::{
+    extension::console_attach::{ConsoleAttachExt, ConsoleAttachOptions, DEFAULT_DETACH_KEY},
+    runtime::{tokio::TokioRuntime, Runtime},
+    vm::{
+        shutdown::{VmShutdownAction, VmShutdownMethod},
+        VmError,
+    },
+};
+use futures_util::{AsyncRead, AsyncReadExt, AsyncWriteExt, StreamExt};
+use test_framework::{
+    get_mock_configuration, get_mock_executors, prepare_mock_vm, shutdown_mock_vm, MOCK_SOCKET_WAIT_TIMEOUT,
+};
+
+mod test_framework;
+
+#[tokio::test]
+async fn mock_vm_console_is_shared_with_serial_shutdown() {
+    // the daemonized jailer detaches the VMM from the pipes, so there is no console to share
+    for executor in get_mock_executors(&[]).into_iter().take(2) {
+        let mut vm = prepare_mock_vm(executor, get_mock_configuration()).await;
+        vm.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+
+        let console = vm.console().unwrap();
+        assert!(console
+            .wait_for("login:", MOCK_SOCKET_WAIT_TIMEOUT)
+            .await
+            .unwrap()
+            .starts_with("fctools-mock login:"));
+        assert!(console.scrollback()[0].content.contains("console=ttyS0"));
+        assert_matches!(vm.take_pipes(), Err(VmError::ProcessError(_)));
+
+        let mut subscriber = vm.console().unwrap().subscribe(16);
+        console.write_line("echo hello").await.unwrap();
+        assert_eq!(subscriber.next().await.unwrap().content, "echo hello");
+
+        let outcome = vm
+            .shutdown([VmShutdownAction {
+                method: VmShutdownMethod::WriteToSerial(b"reboot\n".to_vec()),
+                timeout: Some(MOCK_SOCKET_WAIT_TIMEOUT),
+                graceful: true,
+            }])
+            .await
+            .unwrap();
+        assert!(outcome.fully_graceful());
+        assert_eq!(subscriber.next().await.unwrap().content, "reboot");
+        assert!(subscriber.next().await.is_none());
+        vm.cleanup().await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn mock_vm_console_can_be_attached_over_socket_and_pty() {
+    let mut vm = prepare_mock_vm(get_mock_executors(&[]).remove(0), get_mock_configuration()).await;
+    vm.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+    vm.console()
+        .unwrap()
+        .wait_for("login:", MOCK_SOCKET_WAIT_TIMEOUT)
+        .await
+        .unwrap();
+
+    let socket_path = PathBuf::from(format!("/tmp/{}.sock", rand::random::<u32>()));
+    let server = vm
+        .serve_console(ConsoleAttachOptions::new(&socket_path).pty())
+        .await
+        .unwrap();
+    let mut sessions = [
+        TokioRuntime.unix_connect(&socket_path).await.unwrap(),
+        TokioRuntime.unix_connect(&socket_path).await.unwrap(),
+    ];
+
+    for session in &mut sessions {
+        let output = read_console_until(session, "login: root").await;
+        assert!(output.contains("Command line:"));
+    }
+
+    sessions[0].write_all(b"echo from socket\n").await.unwrap();
+    for session in &mut sessions {
+        read_console_until(session, "echo from socket").await;
+    }
+
+    let mut pty = tokio::fs::OpenOptions::new()
+        .write(true)
+        .open(server.pty_path().unwrap())
+        .await
+        .unwrap();
+    tokio::io::AsyncWriteExt::write_all(&mut pty, b"echo from pty\n")
+        .await
+        .unwrap();
+    read_console_until(&mut sessions[1], "echo from pty").await;
+
+    sessions[1].write_all(&[DEFAULT_DETACH_KEY]).await.unwrap();
+    assert_eq!(sessions[1].read(&mut [0; 64]).await.unwrap(), 0);
+    sessions[0].write_all(b"still attached\n").await.unwrap();
+    read_console_until(&mut sessions[0], "still attached").await;
+
+    server.stop().await.unwrap();
+    assert_eq!(sessions[0].read(&mut [0; 64]).await.unwrap(), 0);
+    assert!(!tokio::fs::try_exists(&socket_path).await.unwrap());
+    shutdown_mock_vm(&mut vm).await;
+}
+
+async fn read_console_until(session: &mut (impl AsyncRead + Unpin), pattern: &str) -> String {
+    let mut output = String::new();
+    let mut buf = [0; 4096];
+
+    while !output.contains(pattern) {
+        let read = tokio::time::timeout(MOCK_SOCKET_WAIT_TIMEOUT, session.read(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_ne!(read, 0, "The console session was closed before \"{pattern}\" was read");
+        output.push_str(&String::from_utf8_lossy(&buf[..read]));
+    }
+
+    output
+}
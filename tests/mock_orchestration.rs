@@ -0,0 +1,531 @@
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use assert_matches::assert_matches;
+use fctools::{
+    extension::{
+        fleet::{VmFleet, VmFleetError},
+        hibernation::{HibernationOptions, HibernationState, VmHibernationManager},
+        job_runner::{JobError, JobRunner, JobSpec, JobTermination},
+        supervisor::{VmRestartPolicy, VmSupervisor, VmSupervisorEvent, VmSupervisorOptions},
+        vsock_forward::VsockForwardAddress,
+        warm_pool::{WarmPool, WarmPoolError, WarmPoolHealth, WarmPoolOptions, WarmPoolTemplate},
+    },
+    process_spawner::DirectProcessSpawner,
+    runtime::tokio::TokioRuntime,
+    testing::{
+        fault::{MockFault, MockFaultAction},
+        MOCK_VSOCK_ECHO_PORT,
+    },
+    vm::{
+        api::VmApi,
+        configuration::{InitMethod, VmConfiguration},
+        shutdown::{VmShutdownAction, VmShutdownMethod},
+        VmState,
+    },
+    vmm::{
+        arguments::{jailer::JailerArguments, VmmApiSocket, VmmArguments},
+        executor::{
+            either::EitherVmmExecutor,
+            jailed::{FlatJailRenamer, JailedVmmExecutor},
+            unrestricted::UnrestrictedVmmExecutor,
+            VmmExecutor,
+        },
+        ownership::VmmOwnershipModel,
+        resource::{MovedVmmResource, VmmResourceMoveMethod},
+    },
+};
+use futures_util::StreamExt;
+use test_framework::{
+    get_fake_firecracker_installation, get_mock_configuration, get_mock_configuration_data, get_mock_executors,
+    get_mock_vsock_configuration, get_tmp_path, prepare_mock_vm, shutdown_mock_vm, MOCK_SOCKET_WAIT_TIMEOUT,
+};
+
+mod test_framework;
+
+#[tokio::test]
+async fn mock_vm_job_finishes_on_exit_marker() {
+    let runner = new_job_runner();
+
+    for executor in get_mock_executors(&[]).into_iter().take(2) {
+        let (sender, mut receiver) = futures_channel::mpsc::unbounded();
+        let result = runner
+            .run(
+                executor,
+                get_mock_configuration(),
+                JobSpec::new(MOCK_SOCKET_WAIT_TIMEOUT)
+                    .input("echo job output\nFCTOOLS_JOB_EXIT_CODE=7\n")
+                    .output_sender(sender),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(result.termination, JobTermination::ExitMarker { exit_code: 7 });
+        assert_eq!(result.exit_code(), Some(7));
+        assert!(result.serial_output().contains("login:"));
+        assert!(result.serial_output().contains("echo job output"));
+        assert!(result.shutdown_outcome.unwrap().graceful);
+        assert_eq!(result.vm_state, VmState::Exited);
+        assert!(result.cleanup_error.is_none());
+        assert!(result.timings.total >= result.timings.start + result.timings.run);
+        assert!(receiver.next().await.unwrap().content.contains("Command line"));
+    }
+}
+
+#[tokio::test]
+async fn mock_vm_job_finishes_on_vm_exit_or_timeout() {
+    let runner = new_job_runner();
+    let mut executors = get_mock_executors(&[]).into_iter();
+
+    let result = runner
+        .run(
+            executors.next().unwrap(),
+            get_mock_configuration(),
+            JobSpec::new(MOCK_SOCKET_WAIT_TIMEOUT)
+                .disable_exit_marker()
+                .input("reboot\n"),
+        )
+        .await
+        .unwrap();
+    assert_eq!(result.termination, JobTermination::VmExited);
+    assert_eq!(result.exit_code(), Some(0));
+    assert!(result.shutdown_outcome.is_none());
+    assert!(result.cleanup_error.is_none());
+
+    let result = runner
+        .run(
+            executors.next().unwrap(),
+            get_mock_configuration(),
+            JobSpec::new(Duration::from_millis(500)).socket_wait_timeout(MOCK_SOCKET_WAIT_TIMEOUT),
+        )
+        .await
+        .unwrap();
+    assert_eq!(result.termination, JobTermination::TimedOut);
+    assert_eq!(result.exit_code(), None);
+    assert!(result.shutdown_outcome.is_some());
+    assert!(result.cleanup_error.is_none());
+}
+
+#[tokio::test]
+async fn mock_vm_job_is_torn_down_when_shutdown_fails() {
+    let executor = get_mock_executors(&[]).into_iter().next().unwrap();
+    let socket_path = executor.get_socket_path(&get_fake_firecracker_installation()).unwrap();
+    let result = new_job_runner()
+        .run(
+            executor,
+            get_mock_configuration(),
+            JobSpec::new(Duration::from_millis(500))
+                .socket_wait_timeout(MOCK_SOCKET_WAIT_TIMEOUT)
+                .shutdown_actions([VmShutdownAction {
+                    method: VmShutdownMethod::WriteToSerial(b"true\n".to_vec()),
+                    timeout: Some(Duration::from_millis(100)),
+                    graceful: true,
+                }]),
+        )
+        .await;
+
+    assert_matches!(
+        result.map(|_| ()),
+        Err(JobError::ShutdownError {
+            cleanup_error: None,
+            ..
+        })
+    );
+    assert!(!socket_path.exists());
+}
+
+#[tokio::test]
+async fn mock_vm_fleet_allocates_ids_and_manages_vms_in_bulk() {
+    let fleet = VmFleet::new(
+        DirectProcessSpawner,
+        TokioRuntime,
+        VmmOwnershipModel::Shared,
+        Arc::new(get_fake_firecracker_installation()),
+    )
+    .id_prefix(format!("fleet{}", rand::random::<u32>()))
+    .max_concurrent_prepares(2)
+    .max_concurrent_starts(1);
+
+    let new_executor = |id: &fctools::vmm::id::VmmId, jailed: bool| {
+        let vmm_arguments = VmmArguments::new(VmmApiSocket::Enabled(PathBuf::from(format!(
+            "/tmp/{}.sock",
+            rand::random::<u32>()
+        ))));
+
+        match jailed {
+            true => EitherVmmExecutor::Jailed(JailedVmmExecutor::new(
+                vmm_arguments,
+                JailerArguments::new(id.clone()).chroot_base_dir(format!("/tmp/j{}", rand::random::<u32>())),
+                FlatJailRenamer,
+            )),
+            false => EitherVmmExecutor::Unrestricted(UnrestrictedVmmExecutor::new(vmm_arguments).id(id.clone())),
+        }
+    };
+
+    let members = futures_util::future::join_all((0..4).map(|index| {
+        let jailed = index % 2 == 1;
+        fleet.prepare(
+            move |id| new_executor(id, jailed),
+            get_mock_configuration(),
+            [("jailed".to_owned(), jailed.to_string())],
+        )
+    }))
+    .await
+    .into_iter()
+    .map(Result::unwrap)
+    .collect::<Vec<_>>();
+
+    assert_eq!(fleet.len(), 4);
+    assert_eq!(fleet.find("jailed", "true").len(), 2);
+    assert!(matches!(
+        fleet
+            .prepare_with_id(
+                members[0].id().clone(),
+                |id| new_executor(id, false),
+                get_mock_configuration(),
+                []
+            )
+            .await,
+        Err(VmFleetError::IdAlreadyInUse(_))
+    ));
+
+    futures_util::future::join_all(
+        fleet
+            .ids()
+            .iter()
+            .map(|id| async { fleet.start(id, MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap() }),
+    )
+    .await;
+
+    let unrestricted_member = fleet.find("jailed", "false").pop().unwrap();
+    assert_eq!(
+        unrestricted_member.lock().await.api_get_info().await.unwrap().id,
+        unrestricted_member.id().as_ref()
+    );
+
+    let shutdown_results = fleet
+        .shutdown_all([VmShutdownAction {
+            method: VmShutdownMethod::CtrlAltDel,
+            timeout: Some(MOCK_SOCKET_WAIT_TIMEOUT),
+            graceful: true,
+        }])
+        .await;
+    assert_eq!(shutdown_results.len(), 4);
+    assert!(shutdown_results
+        .into_iter()
+        .all(|(_, result)| result.unwrap().fully_graceful()));
+
+    let cleanup_results = fleet.cleanup_all().await;
+    assert_eq!(cleanup_results.len(), 4);
+    assert!(cleanup_results.into_iter().all(|(_, result)| result.is_ok()));
+    assert!(fleet.is_empty());
+    assert_eq!(members[0].lock().await.state(), VmState::Exited);
+}
+
+#[tokio::test]
+async fn mock_vm_fleet_reserves_ids_while_preparing() {
+    let fleet = VmFleet::new(
+        DirectProcessSpawner,
+        TokioRuntime,
+        VmmOwnershipModel::Shared,
+        Arc::new(get_fake_firecracker_installation()),
+    );
+    let id = fctools::vmm::id::VmmId::new(format!("reserved{}", rand::random::<u32>())).unwrap();
+    let new_executor = |id: &fctools::vmm::id::VmmId| {
+        UnrestrictedVmmExecutor::new(VmmArguments::new(VmmApiSocket::Enabled(PathBuf::from(format!(
+            "/tmp/{}.sock",
+            rand::random::<u32>()
+        )))))
+        .id(id.clone())
+    };
+
+    let (first, second) = tokio::join!(
+        fleet.prepare_with_id(id.clone(), new_executor, get_mock_configuration(), []),
+        fleet.prepare_with_id(id.clone(), new_executor, get_mock_configuration(), [])
+    );
+    assert!(first.is_ok());
+    assert!(matches!(second, Err(VmFleetError::IdAlreadyInUse(_))));
+    assert_eq!(fleet.ids(), vec![id.clone()]);
+
+    assert!(fleet.remove(&id).is_some());
+    assert!(fleet.is_empty());
+
+    let mut data = get_mock_configuration_data();
+    data.boot_source.kernel_image = MovedVmmResource::new("/nonexistent/kernel", VmmResourceMoveMethod::HardLinkOrCopy);
+    let failing_configuration = VmConfiguration::New {
+        init_method: InitMethod::ViaApiCalls,
+        data,
+    };
+    assert!(matches!(
+        fleet
+            .prepare_with_id(id.clone(), new_executor, failing_configuration, [])
+            .await,
+        Err(VmFleetError::PrepareError(_))
+    ));
+    assert!(fleet.is_empty());
+    assert!(fleet
+        .prepare_with_id(id.clone(), new_executor, get_mock_configuration(), [])
+        .await
+        .is_ok());
+    fleet.cleanup_all().await;
+}
+
+#[tokio::test]
+async fn mock_vm_warm_pool_refills_after_take_and_evicts_crashed_vms() {
+    let socket_paths = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let pool = WarmPool::start(
+        DirectProcessSpawner,
+        TokioRuntime,
+        VmmOwnershipModel::Shared,
+        Arc::new(get_fake_firecracker_installation()),
+        {
+            let socket_paths = socket_paths.clone();
+            move || {
+                let executor = get_mock_executors(&[MockFault::new("/vm", MockFaultAction::Crash { exit_code: 1 })])
+                    .into_iter()
+                    .next()
+                    .unwrap();
+                socket_paths
+                    .lock()
+                    .unwrap()
+                    .push(executor.get_socket_path(&get_fake_firecracker_installation()).unwrap());
+                executor
+            }
+        },
+        WarmPoolTemplate::New {
+            init_method: InitMethod::ViaApiCalls,
+            data: get_mock_configuration_data(),
+        },
+        WarmPoolOptions::new(2, MOCK_SOCKET_WAIT_TIMEOUT).health_check_interval(Duration::from_millis(50)),
+    );
+
+    let mut vm = pool.take(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+    assert_eq!(vm.state(), VmState::Running);
+    shutdown_mock_vm(&mut vm).await;
+    wait_for_warm_pool(&pool, |health| health.ready == 2).await;
+    assert_eq!(pool.health().taken, 1);
+
+    // the socket of the VM that was taken and shut down no longer accepts connections
+    let crashed_socket_paths = socket_paths.lock().unwrap().clone();
+    for socket_path in crashed_socket_paths {
+        if let Ok(mut stream) = tokio::net::UnixStream::connect(socket_path).await {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+            stream
+                .write_all(b"PATCH /vm HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            let _ = stream.read_to_end(&mut Vec::new()).await;
+        }
+    }
+
+    wait_for_warm_pool(&pool, |health| health.evicted == 2 && health.ready == 2).await;
+    assert_eq!(pool.health().failed_boots, 0);
+
+    let shutdown_results = pool
+        .shutdown([VmShutdownAction {
+            method: VmShutdownMethod::CtrlAltDel,
+            timeout: Some(MOCK_SOCKET_WAIT_TIMEOUT),
+            graceful: true,
+        }])
+        .await;
+    assert_eq!(shutdown_results.len(), 2);
+    assert!(shutdown_results.into_iter().all(|result| result.is_ok()));
+}
+
+#[tokio::test]
+async fn mock_vm_warm_pool_take_times_out_when_boots_fail() {
+    let pool = WarmPool::start(
+        DirectProcessSpawner,
+        TokioRuntime,
+        VmmOwnershipModel::Shared,
+        Arc::new(get_fake_firecracker_installation()),
+        || {
+            get_mock_executors(&[MockFault::new(
+                "/actions",
+                MockFaultAction::ErrorResponse {
+                    status_code: 400,
+                    fault_message: "Injected fault".to_string(),
+                },
+            )])
+            .into_iter()
+            .next()
+            .unwrap()
+        },
+        WarmPoolTemplate::New {
+            init_method: InitMethod::ViaApiCalls,
+            data: get_mock_configuration_data(),
+        },
+        WarmPoolOptions::new(1, MOCK_SOCKET_WAIT_TIMEOUT).health_check_interval(Duration::from_millis(50)),
+    );
+
+    assert_matches!(
+        pool.take(Duration::from_millis(200)).await.map(|_| ()),
+        Err(WarmPoolError::TakeTimeout)
+    );
+    assert!(pool.health().failed_boots > 0);
+    assert!(pool.shutdown([]).await.is_empty());
+}
+
+#[tokio::test]
+async fn mock_vm_supervisor_restarts_crashed_vm() {
+    let (sender, mut receiver) = futures_channel::mpsc::unbounded();
+    let supervisor = VmSupervisor::new(
+        DirectProcessSpawner,
+        TokioRuntime,
+        VmmOwnershipModel::Shared,
+        Arc::new(get_fake_firecracker_installation()),
+    )
+    .event_sender(sender);
+    let new_executor = || {
+        get_mock_executors(&[MockFault::new("/vm", MockFaultAction::Crash { exit_code: 1 })])
+            .into_iter()
+            .next()
+            .unwrap()
+    };
+
+    let mut vm = prepare_mock_vm(new_executor(), get_mock_configuration()).await;
+    vm.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+    let supervised_vm = supervisor.supervise(
+        "supervised".to_owned().try_into().unwrap(),
+        vm,
+        get_mock_configuration(),
+        new_executor,
+        VmSupervisorOptions::new(VmRestartPolicy::OnFailure, MOCK_SOCKET_WAIT_TIMEOUT)
+            .poll_interval(Duration::from_millis(10))
+            .backoff(Duration::from_millis(10), Duration::from_millis(100)),
+    );
+
+    supervised_vm.lock().await.api_pause().await.unwrap_err();
+    assert_matches!(
+        receiver.next().await.unwrap(),
+        VmSupervisorEvent::Exited {
+            state: VmState::Crashed(_),
+            cleanup_error: None,
+            ..
+        }
+    );
+    assert_matches!(
+        receiver.next().await.unwrap(),
+        VmSupervisorEvent::Restarted { restarts: 1, .. }
+    );
+    assert_eq!(supervised_vm.restarts(), 1);
+
+    let mut vm = supervised_vm.stop().await;
+    assert_eq!(vm.state(), VmState::Running);
+    shutdown_mock_vm(&mut vm).await;
+
+    let mut vm = prepare_mock_vm(new_executor(), get_mock_configuration()).await;
+    vm.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+    let supervised_vm = supervisor.supervise(
+        "unsupervised".to_owned().try_into().unwrap(),
+        vm,
+        get_mock_configuration(),
+        new_executor,
+        VmSupervisorOptions::new(VmRestartPolicy::Never, MOCK_SOCKET_WAIT_TIMEOUT)
+            .poll_interval(Duration::from_millis(10)),
+    );
+
+    supervised_vm.lock().await.api_pause().await.unwrap_err();
+    assert_matches!(receiver.next().await.unwrap(), VmSupervisorEvent::Exited { .. });
+    assert_matches!(
+        receiver.next().await.unwrap(),
+        VmSupervisorEvent::GaveUp { restarts: 0, .. }
+    );
+    assert_matches!(supervised_vm.stop().await.state(), VmState::Crashed(_));
+}
+
+#[tokio::test]
+async fn mock_vm_hibernates_when_idle_and_wakes_on_connection() {
+    let manager = VmHibernationManager::new(
+        DirectProcessSpawner,
+        TokioRuntime,
+        VmmOwnershipModel::Shared,
+        Arc::new(get_fake_firecracker_installation()),
+    );
+
+    for index in 0..2 {
+        let new_executor = move || get_mock_executors(&[]).into_iter().nth(index).unwrap();
+        let mut vm = prepare_mock_vm(new_executor(), get_mock_vsock_configuration()).await;
+        vm.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+
+        let snapshot_directory = get_tmp_path();
+        let hibernating_vm = manager
+            .manage(
+                vm,
+                new_executor,
+                HibernationOptions::new(
+                    VsockForwardAddress::Unix(get_tmp_path()),
+                    MOCK_VSOCK_ECHO_PORT,
+                    &snapshot_directory,
+                    MOCK_SOCKET_WAIT_TIMEOUT,
+                )
+                .idle_timeout(Duration::from_millis(200))
+                .idle_check_interval(Duration::from_millis(20)),
+            )
+            .await
+            .unwrap();
+        let VsockForwardAddress::Unix(socket_path) = hibernating_vm.local_address().clone() else {
+            unreachable!()
+        };
+
+        for expected_wakes in 0..2 {
+            let mut stream = tokio::net::UnixStream::connect(&socket_path).await.unwrap();
+            tokio::io::AsyncWriteExt::write_all(&mut stream, b"wake\n")
+                .await
+                .unwrap();
+            let mut buf = [0; 5];
+            tokio::io::AsyncReadExt::read_exact(&mut stream, &mut buf)
+                .await
+                .unwrap();
+            assert_eq!(&buf, b"wake\n");
+            assert_eq!(hibernating_vm.stats().wakes, expected_wakes);
+            drop(stream);
+
+            tokio::time::timeout(MOCK_SOCKET_WAIT_TIMEOUT, async {
+                while !hibernating_vm.is_hibernated().await {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .unwrap();
+            assert!(snapshot_directory.join("hibernation.mem").exists());
+        }
+
+        assert_eq!(hibernating_vm.wake().await.unwrap().state(), VmState::Running);
+        assert!(!snapshot_directory.join("hibernation.mem").exists());
+
+        let stats = hibernating_vm.stats();
+        assert_eq!(stats.hibernations, 2);
+        assert_eq!(stats.wakes, 2);
+        assert_eq!(stats.failed_connections, 0);
+        assert_eq!(stats.failed_hibernations, 0);
+
+        let HibernationState::Awake(mut vm) = hibernating_vm.stop().await.unwrap() else {
+            panic!("The VM was hibernated after being woken up");
+        };
+        assert!(!socket_path.exists());
+        shutdown_mock_vm(&mut vm).await;
+    }
+}
+
+async fn wait_for_warm_pool<E: VmmExecutor + 'static>(
+    pool: &WarmPool<E, DirectProcessSpawner, TokioRuntime>,
+    predicate: impl Fn(WarmPoolHealth) -> bool,
+) {
+    tokio::time::timeout(MOCK_SOCKET_WAIT_TIMEOUT, async {
+        while !predicate(pool.health()) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("Warm pool didn't reach the expected health: {:?}", pool.health()));
+}
+
+fn new_job_runner() -> JobRunner<DirectProcessSpawner, TokioRuntime> {
+    JobRunner::new(
+        DirectProcessSpawner,
+        TokioRuntime,
+        VmmOwnershipModel::Shared,
+        Arc::new(get_fake_firecracker_installation()),
+    )
+}
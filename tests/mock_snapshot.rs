@@ -0,0 +1,776 @@
+use std::{
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use assert_matches::assert_matches;
+use fctools::{
+    extension::{
+        balloon_snapshot::{punch_zero_pages, BalloonSnapshotExt, BalloonSnapshotOptions},
+        fork::{VmForkError, VmForkOptions, VmForker},
+        migration::{VmMigrationCancellation, VmMigrationError, VmMigrationOptions, VmMigrationPhase, VmMigrator},
+        snapshot_bundle::{
+            export_snapshot_bundle, import_snapshot_bundle, SnapshotBundleCompression, SnapshotBundleError,
+        },
+        snapshot_catalog::{SnapshotCatalog, SnapshotCatalogError, SnapshotRetentionPolicy},
+        snapshot_editor::SnapshotEditorExt,
+        snapshot_encryption::{
+            decrypt_file, SnapshotEncryptionError, SnapshotEncryptionExt, SnapshotEncryptionKey,
+            StaticSnapshotKeyProvider, ENCRYPTED_CHUNK_SIZE, ENCRYPTED_FILE_MAGIC,
+        },
+        vsock_stream::VsockStreamExt,
+    },
+    process_spawner::DirectProcessSpawner,
+    runtime::{tokio::TokioRuntime, Runtime, RuntimeListener},
+    testing::{MOCK_FIRECRACKER_VERSION, MOCK_VSOCK_ECHO_PORT},
+    vm::{
+        api::VmApi,
+        configuration::{InitMethod, VmConfiguration},
+        models::{BalloonDevice, CreateSnapshot, NetworkInterface, SnapshotType},
+        VmState,
+    },
+    vmm::{
+        arguments::{jailer::JailerArguments, VmmApiSocket, VmmArguments},
+        executor::{
+            either::EitherVmmExecutor,
+            jailed::{FlatJailRenamer, JailedVmmExecutor},
+            unrestricted::UnrestrictedVmmExecutor,
+        },
+        installation::VmmInstallation,
+        ownership::VmmOwnershipModel,
+        resource::{MovedVmmResource, VmmResourceMoveMethod},
+    },
+};
+use futures_util::{AsyncReadExt, AsyncWriteExt};
+use test_framework::{
+    get_create_snapshot, get_fake_firecracker_installation, get_mock_configuration, get_mock_configuration_data,
+    get_mock_executors, get_mock_file, get_mock_vsock_configuration, get_tmp_path, prepare_mock_vm, shutdown_mock_vm,
+    MOCK_SOCKET_WAIT_TIMEOUT,
+};
+
+mod test_framework;
+
+#[tokio::test]
+async fn mock_vm_balloon_is_inflated_before_snapshot_and_zero_pages_are_punched() {
+    let mut data = get_mock_configuration_data();
+    data.machine_configuration.mem_size_mib = 8;
+    data.balloon_device = Some(BalloonDevice {
+        amount_mib: 1,
+        deflate_on_oom: false,
+        stats_polling_interval_s: Some(1),
+    });
+    let mut vm = prepare_mock_vm(
+        get_mock_executors(&[]).into_iter().next().unwrap(),
+        VmConfiguration::New {
+            init_method: InitMethod::ViaApiCalls,
+            data,
+        },
+    )
+    .await;
+    vm.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+
+    let (snapshot, report) = vm
+        .api_create_ballooned_snapshot(
+            get_create_snapshot(),
+            BalloonSnapshotOptions::new(Duration::from_secs(1)).headroom_mib(2),
+        )
+        .await
+        .unwrap();
+    assert_eq!(vm.state(), VmState::Running);
+    assert_eq!(vm.api_get_balloon_device().await.unwrap().amount_mib, 1);
+    assert_eq!(report.reclaimed_mib, 5);
+    assert_eq!(report.mem_file_size, 8 * 1024 * 1024);
+    assert_eq!(report.saved_bytes(), report.mem_file_size - report.allocated_bytes);
+    // the mock writes a sparse memory file, whose holes aren't counted as punched
+    assert_eq!(report.punched_bytes, 0);
+    shutdown_mock_vm(&mut vm).await;
+    snapshot.remove(&TokioRuntime).await;
+
+    // a fully allocated file with data in every other MiB keeps its data and loses the allocation of its zero pages
+    let path = get_tmp_path();
+    let mut content = vec![0u8; 8 * 1024 * 1024];
+    for mib in (0..8).step_by(2) {
+        content[mib * 1024 * 1024] = 1;
+    }
+    std::fs::write(&path, &content).unwrap();
+    let allocated_bytes = std::fs::metadata(&path).unwrap().blocks() * 512;
+
+    let punched_bytes = punch_zero_pages(&path).await.unwrap();
+    assert_eq!(punched_bytes, 8 * 1024 * 1024 - 4 * 4096);
+    assert_eq!(punch_zero_pages(&path).await.unwrap(), 0);
+    assert!(std::fs::metadata(&path).unwrap().blocks() * 512 < allocated_bytes);
+    assert_eq!(std::fs::read(&path).unwrap(), content);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn mock_vm_forks_into_clones_with_own_resources() {
+    let forker = VmForker::new(
+        DirectProcessSpawner,
+        TokioRuntime,
+        VmmOwnershipModel::Shared,
+        Arc::new(get_fake_firecracker_installation()),
+    );
+
+    for source_executor in get_mock_executors(&[]).into_iter().take(2) {
+        let mut configuration = get_mock_vsock_configuration();
+        configuration.data_mut().network_interfaces.push(NetworkInterface {
+            iface_id: "eth0".to_owned(),
+            host_dev_name: "tap0".to_owned(),
+            guest_mac: None,
+            rx_rate_limiter: None,
+            tx_rate_limiter: None,
+        });
+        let mut source = prepare_mock_vm(source_executor, configuration).await;
+        source.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+
+        let snapshot_directory = get_tmp_path();
+        let mut fork = forker
+            .fork(
+                &mut source,
+                3,
+                |id| {
+                    let vmm_arguments = VmmArguments::new(VmmApiSocket::Enabled(PathBuf::from(format!(
+                        "/tmp/{}.sock",
+                        rand::random::<u32>()
+                    ))));
+
+                    match id.as_ref().ends_with("-1") {
+                        true => EitherVmmExecutor::Jailed(JailedVmmExecutor::new(
+                            vmm_arguments,
+                            JailerArguments::new(id.clone())
+                                .chroot_base_dir(format!("/tmp/j{}", rand::random::<u32>())),
+                            FlatJailRenamer,
+                        )),
+                        false => {
+                            EitherVmmExecutor::Unrestricted(UnrestrictedVmmExecutor::new(vmm_arguments).id(id.clone()))
+                        }
+                    }
+                },
+                |id, data| {
+                    let index = id.as_ref().rsplit('-').next().unwrap();
+                    data.network_interfaces[0].host_dev_name = format!("tap-{index}");
+                    data.drives[0].block = Some(MovedVmmResource::new(get_mock_file(), VmmResourceMoveMethod::Copy));
+                },
+                VmForkOptions::new(&snapshot_directory, MOCK_SOCKET_WAIT_TIMEOUT)
+                    .id_prefix(format!("fork{}", rand::random::<u32>())),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(source.state(), VmState::Running);
+        assert_eq!(fork.clones.len(), 3);
+        assert!(fork.snapshot.mem_file.effective_path().starts_with(&snapshot_directory));
+        assert!(fork.snapshot.mem_file.effective_path().exists());
+        let mem_file_path = fork.snapshot.mem_file.effective_path().to_owned();
+
+        let source_uds_path = source
+            .configuration()
+            .data()
+            .vsock_device
+            .as_ref()
+            .unwrap()
+            .uds
+            .local_path()
+            .to_owned();
+        let mut uds_paths = Vec::new();
+
+        for (index, clone) in fork.clones.iter_mut().enumerate() {
+            assert!(clone.id.as_ref().ends_with(&format!("-{index}")));
+            assert_eq!(clone.vm.state(), VmState::Running);
+
+            let data = clone.vm.configuration().data();
+            assert_eq!(data.network_interfaces[0].host_dev_name, format!("tap-{index}"));
+            let uds_path = data.vsock_device.as_ref().unwrap().uds.effective_path().to_owned();
+            assert_ne!(uds_path, source_uds_path);
+            assert!(uds_path.to_string_lossy().contains(clone.id.as_ref()));
+            assert!(uds_path.exists());
+            uds_paths.push(uds_path);
+
+            let mut stream = clone.vm.vsock_connect(MOCK_VSOCK_ECHO_PORT).await.unwrap();
+            stream.write_all(b"fork\n").await.unwrap();
+            let mut buf = [0; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"fork\n");
+        }
+
+        uds_paths.dedup();
+        assert_eq!(uds_paths.len(), 3);
+
+        for clone in &mut fork.clones {
+            shutdown_mock_vm(&mut clone.vm).await;
+        }
+
+        shutdown_mock_vm(&mut source).await;
+        fork.snapshot.remove(&TokioRuntime).await;
+        assert!(!mem_file_path.exists());
+    }
+}
+
+#[tokio::test]
+async fn mock_vm_fork_rejects_writable_drives_shared_with_source() {
+    let forker = VmForker::new(
+        DirectProcessSpawner,
+        TokioRuntime,
+        VmmOwnershipModel::Shared,
+        Arc::new(get_fake_firecracker_installation()),
+    );
+    let mut source = prepare_mock_vm(get_mock_executors(&[]).remove(0), get_mock_configuration()).await;
+    source.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+
+    let snapshot_directory = get_tmp_path();
+    let result = forker
+        .fork(
+            &mut source,
+            2,
+            |id| {
+                UnrestrictedVmmExecutor::new(VmmArguments::new(VmmApiSocket::Enabled(PathBuf::from(format!(
+                    "/tmp/{}.sock",
+                    rand::random::<u32>()
+                )))))
+                .id(id.clone())
+            },
+            |_, _| {},
+            VmForkOptions::new(&snapshot_directory, MOCK_SOCKET_WAIT_TIMEOUT)
+                .id_prefix(format!("fork{}", rand::random::<u32>())),
+        )
+        .await;
+
+    assert_matches!(result.err(), Some(VmForkError::SharedWritableDrive { drive_id, .. }) if drive_id == "rootfs");
+    assert_eq!(source.state(), VmState::Running);
+    assert!(!snapshot_directory.exists());
+    shutdown_mock_vm(&mut source).await;
+}
+
+#[tokio::test]
+async fn mock_vm_fork_copies_writable_drives_of_paused_source() {
+    let forker = VmForker::new(
+        DirectProcessSpawner,
+        TokioRuntime,
+        VmmOwnershipModel::Shared,
+        Arc::new(get_fake_firecracker_installation()),
+    );
+    let mut source = prepare_mock_vm(get_mock_executors(&[]).remove(1), get_mock_configuration()).await;
+    source.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+
+    // the source writes to its own copy of the drive inside its jail, which the clones have to start from
+    let source_block = source.configuration().data().drives[0].block.clone().unwrap();
+    std::fs::write(source_block.effective_path(), b"written by source").unwrap();
+
+    let snapshot_directory = get_tmp_path();
+    let mut fork = forker
+        .fork(
+            &mut source,
+            2,
+            |id| {
+                EitherVmmExecutor::Jailed(JailedVmmExecutor::new(
+                    VmmArguments::new(VmmApiSocket::Enabled(PathBuf::from(format!(
+                        "/tmp/{}.sock",
+                        rand::random::<u32>()
+                    )))),
+                    JailerArguments::new(id.clone()).chroot_base_dir(format!("/tmp/j{}", rand::random::<u32>())),
+                    FlatJailRenamer,
+                ))
+            },
+            |_, data| {
+                let source_path = data.drives[0].block.as_ref().unwrap().source_path().to_owned();
+                data.drives[0].block = Some(MovedVmmResource::new(source_path, VmmResourceMoveMethod::FastCopy));
+            },
+            VmForkOptions::new(&snapshot_directory, MOCK_SOCKET_WAIT_TIMEOUT)
+                .id_prefix(format!("fork{}", rand::random::<u32>())),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(source.state(), VmState::Running);
+    assert_eq!(std::fs::read(source_block.source_path()).unwrap(), b"mock");
+
+    for clone in &mut fork.clones {
+        let block = clone.vm.configuration().data().drives[0].block.clone().unwrap();
+        assert_eq!(block.local_path(), source_block.local_path());
+        assert_ne!(block.effective_path(), source_block.effective_path());
+        assert_eq!(std::fs::read(block.effective_path()).unwrap(), b"written by source");
+        shutdown_mock_vm(&mut clone.vm).await;
+    }
+
+    // only the snapshot is left behind, since the copies of the drives were moved into the clones
+    assert_eq!(std::fs::read_dir(&snapshot_directory).unwrap().count(), 2);
+    shutdown_mock_vm(&mut source).await;
+    fork.snapshot.remove(&TokioRuntime).await;
+}
+
+#[tokio::test]
+async fn mock_vm_snapshot_catalog_records_flattens_and_prunes_chains() {
+    let mut data = get_mock_configuration_data();
+    data.boot_source.kernel_image = MovedVmmResource::new(get_mock_file(), VmmResourceMoveMethod::HardLinkOrCopy);
+    let mut vm = prepare_mock_vm(
+        get_mock_executors(&[]).into_iter().next().unwrap(),
+        VmConfiguration::New {
+            init_method: InitMethod::ViaApiCalls,
+            data,
+        },
+    )
+    .await;
+    vm.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+    vm.api_pause().await.unwrap();
+
+    let catalog_directory = get_tmp_path();
+    let mut catalog = SnapshotCatalog::open(&catalog_directory, TokioRuntime).await.unwrap();
+    let firecracker_version = vm.api_get_firecracker_version().await.unwrap();
+
+    let mut parent = None;
+    for snapshot_type in [SnapshotType::Full, SnapshotType::Diff, SnapshotType::Diff] {
+        let snapshot = vm
+            .api_create_snapshot(CreateSnapshot {
+                snapshot_type: Some(snapshot_type),
+                ..get_create_snapshot()
+            })
+            .await
+            .unwrap();
+        parent = Some(
+            catalog
+                .record(snapshot, snapshot_type, parent, &firecracker_version)
+                .await
+                .unwrap(),
+        );
+    }
+    shutdown_mock_vm(&mut vm).await;
+
+    let chain_ids = catalog
+        .chain(2)
+        .unwrap()
+        .iter()
+        .map(|entry| entry.id)
+        .collect::<Vec<_>>();
+    assert_eq!(chain_ids, [0, 1, 2]);
+    assert_matches!(
+        catalog.restore(2, VmmResourceMoveMethod::Copy, None, Some(true)),
+        Err(SnapshotCatalogError::NotRestorable(2))
+    );
+
+    // the fake snapshot-editor only records the memory files it was asked to rebase
+    let rebase_log_path = get_tmp_path();
+    let snapshot_editor_path = get_tmp_path();
+    std::fs::write(
+        &snapshot_editor_path,
+        format!("#!/bin/sh\necho \"$@\" >> {}\n", rebase_log_path.display()),
+    )
+    .unwrap();
+    std::fs::set_permissions(
+        &snapshot_editor_path,
+        std::os::unix::fs::PermissionsExt::from_mode(0o755),
+    )
+    .unwrap();
+    let installation = VmmInstallation {
+        snapshot_editor_path,
+        ..get_fake_firecracker_installation()
+    };
+
+    let flattened_id = catalog
+        .flatten(2, &installation.snapshot_editor(TokioRuntime))
+        .await
+        .unwrap();
+    assert_eq!(flattened_id, 3);
+    assert_eq!(catalog.get(3).unwrap().snapshot_type, SnapshotType::Full);
+    assert_eq!(catalog.get(3).unwrap().parent, Some(2));
+
+    let rebase_log = std::fs::read_to_string(&rebase_log_path).unwrap();
+    let rebased_diffs = rebase_log
+        .lines()
+        .map(|line| line.rsplit(' ').next().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        rebased_diffs,
+        [
+            catalog.mem_file_path(1).to_str().unwrap(),
+            catalog.mem_file_path(2).to_str().unwrap()
+        ]
+    );
+
+    let entries = catalog.entries().to_vec();
+    let mut catalog = SnapshotCatalog::open(&catalog_directory, TokioRuntime).await.unwrap();
+    assert_eq!(catalog.entries(), entries);
+
+    let mut vm = prepare_mock_vm(
+        get_mock_executors(&[]).into_iter().next().unwrap(),
+        catalog
+            .restore(3, VmmResourceMoveMethod::Copy, None, Some(true))
+            .unwrap(),
+    )
+    .await;
+    vm.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+    assert_eq!(vm.state(), VmState::Running);
+    shutdown_mock_vm(&mut vm).await;
+
+    assert_eq!(
+        catalog
+            .prune(SnapshotRetentionPolicy::new().keep_generations(1))
+            .await
+            .unwrap(),
+        [0, 1, 2]
+    );
+    assert_eq!(catalog.entries().len(), 1);
+    assert_eq!(catalog.get(3).unwrap().parent, None);
+    assert!(!catalog.mem_file_path(0).exists());
+    assert!(catalog.mem_file_path(3).exists());
+}
+
+#[tokio::test]
+async fn mock_vm_snapshot_bundle_exports_and_imports_with_checksums() {
+    let mut data = get_mock_configuration_data();
+    data.machine_configuration.mem_size_mib = 8;
+    let kernel_path = data.boot_source.kernel_image.source_path().to_owned();
+    let mut vm = prepare_mock_vm(
+        get_mock_executors(&[]).into_iter().next().unwrap(),
+        VmConfiguration::New {
+            init_method: InitMethod::ViaApiCalls,
+            data,
+        },
+    )
+    .await;
+    vm.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+    vm.api_pause().await.unwrap();
+    let snapshot = vm.api_create_snapshot(get_create_snapshot()).await.unwrap();
+    shutdown_mock_vm(&mut vm).await;
+
+    for compression in [
+        SnapshotBundleCompression::None,
+        SnapshotBundleCompression::Gzip,
+        SnapshotBundleCompression::Zstd,
+    ] {
+        let bundle_path = get_tmp_path();
+        export_snapshot_bundle(
+            &TokioRuntime,
+            &snapshot,
+            MOCK_FIRECRACKER_VERSION,
+            compression,
+            TokioRuntime.fs_open_file_for_write(&bundle_path).await.unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let import_directory = get_tmp_path();
+        let imported = import_snapshot_bundle(
+            &TokioRuntime,
+            TokioRuntime.fs_open_file_for_read(&bundle_path).await.unwrap(),
+            &import_directory,
+        )
+        .await
+        .unwrap();
+        assert_eq!(imported.firecracker_version, MOCK_FIRECRACKER_VERSION);
+        assert_eq!(
+            std::fs::read(imported.snapshot.snapshot.effective_path()).unwrap(),
+            std::fs::read(snapshot.snapshot.effective_path()).unwrap()
+        );
+
+        let kernel_image = &imported.snapshot.configuration_data.boot_source.kernel_image;
+        assert!(kernel_image.source_path().starts_with(&import_directory));
+        assert_eq!(
+            std::fs::read(kernel_image.source_path()).unwrap(),
+            std::fs::read(&kernel_path).unwrap()
+        );
+
+        let mut vm = prepare_mock_vm(
+            get_mock_executors(&[]).into_iter().next().unwrap(),
+            imported.into_configuration(VmmResourceMoveMethod::Copy, None, Some(true)),
+        )
+        .await;
+        vm.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+        assert_eq!(vm.state(), VmState::Running);
+        shutdown_mock_vm(&mut vm).await;
+    }
+
+    // corrupt the first byte of the snapshot file, which directly follows the first tar header
+    let bundle_path = get_tmp_path();
+    export_snapshot_bundle(
+        &TokioRuntime,
+        &snapshot,
+        MOCK_FIRECRACKER_VERSION,
+        SnapshotBundleCompression::None,
+        TokioRuntime.fs_open_file_for_write(&bundle_path).await.unwrap(),
+    )
+    .await
+    .unwrap();
+    let mut bundle = std::fs::read(&bundle_path).unwrap();
+    bundle[512] ^= 0xff;
+    std::fs::write(&bundle_path, bundle).unwrap();
+
+    let import_directory = get_tmp_path();
+    assert_matches!(
+        import_snapshot_bundle(
+            &TokioRuntime,
+            TokioRuntime.fs_open_file_for_read(&bundle_path).await.unwrap(),
+            &import_directory,
+        )
+        .await,
+        Err(SnapshotBundleError::ChecksumMismatch(name)) if name == "snapshot"
+    );
+    assert!(!import_directory.join("snapshot").exists());
+}
+
+#[tokio::test]
+async fn mock_vm_migrates_over_tcp_and_resumes_source_on_cancellation() {
+    let migrator = VmMigrator::new(
+        DirectProcessSpawner,
+        TokioRuntime,
+        VmmOwnershipModel::Shared,
+        Arc::new(get_fake_firecracker_installation()),
+    );
+    let mut data = get_mock_configuration_data();
+    data.machine_configuration.mem_size_mib = 8;
+    let mut source = prepare_mock_vm(
+        get_mock_executors(&[]).into_iter().next().unwrap(),
+        VmConfiguration::New {
+            init_method: InitMethod::ViaApiCalls,
+            data,
+        },
+    )
+    .await;
+    source.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+    let (listener, address) = TokioRuntime.tcp_bind("127.0.0.1:0".parse().unwrap()).unwrap();
+
+    let cancellation = VmMigrationCancellation::new();
+    cancellation.cancel();
+    let snapshot_directory = get_tmp_path();
+    assert_matches!(
+        migrator
+            .send(
+                &mut source,
+                TokioRuntime.tcp_connect(address).await.unwrap(),
+                VmMigrationOptions::new(&snapshot_directory, MOCK_SOCKET_WAIT_TIMEOUT).cancellation(cancellation),
+                |_| {},
+            )
+            .await,
+        Err(VmMigrationError::Cancelled)
+    );
+    assert_eq!(source.state(), VmState::Running);
+    assert_eq!(std::fs::read_dir(&snapshot_directory).unwrap().count(), 0);
+    drop(listener.accept().await.unwrap());
+
+    let receive_directory = get_tmp_path();
+    let sent_progress = std::sync::Mutex::new(Vec::new());
+    let received_progress = std::sync::Mutex::new(Vec::new());
+    let (sent, received) = futures_util::join!(
+        async {
+            migrator
+                .send(
+                    &mut source,
+                    TokioRuntime.tcp_connect(address).await.unwrap(),
+                    VmMigrationOptions::new(get_tmp_path(), MOCK_SOCKET_WAIT_TIMEOUT),
+                    |progress| sent_progress.lock().unwrap().push(progress),
+                )
+                .await
+        },
+        async {
+            migrator
+                .receive(
+                    listener.accept().await.unwrap(),
+                    get_mock_executors(&[]).into_iter().next().unwrap(),
+                    |data| data.machine_configuration.vcpu_count = 2,
+                    VmMigrationOptions::new(&receive_directory, MOCK_SOCKET_WAIT_TIMEOUT),
+                    |progress| received_progress.lock().unwrap().push(progress),
+                )
+                .await
+        }
+    );
+
+    sent.unwrap();
+    let mut received = received.unwrap();
+    assert_matches!(source.state(), VmState::Exited | VmState::Crashed(_));
+    assert_eq!(received.vm.state(), VmState::Running);
+    assert_eq!(received.vm.configuration().data().machine_configuration.vcpu_count, 2);
+    assert_eq!(
+        std::fs::metadata(received.snapshot.mem_file.effective_path())
+            .unwrap()
+            .len(),
+        8 * 1024 * 1024
+    );
+
+    for progress in [
+        sent_progress.into_inner().unwrap(),
+        received_progress.into_inner().unwrap(),
+    ] {
+        let last = progress.last().unwrap();
+        assert_eq!(last.phase, VmMigrationPhase::Completed);
+        assert_eq!(last.transferred_bytes, last.total_bytes);
+        assert!(progress
+            .iter()
+            .any(|progress| progress.phase == VmMigrationPhase::Transferring));
+    }
+
+    shutdown_mock_vm(&mut received.vm).await;
+    received.snapshot.remove(&TokioRuntime).await;
+    assert_eq!(std::fs::read_dir(&receive_directory).unwrap().count(), 0);
+}
+
+#[tokio::test]
+async fn mock_vm_migration_is_torn_down_on_receiving_end_without_commit() {
+    let migrator = VmMigrator::new(
+        DirectProcessSpawner,
+        TokioRuntime,
+        VmmOwnershipModel::Shared,
+        Arc::new(get_fake_firecracker_installation()),
+    );
+    let mut source = prepare_mock_vm(
+        get_mock_executors(&[]).into_iter().next().unwrap(),
+        get_mock_configuration(),
+    )
+    .await;
+    source.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+    let (listener, address) = TokioRuntime.tcp_bind("127.0.0.1:0".parse().unwrap()).unwrap();
+
+    // forward everything up to the status of the receiving end, then drop the connection before the commit
+    let proxy = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy_address = proxy.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (sender_stream, _) = proxy.accept().await.unwrap();
+        let (mut sender_read, mut sender_write) = sender_stream.into_split();
+        let (mut receiver_read, mut receiver_write) =
+            tokio::net::TcpStream::connect(address).await.unwrap().into_split();
+        let forward =
+            tokio::spawn(async move { tokio::io::copy(&mut sender_read, &mut receiver_write).await.unwrap() });
+
+        let mut status = [0];
+        tokio::io::AsyncReadExt::read_exact(&mut receiver_read, &mut status)
+            .await
+            .unwrap();
+        forward.abort();
+        let _ = forward.await;
+        tokio::io::AsyncWriteExt::write_all(&mut sender_write, &status)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    });
+
+    let receive_directory = get_tmp_path();
+    let (sent, received) = futures_util::join!(
+        migrator.send(
+            &mut source,
+            TokioRuntime.tcp_connect(proxy_address).await.unwrap(),
+            VmMigrationOptions::new(get_tmp_path(), MOCK_SOCKET_WAIT_TIMEOUT),
+            |_| {},
+        ),
+        async {
+            migrator
+                .receive(
+                    listener.accept().await.unwrap(),
+                    get_mock_executors(&[]).into_iter().next().unwrap(),
+                    |_| {},
+                    VmMigrationOptions::new(&receive_directory, MOCK_SOCKET_WAIT_TIMEOUT),
+                    |_| {},
+                )
+                .await
+        }
+    );
+
+    sent.unwrap();
+    assert_matches!(source.state(), VmState::Exited | VmState::Crashed(_));
+    assert_matches!(received.map(|_| ()), Err(VmMigrationError::TransferError(_)));
+    assert_eq!(std::fs::read_dir(&receive_directory).unwrap().count(), 0);
+}
+
+#[tokio::test]
+async fn mock_vm_snapshot_is_encrypted_at_rest_and_decrypted_for_restore() {
+    let key_provider = StaticSnapshotKeyProvider::new("current", SnapshotEncryptionKey::generate())
+        .retired_key("old", SnapshotEncryptionKey::generate());
+    let mut data = get_mock_configuration_data();
+    data.machine_configuration.mem_size_mib = 8;
+    let mut vm = prepare_mock_vm(
+        get_mock_executors(&[]).into_iter().next().unwrap(),
+        VmConfiguration::New {
+            init_method: InitMethod::ViaApiCalls,
+            data,
+        },
+    )
+    .await;
+    vm.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+    vm.api_pause().await.unwrap();
+    let snapshot = vm
+        .api_create_encrypted_snapshot(get_create_snapshot(), &key_provider)
+        .await
+        .unwrap();
+    shutdown_mock_vm(&mut vm).await;
+
+    for path in [snapshot.snapshot.effective_path(), snapshot.mem_file.effective_path()] {
+        let content = std::fs::read(path).unwrap();
+        assert!(content.starts_with(ENCRYPTED_FILE_MAGIC));
+        assert!(content[ENCRYPTED_FILE_MAGIC.len() + 2..].starts_with(b"current"));
+    }
+
+    for executor in get_mock_executors(&[]).into_iter().take(2) {
+        let mut vm = prepare_mock_vm(
+            executor,
+            snapshot
+                .clone()
+                .into_configuration(VmmResourceMoveMethod::Copy, None, Some(true)),
+        )
+        .await;
+        vm.start_decrypted(MOCK_SOCKET_WAIT_TIMEOUT, &key_provider)
+            .await
+            .unwrap();
+        assert_eq!(vm.state(), VmState::Running);
+
+        let VmConfiguration::RestoredFromSnapshot { ref load_snapshot, .. } = vm.configuration() else {
+            unreachable!()
+        };
+        assert!(!load_snapshot.snapshot.effective_path().exists());
+        assert!(!load_snapshot.mem_backend.backend.effective_path().exists());
+        assert!(std::fs::read(snapshot.snapshot.effective_path())
+            .unwrap()
+            .starts_with(ENCRYPTED_FILE_MAGIC));
+        shutdown_mock_vm(&mut vm).await;
+    }
+
+    let other_key_provider = StaticSnapshotKeyProvider::new("current", SnapshotEncryptionKey::generate());
+    let mut vm = prepare_mock_vm(
+        get_mock_executors(&[]).into_iter().next().unwrap(),
+        snapshot
+            .clone()
+            .into_configuration(VmmResourceMoveMethod::Copy, None, Some(true)),
+    )
+    .await;
+    assert_matches!(
+        vm.start_decrypted(MOCK_SOCKET_WAIT_TIMEOUT, &other_key_provider).await,
+        Err(SnapshotEncryptionError::AuthenticationFailed)
+    );
+    assert_eq!(vm.state(), VmState::NotStarted);
+    let VmConfiguration::RestoredFromSnapshot { ref load_snapshot, .. } = vm.configuration() else {
+        unreachable!()
+    };
+    assert!(!load_snapshot.snapshot.effective_path().exists());
+
+    let decrypted_path = get_tmp_path();
+    decrypt_file(
+        &TokioRuntime,
+        snapshot.snapshot.effective_path(),
+        &decrypted_path,
+        &key_provider,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        std::fs::metadata(&decrypted_path).unwrap().permissions().mode() & 0o777,
+        0o600
+    );
+    std::fs::remove_file(&decrypted_path).unwrap();
+
+    // truncating the memory file by a whole chunk must be detected even though every remaining chunk is authentic
+    let mem_file_path = snapshot.mem_file.effective_path();
+    let length = std::fs::metadata(mem_file_path).unwrap().len();
+    assert!(length > ENCRYPTED_CHUNK_SIZE as u64 * 2);
+    std::fs::File::options()
+        .write(true)
+        .open(mem_file_path)
+        .unwrap()
+        .set_len(length - ENCRYPTED_CHUNK_SIZE as u64 - 16)
+        .unwrap();
+    let decrypted_path = get_tmp_path();
+    assert_matches!(
+        decrypt_file(&TokioRuntime, mem_file_path, &decrypted_path, &key_provider).await,
+        Err(SnapshotEncryptionError::AuthenticationFailed)
+    );
+    assert!(!decrypted_path.exists());
+    snapshot.remove(&TokioRuntime).await;
+}
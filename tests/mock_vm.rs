@@ -0,0 +1,479 @@
+use std::time::Duration;
+
+use assert_matches::assert_matches;
+use fctools::{
+    runtime::tokio::TokioRuntime,
+    testing::{
+        fault::{MockFault, MockFaultAction},
+        server::{MockApiServer, MockApiServerError, MockVmState, MockVmmEvent},
+        MOCK_FIRECRACKER_VERSION,
+    },
+    vm::{
+        api::VmApi,
+        configuration::{InitMethod, VmConfiguration},
+        models::{BalloonDevice, MmdsConfiguration, MmdsVersion, NetworkInterface},
+        shutdown::{VmShutdownAction, VmShutdownMethod},
+        VmError, VmState,
+    },
+    vmm::{
+        executor::jailed::scan_jails,
+        process::{VmmProcessDescriptor, VmmProcessError},
+        resource::VmmResourceMoveMethod,
+    },
+};
+use futures_util::{io::BufReader, AsyncBufReadExt, StreamExt};
+use test_framework::{
+    get_create_snapshot, get_fake_firecracker_installation, get_mock_configuration, get_mock_configuration_data,
+    get_mock_executor_pairs, get_mock_executors, get_mock_file, get_tmp_path, prepare_mock_vm, recover_mock_vm,
+    shutdown_mock_vm, MOCK_SOCKET_WAIT_TIMEOUT,
+};
+
+mod test_framework;
+
+#[tokio::test]
+async fn mock_vm_can_boot_via_api_calls() {
+    for executor in get_mock_executors(&[]) {
+        let mut vm = prepare_mock_vm(
+            executor,
+            VmConfiguration::New {
+                init_method: InitMethod::ViaApiCalls,
+                data: get_mock_configuration_data(),
+            },
+        )
+        .await;
+        vm.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+        assert_eq!(vm.state(), VmState::Running);
+
+        let info = vm.api_get_info().await.unwrap();
+        assert!(!info.is_paused);
+        assert_eq!(info.app_name, "Firecracker");
+        assert_eq!(
+            vm.api_get_firecracker_version().await.unwrap(),
+            MOCK_FIRECRACKER_VERSION
+        );
+        assert_eq!(vm.api_get_machine_configuration().await.unwrap().mem_size_mib, 128);
+
+        shutdown_mock_vm(&mut vm).await;
+    }
+}
+
+#[tokio::test]
+async fn mock_vm_can_boot_via_json() {
+    for executor in get_mock_executors(&[]) {
+        let mut vm = prepare_mock_vm(
+            executor,
+            VmConfiguration::New {
+                init_method: InitMethod::ViaJsonConfiguration(get_tmp_path()),
+                data: get_mock_configuration_data(),
+            },
+        )
+        .await;
+        vm.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+        assert!(!vm.api_get_info().await.unwrap().is_paused);
+        shutdown_mock_vm(&mut vm).await;
+    }
+}
+
+#[tokio::test]
+async fn mock_vm_can_be_paused_and_resumed() {
+    for executor in get_mock_executors(&[]) {
+        let mut vm = prepare_mock_vm(executor, get_mock_configuration()).await;
+        vm.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+
+        vm.api_pause().await.unwrap();
+        assert_eq!(vm.state(), VmState::Paused);
+        assert!(vm.api_get_info().await.unwrap().is_paused);
+        vm.api_resume().await.unwrap();
+        assert_eq!(vm.state(), VmState::Running);
+        assert!(!vm.api_get_info().await.unwrap().is_paused);
+
+        shutdown_mock_vm(&mut vm).await;
+    }
+}
+
+#[tokio::test]
+async fn mock_vm_can_be_killed() {
+    for executor in get_mock_executors(&[]) {
+        let mut vm = prepare_mock_vm(executor, get_mock_configuration()).await;
+        vm.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+        let outcome = vm
+            .shutdown([VmShutdownAction {
+                method: VmShutdownMethod::Kill,
+                timeout: Some(MOCK_SOCKET_WAIT_TIMEOUT),
+                graceful: false,
+            }])
+            .await
+            .unwrap();
+        assert!(outcome.errors.is_empty());
+        vm.cleanup().await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn mock_vm_serial_console_is_emulated() {
+    let executor = get_mock_executors(&[]).remove(0);
+    let mut vm = prepare_mock_vm(executor, get_mock_configuration()).await;
+    vm.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+
+    let mut pipes = vm.take_pipes().unwrap();
+    let mut lines = BufReader::new(&mut pipes.stdout).lines();
+    let first_line = lines.next().await.unwrap().unwrap();
+    assert!(first_line.contains("console=ttyS0 reboot=k panic=1 pci=off"));
+    drop(lines);
+    drop(pipes);
+    shutdown_mock_vm(&mut vm).await;
+}
+
+#[tokio::test]
+async fn mock_vm_can_be_shut_down_by_writing_to_serial() {
+    let executor = get_mock_executors(&[]).remove(0);
+    let mut vm = prepare_mock_vm(executor, get_mock_configuration()).await;
+    vm.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let outcome = vm
+        .shutdown([VmShutdownAction {
+            method: VmShutdownMethod::WriteToSerial(b"reboot\n".to_vec()),
+            timeout: Some(MOCK_SOCKET_WAIT_TIMEOUT),
+            graceful: true,
+        }])
+        .await
+        .unwrap();
+    assert!(outcome.fully_graceful());
+    vm.cleanup().await.unwrap();
+}
+
+#[tokio::test]
+async fn mock_vm_can_take_and_restore_snapshot() {
+    for (executor, restore_executor) in get_mock_executors(&[]).into_iter().zip(get_mock_executors(&[])) {
+        let mut vm = prepare_mock_vm(executor, get_mock_configuration()).await;
+        vm.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+        vm.api_create_snapshot(get_create_snapshot()).await.unwrap_err();
+
+        vm.api_pause().await.unwrap();
+        let mut snapshot = vm.api_create_snapshot(get_create_snapshot()).await.unwrap();
+        assert_eq!(
+            tokio::fs::metadata(snapshot.mem_file.effective_path())
+                .await
+                .unwrap()
+                .len(),
+            128 * 1024 * 1024
+        );
+        snapshot
+            .copy(get_tmp_path(), get_tmp_path(), &fctools::runtime::tokio::TokioRuntime)
+            .await
+            .unwrap();
+        vm.api_resume().await.unwrap();
+        shutdown_mock_vm(&mut vm).await;
+
+        let mut restored_vm = prepare_mock_vm(
+            restore_executor,
+            snapshot.into_configuration(VmmResourceMoveMethod::Copy, None, Some(true)),
+        )
+        .await;
+        restored_vm.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+        assert!(!restored_vm.api_get_info().await.unwrap().is_paused);
+        shutdown_mock_vm(&mut restored_vm).await;
+    }
+}
+
+#[tokio::test]
+async fn mock_vm_restored_from_snapshot_without_resume_is_paused() {
+    for resume_vm in [None, Some(false)] {
+        let mut vm = prepare_mock_vm(get_mock_executors(&[]).remove(0), get_mock_configuration()).await;
+        vm.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+        vm.api_pause().await.unwrap();
+        let mut snapshot = vm.api_create_snapshot(get_create_snapshot()).await.unwrap();
+        snapshot
+            .copy(get_tmp_path(), get_tmp_path(), &fctools::runtime::tokio::TokioRuntime)
+            .await
+            .unwrap();
+        vm.api_resume().await.unwrap();
+        shutdown_mock_vm(&mut vm).await;
+
+        let mut restored_vm = prepare_mock_vm(
+            get_mock_executors(&[]).remove(0),
+            snapshot.into_configuration(VmmResourceMoveMethod::Copy, None, resume_vm),
+        )
+        .await;
+        restored_vm.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+        assert_eq!(restored_vm.state(), VmState::Paused);
+        assert!(restored_vm.api_get_info().await.unwrap().is_paused);
+
+        restored_vm.api_resume().await.unwrap();
+        assert_eq!(restored_vm.state(), VmState::Running);
+        shutdown_mock_vm(&mut restored_vm).await;
+    }
+}
+
+#[tokio::test]
+async fn mock_vm_start_fails_on_injected_error_response() {
+    let faults = [MockFault::new(
+        "/boot-source",
+        MockFaultAction::ErrorResponse {
+            status_code: 400,
+            fault_message: "Injected fault".to_string(),
+        },
+    )
+    .method("PUT")];
+
+    for executor in get_mock_executors(&faults) {
+        let mut vm = prepare_mock_vm(executor, get_mock_configuration()).await;
+        assert_matches!(vm.start(MOCK_SOCKET_WAIT_TIMEOUT).await, Err(VmError::ApiError(_)));
+        vm.shutdown([VmShutdownAction {
+            method: VmShutdownMethod::Kill,
+            timeout: None,
+            graceful: false,
+        }])
+        .await
+        .unwrap();
+        vm.cleanup().await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn mock_vm_crashes_on_injected_crash() {
+    let faults = [MockFault::new("/vm", MockFaultAction::Crash { exit_code: 3 })];
+
+    for executor in get_mock_executors(&faults) {
+        let mut vm = prepare_mock_vm(executor, get_mock_configuration()).await;
+        vm.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+        vm.api_pause().await.unwrap_err();
+
+        tokio::time::timeout(MOCK_SOCKET_WAIT_TIMEOUT, async {
+            while vm.state() == VmState::Running {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_matches!(vm.state(), VmState::Crashed(_) | VmState::Exited);
+        vm.cleanup().await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn mock_vm_can_roundtrip_mmds() {
+    for executor in get_mock_executors(&[]) {
+        let mut configuration_data = get_mock_configuration_data();
+        configuration_data.network_interfaces.push(NetworkInterface {
+            iface_id: "eth0".to_string(),
+            host_dev_name: "tap0".to_string(),
+            guest_mac: None,
+            rx_rate_limiter: None,
+            tx_rate_limiter: None,
+        });
+        configuration_data.mmds_configuration = Some(MmdsConfiguration {
+            version: MmdsVersion::V2,
+            network_interfaces: vec!["eth0".to_string()],
+            ipv4_address: None,
+        });
+
+        let mut vm = prepare_mock_vm(
+            executor,
+            VmConfiguration::New {
+                init_method: InitMethod::ViaApiCalls,
+                data: configuration_data,
+            },
+        )
+        .await;
+        vm.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+
+        vm.api_create_mmds_untyped(&serde_json::json!({ "a": { "b": 1 } }))
+            .await
+            .unwrap();
+        vm.api_update_mmds_untyped(&serde_json::json!({ "a": { "c": 2 } }))
+            .await
+            .unwrap();
+        assert_eq!(
+            vm.api_get_mmds_untyped().await.unwrap(),
+            serde_json::json!({ "a": { "b": 1, "c": 2 } })
+        );
+
+        shutdown_mock_vm(&mut vm).await;
+    }
+}
+
+#[tokio::test]
+async fn mock_vm_balloon_statistics_require_polling() {
+    for polling_interval_s in [None, Some(1)] {
+        let executor = get_mock_executors(&[]).remove(0);
+        let mut configuration_data = get_mock_configuration_data();
+        configuration_data.balloon_device = Some(BalloonDevice {
+            amount_mib: 64,
+            deflate_on_oom: false,
+            stats_polling_interval_s: polling_interval_s,
+        });
+
+        let mut vm = prepare_mock_vm(
+            executor,
+            VmConfiguration::New {
+                init_method: InitMethod::ViaApiCalls,
+                data: configuration_data,
+            },
+        )
+        .await;
+        vm.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+
+        match polling_interval_s {
+            Some(_) => assert_eq!(vm.api_get_balloon_statistics().await.unwrap().target_mib, 64),
+            None => drop(vm.api_get_balloon_statistics().await.unwrap_err()),
+        }
+
+        shutdown_mock_vm(&mut vm).await;
+    }
+}
+
+#[tokio::test]
+async fn mock_vm_can_be_recovered_from_descriptor() {
+    for (executor, recovery_executor) in get_mock_executor_pairs() {
+        let configuration = get_mock_configuration();
+        let mut vm = prepare_mock_vm(executor, configuration.clone()).await;
+        vm.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+        vm.api_pause().await.unwrap();
+
+        let descriptor = serde_json::to_string(&vm.descriptor().unwrap()).unwrap();
+        drop(vm);
+
+        let descriptor = serde_json::from_str::<VmmProcessDescriptor>(&descriptor).unwrap();
+        let mut vm = recover_mock_vm(recovery_executor, configuration, &descriptor)
+            .await
+            .unwrap();
+        assert_eq!(vm.state(), VmState::Paused);
+        assert!(vm
+            .configuration()
+            .data()
+            .boot_source
+            .kernel_image
+            .effective_path_checked()
+            .is_some());
+        assert_matches!(vm.take_pipes(), Err(VmError::ProcessError(_)));
+
+        vm.api_resume().await.unwrap();
+        assert_eq!(vm.state(), VmState::Running);
+        shutdown_mock_vm(&mut vm).await;
+    }
+}
+
+#[tokio::test]
+async fn mock_vm_recovery_rejects_mismatched_descriptor() {
+    for (executor, recovery_executor) in get_mock_executor_pairs() {
+        let configuration = get_mock_configuration();
+        let mut vm = prepare_mock_vm(executor, configuration.clone()).await;
+        vm.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+
+        let mut descriptor = vm.descriptor().unwrap();
+        descriptor.socket_path = Some(get_tmp_path());
+        assert_matches!(
+            recover_mock_vm(recovery_executor, configuration, &descriptor)
+                .await
+                .err(),
+            Some(VmError::ProcessError(VmmProcessError::DescriptorMismatch { .. }))
+        );
+
+        shutdown_mock_vm(&mut vm).await;
+    }
+}
+
+#[tokio::test]
+async fn mock_vm_jails_can_be_scanned() {
+    let installation = get_fake_firecracker_installation();
+
+    for (index, executor) in get_mock_executors(&[]).into_iter().enumerate().skip(1) {
+        let mut vm = prepare_mock_vm(executor, get_mock_configuration()).await;
+        vm.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+
+        let descriptor = vm.descriptor().unwrap();
+        // the jail path is <chroot base dir>/<firecracker file name>/<jail id>/root
+        let chroot_base_dir = descriptor.root_path.ancestors().nth(3).unwrap();
+        let scanned_jails = scan_jails(Some(chroot_base_dir), &installation, &TokioRuntime)
+            .await
+            .unwrap();
+        assert_eq!(scanned_jails.len(), 1);
+        assert_eq!(scanned_jails[0].jail_path, descriptor.root_path);
+
+        // only the daemonized jailer in a new PID namespace writes out a PID file
+        match index {
+            1 => assert_eq!(scanned_jails[0].pid, None),
+            _ => assert_eq!(scanned_jails[0].pid, Some(descriptor.pid)),
+        }
+
+        shutdown_mock_vm(&mut vm).await;
+        assert!(scan_jails(Some(chroot_base_dir), &installation, &TokioRuntime)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
+
+#[test]
+fn mock_api_server_validates_state() {
+    let socket_path = get_tmp_path();
+    let handle = MockApiServer::new().socket_path(&socket_path).start().unwrap();
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        assert_eq!(send(&socket_path, "PATCH", "/vm", r#"{"state":"Paused"}"#).await, 400);
+        assert_eq!(
+            send(&socket_path, "PUT", "/actions", r#"{"action_type":"InstanceStart"}"#).await,
+            400
+        );
+
+        let kernel_path = get_mock_file();
+        let boot_source = serde_json::json!({ "kernel_image_path": kernel_path }).to_string();
+        assert_eq!(send(&socket_path, "PUT", "/boot-source", &boot_source).await, 204);
+        assert_eq!(
+            send(&socket_path, "PUT", "/actions", r#"{"action_type":"InstanceStart"}"#).await,
+            204
+        );
+        assert_eq!(send(&socket_path, "PUT", "/boot-source", &boot_source).await, 400);
+        assert_eq!(send(&socket_path, "GET", "/unknown", "").await, 400);
+    });
+
+    assert_eq!(
+        handle.recv_event_timeout(Duration::from_secs(1)),
+        Some(MockVmmEvent::Started)
+    );
+    let state = handle.state();
+    assert_eq!(state.vm_state, MockVmState::Running);
+    assert_eq!(state.requests.len(), 6);
+    assert_eq!(state.requests[2].status_code, Some(204));
+    handle.shutdown();
+}
+
+#[test]
+fn mock_api_server_rejects_invalid_configuration_file() {
+    let config_path = get_tmp_path();
+    std::fs::write(
+        &config_path,
+        serde_json::json!({ "boot-source": { "kernel_image_path": get_tmp_path() } }).to_string(),
+    )
+    .unwrap();
+
+    assert_matches!(
+        MockApiServer::new().config_path(config_path).start(),
+        Err(MockApiServerError::ConfigurationRejected { .. })
+    );
+}
+
+async fn send(socket_path: &std::path::Path, method: &str, route: &str, body: &str) -> u16 {
+    use http_body_util::{BodyExt, Full};
+    use hyper_client_sockets::unix::{connector::HyperUnixConnector, UnixUriExt};
+
+    let client = hyper_util::client::legacy::Builder::new(hyper_util::rt::TokioExecutor::new())
+        .build::<_, Full<bytes::Bytes>>(HyperUnixConnector {
+            backend: hyper_client_sockets::Backend::Tokio,
+        });
+    let request = http::Request::builder()
+        .method(method)
+        .uri(hyper::Uri::unix(socket_path, route).unwrap())
+        .body(Full::new(bytes::Bytes::from(body.to_owned())))
+        .unwrap();
+    let response = client.request(request).await.unwrap();
+    let status_code = response.status().as_u16();
+    response.into_body().collect().await.unwrap();
+    status_code
+}
@@ -0,0 +1,206 @@
+use std::{path::PathBuf, time::Duration};
+
+use assert_matches::assert_matches;
+use fctools::{
+    extension::{
+        vsock_forward::{VsockForwardAddress, VsockForwardExt, VsockForwardOptions},
+        vsock_listener::{VsockListenerError, VsockListenerExt},
+        vsock_stream::{VsockStreamError, VsockStreamExt},
+    },
+    runtime::{tokio::TokioRuntime, Runtime},
+    testing::MOCK_VSOCK_ECHO_PORT,
+};
+use futures_util::{AsyncReadExt, AsyncWriteExt, StreamExt};
+use test_framework::{
+    get_mock_configuration, get_mock_executors, get_mock_vsock_configuration, prepare_mock_vm, shutdown_mock_vm,
+    MOCK_SOCKET_WAIT_TIMEOUT,
+};
+
+mod test_framework;
+
+#[tokio::test]
+async fn mock_vm_accepts_guest_initiated_vsock_connections() {
+    for executor in get_mock_executors(&[]) {
+        let mut vm = prepare_mock_vm(executor, get_mock_vsock_configuration()).await;
+        vm.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+
+        let uds_path = vm
+            .configuration()
+            .data()
+            .vsock_device
+            .as_ref()
+            .unwrap()
+            .uds
+            .effective_path()
+            .to_owned();
+        let listener = vm.vsock_listen(5000).await.unwrap();
+        assert_eq!(
+            listener.socket_path(),
+            PathBuf::from(format!("{}_5000", uds_path.display()))
+        );
+
+        // the VMM connects to the socket on behalf of the guest, so connecting to it directly emulates a guest
+        let mut guest_stream = TokioRuntime.unix_connect(listener.socket_path()).await.unwrap();
+        let mut host_stream = std::pin::pin!(listener.incoming()).next().await.unwrap().unwrap();
+        guest_stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        host_stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        let socket_path = listener.socket_path().to_owned();
+        listener.cleanup().await.unwrap();
+        assert!(!tokio::fs::try_exists(&socket_path).await.unwrap());
+        shutdown_mock_vm(&mut vm).await;
+    }
+}
+
+#[tokio::test]
+async fn mock_vm_cleanup_removes_vsock_listener_sockets() {
+    for executor in get_mock_executors(&[]) {
+        let mut vm = prepare_mock_vm(executor, get_mock_vsock_configuration()).await;
+        vm.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+
+        let listener = vm.vsock_listen(5000).await.unwrap();
+        let socket_path = listener.socket_path().to_owned();
+        drop(listener);
+        assert!(tokio::fs::try_exists(&socket_path).await.unwrap());
+
+        shutdown_mock_vm(&mut vm).await;
+        assert!(!tokio::fs::try_exists(&socket_path).await.unwrap());
+    }
+}
+
+#[tokio::test]
+async fn mock_vm_vsock_listener_requires_vsock_device() {
+    let mut vm = prepare_mock_vm(get_mock_executors(&[]).remove(0), get_mock_configuration()).await;
+    vm.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+    assert_matches!(
+        vm.vsock_listen(5000).await.err(),
+        Some(VsockListenerError::VsockNotConfigured)
+    );
+    shutdown_mock_vm(&mut vm).await;
+}
+
+#[tokio::test]
+async fn mock_vm_can_make_raw_vsock_connections() {
+    for executor in get_mock_executors(&[]) {
+        let mut vm = prepare_mock_vm(executor, get_mock_vsock_configuration()).await;
+        vm.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+
+        let mut stream = vm.vsock_connect(MOCK_VSOCK_ECHO_PORT).await.unwrap();
+        assert_eq!(stream.guest_port(), MOCK_VSOCK_ECHO_PORT);
+        assert!(stream.host_port() >= 1 << 30);
+        stream.write_all(b"\x00\x01binary\n").await.unwrap();
+        let mut buf = [0; 9];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"\x00\x01binary\n");
+
+        let second_stream = vm
+            .vsock_connect_with_retry(
+                MOCK_VSOCK_ECHO_PORT,
+                Duration::from_millis(10),
+                MOCK_SOCKET_WAIT_TIMEOUT,
+            )
+            .await
+            .unwrap();
+        assert_ne!(second_stream.host_port(), stream.host_port());
+
+        assert_matches!(
+            vm.vsock_connect(MOCK_VSOCK_ECHO_PORT + 1).await,
+            Err(VsockStreamError::ConnectionRefused { guest_port }) if guest_port == MOCK_VSOCK_ECHO_PORT + 1
+        );
+        assert_matches!(
+            vm.vsock_connect_with_retry(MOCK_VSOCK_ECHO_PORT + 1, Duration::from_millis(50), Duration::from_secs(1))
+                .await,
+            Err(VsockStreamError::RetryTimedOut(err)) if matches!(*err, VsockStreamError::ConnectionRefused { .. })
+        );
+        shutdown_mock_vm(&mut vm).await;
+    }
+}
+
+#[tokio::test]
+async fn mock_vm_raw_vsock_connection_requires_vsock_device() {
+    let mut vm = prepare_mock_vm(get_mock_executors(&[]).remove(0), get_mock_configuration()).await;
+    vm.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+    assert_matches!(
+        vm.vsock_connect(MOCK_VSOCK_ECHO_PORT).await,
+        Err(VsockStreamError::VsockNotConfigured)
+    );
+    shutdown_mock_vm(&mut vm).await;
+}
+
+#[tokio::test]
+async fn mock_vm_vsock_port_can_be_forwarded_over_tcp() {
+    let mut vm = prepare_mock_vm(get_mock_executors(&[]).remove(1), get_mock_vsock_configuration()).await;
+    vm.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+
+    let forwarder = vm
+        .vsock_forward(
+            VsockForwardOptions::new(
+                VsockForwardAddress::Tcp("127.0.0.1:0".parse().unwrap()),
+                MOCK_VSOCK_ECHO_PORT,
+            )
+            .max_connections(1),
+        )
+        .await
+        .unwrap();
+    let VsockForwardAddress::Tcp(address) = forwarder.local_address().clone() else {
+        panic!("The forwarder isn't bound to a TCP address");
+    };
+    assert_ne!(address.port(), 0);
+
+    let mut client = tokio::net::TcpStream::connect(address).await.unwrap();
+    tokio::io::AsyncWriteExt::write_all(&mut client, b"hello")
+        .await
+        .unwrap();
+    let mut buf = [0; 5];
+    tokio::io::AsyncReadExt::read_exact(&mut client, &mut buf)
+        .await
+        .unwrap();
+    assert_eq!(&buf, b"hello");
+
+    let mut rejected_client = tokio::net::TcpStream::connect(address).await.unwrap();
+    assert_eq!(
+        tokio::io::AsyncReadExt::read(&mut rejected_client, &mut buf)
+            .await
+            .unwrap(),
+        0
+    );
+
+    let stats = forwarder.stats();
+    assert_eq!(stats.forwarded_connections, 1);
+    assert_eq!(stats.rejected_connections, 1);
+    assert_eq!((stats.bytes_to_guest, stats.bytes_from_guest), (5, 5));
+    assert_eq!(stats.active_connections.len(), 1);
+    assert_eq!(stats.active_connections[0].bytes_from_guest, 5);
+
+    forwarder.stop(Duration::from_millis(100)).await.unwrap();
+    assert_eq!(tokio::io::AsyncReadExt::read(&mut client, &mut buf).await.unwrap(), 0);
+    tokio::net::TcpStream::connect(address).await.unwrap_err();
+    shutdown_mock_vm(&mut vm).await;
+}
+
+#[tokio::test]
+async fn mock_vm_vsock_port_can_be_forwarded_over_unix_socket() {
+    let mut vm = prepare_mock_vm(get_mock_executors(&[]).remove(0), get_mock_vsock_configuration()).await;
+    vm.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+
+    let socket_path = PathBuf::from(format!("/tmp/{}.sock", rand::random::<u32>()));
+    let forwarder = vm
+        .vsock_forward(VsockForwardOptions::new(
+            VsockForwardAddress::Unix(socket_path.clone()),
+            MOCK_VSOCK_ECHO_PORT + 1,
+        ))
+        .await
+        .unwrap();
+
+    // the guest port is refused, so the connection is closed after it's accepted
+    let mut client = TokioRuntime.unix_connect(&socket_path).await.unwrap();
+    assert_eq!(client.read(&mut [0; 8]).await.unwrap(), 0);
+    assert_eq!(forwarder.stats().failed_connections, 1);
+    assert!(forwarder.stats().active_connections.is_empty());
+
+    forwarder.stop(Duration::from_millis(100)).await.unwrap();
+    assert!(!tokio::fs::try_exists(&socket_path).await.unwrap());
+    shutdown_mock_vm(&mut vm).await;
+}
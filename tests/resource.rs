@@ -0,0 +1,496 @@
+use std::{
+    os::unix::fs::MetadataExt,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use assert_matches::assert_matches;
+use fctools::{
+    extension::{
+        resource_source::{
+            DigestResourceSource, HttpResourceSource, LocalResourceSource, ResourceSourceError, ResourceSourceUriError,
+        },
+        resource_store::{ResourceStore, ResourceStoreError, ResourceStoreLinkMethod},
+    },
+    process_spawner::DirectProcessSpawner,
+    runtime::{tokio::TokioRuntime, FastCopyStrategy, Runtime},
+    vmm::{
+        arguments::{jailer::JailerArguments, VmmApiSocket, VmmArguments},
+        executor::{
+            either::EitherVmmExecutor,
+            jailed::{FlatJailRenamer, JailedVmmExecutor},
+            unrestricted::UnrestrictedVmmExecutor,
+            VmmExecutor, VmmExecutorContext, VmmExecutorError,
+        },
+        id::VmmId,
+        ownership::VmmOwnershipModel,
+        resource::{
+            CreatedVmmResource, CreatedVmmResourceType, MovedVmmResource, VmmResourceError, VmmResourceJournal,
+            VmmResourceMoveMethod, VmmResourceOperation,
+        },
+    },
+};
+use test_framework::{
+    get_fake_firecracker_installation, get_mock_configuration, get_mock_executors, get_mock_file, get_tmp_path,
+    prepare_mock_vm, shutdown_mock_vm, TestVm, MOCK_SOCKET_WAIT_TIMEOUT,
+};
+
+mod test_framework;
+
+#[tokio::test]
+async fn mock_vm_resources_are_bind_mounted_into_jail_and_unmounted_on_cleanup() {
+    for executor in get_mock_executors(&[]).into_iter().skip(1) {
+        let mut configuration = get_mock_configuration();
+        let data = configuration.data_mut();
+        let kernel_path = get_mock_file();
+        let block_path = get_mock_file();
+        data.boot_source.kernel_image =
+            MovedVmmResource::new(&kernel_path, VmmResourceMoveMethod::BindMount { read_only: true });
+        data.drives[0].block = Some(MovedVmmResource::new(
+            &block_path,
+            VmmResourceMoveMethod::BindMount { read_only: false },
+        ));
+
+        // an upgrading ownership model must leave the owner of the read-only source untouched
+        std::os::unix::fs::chown(&kernel_path, Some(1), Some(1)).unwrap();
+
+        let mut vm = TestVm::prepare(
+            executor,
+            DirectProcessSpawner,
+            TokioRuntime,
+            VmmOwnershipModel::UpgradedPermanently,
+            Arc::new(get_fake_firecracker_installation()),
+            configuration,
+        )
+        .await
+        .unwrap();
+        let data = vm.configuration().data();
+        let kernel_effective_path = data.boot_source.kernel_image.effective_path().to_owned();
+        let block_effective_path = data.drives[0].block.as_ref().unwrap().effective_path().to_owned();
+        assert_ne!(kernel_effective_path, kernel_path);
+        assert_eq!(std::fs::read(&kernel_effective_path).unwrap(), b"mock");
+        assert!(std::fs::write(&kernel_effective_path, b"changed").is_err());
+        std::fs::write(&block_effective_path, b"changed").unwrap();
+        assert_eq!(std::fs::read(&block_path).unwrap(), b"changed");
+
+        vm.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+        shutdown_mock_vm(&mut vm).await;
+
+        assert!(!kernel_effective_path.exists());
+        assert!(!block_effective_path.exists());
+        assert_eq!(std::fs::read(&kernel_path).unwrap(), b"mock");
+        assert_eq!(std::fs::read(&block_path).unwrap(), b"changed");
+        assert_eq!(std::fs::metadata(&kernel_path).unwrap().uid(), 1);
+    }
+}
+
+#[tokio::test]
+async fn failed_prepare_rolls_back_resource_initialization() {
+    let installation = Arc::new(get_fake_firecracker_installation());
+    let chroot_base_dir = get_tmp_path();
+    let logs_dir = get_tmp_path();
+    let executors = [
+        EitherVmmExecutor::Unrestricted(UnrestrictedVmmExecutor::new(
+            VmmArguments::new(VmmApiSocket::Enabled(get_tmp_path())).logs(CreatedVmmResource::new(
+                logs_dir.join("nested/logs"),
+                CreatedVmmResourceType::File,
+            )),
+        )),
+        EitherVmmExecutor::Jailed(JailedVmmExecutor::new(
+            VmmArguments::new(VmmApiSocket::Enabled(PathBuf::from("/api.sock")))
+                .logs(CreatedVmmResource::new("/logs", CreatedVmmResourceType::Fifo)),
+            JailerArguments::new(VmmId::new("rollback").unwrap()).chroot_base_dir(&chroot_base_dir),
+            FlatJailRenamer,
+        )),
+    ];
+
+    // a jail of another VMM sharing the chroot base directory must survive the rollback
+    let jails_dir = chroot_base_dir.join(installation.firecracker_path.file_name().unwrap());
+    let other_jail_file_path = jails_dir.join("other/root/kernel");
+    std::fs::create_dir_all(other_jail_file_path.parent().unwrap()).unwrap();
+    std::fs::write(&other_jail_file_path, b"mock").unwrap();
+
+    for mut executor in executors {
+        let kernel_path = get_mock_file();
+        let missing_block_path = get_tmp_path();
+        let mut configuration = get_mock_configuration();
+        let data = configuration.data_mut();
+        data.boot_source.kernel_image = MovedVmmResource::new(&kernel_path, VmmResourceMoveMethod::Rename);
+        data.drives[0].block = Some(MovedVmmResource::new(&missing_block_path, VmmResourceMoveMethod::Copy));
+
+        let context = VmmExecutorContext {
+            installation: installation.clone(),
+            process_spawner: DirectProcessSpawner,
+            runtime: TokioRuntime,
+            ownership_model: VmmOwnershipModel::Shared,
+        };
+        assert_matches!(
+            executor.prepare(context, configuration.resource_references()).await,
+            Err(VmmExecutorError::ResourceError(VmmResourceError::SourcePathMissing(path))) if path == missing_block_path
+        );
+
+        assert_eq!(std::fs::read(&kernel_path).unwrap(), b"mock");
+        assert!(!logs_dir.exists());
+        assert!(!jails_dir.join("rollback").exists());
+        assert_eq!(std::fs::read(&other_jail_file_path).unwrap(), b"mock");
+        std::fs::remove_file(kernel_path).unwrap();
+    }
+
+    std::fs::remove_dir_all(chroot_base_dir).unwrap();
+}
+
+#[tokio::test]
+async fn journal_rollback_undoes_renames_before_removing_directories() {
+    let source_path = get_mock_file();
+    let directory_path = get_tmp_path();
+    let destination_path = directory_path.join("nested/kernel");
+    std::fs::create_dir_all(destination_path.parent().unwrap()).unwrap();
+    std::fs::rename(&source_path, &destination_path).unwrap();
+
+    // a concurrent initialization recorded the directories only after the rename into them
+    let journal = VmmResourceJournal::new();
+    journal.record(VmmResourceOperation::Renamed {
+        source_path: source_path.clone(),
+        destination_path,
+    });
+    journal.record(VmmResourceOperation::CreatedDirectory(directory_path.clone()));
+    journal.record(VmmResourceOperation::CreatedDirectory(directory_path.join("nested")));
+
+    journal
+        .roll_back(VmmOwnershipModel::Shared, &DirectProcessSpawner, &TokioRuntime)
+        .await
+        .unwrap();
+    assert!(journal.operations().is_empty());
+    assert_eq!(std::fs::read(&source_path).unwrap(), b"mock");
+    assert!(!directory_path.exists());
+    std::fs::remove_file(source_path).unwrap();
+}
+
+#[tokio::test]
+async fn fast_copy_preserves_holes_and_reports_strategy() {
+    let source_path = get_tmp_path();
+    let file = std::fs::File::create(&source_path).unwrap();
+    file.set_len(16 * 1024 * 1024).unwrap();
+    std::os::unix::fs::FileExt::write_all_at(&file, &[1; 4096], 0).unwrap();
+    std::os::unix::fs::FileExt::write_all_at(&file, &[2; 4096], 9 * 1024 * 1024).unwrap();
+    drop(file);
+
+    let destination_path = get_tmp_path();
+    let strategy = TokioRuntime
+        .fs_fast_copy(&source_path, &destination_path)
+        .await
+        .unwrap();
+    assert_matches!(strategy, FastCopyStrategy::Reflink | FastCopyStrategy::CopyFileRange);
+    assert_eq!(
+        std::fs::read(&destination_path).unwrap(),
+        std::fs::read(&source_path).unwrap()
+    );
+    assert!(std::fs::metadata(&destination_path).unwrap().blocks() * 512 < 1024 * 1024);
+
+    let effective_path = get_tmp_path();
+    let mut resource = MovedVmmResource::new(&source_path, VmmResourceMoveMethod::FastCopy);
+    resource
+        .initialize(
+            effective_path.clone(),
+            effective_path.clone(),
+            VmmOwnershipModel::Shared,
+            DirectProcessSpawner,
+            TokioRuntime,
+        )
+        .await
+        .unwrap();
+    assert_eq!(resource.copy_strategy(), Some(strategy));
+    assert_eq!(
+        std::fs::read(&effective_path).unwrap(),
+        std::fs::read(&source_path).unwrap()
+    );
+
+    let mut resource = MovedVmmResource::new(&source_path, VmmResourceMoveMethod::HardLinkOrFastCopy);
+    let linked_path = get_tmp_path();
+    resource
+        .initialize(
+            linked_path.clone(),
+            linked_path.clone(),
+            VmmOwnershipModel::Shared,
+            DirectProcessSpawner,
+            TokioRuntime,
+        )
+        .await
+        .unwrap();
+    assert_eq!(resource.copy_strategy(), None);
+
+    for path in [source_path, destination_path, effective_path, linked_path] {
+        std::fs::remove_file(path).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn resource_store_deduplicates_entries_and_collects_unreferenced_ones() {
+    let store = ResourceStore::open(
+        get_tmp_path(),
+        DirectProcessSpawner,
+        TokioRuntime,
+        VmmOwnershipModel::Shared,
+    )
+    .await
+    .unwrap();
+    let digest = store.ingest(get_mock_file()).await.unwrap();
+    assert_eq!(store.ingest(get_mock_file()).await.unwrap(), digest);
+    assert_eq!(store.digests().await.unwrap(), vec![digest.clone()]);
+    assert_matches!(
+        store
+            .acquire(
+                "../etc",
+                &VmmId::new("store-vm").unwrap(),
+                VmmOwnershipModel::Shared,
+                ResourceStoreLinkMethod::HardLink
+            )
+            .await,
+        Err(ResourceStoreError::InvalidDigest(_))
+    );
+
+    let first_id = VmmId::new(format!("store{}", rand::random::<u32>())).unwrap();
+    let second_id = VmmId::new(format!("store{}", rand::random::<u32>())).unwrap();
+    let mut configuration = get_mock_configuration();
+    assert_matches!(
+        store
+            .acquire(
+                &digest,
+                &first_id,
+                VmmOwnershipModel::Downgraded { uid: 1, gid: 1 },
+                ResourceStoreLinkMethod::HardLink
+            )
+            .await,
+        Err(ResourceStoreError::OwnershipModelMismatch(_))
+    );
+    configuration.data_mut().boot_source.kernel_image = store
+        .acquire(
+            &digest,
+            &first_id,
+            VmmOwnershipModel::Shared,
+            ResourceStoreLinkMethod::HardLink,
+        )
+        .await
+        .unwrap();
+    store
+        .acquire(
+            &digest,
+            &second_id,
+            VmmOwnershipModel::Downgraded { uid: 1, gid: 1 },
+            ResourceStoreLinkMethod::Reflink,
+        )
+        .await
+        .unwrap();
+    assert_eq!(store.references(&digest).await.unwrap().len(), 2);
+
+    let entry_path = store.entry_path(&digest).unwrap();
+    let mut vm = prepare_mock_vm(get_mock_executors(&[]).remove(1), configuration).await;
+    let kernel_path = vm.configuration().data().boot_source.kernel_image.effective_path();
+    assert_eq!(
+        std::fs::metadata(kernel_path).unwrap().ino(),
+        std::fs::metadata(&entry_path).unwrap().ino()
+    );
+    vm.start(MOCK_SOCKET_WAIT_TIMEOUT).await.unwrap();
+    shutdown_mock_vm(&mut vm).await;
+
+    store.release(&first_id).await.unwrap();
+    assert_eq!(store.references(&digest).await.unwrap(), vec![second_id.clone()]);
+    assert!(store.collect_garbage().await.unwrap().removed_digests.is_empty());
+
+    store.release(&second_id).await.unwrap();
+    let report = store.collect_garbage().await.unwrap();
+    assert_eq!(report.removed_digests, vec![digest.clone()]);
+    assert_eq!(report.removed_bytes, 4);
+    assert!(!entry_path.exists());
+    assert_matches!(
+        store
+            .acquire(
+                &digest,
+                &first_id,
+                VmmOwnershipModel::Shared,
+                ResourceStoreLinkMethod::HardLink
+            )
+            .await,
+        Err(ResourceStoreError::NotFound(_))
+    );
+    assert!(store.references(&digest).await.unwrap().is_empty());
+
+    std::fs::remove_dir_all(store.directory()).unwrap();
+}
+
+#[tokio::test]
+async fn resources_are_resolved_from_http_file_and_digest_sources() {
+    const CONTENT: &[u8] = b"kernel served over http";
+    let request_count = Arc::new(AtomicUsize::new(0));
+    let address = serve_http_resource(CONTENT, request_count.clone()).await;
+    let url = format!("http://{address}/redirect");
+
+    let content_path = get_tmp_path();
+    std::fs::write(&content_path, CONTENT).unwrap();
+    let digest = ResourceStore::open(
+        get_tmp_path(),
+        DirectProcessSpawner,
+        TokioRuntime,
+        VmmOwnershipModel::Shared,
+    )
+    .await
+    .unwrap()
+    .ingest(&content_path)
+    .await
+    .unwrap();
+
+    let cache_directory = get_tmp_path();
+    let mut resources = (0..3)
+        .map(|_| {
+            MovedVmmResource::from_source(
+                HttpResourceSource::new(&url, &cache_directory, TokioRuntime)
+                    .unwrap()
+                    .sha256(&digest),
+                VmmResourceMoveMethod::Copy,
+            )
+        })
+        .collect::<Vec<_>>();
+    futures_util::future::try_join_all(resources.iter_mut().map(|resource| {
+        let effective_path = get_tmp_path();
+        resource.initialize(
+            effective_path.clone(),
+            effective_path,
+            VmmOwnershipModel::Shared,
+            DirectProcessSpawner,
+            TokioRuntime,
+        )
+    }))
+    .await
+    .unwrap();
+    assert_eq!(request_count.load(Ordering::SeqCst), 1);
+    for resource in resources {
+        assert_eq!(resource.source_path(), cache_directory.join(&digest));
+        assert_eq!(std::fs::read(resource.effective_path()).unwrap(), CONTENT);
+    }
+
+    let mismatched_cache_directory = get_tmp_path();
+    let mut resource = MovedVmmResource::from_source(
+        HttpResourceSource::new(
+            format!("http://{address}/kernel"),
+            &mismatched_cache_directory,
+            TokioRuntime,
+        )
+        .unwrap()
+        .sha256("0".repeat(64)),
+        VmmResourceMoveMethod::Copy,
+    );
+    assert_matches!(
+        resource
+            .initialize_with_same_path(VmmOwnershipModel::Shared, DirectProcessSpawner, TokioRuntime)
+            .await,
+        Err(VmmResourceError::SourceResolutionFailed(err))
+            if matches!(err.downcast_ref(), Some(ResourceSourceError::ChecksumMismatch { .. }))
+    );
+    assert_eq!(std::fs::read_dir(&mismatched_cache_directory).unwrap().count(), 0);
+    assert_eq!(request_count.load(Ordering::SeqCst), 2);
+
+    let mut resource = MovedVmmResource::from_source(
+        HttpResourceSource::new(format!("http://{address}/missing"), get_tmp_path(), TokioRuntime).unwrap(),
+        VmmResourceMoveMethod::Copy,
+    );
+    assert_matches!(
+        resource
+            .initialize_with_same_path(VmmOwnershipModel::Shared, DirectProcessSpawner, TokioRuntime)
+            .await,
+        Err(VmmResourceError::SourceResolutionFailed(err))
+            if matches!(err.downcast_ref(), Some(ResourceSourceError::Other(_)))
+    );
+
+    let mut resource = MovedVmmResource::from_source(
+        LocalResourceSource::from_file_uri(format!("file://{}", content_path.display()), TokioRuntime)
+            .unwrap()
+            .sha256(&digest),
+        VmmResourceMoveMethod::Copy,
+    );
+    resource
+        .initialize_with_same_path(VmmOwnershipModel::Shared, DirectProcessSpawner, TokioRuntime)
+        .await
+        .unwrap();
+    assert_eq!(resource.effective_path(), content_path);
+    assert_matches!(
+        LocalResourceSource::from_file_uri(&url, TokioRuntime),
+        Err(ResourceSourceUriError::UnsupportedScheme(_))
+    );
+
+    let store = Arc::new(
+        ResourceStore::open(
+            get_tmp_path(),
+            DirectProcessSpawner,
+            TokioRuntime,
+            VmmOwnershipModel::Shared,
+        )
+        .await
+        .unwrap(),
+    );
+    let vmm_id = VmmId::new(format!("source{}", rand::random::<u32>())).unwrap();
+    let mut resource = MovedVmmResource::from_source(
+        DigestResourceSource::new(store.clone(), &digest, vmm_id.clone())
+            .unwrap()
+            .fallback(HttpResourceSource::new(&url, get_tmp_path(), TokioRuntime).unwrap()),
+        VmmResourceMoveMethod::HardLink,
+    );
+    let effective_path = get_tmp_path();
+    resource
+        .initialize(
+            effective_path.clone(),
+            effective_path.clone(),
+            VmmOwnershipModel::Shared,
+            DirectProcessSpawner,
+            TokioRuntime,
+        )
+        .await
+        .unwrap();
+    assert_eq!(request_count.load(Ordering::SeqCst), 3);
+    assert_eq!(store.references(&digest).await.unwrap(), vec![vmm_id]);
+    assert_eq!(std::fs::read(&effective_path).unwrap(), CONTENT);
+}
+
+// serves the content at /kernel after a delay that lets concurrent requests overlap, with /redirect redirecting to it
+async fn serve_http_resource(content: &'static [u8], request_count: Arc<AtomicUsize>) -> std::net::SocketAddr {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let request_count = request_count.clone();
+
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let read = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..read]);
+                }
+
+                let mut response = if request.starts_with(b"GET /redirect ") {
+                    b"HTTP/1.1 302 Found\r\nLocation: /kernel\r\nContent-Length: 0\r\n\r\n".to_vec()
+                } else if request.starts_with(b"GET /kernel ") {
+                    request_count.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", content.len()).into_bytes()
+                } else {
+                    b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_vec()
+                };
+                if response.starts_with(b"HTTP/1.1 200") {
+                    response.extend_from_slice(content);
+                }
+
+                stream.write_all(&response).await.unwrap();
+            });
+        }
+    });
+
+    address
+}
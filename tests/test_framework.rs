@@ -13,6 +13,7 @@ use fctools::{
     extension::link_local::LinkLocalSubnet,
     process_spawner::{DirectProcessSpawner, ProcessSpawner},
     runtime::{tokio::TokioRuntime, Runtime},
+    testing::fault::{MockFault, MockFaultCommandModifier},
     vm::{
         configuration::{InitMethod, VmConfiguration, VmConfigurationData},
         models::{
@@ -20,6 +21,7 @@ use fctools::{
             MmdsConfiguration, MmdsVersion, NetworkInterface, SnapshotType, VsockDevice,
         },
         shutdown::{VmShutdownAction, VmShutdownMethod},
+        VmError,
     },
     vmm::{
         arguments::{
@@ -34,7 +36,7 @@ use fctools::{
         },
         installation::VmmInstallation,
         ownership::VmmOwnershipModel,
-        process::VmmProcessState,
+        process::{VmmProcessDescriptor, VmmProcessState},
         resource::{
             CreatedVmmResource, CreatedVmmResourceType, MovedVmmResource, ProducedVmmResource, VmmResourceMoveMethod,
             VmmResourceReferences,
@@ -96,7 +98,11 @@ impl TestOptions {
 
 #[allow(unused)]
 pub fn get_fake_firecracker_installation() -> VmmInstallation {
-    get_real_firecracker_installation()
+    VmmInstallation {
+        firecracker_path: PathBuf::from(env!("CARGO_BIN_EXE_fctools-fake-firecracker")),
+        jailer_path: PathBuf::from(env!("CARGO_BIN_EXE_fctools-fake-jailer")),
+        snapshot_editor_path: get_test_path("toolchain/snapshot-editor"),
+    }
 }
 
 pub fn get_test_path(path: &str) -> PathBuf {
@@ -141,6 +147,7 @@ pub fn get_process_spawner() -> Arc<impl ProcessSpawner> {
     Arc::new(DirectProcessSpawner)
 }
 
+#[allow(unused)]
 #[derive(Default, Clone)]
 pub struct FailingRunner;
 
@@ -163,7 +170,7 @@ pub type TestVmmProcess =
     fctools::vmm::process::VmmProcess<EitherVmmExecutor<FlatJailRenamer>, DirectProcessSpawner, TokioRuntime>;
 
 #[allow(unused)]
-pub fn get_resource_references_for_vec(resources: &mut Vec<MovedVmmResource>) -> VmmResourceReferences<'_> {
+pub fn get_resource_references_for_vec(resources: &mut [MovedVmmResource]) -> VmmResourceReferences<'_> {
     VmmResourceReferences {
         moved_resources: resources.iter_mut().collect(),
         created_resources: Vec::new(),
@@ -181,7 +188,7 @@ where
     async fn init_process(
         process: &mut TestVmmProcess,
         config_path: impl Into<PathBuf>,
-        resources: &mut Vec<MovedVmmResource>,
+        resources: &mut [MovedVmmResource],
     ) {
         process.wait_for_exit().await.unwrap_err();
         process.send_ctrl_alt_del().await.unwrap_err();
@@ -239,10 +246,10 @@ async fn get_vmm_processes(
     }
 
     let unrestricted_executor = UnrestrictedVmmExecutor::new(vmm_arguments.clone());
-    let jailed_executor = JailedVmmExecutor::new(vmm_arguments, jailer_arguments, FlatJailRenamer::default());
+    let jailed_executor = JailedVmmExecutor::new(vmm_arguments, jailer_arguments, FlatJailRenamer);
     let ownership_model = VmmOwnershipModel::Downgraded {
-        uid: TestOptions::get().await.jailer_uid.into(),
-        gid: TestOptions::get().await.jailer_gid.into(),
+        uid: TestOptions::get().await.jailer_uid,
+        gid: TestOptions::get().await.jailer_gid,
     };
 
     let mut jailed_resources = Vec::new();
@@ -314,6 +321,12 @@ pub struct VmBuilder {
     new_pid_ns: bool,
 }
 
+impl Default for VmBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[allow(unused)]
 impl VmBuilder {
     pub fn new() -> Self {
@@ -569,7 +582,7 @@ impl VmBuilder {
         let jailed_executor = EitherVmmExecutor::Jailed(JailedVmmExecutor::new(
             VmmArguments::new(VmmApiSocket::Enabled(socket_path)),
             jailer_arguments,
-            FlatJailRenamer::default(),
+            FlatJailRenamer,
         ));
 
         // add components from builder to data
@@ -733,6 +746,187 @@ pub async fn shutdown_test_vm(vm: &mut TestVm) {
     vm.cleanup().await.unwrap();
 }
 
+// MOCK VM TEST FRAMEWORK
+
+#[allow(unused)]
+pub const MOCK_SOCKET_WAIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[allow(unused)]
+pub fn get_mock_file() -> PathBuf {
+    let path = get_tmp_path();
+    std::fs::write(&path, b"mock").unwrap();
+    path
+}
+
+/// Get an unrestricted executor, a jailed executor and a daemonized jailed executor in a new PID namespace, all
+/// targeting the fake binaries and injecting the given faults.
+#[allow(unused)]
+pub fn get_mock_executors(faults: &[MockFault]) -> Vec<EitherVmmExecutor<FlatJailRenamer>> {
+    get_mock_executor_arguments()
+        .into_iter()
+        .map(|(vmm_arguments, jailer_arguments)| new_mock_executor(vmm_arguments, jailer_arguments, faults))
+        .collect()
+}
+
+/// Get the same set of executors as [get_mock_executors] without faults, but with each one paired with an equally
+/// configured executor that can be used to recover a VM prepared with the first one.
+#[allow(unused)]
+pub fn get_mock_executor_pairs() -> Vec<(EitherVmmExecutor<FlatJailRenamer>, EitherVmmExecutor<FlatJailRenamer>)> {
+    get_mock_executor_arguments()
+        .into_iter()
+        .map(|(vmm_arguments, jailer_arguments)| {
+            (
+                new_mock_executor(vmm_arguments.clone(), jailer_arguments.clone(), &[]),
+                new_mock_executor(vmm_arguments, jailer_arguments, &[]),
+            )
+        })
+        .collect()
+}
+
+fn get_mock_executor_arguments() -> Vec<(VmmArguments, Option<JailerArguments>)> {
+    // effective socket paths inside jails need to be kept short so that they don't exceed SUN_LEN
+    let new_vmm_arguments = || {
+        VmmArguments::new(VmmApiSocket::Enabled(PathBuf::from(format!(
+            "/tmp/{}.sock",
+            rand::thread_rng().next_u32()
+        ))))
+    };
+    let new_jailer_arguments = || {
+        JailerArguments::new(rand::thread_rng().next_u32().to_string().try_into().unwrap())
+            .chroot_base_dir(format!("/tmp/j{}", rand::thread_rng().next_u32()))
+    };
+
+    vec![
+        (new_vmm_arguments(), None),
+        (new_vmm_arguments(), Some(new_jailer_arguments())),
+        (
+            new_vmm_arguments(),
+            Some(new_jailer_arguments().daemonize().exec_in_new_pid_ns()),
+        ),
+    ]
+}
+
+fn new_mock_executor(
+    vmm_arguments: VmmArguments,
+    jailer_arguments: Option<JailerArguments>,
+    faults: &[MockFault],
+) -> EitherVmmExecutor<FlatJailRenamer> {
+    let fault_modifier = MockFaultCommandModifier::new(faults.to_vec());
+
+    match jailer_arguments {
+        Some(jailer_arguments) => EitherVmmExecutor::Jailed(
+            JailedVmmExecutor::new(vmm_arguments, jailer_arguments, FlatJailRenamer).command_modifier(fault_modifier),
+        ),
+        None => EitherVmmExecutor::Unrestricted(
+            UnrestrictedVmmExecutor::new(vmm_arguments).command_modifier(fault_modifier),
+        ),
+    }
+}
+
+#[allow(unused)]
+pub fn get_mock_configuration_data() -> VmConfigurationData {
+    VmConfigurationData {
+        boot_source: BootSource {
+            kernel_image: MovedVmmResource::new(get_mock_file(), VmmResourceMoveMethod::Copy),
+            boot_args: Some("console=ttyS0 reboot=k panic=1 pci=off".to_string()),
+            initrd: None,
+        },
+        drives: vec![Drive {
+            drive_id: "rootfs".to_string(),
+            is_root_device: true,
+            cache_type: None,
+            partuuid: None,
+            is_read_only: Some(false),
+            block: Some(MovedVmmResource::new(get_mock_file(), VmmResourceMoveMethod::Copy)),
+            rate_limiter: None,
+            io_engine: None,
+            socket: None,
+        }],
+        machine_configuration: MachineConfiguration {
+            vcpu_count: 1,
+            mem_size_mib: 128,
+            smt: None,
+            track_dirty_pages: Some(true),
+            huge_pages: None,
+        },
+        cpu_template: None,
+        network_interfaces: Vec::new(),
+        balloon_device: None,
+        vsock_device: None,
+        logger_system: None,
+        metrics_system: None,
+        mmds_configuration: None,
+        entropy_device: None,
+    }
+}
+
+#[allow(unused)]
+pub fn get_mock_configuration() -> VmConfiguration {
+    VmConfiguration::New {
+        init_method: InitMethod::ViaApiCalls,
+        data: get_mock_configuration_data(),
+    }
+}
+
+#[allow(unused)]
+pub fn get_mock_vsock_configuration() -> VmConfiguration {
+    let mut data = get_mock_configuration_data();
+    data.vsock_device = Some(VsockDevice {
+        guest_cid: 3,
+        uds: ProducedVmmResource::new(format!("/tmp/{}.v", rand::random::<u32>())),
+    });
+
+    VmConfiguration::New {
+        init_method: InitMethod::ViaApiCalls,
+        data,
+    }
+}
+
+#[allow(unused)]
+pub async fn prepare_mock_vm(executor: EitherVmmExecutor<FlatJailRenamer>, configuration: VmConfiguration) -> TestVm {
+    TestVm::prepare(
+        executor,
+        DirectProcessSpawner,
+        TokioRuntime,
+        VmmOwnershipModel::Shared,
+        Arc::new(get_fake_firecracker_installation()),
+        configuration,
+    )
+    .await
+    .unwrap()
+}
+
+#[allow(unused)]
+pub async fn recover_mock_vm(
+    executor: EitherVmmExecutor<FlatJailRenamer>,
+    configuration: VmConfiguration,
+    descriptor: &VmmProcessDescriptor,
+) -> Result<TestVm, VmError> {
+    TestVm::recover(
+        executor,
+        DirectProcessSpawner,
+        TokioRuntime,
+        Arc::new(get_fake_firecracker_installation()),
+        configuration,
+        descriptor,
+    )
+    .await
+}
+
+#[allow(unused)]
+pub async fn shutdown_mock_vm(vm: &mut TestVm) {
+    let outcome = vm
+        .shutdown([VmShutdownAction {
+            method: VmShutdownMethod::CtrlAltDel,
+            timeout: Some(MOCK_SOCKET_WAIT_TIMEOUT),
+            graceful: true,
+        }])
+        .await
+        .unwrap();
+    assert!(outcome.fully_graceful());
+    vm.cleanup().await.unwrap();
+}
+
 static NETWORK_LOCKING_MUTEX: Mutex<()> = Mutex::const_new(());
 
 #[allow(unused)]
@@ -746,7 +940,7 @@ async fn get_network_lock<'a>() -> NetworkLock<'a> {
     let file_lock = tokio::task::spawn_blocking(|| {
         let file_options = file_lock::FileOptions::new().write(true).create(true);
         let mut lock = file_lock::FileLock::lock("/tmp/fctools_test_net_lock", true, file_options).unwrap();
-        lock.file.write(b"lock_data").unwrap();
+        lock.file.write_all(b"lock_data").unwrap();
         lock
     })
     .await
@@ -0,0 +1,205 @@
+use std::time::Duration;
+
+use fctools::{
+    extension::uffd_handler::{UffdFaultStrategy, UffdHandler, UffdHandlerError, UffdHandlerOptions, UffdHandlerStats},
+    runtime::tokio::TokioRuntime,
+    vmm::ownership::VmmOwnershipModel,
+};
+use test_framework::get_tmp_path;
+
+mod test_framework;
+
+#[tokio::test]
+async fn uffd_handler_serves_faults_lazily_and_eagerly() {
+    const PAGE_SIZE: usize = 4096;
+    const PAGE_COUNT: usize = 1024;
+
+    // every third page is a zero page, the rest are filled with a per-page pattern
+    let mem_file = (0..PAGE_COUNT)
+        .flat_map(|page| match page % 3 {
+            0 => [0; PAGE_SIZE],
+            _ => [(page % 251 + 1) as u8; PAGE_SIZE],
+        })
+        .collect::<Vec<_>>();
+    let mem_file_path = get_tmp_path();
+    std::fs::write(&mem_file_path, &mem_file).unwrap();
+    let zero_page_count = PAGE_COUNT.div_ceil(3) as u64;
+    let data_page_count = PAGE_COUNT as u64 - zero_page_count;
+
+    for strategy in [UffdFaultStrategy::Lazy, UffdFaultStrategy::EagerPrefetch] {
+        let socket_path = get_tmp_path();
+        let handler = UffdHandler::bind(
+            &socket_path,
+            &mem_file_path,
+            VmmOwnershipModel::Shared,
+            TokioRuntime,
+            UffdHandlerOptions::new().strategy(strategy).detect_zero_pages(true),
+        )
+        .await
+        .unwrap();
+        assert_eq!(handler.socket().effective_path(), socket_path);
+
+        let guest_memory = MockGuestMemory::connect(&socket_path, PAGE_SIZE * PAGE_COUNT);
+        if strategy == UffdFaultStrategy::EagerPrefetch {
+            while handler.stats().prefetched_pages < data_page_count {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        }
+
+        let mem_file = mem_file.clone();
+        let guest_memory = tokio::task::spawn_blocking(move || {
+            assert!(guest_memory.read() == mem_file);
+            // removing a data page makes the next fault on it be served as a zero page
+            guest_memory.remove(PAGE_SIZE, PAGE_SIZE);
+            assert!(guest_memory.read()[PAGE_SIZE..2 * PAGE_SIZE]
+                .iter()
+                .all(|byte| *byte == 0));
+            guest_memory
+        })
+        .await
+        .unwrap();
+
+        let (page_faults, prefetched_pages) = match strategy {
+            UffdFaultStrategy::Lazy => (PAGE_COUNT as u64 + 1, 0),
+            UffdFaultStrategy::EagerPrefetch => (zero_page_count + 1, data_page_count),
+        };
+        assert_eq!(
+            handler.stats(),
+            UffdHandlerStats {
+                page_faults,
+                copied_pages: data_page_count,
+                zero_pages: zero_page_count + 1,
+                prefetched_pages,
+                removed_ranges: 1,
+            }
+        );
+
+        drop(guest_memory);
+        handler.join().await.unwrap();
+        assert!(!socket_path.exists());
+    }
+}
+
+#[tokio::test]
+async fn uffd_handler_rejects_empty_mem_file() {
+    let mem_file_path = get_tmp_path();
+    std::fs::write(&mem_file_path, b"").unwrap();
+    let socket_path = get_tmp_path();
+
+    let result = UffdHandler::bind(
+        &socket_path,
+        &mem_file_path,
+        VmmOwnershipModel::Shared,
+        TokioRuntime,
+        UffdHandlerOptions::new(),
+    )
+    .await;
+    assert!(matches!(result.err(), Some(UffdHandlerError::EmptyMemFile)));
+    assert!(!socket_path.exists());
+    std::fs::remove_file(mem_file_path).unwrap();
+}
+
+// acts as Firecracker by registering anonymous guest memory with a new userfaultfd and sending both to the handler
+struct MockGuestMemory {
+    _stream: std::os::unix::net::UnixStream,
+    _uffd: std::os::fd::OwnedFd,
+    address: usize,
+    size: usize,
+}
+
+impl MockGuestMemory {
+    fn connect(socket_path: &std::path::Path, size: usize) -> Self {
+        use nix::libc;
+        use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+        #[repr(C)]
+        struct UffdioApi {
+            api: u64,
+            features: u64,
+            ioctls: u64,
+        }
+
+        #[repr(C)]
+        struct UffdioRegister {
+            start: u64,
+            len: u64,
+            mode: u64,
+            ioctls: u64,
+        }
+
+        let uffd = unsafe { libc::syscall(libc::SYS_userfaultfd, libc::O_CLOEXEC | libc::O_NONBLOCK) };
+        assert!(uffd >= 0, "userfaultfd failed: {}", std::io::Error::last_os_error());
+        let uffd = unsafe { OwnedFd::from_raw_fd(uffd as i32) };
+
+        // UFFDIO_API with UFFD_FEATURE_EVENT_REMOVE
+        let mut api = UffdioApi {
+            api: 0xaa,
+            features: 1 << 3,
+            ioctls: 0,
+        };
+        assert_eq!(unsafe { libc::ioctl(uffd.as_raw_fd(), 0xc018aa3f, &mut api) }, 0);
+
+        let address = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(address, libc::MAP_FAILED);
+
+        // UFFDIO_REGISTER with UFFDIO_REGISTER_MODE_MISSING
+        let mut register = UffdioRegister {
+            start: address as u64,
+            len: size as u64,
+            mode: 1,
+            ioctls: 0,
+        };
+        assert_eq!(unsafe { libc::ioctl(uffd.as_raw_fd(), 0xc020aa00, &mut register) }, 0);
+
+        let mappings = format!(
+            r#"[{{"base_host_virt_addr":{},"size":{size},"offset":0,"page_size_kib":4}}]"#,
+            address as u64
+        );
+        let stream = std::os::unix::net::UnixStream::connect(socket_path).unwrap();
+        nix::sys::socket::sendmsg::<()>(
+            stream.as_raw_fd(),
+            &[std::io::IoSlice::new(mappings.as_bytes())],
+            &[nix::sys::socket::ControlMessage::ScmRights(&[uffd.as_raw_fd()])],
+            nix::sys::socket::MsgFlags::empty(),
+            None,
+        )
+        .unwrap();
+
+        Self {
+            _stream: stream,
+            _uffd: uffd,
+            address: address as usize,
+            size,
+        }
+    }
+
+    fn read(&self) -> Vec<u8> {
+        unsafe { std::slice::from_raw_parts(self.address as *const u8, self.size) }.to_vec()
+    }
+
+    fn remove(&self, offset: usize, size: usize) {
+        let ret = unsafe {
+            nix::libc::madvise(
+                (self.address + offset) as *mut nix::libc::c_void,
+                size,
+                nix::libc::MADV_DONTNEED,
+            )
+        };
+        assert_eq!(ret, 0);
+    }
+}
+
+impl Drop for MockGuestMemory {
+    fn drop(&mut self) {
+        unsafe { nix::libc::munmap(self.address as *mut nix::libc::c_void, self.size) };
+    }
+}
@@ -287,7 +287,7 @@ async fn restore_vm_from_snapshot(snapshot: VmSnapshot, is_jailed: bool) {
         true => EitherVmmExecutor::Jailed(JailedVmmExecutor::new(
             VmmArguments::new(VmmApiSocket::Enabled(get_tmp_path())),
             JailerArguments::new(rand::thread_rng().next_u32().to_string().try_into().unwrap()),
-            FlatJailRenamer::default(),
+            FlatJailRenamer,
         )),
         false => EitherVmmExecutor::Unrestricted(UnrestrictedVmmExecutor::new(VmmArguments::new(
             VmmApiSocket::Enabled(get_tmp_path()),